@@ -0,0 +1,128 @@
+//! Measures order entry through the whole exchange: holds, matching,
+//! settlement and the transactions each trade records
+//!
+//! Run with `cargo bench --bench exchange`.
+
+use blockchain_exchange::amount::Amount;
+use blockchain_exchange::exchange::Exchange;
+use blockchain_exchange::order::{OrderSide, TradingPair};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+
+/// An exchange with a funded buyer and seller, and the buyer's and seller's addresses
+fn funded_exchange() -> (Exchange, String, String) {
+    let mut exchange = Exchange::new("BenchExchange");
+    let buyer = exchange.create_wallet("Buyer");
+    let seller = exchange.create_wallet("Seller");
+    exchange
+        .deposit(&buyer, "USDT", Amount::from(1_000_000_000))
+        .unwrap();
+    exchange
+        .deposit(&seller, "BTC", Amount::from(1_000_000))
+        .unwrap();
+    (exchange, buyer, seller)
+}
+
+/// Places `n` sell orders spread over 100 price levels above 50,000
+fn place_asks(exchange: &mut Exchange, seller: &str, n: usize) {
+    let pair = TradingPair::new("BTC", "USDT");
+    for i in 0..n {
+        let price = 50_001 + (i as i64 * 7919) % 100;
+        exchange
+            .place_order(
+                seller.to_string(),
+                pair.clone(),
+                OrderSide::Sell,
+                Amount::from(price),
+                Amount::from(1),
+            )
+            .unwrap();
+    }
+}
+
+fn bench_place_resting(c: &mut Criterion) {
+    let mut group = c.benchmark_group("exchange_place_resting");
+    group.sample_size(10);
+    for n in [1_000, 10_000] {
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, &n| {
+            b.iter_batched(
+                funded_exchange,
+                |(mut exchange, _, seller)| {
+                    place_asks(&mut exchange, &seller, n);
+                    exchange
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_place_matching(c: &mut Criterion) {
+    let mut group = c.benchmark_group("exchange_place_matching");
+    group.sample_size(10);
+    let pair = TradingPair::new("BTC", "USDT");
+    for n in [100, 1_000] {
+        // Each buy takes the best ask, so every order trades and settles
+        let setup = || {
+            let (mut exchange, buyer, seller) = funded_exchange();
+            place_asks(&mut exchange, &seller, n);
+            (exchange, buyer)
+        };
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, &n| {
+            b.iter_batched(
+                setup,
+                |(mut exchange, buyer)| {
+                    for _ in 0..n {
+                        let order = exchange.place_order(
+                            buyer.clone(),
+                            pair.clone(),
+                            OrderSide::Buy,
+                            Amount::from(50_100),
+                            Amount::from(1),
+                        );
+                        black_box(order.unwrap());
+                    }
+                    exchange
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_cancel(c: &mut Criterion) {
+    let mut group = c.benchmark_group("exchange_cancel");
+    group.sample_size(10);
+    let pair = TradingPair::new("BTC", "USDT");
+    for n in [1_000, 10_000] {
+        let setup = || {
+            let (mut exchange, _, seller) = funded_exchange();
+            place_asks(&mut exchange, &seller, n);
+            let book = exchange.get_order_book(&pair).unwrap();
+            let ids: Vec<String> = book.sell_orders().step_by(10).map(|o| o.id.clone()).collect();
+            (exchange, ids)
+        };
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, _| {
+            b.iter_batched(
+                setup,
+                |(mut exchange, ids)| {
+                    for id in ids.iter().rev() {
+                        exchange.cancel_order(id, &pair).unwrap();
+                    }
+                    exchange
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_place_resting,
+    bench_place_matching,
+    bench_cancel
+);
+criterion_main!(benches);
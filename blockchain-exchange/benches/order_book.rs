@@ -0,0 +1,154 @@
+//! Compares the price-level order book with the previous sorted-`Vec` book
+//!
+//! Run with `cargo bench --bench order_book`.
+
+use blockchain_exchange::amount::Amount;
+use blockchain_exchange::order::{Order, OrderBook, OrderSide, TradingPair};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+/// The order book as it was before price levels: one `Vec` per side, re-sorted
+/// on every insert and scanned for lookups
+mod legacy {
+    use super::*;
+
+    pub struct OrderBook {
+        pub buy_orders: Vec<Order>,
+        pub sell_orders: Vec<Order>,
+    }
+
+    impl OrderBook {
+        pub fn new() -> Self {
+            OrderBook {
+                buy_orders: vec![],
+                sell_orders: vec![],
+            }
+        }
+
+        pub fn add_order(&mut self, order: Order) {
+            match order.side {
+                OrderSide::Buy => {
+                    self.buy_orders.push(order);
+                    self.buy_orders
+                        .sort_by(|a, b| b.price.cmp(&a.price).then(a.timestamp.cmp(&b.timestamp)));
+                }
+                OrderSide::Sell => {
+                    self.sell_orders.push(order);
+                    self.sell_orders
+                        .sort_by(|a, b| a.price.cmp(&b.price).then(a.timestamp.cmp(&b.timestamp)));
+                }
+            }
+        }
+
+        pub fn cancel_order(&mut self, order_id: &str) {
+            if let Some(order) = self
+                .buy_orders
+                .iter_mut()
+                .chain(self.sell_orders.iter_mut())
+                .find(|o| o.id == order_id)
+            {
+                order.cancel();
+            }
+            self.buy_orders.retain(|o| o.is_active());
+            self.sell_orders.retain(|o| o.is_active());
+        }
+
+        pub fn best_bid(&self) -> Option<Amount> {
+            self.buy_orders.iter().filter(|o| o.is_active()).map(|o| o.price).next()
+        }
+    }
+}
+
+/// Builds `n` resting orders spread over 100 price levels on each side
+fn orders(n: usize) -> Vec<Order> {
+    let pair = TradingPair::new("BTC", "USDT");
+    (0..n)
+        .map(|i| {
+            let (side, price) = if i % 2 == 0 {
+                (OrderSide::Buy, 50_000 - (i as i64 * 7919) % 100)
+            } else {
+                (OrderSide::Sell, 50_001 + (i as i64 * 7919) % 100)
+            };
+            Order::new(
+                format!("user{}", i),
+                pair.clone(),
+                side,
+                Amount::from(price),
+                Amount::from(1),
+            )
+        })
+        .collect()
+}
+
+fn bench_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    for n in [1_000, 10_000] {
+        let input = orders(n);
+        group.bench_with_input(BenchmarkId::new("price_levels", n), &input, |b, input| {
+            b.iter(|| {
+                let mut book = OrderBook::new(TradingPair::new("BTC", "USDT"));
+                for order in input.iter().cloned() {
+                    book.add_order(order);
+                }
+                black_box(book.best_bid())
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("legacy_vec", n), &input, |b, input| {
+            b.iter(|| {
+                let mut book = legacy::OrderBook::new();
+                for order in input.iter().cloned() {
+                    book.add_order(order);
+                }
+                black_box(book.best_bid())
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_cancel(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cancel");
+    for n in [1_000, 10_000] {
+        let input = orders(n);
+        // Cancel every tenth order, in an order unrelated to book position
+        let ids: Vec<String> = input.iter().step_by(10).rev().map(|o| o.id.clone()).collect();
+
+        let mut book = OrderBook::new(TradingPair::new("BTC", "USDT"));
+        let mut old_book = legacy::OrderBook::new();
+        for order in input.iter().cloned() {
+            book.add_order(order.clone());
+            old_book.add_order(order);
+        }
+
+        group.bench_with_input(BenchmarkId::new("price_levels", n), &ids, |b, ids| {
+            b.iter_batched(
+                || book.clone(),
+                |mut book| {
+                    for id in ids {
+                        book.remove_order(id);
+                    }
+                    book
+                },
+                criterion::BatchSize::LargeInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("legacy_vec", n), &ids, |b, ids| {
+            b.iter_batched(
+                || legacy::OrderBook {
+                    buy_orders: old_book.buy_orders.clone(),
+                    sell_orders: old_book.sell_orders.clone(),
+                },
+                |mut book| {
+                    for id in ids {
+                        book.cancel_order(id);
+                    }
+                    book
+                },
+                criterion::BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_insert, bench_cancel);
+criterion_main!(benches);
@@ -0,0 +1,326 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+/// Fixed-point decimal amount used for balances, prices and quantities
+///
+/// Amounts are exact base-10 values, so sums and comparisons never drift the
+/// way `f64` does. They serialize as strings to keep JSON (and therefore block
+/// hashes) lossless.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(Decimal);
+
+/// Rounding rule applied when an amount has to be cut to an asset's scale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// Towards zero; used for amounts the exchange pays out
+    Down,
+    /// Away from zero; used for amounts the exchange collects
+    Up,
+}
+
+impl Amount {
+    pub const ZERO: Amount = Amount(Decimal::ZERO);
+
+    /// Largest price, quantity or transfer accepted from outside, one trillion
+    ///
+    /// Products and sums of amounts this size stay far below what the
+    /// underlying decimal can hold.
+    pub const MAX: Amount = Amount(Decimal::from_parts(0xD4A5_1000, 0xE8, 0, false, 0));
+
+    /// Creates an amount from an integer mantissa and a number of decimal places,
+    /// e.g. `Amount::new(15, 1)` is `1.5`
+    pub fn new(mantissa: i64, scale: u32) -> Self {
+        Amount(Decimal::new(mantissa, scale))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    /// Returns true if the amount is strictly greater than zero
+    pub fn is_positive(&self) -> bool {
+        self.0 > Decimal::ZERO
+    }
+
+    /// Number of significant decimal places (trailing zeros are ignored)
+    pub fn decimal_places(&self) -> u32 {
+        self.0.normalize().scale()
+    }
+
+    /// Rounds the amount to the given number of decimal places
+    pub fn round_to(self, scale: u32, rounding: Rounding) -> Self {
+        let strategy = match rounding {
+            Rounding::Down => RoundingStrategy::ToZero,
+            Rounding::Up => RoundingStrategy::AwayFromZero,
+        };
+        Amount(self.0.round_dp_with_strategy(scale, strategy))
+    }
+
+    /// Sum of two amounts, or an error if it does not fit
+    pub fn checked_add(self, rhs: Amount) -> Result<Amount, String> {
+        self.0
+            .checked_add(rhs.0)
+            .map(Amount)
+            .ok_or_else(|| format!("Amount overflow adding {} and {}", self, rhs))
+    }
+
+    /// Difference of two amounts, or an error if it does not fit
+    pub fn checked_sub(self, rhs: Amount) -> Result<Amount, String> {
+        self.0
+            .checked_sub(rhs.0)
+            .map(Amount)
+            .ok_or_else(|| format!("Amount overflow subtracting {} from {}", rhs, self))
+    }
+
+    /// Exact product of two amounts, or an error if it does not fit
+    pub fn checked_mul(self, rhs: Amount) -> Result<Amount, String> {
+        self.0
+            .checked_mul(rhs.0)
+            .map(Amount)
+            .ok_or_else(|| format!("Amount overflow multiplying {} by {}", self, rhs))
+    }
+
+    /// Multiplies two amounts and rounds the product to the given scale
+    ///
+    /// This is how quote amounts (`price * quantity`) are derived.
+    pub fn mul_round(self, rhs: Amount, scale: u32, rounding: Rounding) -> Self {
+        (self * rhs).round_to(scale, rounding)
+    }
+
+    /// Divides two amounts and rounds the quotient to the given scale
+    pub fn div_round(self, rhs: Amount, scale: u32, rounding: Rounding) -> Self {
+        Amount(self.0 / rhs.0).round_to(scale, rounding)
+    }
+}
+
+impl From<i64> for Amount {
+    fn from(value: i64) -> Self {
+        Amount(Decimal::from(value))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Decimal::from_str(s.trim())
+            .map(Amount)
+            .map_err(|_| format!("Invalid amount: {}", s))
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Panics on overflow; use [`Amount::checked_add`] on amounts that are not
+/// known to be bounded by [`Amount::MAX`]
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, rhs: Amount) -> Amount {
+        Amount(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, rhs: Amount) -> Amount {
+        Amount(self.0 - rhs.0)
+    }
+}
+
+/// Exact product; round with [`Amount::round_to`] before crediting a balance
+///
+/// Panics on overflow; use [`Amount::checked_mul`] on amounts that are not
+/// known to be bounded by [`Amount::MAX`].
+impl Mul for Amount {
+    type Output = Amount;
+
+    fn mul(self, rhs: Amount) -> Amount {
+        Amount(self.0 * rhs.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, rhs: Amount) {
+        self.0 += rhs.0;
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, rhs: Amount) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Neg for Amount {
+    type Output = Amount;
+
+    fn neg(self) -> Amount {
+        Amount(-self.0)
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I: Iterator<Item = Amount>>(iter: I) -> Amount {
+        iter.fold(Amount::ZERO, Add::add)
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AmountVisitor;
+
+        impl de::Visitor<'_> for AmountVisitor {
+            type Value = Amount;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a decimal string or an integer")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Amount, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Amount, E> {
+                Ok(Amount::from(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Amount, E> {
+                Ok(Amount(Decimal::from(v)))
+            }
+        }
+
+        deserializer.deserialize_any(AmountVisitor)
+    }
+}
+
+/// Number of decimal places allowed for each asset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssetScales {
+    scales: HashMap<String, u32>,
+}
+
+impl AssetScales {
+    /// Scale used for assets that have not been configured explicitly
+    pub const DEFAULT_SCALE: u32 = 8;
+
+    pub fn new() -> Self {
+        AssetScales {
+            scales: HashMap::new(),
+        }
+    }
+
+    /// Sets the number of decimal places for an asset
+    pub fn set_scale(&mut self, asset: &str, scale: u32) {
+        self.scales.insert(asset.to_string(), scale);
+    }
+
+    /// Gets the number of decimal places for an asset
+    pub fn scale(&self, asset: &str) -> u32 {
+        *self.scales.get(asset).unwrap_or(&Self::DEFAULT_SCALE)
+    }
+
+    /// Rejects amounts that are more precise than the asset allows or larger
+    /// than [`Amount::MAX`]
+    pub fn validate(&self, asset: &str, amount: Amount) -> Result<(), String> {
+        if amount > Amount::MAX {
+            return Err(format!(
+                "{} amount {} is above the limit of {}",
+                asset, amount, Amount::MAX
+            ));
+        }
+        let scale = self.scale(asset);
+        if amount.decimal_places() > scale {
+            return Err(format!(
+                "{} amounts support at most {} decimal places, got {}",
+                asset, scale, amount
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for AssetScales {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amt(s: &str) -> Amount {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_exact_arithmetic() {
+        let sum = amt("0.1") + amt("0.2");
+        assert_eq!(sum, amt("0.3"));
+        assert_eq!(amt("1.0") - amt("0.7") - amt("0.3"), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        assert_eq!(Amount::MAX, Amount::from(1_000_000_000_000));
+        assert_eq!(amt("0.1").checked_add(amt("0.2")), Ok(amt("0.3")));
+        assert_eq!(amt("1").checked_sub(amt("3")), Ok(amt("-2")));
+        assert_eq!(Amount::MAX.checked_mul(Amount::MAX), Ok(amt("1000000000000000000000000")));
+
+        let huge = amt("79228162514264337593543950335");
+        assert!(huge.checked_add(Amount::from(1)).is_err());
+        assert!((-huge).checked_sub(Amount::from(1)).is_err());
+        assert!(huge.checked_mul(Amount::from(2)).is_err());
+    }
+
+    #[test]
+    fn test_mul_round() {
+        let price = amt("33.333333");
+        let quantity = amt("0.3");
+        assert_eq!(price.mul_round(quantity, 2, Rounding::Down), amt("9.99"));
+        assert_eq!(price.mul_round(quantity, 2, Rounding::Up), amt("10.00"));
+        assert_eq!(amt("10").div_round(amt("3"), 4, Rounding::Down), amt("3.3333"));
+        assert_eq!(amt("10").div_round(amt("3"), 4, Rounding::Up), amt("3.3334"));
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let value = amt("1234.50");
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(json, "\"1234.50\"");
+        let back: Amount = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, value);
+        let from_int: Amount = serde_json::from_str("5").unwrap();
+        assert_eq!(from_int, Amount::from(5));
+    }
+
+    #[test]
+    fn test_asset_scale_validation() {
+        let mut scales = AssetScales::new();
+        scales.set_scale("USDT", 2);
+        assert!(scales.validate("USDT", amt("10.25")).is_ok());
+        assert!(scales.validate("USDT", amt("10.250")).is_ok());
+        assert!(scales.validate("USDT", amt("10.255")).is_err());
+        assert!(scales.validate("USDT", Amount::MAX).is_ok());
+        let above = Amount::MAX + amt("0.01");
+        assert!(scales.validate("USDT", above).unwrap_err().contains("limit"));
+        assert_eq!(scales.scale("XYZ"), AssetScales::DEFAULT_SCALE);
+    }
+}
@@ -0,0 +1,979 @@
+use axum::extract::{FromRef, Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::depth::DepthSnapshot;
+use crate::exchange::Exchange;
+use crate::keys;
+use crate::log::{self, Log};
+use crate::market_data::MarketEvent;
+use crate::merkle::InclusionProof;
+use crate::order::{MarketOrderSize, Order, OrderOptions, OrderSide, Trade, TradingPair};
+use crate::p2p::Node;
+use crate::target::Target;
+use crate::transaction::Transaction;
+use crate::user_data::UserEvent;
+use crate::wallet::Balance;
+use crate::ws;
+
+/// An exchange shared between request handlers
+///
+/// Every request locks the whole exchange, so requests are applied one at a
+/// time in the order they take the lock.
+pub type SharedExchange = Arc<Mutex<Exchange>>;
+
+/// Market data events kept for WebSocket clients that fall behind
+const MARKET_DATA_BUFFER: usize = 1024;
+
+/// Account events kept for WebSocket clients that fall behind
+const USER_DATA_BUFFER: usize = 1024;
+
+/// API keys mapped to the wallet address they act for
+///
+/// Keys are issued when a wallet is created through the API and are kept in
+/// memory only, so they do not survive a restart.
+type ApiKeys = Arc<Mutex<HashMap<String, String>>>;
+
+/// Broadcasts the events of a shared exchange to every connection that streams them
+///
+/// The HTTP API and the FIX gateway both take events from the exchange, so
+/// when they serve the same exchange they must share one hub.
+#[derive(Clone)]
+pub struct EventHub {
+    /// Market data events, published while the exchange is locked
+    pub(crate) market_data: broadcast::Sender<MarketEvent>,
+    /// Account events of every wallet, published while the exchange is locked
+    pub(crate) user_data: broadcast::Sender<UserEvent>,
+    /// Chain node the exchange's transactions are submitted to, if any
+    node: Option<Node>,
+    /// Where transactions the node refuses are reported
+    log: Log,
+}
+
+impl EventHub {
+    pub fn new() -> Self {
+        let (market_data, _) = broadcast::channel(MARKET_DATA_BUFFER);
+        let (user_data, _) = broadcast::channel(USER_DATA_BUFFER);
+        EventHub {
+            market_data,
+            user_data,
+            node: None,
+            log: log::discard(),
+        }
+    }
+
+    /// Submits the transactions the exchange records to `node`, which relays
+    /// them to its peers
+    ///
+    /// The node's chain must take deposits from the exchange's custodian.
+    pub fn with_node(mut self, node: Node) -> Self {
+        self.node = Some(node);
+        self
+    }
+
+    /// Reports the transactions the node refuses to `log`
+    pub fn with_log(mut self, log: Log) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// Broadcasts the market data and account events the exchange has recorded, then unlocks it
+    ///
+    /// Events go out before the exchange is unlocked, so they go out in the
+    /// order they happened. The transactions the exchange recorded go to the
+    /// node after, in the same order, so the node's chain is never locked
+    /// under the exchange; any the node refuses are only reported to the
+    /// hub's log, as the exchange's own chain has already taken them.
+    pub fn publish(&self, mut exchange: MutexGuard<'_, Exchange>) {
+        // No subscribers is not an error
+        for event in exchange.take_market_events() {
+            let _ = self.market_data.send(event);
+        }
+        for event in exchange.take_user_events() {
+            let _ = self.user_data.send(event);
+        }
+        let transactions = exchange.take_recorded_transactions();
+        drop(exchange);
+        if let Some(node) = &self.node {
+            for transaction in transactions {
+                let id = transaction.id.clone();
+                if let Err(e) = node.submit_transaction(transaction) {
+                    (self.log)(&format!("Node rejected transaction {}: {}", id, e));
+                }
+            }
+        }
+    }
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// State shared by all handlers
+#[derive(Clone)]
+pub(crate) struct ApiState {
+    pub(crate) exchange: SharedExchange,
+    pub(crate) events: EventHub,
+    api_keys: ApiKeys,
+    /// Key of the exchange's operator, who alone may credit deposits
+    operator_key: Arc<str>,
+}
+
+impl ApiState {
+    fn publish(&self, exchange: MutexGuard<'_, Exchange>) {
+        self.events.publish(exchange);
+    }
+
+    /// Issues a new API key for a wallet
+    fn issue_api_key(&self, address: &str) -> Result<String, ApiError> {
+        let key = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        self.api_keys
+            .lock()
+            .map_err(|_| ApiError::internal("API keys are unavailable after a failure"))?
+            .insert(key.clone(), address.to_string());
+        Ok(key)
+    }
+
+    /// Wallet address of the API key in an `Authorization: Bearer <key>` header
+    pub(crate) fn authenticate(&self, headers: &HeaderMap) -> Result<String, ApiError> {
+        let key = bearer_key(headers)?;
+        self.api_keys
+            .lock()
+            .map_err(|_| ApiError::internal("API keys are unavailable after a failure"))?
+            .get(key.trim())
+            .cloned()
+            .ok_or_else(|| ApiError::unauthorized("Unknown API key"))
+    }
+
+    /// Checks that the request carries the operator's key
+    ///
+    /// Wallet keys are refused: their holders could otherwise credit
+    /// themselves funds that never reached the exchange.
+    fn authorize_operator(&self, headers: &HeaderMap) -> Result<(), ApiError> {
+        if keys::secrets_match(bearer_key(headers)?, &self.operator_key) {
+            return Ok(());
+        }
+        self.authenticate(headers)?;
+        Err(ApiError::forbidden("Only the operator's key can do this"))
+    }
+
+    /// Checks that the request carries the API key of the wallet it acts on
+    pub(crate) fn authorize(&self, headers: &HeaderMap, address: &str) -> Result<(), ApiError> {
+        if self.authenticate(headers)? != address {
+            return Err(ApiError::forbidden(format!(
+                "API key does not belong to wallet {}",
+                address
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Key in an `Authorization: Bearer <key>` header
+fn bearer_key(headers: &HeaderMap) -> Result<&str, ApiError> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or_else(|| ApiError::unauthorized("Missing bearer API key"))
+}
+
+impl FromRef<ApiState> for SharedExchange {
+    fn from_ref(state: &ApiState) -> Self {
+        state.exchange.clone()
+    }
+}
+
+/// Error returned by the API, rendered as `{"error": {"code": ..., "message": ...}}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiError {
+    pub status: StatusCode,
+    /// Stable, machine-readable reason
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    /// Missing or unknown API key
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    /// An API key acting on a wallet it does not belong to
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    /// The exchange refused a well-formed request (insufficient funds, ...)
+    pub fn rejected(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, "rejected", message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", message)
+    }
+
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = json!({ "error": { "code": self.code, "message": self.message } });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/// Builds the HTTP API around an exchange
+///
+/// Trading pairs appear in paths as `BASE-QUOTE`, e.g. `/pairs/BTC-USDT/book`.
+/// Every request that changes a wallet, its orders or the chain must carry
+/// `Authorization: Bearer <api key>` with the key returned when that wallet
+/// was created. Deposits instead take `operator_key`, as they credit funds
+/// that arrive outside the exchange. Market data streams from `/ws`
+/// (WebSocket), and each account's orders and balances from `/ws/user`,
+/// authenticated the same way.
+pub fn router(exchange: SharedExchange, operator_key: &str) -> Router {
+    router_with_events(exchange, EventHub::new(), operator_key)
+}
+
+/// Builds the HTTP API around an exchange whose events also feed other servers
+pub fn router_with_events(
+    exchange: SharedExchange,
+    events: EventHub,
+    operator_key: &str,
+) -> Router {
+    let state = ApiState {
+        exchange,
+        events,
+        api_keys: ApiKeys::default(),
+        operator_key: operator_key.into(),
+    };
+    Router::new()
+        .route("/wallets", post(create_wallet))
+        .route("/wallets/:address/balances", get(balances))
+        .route("/wallets/:address/orders", get(open_orders))
+        .route("/wallets/:address/deposits", post(deposit))
+        .route("/wallets/:address/withdrawals", post(withdraw))
+        .route("/pairs", get(pairs))
+        .route("/pairs/:pair/book", get(order_book))
+        .route("/pairs/:pair/trades", get(trades))
+        .route("/pairs/:pair/orders", post(place_order))
+        .route("/pairs/:pair/orders/:order_id", delete(cancel_order))
+        .route("/chain", get(chain_info))
+        .route("/chain/blocks/:index", get(block))
+        .route("/chain/transactions/:id/proof", get(transaction_proof))
+        .route("/chain/proofs/verify", post(verify_proof))
+        .route("/chain/mine", post(mine))
+        .route("/ws", get(ws::market_data))
+        .route("/ws/user", get(ws::user_data))
+        .with_state(state)
+}
+
+/// Locks the exchange, failing if a handler panicked while holding it
+pub(crate) fn lock(exchange: &SharedExchange) -> Result<MutexGuard<'_, Exchange>, ApiError> {
+    exchange
+        .lock()
+        .map_err(|_| ApiError::internal("Exchange state is unavailable after a failure"))
+}
+
+/// Parses a `BASE-QUOTE` path segment into a pair the exchange trades
+pub(crate) fn supported_pair(exchange: &Exchange, symbol: &str) -> Result<TradingPair, ApiError> {
+    let (base, quote) = symbol
+        .split_once('-')
+        .ok_or_else(|| ApiError::bad_request(format!("Invalid trading pair {}", symbol)))?;
+    let pair = TradingPair::new(base, quote);
+    if exchange.get_order_book(&pair).is_none() {
+        return Err(ApiError::not_found(format!(
+            "Trading pair {} not supported",
+            symbol
+        )));
+    }
+    Ok(pair)
+}
+
+fn require_wallet(exchange: &Exchange, address: &str) -> Result<(), ApiError> {
+    match exchange.wallet_manager.get_wallet(address) {
+        Some(_) => Ok(()),
+        None => Err(ApiError::not_found(format!("Wallet {} not found", address))),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateWalletRequest {
+    owner: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CreateWalletResponse {
+    address: String,
+    /// Key that authorizes requests on the wallet, shown only once
+    api_key: String,
+}
+
+async fn create_wallet(
+    State(state): State<ApiState>,
+    Json(request): Json<CreateWalletRequest>,
+) -> Result<(StatusCode, Json<CreateWalletResponse>), ApiError> {
+    let address = lock(&state.exchange)?.create_wallet(&request.owner);
+    let api_key = state.issue_api_key(&address)?;
+    Ok((
+        StatusCode::CREATED,
+        Json(CreateWalletResponse { address, api_key }),
+    ))
+}
+
+async fn balances(
+    State(exchange): State<SharedExchange>,
+    Path(address): Path<String>,
+) -> ApiResult<BTreeMap<String, Balance>> {
+    let exchange = lock(&exchange)?;
+    let wallet = exchange
+        .wallet_manager
+        .get_wallet(&address)
+        .ok_or_else(|| ApiError::not_found(format!("Wallet {} not found", address)))?;
+    Ok(Json(wallet.balances.clone().into_iter().collect()))
+}
+
+/// Resting and pending stop orders of a wallet, oldest first
+async fn open_orders(
+    State(exchange): State<SharedExchange>,
+    Path(address): Path<String>,
+) -> ApiResult<Vec<Order>> {
+    let exchange = lock(&exchange)?;
+    require_wallet(&exchange, &address)?;
+
+    let resting = exchange
+        .order_books
+        .values()
+        .flat_map(|book| book.buy_orders().chain(book.sell_orders()));
+    let mut orders: Vec<Order> = resting
+        .chain(exchange.stop_orders.values().flatten())
+        .filter(|order| order.user_address == address)
+        .cloned()
+        .collect();
+    orders.sort_by_key(|order| order.timestamp);
+    Ok(Json(orders))
+}
+
+#[derive(Debug, Deserialize)]
+struct FundsRequest {
+    currency: String,
+    amount: Amount,
+}
+
+async fn deposit(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+    Json(request): Json<FundsRequest>,
+) -> ApiResult<Balance> {
+    state.authorize_operator(&headers)?;
+    let mut exchange = lock(&state.exchange)?;
+    require_wallet(&exchange, &address)?;
+    let result = exchange.deposit(&address, &request.currency, request.amount);
+    let balance = wallet_balance(&exchange, &address, &request.currency);
+    state.publish(exchange);
+    result.map_err(ApiError::rejected)?;
+    Ok(Json(balance))
+}
+
+async fn withdraw(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+    Json(request): Json<FundsRequest>,
+) -> ApiResult<Balance> {
+    state.authorize(&headers, &address)?;
+    let mut exchange = lock(&state.exchange)?;
+    require_wallet(&exchange, &address)?;
+    let result = exchange.withdraw(&address, &request.currency, request.amount);
+    let balance = wallet_balance(&exchange, &address, &request.currency);
+    state.publish(exchange);
+    result.map_err(ApiError::rejected)?;
+    Ok(Json(balance))
+}
+
+fn wallet_balance(exchange: &Exchange, address: &str, currency: &str) -> Balance {
+    exchange
+        .wallet_manager
+        .get_wallet(address)
+        .map(|wallet| wallet.balance(currency))
+        .unwrap_or_default()
+}
+
+async fn pairs(State(exchange): State<SharedExchange>) -> ApiResult<Vec<TradingPair>> {
+    Ok(Json(lock(&exchange)?.supported_pairs.clone()))
+}
+
+#[derive(Debug, Deserialize)]
+struct BookQuery {
+    levels: Option<usize>,
+}
+
+async fn order_book(
+    State(exchange): State<SharedExchange>,
+    Path(pair): Path<String>,
+    Query(query): Query<BookQuery>,
+) -> ApiResult<DepthSnapshot> {
+    let exchange = lock(&exchange)?;
+    let pair = supported_pair(&exchange, &pair)?;
+    let depth = exchange
+        .depth(&pair, query.levels.unwrap_or(20))
+        .ok_or_else(|| ApiError::not_found(format!("No order book for {}", pair.symbol())))?;
+    Ok(Json(depth))
+}
+
+#[derive(Debug, Deserialize)]
+struct TradesQuery {
+    limit: Option<usize>,
+}
+
+/// Most recent trades of a pair, newest first
+async fn trades(
+    State(exchange): State<SharedExchange>,
+    Path(pair): Path<String>,
+    Query(query): Query<TradesQuery>,
+) -> ApiResult<Vec<Trade>> {
+    let exchange = lock(&exchange)?;
+    let pair = supported_pair(&exchange, &pair)?;
+    let trades = exchange
+        .trades
+        .iter()
+        .rev()
+        .filter(|trade| trade.pair == pair)
+        .take(query.limit.unwrap_or(50))
+        .cloned()
+        .collect();
+    Ok(Json(trades))
+}
+
+/// A limit order, or a market order sized in the base currency when `price` is absent
+#[derive(Debug, Deserialize)]
+struct PlaceOrderRequest {
+    user_address: String,
+    side: OrderSide,
+    quantity: Amount,
+    price: Option<Amount>,
+    #[serde(default)]
+    options: OrderOptions,
+}
+
+#[derive(Debug, Serialize)]
+struct PlaceOrderResponse {
+    order_id: String,
+}
+
+async fn place_order(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(pair): Path<String>,
+    Json(request): Json<PlaceOrderRequest>,
+) -> Result<(StatusCode, Json<PlaceOrderResponse>), ApiError> {
+    state.authorize(&headers, &request.user_address)?;
+    let mut exchange = lock(&state.exchange)?;
+    let pair = supported_pair(&exchange, &pair)?;
+    require_wallet(&exchange, &request.user_address)?;
+
+    let result = match request.price {
+        Some(price) => exchange.place_order_with_options(
+            request.user_address,
+            pair,
+            request.side,
+            price,
+            request.quantity,
+            request.options,
+        ),
+        None => exchange.place_market_order(
+            request.user_address,
+            pair,
+            request.side,
+            MarketOrderSize::Base(request.quantity),
+            None,
+        ),
+    };
+    state.publish(exchange);
+    let order_id = result.map_err(ApiError::rejected)?;
+    Ok((StatusCode::CREATED, Json(PlaceOrderResponse { order_id })))
+}
+
+async fn cancel_order(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path((pair, order_id)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    let address = state.authenticate(&headers)?;
+    let mut exchange = lock(&state.exchange)?;
+    let pair = supported_pair(&exchange, &pair)?;
+    let owner = order_owner(&exchange, &pair, &order_id)
+        .ok_or_else(|| ApiError::not_found(format!("Order {} not found", order_id)))?;
+    if owner != address {
+        return Err(ApiError::forbidden(format!(
+            "API key does not belong to the owner of order {}",
+            order_id
+        )));
+    }
+    let result = exchange.cancel_order(&order_id, &pair);
+    state.publish(exchange);
+    result.map_err(ApiError::not_found)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Wallet that placed a resting or pending stop order
+fn order_owner<'a>(exchange: &'a Exchange, pair: &TradingPair, order_id: &str) -> Option<&'a str> {
+    let resting = exchange
+        .get_order_book(pair)
+        .and_then(|book| book.get_order(order_id));
+    let stop = || {
+        exchange
+            .stop_orders
+            .get(&pair.symbol())
+            .and_then(|stops| stops.iter().find(|order| order.id == order_id))
+    };
+    resting
+        .or_else(stop)
+        .map(|order| order.user_address.as_str())
+}
+
+#[derive(Debug, Serialize)]
+struct ChainInfo {
+    length: usize,
+    /// Target of the next block, in compact form
+    target: Target,
+    difficulty: f64,
+    valid: bool,
+    pending_transactions: usize,
+    latest_hash: String,
+}
+
+async fn chain_info(State(exchange): State<SharedExchange>) -> ApiResult<ChainInfo> {
+    let exchange = lock(&exchange)?;
+    Ok(Json(ChainInfo::of(&exchange)))
+}
+
+impl ChainInfo {
+    fn of(exchange: &Exchange) -> Self {
+        let chain = &exchange.blockchain;
+        ChainInfo {
+            length: chain.chain.len(),
+            target: chain.next_target(),
+            difficulty: chain.next_target().difficulty(),
+            valid: chain.is_valid(),
+            pending_transactions: chain.mempool.len(),
+            latest_hash: chain.get_latest_block().hash.clone(),
+        }
+    }
+}
+
+async fn block(
+    State(exchange): State<SharedExchange>,
+    Path(index): Path<usize>,
+) -> ApiResult<Block> {
+    let exchange = lock(&exchange)?;
+    let block = exchange
+        .blockchain
+        .chain
+        .get(index)
+        .ok_or_else(|| ApiError::not_found(format!("Block {} not found", index)))?;
+    Ok(Json(block.clone()))
+}
+
+async fn transaction_proof(
+    State(exchange): State<SharedExchange>,
+    Path(id): Path<String>,
+) -> ApiResult<InclusionProof> {
+    let exchange = lock(&exchange)?;
+    let proof = exchange
+        .blockchain
+        .prove_transaction(&id)
+        .ok_or_else(|| ApiError::not_found(format!("Transaction {} is not mined", id)))?;
+    Ok(Json(proof))
+}
+
+#[derive(Debug, Deserialize)]
+struct VerifyProofRequest {
+    transaction: Transaction,
+    proof: InclusionProof,
+}
+
+#[derive(Debug, Serialize)]
+struct VerifyProofResponse {
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+async fn verify_proof(
+    State(exchange): State<SharedExchange>,
+    Json(request): Json<VerifyProofRequest>,
+) -> ApiResult<VerifyProofResponse> {
+    let exchange = lock(&exchange)?;
+    let result = exchange
+        .blockchain
+        .verify_proof(&request.proof, &request.transaction);
+    Ok(Json(VerifyProofResponse {
+        valid: result.is_ok(),
+        reason: result.err(),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct MineRequest {
+    miner_address: String,
+}
+
+async fn mine(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(request): Json<MineRequest>,
+) -> ApiResult<ChainInfo> {
+    state.authorize(&headers, &request.miner_address)?;
+    let mut exchange = lock(&state.exchange)?;
+    require_wallet(&exchange, &request.miner_address)?;
+    exchange.mine_transactions(&request.miner_address);
+    let info = ChainInfo::of(&exchange);
+    state.publish(exchange);
+    Ok(Json(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    /// Operator key of the test routers
+    const OPERATOR_KEY: &str = "operator-secret";
+
+    fn shared_exchange() -> SharedExchange {
+        Arc::new(Mutex::new(Exchange::new("ApiExchange")))
+    }
+
+    /// Sends a request and returns the status and the JSON body (null when empty)
+    async fn send(app: &Router, method: &str, uri: &str, body: Value) -> (StatusCode, Value) {
+        send_as(app, None, method, uri, body).await
+    }
+
+    /// Sends a request authorized with an API key
+    async fn send_as(
+        app: &Router,
+        api_key: Option<&str>,
+        method: &str,
+        uri: &str,
+        body: Value,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        if let Some(key) = api_key {
+            request = request.header("authorization", format!("Bearer {}", key));
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let json = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (status, json)
+    }
+
+    /// Creates a wallet with a deposit and returns its address and API key
+    async fn funded_wallet(
+        app: &Router,
+        owner: &str,
+        currency: &str,
+        amount: i64,
+    ) -> (String, String) {
+        let (status, body) = send(app, "POST", "/wallets", json!({ "owner": owner })).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let address = body["address"].as_str().unwrap().to_string();
+        let api_key = body["api_key"].as_str().unwrap().to_string();
+        let uri = format!("/wallets/{}/deposits", address);
+        let funds = json!({ "currency": currency, "amount": amount });
+        let (status, _) = send_as(app, Some(OPERATOR_KEY), "POST", &uri, funds).await;
+        assert_eq!(status, StatusCode::OK);
+        (address, api_key)
+    }
+
+    #[tokio::test]
+    async fn test_trade_through_api() {
+        let app = router(shared_exchange(), OPERATOR_KEY);
+        let (alice, alice_key) = funded_wallet(&app, "Alice", "USDT", 100000).await;
+        let (bob, bob_key) = funded_wallet(&app, "Bob", "BTC", 2).await;
+        let as_alice = Some(alice_key.as_str());
+        let as_bob = Some(bob_key.as_str());
+
+        let order = |user: &str, side: &str, price: &str| {
+            json!({ "user_address": user, "side": side, "price": price, "quantity": "1" })
+        };
+        let uri = "/pairs/BTC-USDT/orders";
+        let (status, ask) = send_as(&app, as_bob, "POST", uri, order(&bob, "Sell", "30000")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, bid) = send_as(&app, as_alice, "POST", uri, order(&alice, "Buy", "29000")).await;
+
+        let (_, book) = send(&app, "GET", "/pairs/BTC-USDT/book?levels=5", Value::Null).await;
+        assert_eq!(book["asks"][0]["price"], "30000");
+        assert_eq!(book["bids"][0]["price"], "29000");
+
+        let (_, orders) = send(
+            &app,
+            "GET",
+            &format!("/wallets/{}/orders", alice),
+            Value::Null,
+        )
+        .await;
+        assert_eq!(orders[0]["id"], bid["order_id"]);
+
+        // A market buy takes the ask
+        let market = json!({ "user_address": alice, "side": "Buy", "quantity": "1" });
+        let (status, _) = send_as(&app, as_alice, "POST", uri, market).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, trades) = send(&app, "GET", "/pairs/BTC-USDT/trades", Value::Null).await;
+        assert_eq!(trades[0]["sell_order_id"], ask["order_id"]);
+
+        let cancel = format!(
+            "/pairs/BTC-USDT/orders/{}",
+            bid["order_id"].as_str().unwrap()
+        );
+        let (status, _) = send_as(&app, as_bob, "DELETE", &cancel, Value::Null).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = send_as(&app, as_alice, "DELETE", &cancel, Value::Null).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (_, balances) = send(
+            &app,
+            "GET",
+            &format!("/wallets/{}/balances", alice),
+            Value::Null,
+        )
+        .await;
+        let amount = |value: &Value| value.as_str().unwrap().parse::<Amount>().unwrap();
+        assert_eq!(amount(&balances["BTC"]["available"]), Amount::from(1));
+        assert_eq!(amount(&balances["USDT"]["available"]), Amount::from(70000));
+        assert_eq!(amount(&balances["USDT"]["locked"]), Amount::ZERO);
+
+        let mine = json!({ "miner_address": bob });
+        let (_, chain) = send_as(&app, as_bob, "POST", "/chain/mine", mine).await;
+        assert_eq!(chain["length"], 2);
+        assert_eq!(chain["valid"], true);
+        let (_, block) = send(&app, "GET", "/chain/blocks/1", Value::Null).await;
+        assert_eq!(block["hash"], chain["latest_hash"]);
+    }
+
+    #[tokio::test]
+    async fn test_recorded_transactions_reach_the_node() {
+        let exchange = Exchange::new("ApiExchange");
+        let custodian = exchange.wallet_manager.custodian();
+        let node = Node::new(crate::p2p::new_chain().with_custodian(&custodian));
+        let exchange = Arc::new(Mutex::new(exchange));
+        let events = EventHub::new().with_node(node.clone());
+        let app = router_with_events(exchange.clone(), events, OPERATOR_KEY);
+
+        let (alice, alice_key) = funded_wallet(&app, "Alice", "USDT", 100000).await;
+        let (bob, bob_key) = funded_wallet(&app, "Bob", "BTC", 2).await;
+        let uri = "/pairs/BTC-USDT/orders";
+        let ask = json!({ "user_address": bob, "side": "Sell", "price": "30000", "quantity": "1" });
+        send_as(&app, Some(&bob_key), "POST", uri, ask).await;
+        let bid = json!({ "user_address": alice, "side": "Buy", "quantity": "1" });
+        let (status, _) = send_as(&app, Some(&alice_key), "POST", uri, bid).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        // The deposits and both legs of the trade, in the order the exchange recorded them
+        let ids = |transactions: &[Transaction]| -> Vec<String> {
+            transactions.iter().map(|tx| tx.id.clone()).collect()
+        };
+        let recorded = ids(exchange.lock().unwrap().blockchain.mempool.transactions());
+        assert!(recorded.len() > 2);
+        assert_eq!(ids(node.chain().mempool.transactions()), recorded);
+
+        // A node that takes no deposits refuses them, which the hub reports to its log
+        let lines = Arc::new(Mutex::new(Vec::<String>::new()));
+        let logged = lines.clone();
+        let events = EventHub::new()
+            .with_node(Node::new(crate::p2p::new_chain()))
+            .with_log(Arc::new(move |line| logged.lock().unwrap().push(line.to_string())));
+        let app = router_with_events(shared_exchange(), events, OPERATOR_KEY);
+        funded_wallet(&app, "Carol", "BTC", 1).await;
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("Node rejected transaction"));
+    }
+
+    #[tokio::test]
+    async fn test_inclusion_proofs() {
+        let app = router(shared_exchange(), OPERATOR_KEY);
+        let (alice, alice_key) = funded_wallet(&app, "Alice", "USDT", 100).await;
+        let as_alice = Some(alice_key.as_str());
+        let uri = format!("/wallets/{}/withdrawals", alice);
+        let withdrawal = json!({ "currency": "USDT", "amount": 40 });
+        let (status, _) = send_as(&app, as_alice, "POST", &uri, withdrawal).await;
+        assert_eq!(status, StatusCode::OK);
+        let mine = json!({ "miner_address": alice });
+        send_as(&app, as_alice, "POST", "/chain/mine", mine).await;
+
+        let (_, block) = send(&app, "GET", "/chain/blocks/1", Value::Null).await;
+        let withdrawal = block["transactions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|tx| tx["transaction_type"] == "Withdrawal")
+            .unwrap()
+            .clone();
+        let uri = format!("/chain/transactions/{}/proof", withdrawal["id"].as_str().unwrap());
+        let (status, proof) = send(&app, "GET", &uri, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(proof["merkle_root"], block["merkle_root"]);
+
+        let request = json!({ "transaction": withdrawal, "proof": proof });
+        let (_, result) = send(&app, "POST", "/chain/proofs/verify", request).await;
+        assert_eq!(result["valid"], true);
+        let mut altered = withdrawal.clone();
+        altered["amount"] = json!("4000");
+        let request = json!({ "transaction": altered, "proof": proof });
+        let (_, result) = send(&app, "POST", "/chain/proofs/verify", request).await;
+        assert_eq!(result["valid"], false);
+        assert!(result["reason"].is_string());
+
+        let (status, _) = send(&app, "GET", "/chain/transactions/nope/proof", Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_structured_errors() {
+        let app = router(shared_exchange(), OPERATOR_KEY);
+        let (alice, alice_key) = funded_wallet(&app, "Alice", "USDT", 100).await;
+
+        let (status, body) = send(&app, "GET", "/pairs/DOGE-USDT/book", Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "not_found");
+
+        let (status, body) = send(&app, "GET", "/pairs/BTCUSDT/trades", Value::Null).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "bad_request");
+
+        let uri = format!("/wallets/{}/withdrawals", alice);
+        let withdrawal = json!({ "currency": "USDT", "amount": 500 });
+        let (status, body) = send_as(&app, Some(&alice_key), "POST", &uri, withdrawal).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"]["code"], "rejected");
+        assert!(body["error"]["message"].is_string());
+
+        let (status, _) = send(&app, "GET", "/wallets/nobody/balances", Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_state_changes_require_the_wallets_api_key() {
+        let app = router(shared_exchange(), OPERATOR_KEY);
+        let (alice, alice_key) = funded_wallet(&app, "Alice", "USDT", 100).await;
+        let (bob, bob_key) = funded_wallet(&app, "Bob", "BTC", 1).await;
+
+        let deposits = format!("/wallets/{}/deposits", alice);
+        let funds = json!({ "currency": "USDT", "amount": 1 });
+        let (status, body) = send(&app, "POST", &deposits, funds.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["code"], "unauthorized");
+        let (status, _) = send_as(&app, Some("nope"), "POST", &deposits, funds.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = send_as(&app, Some(&bob_key), "POST", &deposits, funds.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["code"], "forbidden");
+        // Not even Alice can credit herself; only the operator can
+        let (status, _) = send_as(&app, Some(&alice_key), "POST", &deposits, funds).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // Bob's key cannot spend Alice's funds, withdraw them or mine for her
+        let uri = "/pairs/BTC-USDT/orders";
+        let order = json!({ "user_address": alice, "side": "Buy", "price": "1", "quantity": "1" });
+        let (status, _) = send_as(&app, Some(&bob_key), "POST", uri, order).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let withdrawals = format!("/wallets/{}/withdrawals", alice);
+        let funds = json!({ "currency": "USDT", "amount": 100 });
+        let (status, _) = send_as(&app, Some(&bob_key), "POST", &withdrawals, funds).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let mine = json!({ "miner_address": alice });
+        let (status, _) = send_as(&app, Some(&bob_key), "POST", "/chain/mine", mine).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (_, balances) = send(
+            &app,
+            "GET",
+            &format!("/wallets/{}/balances", alice),
+            Value::Null,
+        )
+        .await;
+        assert_eq!(balances["USDT"]["available"], "100");
+        let order = json!({ "user_address": bob, "side": "Sell", "price": "1", "quantity": "1" });
+        let (status, _) = send_as(&app, Some(&bob_key), "POST", uri, order).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_orders_are_serialized() {
+        let exchange = shared_exchange();
+        let app = router(exchange.clone(), OPERATOR_KEY);
+        let buyer = funded_wallet(&app, "Buyer", "USDT", 1000).await;
+        let seller = funded_wallet(&app, "Seller", "BTC", 100).await;
+
+        // Fifty buyers race fifty sellers at the same price
+        let mut handles = vec![];
+        for i in 0..100 {
+            let app = app.clone();
+            let ((user, key), side) = if i % 2 == 0 {
+                (buyer.clone(), "Buy")
+            } else {
+                (seller.clone(), "Sell")
+            };
+            let order =
+                json!({ "user_address": user, "side": side, "price": "10", "quantity": "1" });
+            handles.push(tokio::spawn(async move {
+                let uri = "/pairs/BTC-USDT/orders";
+                send_as(&app, Some(&key), "POST", uri, order).await.0
+            }));
+        }
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), StatusCode::CREATED);
+        }
+
+        let exchange = exchange.lock().unwrap();
+        let pair = TradingPair::new("BTC", "USDT");
+        assert_eq!(exchange.trades.len(), 50);
+        assert_eq!(exchange.get_order_book(&pair).unwrap().order_count(), 0);
+        let (buyer, seller) = (&buyer.0, &seller.0);
+        assert_eq!(exchange.get_balance(buyer, "BTC"), Amount::from(50));
+        assert_eq!(exchange.get_balance(buyer, "USDT"), Amount::from(500));
+        assert_eq!(exchange.get_balance(seller, "USDT"), Amount::from(500));
+        assert_eq!(exchange.get_locked_balance(buyer, "USDT"), Amount::ZERO);
+    }
+}
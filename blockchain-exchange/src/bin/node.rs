@@ -0,0 +1,86 @@
+use std::env;
+
+use blockchain_exchange::block::BlockOutcome;
+use blockchain_exchange::block_store::BlockStore;
+use blockchain_exchange::keys;
+use blockchain_exchange::log;
+use blockchain_exchange::p2p::{self, Node};
+
+/// Address the node listens on unless `NODE_ADDR` is set
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+/// Runs a chain node
+///
+/// `NODE_PEERS` lists the addresses of peers to connect to, separated by
+/// commas. When `NODE_CUSTODIAN` is set to the hex public key an exchange
+/// server prints, the chain takes that exchange's deposits. When
+/// `NODE_MINER` is set, the node mines blocks for that address without
+/// pause. When `NODE_DATA_DIR` is set, the node keeps its blocks there across
+/// restarts, pruning all but the latest `NODE_KEEP_BLOCKS` if that is set too.
+#[tokio::main]
+async fn main() {
+    let addr = env::var("NODE_ADDR").unwrap_or_else(|_| DEFAULT_ADDR.to_string());
+    let mut chain = p2p::new_chain();
+    if let Ok(custodian) = env::var("NODE_CUSTODIAN") {
+        let key = keys::parse_public_key(&custodian)
+            .unwrap_or_else(|e| panic!("Invalid NODE_CUSTODIAN {}: {}", custodian, e));
+        chain = chain.with_custodian(&key);
+    }
+    let mut node = Node::new(chain).with_log(log::stdout());
+    if let Ok(dir) = env::var("NODE_DATA_DIR") {
+        let mut store = BlockStore::open(&dir).unwrap_or_else(|e| panic!("{}", e));
+        if let Ok(keep) = env::var("NODE_KEEP_BLOCKS") {
+            let keep = keep
+                .parse()
+                .unwrap_or_else(|e| panic!("Invalid NODE_KEEP_BLOCKS {}: {}", keep, e));
+            store = store.with_pruning(keep);
+        }
+        store
+            .load(&mut node.chain())
+            .unwrap_or_else(|e| panic!("Failed to load {}: {}", dir, e));
+        println!(
+            "Loaded the chain up to height {} from {}",
+            node.chain().get_latest_block().index,
+            dir
+        );
+        node = node.with_store(store).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind {}: {}", addr, e));
+    println!("Node listening on {}", addr);
+    let server = node.clone();
+    tokio::spawn(async move { server.serve(listener).await.unwrap() });
+
+    let peers = env::var("NODE_PEERS").unwrap_or_default();
+    for peer in peers
+        .split(',')
+        .map(str::trim)
+        .filter(|peer| !peer.is_empty())
+    {
+        match node.connect(peer).await {
+            Ok(()) => println!("Connected to {}", peer),
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    let Ok(miner) = env::var("NODE_MINER") else {
+        std::future::pending::<()>().await;
+        return;
+    };
+    loop {
+        match node.mine(&miner).await {
+            Ok(BlockOutcome::SideBranch) => println!("Mined a block that lost the race"),
+            Ok(_) => {
+                let height = node.chain().get_latest_block().index;
+                println!(
+                    "Chain at height {} with {} peers",
+                    height,
+                    node.peer_count()
+                );
+            }
+            Err(e) => println!("Mined block was rejected: {}", e),
+        }
+    }
+}
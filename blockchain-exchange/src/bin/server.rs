@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use blockchain_exchange::api::{self, EventHub, SharedExchange};
+use blockchain_exchange::clock::{RandomIds, SystemClock};
+use blockchain_exchange::exchange::Exchange;
+use blockchain_exchange::fix_gateway::{FixClient, FixConfig, FixGateway};
+use blockchain_exchange::log;
+use blockchain_exchange::p2p::{self, Node};
+use blockchain_exchange::snapshot::ExchangeSnapshot;
+
+/// Address the server listens on unless `EXCHANGE_ADDR` is set
+const DEFAULT_ADDR: &str = "127.0.0.1:3000";
+
+/// Directory for FIX session state unless `FIX_STORE_DIR` is set
+const DEFAULT_FIX_STORE_DIR: &str = "fix-store";
+
+/// File the exchange is restored from and saved to unless `EXCHANGE_SNAPSHOT` is set
+const DEFAULT_SNAPSHOT: &str = "exchange_snapshot.json";
+
+/// How often the exchange is saved while the server runs
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Restores the exchange saved at `path`, or starts a new one if there is none
+fn load_exchange(path: &Path) -> Exchange {
+    if !path.exists() {
+        println!("No snapshot at {}, starting a new exchange", path.display());
+        return Exchange::new("RustExchange");
+    }
+    let exchange = ExchangeSnapshot::load(path)
+        .and_then(|snapshot| {
+            Exchange::from_snapshot(snapshot, Box::new(SystemClock), Box::new(RandomIds))
+        })
+        .unwrap_or_else(|e| panic!("Failed to restore the exchange: {}", e));
+    println!("Restored the exchange from {}", path.display());
+    exchange
+}
+
+/// Saves the exchange to `path`, writing the file off the async runtime
+async fn save_exchange(exchange: &SharedExchange, path: &Path) -> Result<(), String> {
+    let snapshot = exchange
+        .lock()
+        .map_err(|_| "Exchange state is unavailable after a failure".to_string())?
+        .snapshot();
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || snapshot.save(path))
+        .await
+        .map_err(|e| format!("Failed to save the exchange: {}", e))?
+}
+
+/// Reads the FIX gateway's settings when `FIX_ADDR` is set
+///
+/// `FIX_CLIENTS` lists the clients as `SENDER_COMP_ID=wallet_address:password`
+/// entries separated by commas.
+fn fix_config() -> Option<(String, FixConfig)> {
+    let addr = env::var("FIX_ADDR").ok()?;
+    let clients: HashMap<String, FixClient> = env::var("FIX_CLIENTS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|client| {
+            let (comp_id, login) = client.split_once('=')?;
+            let (address, password) = login.split_once(':')?;
+            let client = FixClient {
+                address: address.trim().to_string(),
+                password: password.trim().to_string(),
+            };
+            Some((comp_id.trim().to_string(), client))
+        })
+        .collect();
+    let config = FixConfig {
+        comp_id: env::var("FIX_COMP_ID").unwrap_or_else(|_| "EXCHANGE".to_string()),
+        store_dir: env::var("FIX_STORE_DIR")
+            .unwrap_or_else(|_| DEFAULT_FIX_STORE_DIR.to_string())
+            .into(),
+        clients,
+    };
+    Some((addr, config))
+}
+
+/// Runs a chain node that the exchange submits its transactions to, when `NODE_ADDR` is set
+///
+/// The node listens on `NODE_ADDR` and connects to the comma-separated
+/// addresses in `NODE_PEERS`. Other nodes must be started with the printed
+/// custodian key as `NODE_CUSTODIAN` to take the exchange's deposits.
+async fn start_node(exchange: &Exchange) -> Option<Node> {
+    let addr = env::var("NODE_ADDR").ok()?;
+    let custodian = exchange.wallet_manager.custodian();
+    let node = Node::new(p2p::new_chain().with_custodian(&custodian)).with_log(log::stdout());
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind {}: {}", addr, e));
+    println!(
+        "Chain node listening on {} with custodian {}",
+        addr,
+        hex::encode(custodian.as_bytes())
+    );
+    let server = node.clone();
+    tokio::spawn(async move { server.serve(listener).await.unwrap() });
+
+    let peers = env::var("NODE_PEERS").unwrap_or_default();
+    for peer in peers
+        .split(',')
+        .map(str::trim)
+        .filter(|peer| !peer.is_empty())
+    {
+        match node.connect(peer).await {
+            Ok(()) => println!("Connected to {}", peer),
+            Err(e) => println!("{}", e),
+        }
+    }
+    Some(node)
+}
+
+/// Serves the exchange API on `EXCHANGE_ADDR`
+///
+/// Deposits are credited with the bearer key in `EXCHANGE_OPERATOR_KEY`,
+/// which must be set. The exchange is restored from the snapshot at
+/// `EXCHANGE_SNAPSHOT` if there is one, and saved there every
+/// [`SNAPSHOT_INTERVAL`] and on Ctrl-C, so wallets and FIX sessions
+/// outlive a restart. FIX clients must trade for wallets of the restored
+/// exchange.
+#[tokio::main]
+async fn main() {
+    let addr = env::var("EXCHANGE_ADDR").unwrap_or_else(|_| DEFAULT_ADDR.to_string());
+    let operator_key = env::var("EXCHANGE_OPERATOR_KEY")
+        .unwrap_or_else(|_| panic!("Set EXCHANGE_OPERATOR_KEY to the key that credits deposits"));
+    let snapshot_path: PathBuf = env::var("EXCHANGE_SNAPSHOT")
+        .unwrap_or_else(|_| DEFAULT_SNAPSHOT.to_string())
+        .into();
+    let exchange = load_exchange(&snapshot_path);
+    let fix = fix_config();
+    if let Some((_, config)) = &fix {
+        config
+            .check_clients(&exchange)
+            .unwrap_or_else(|e| panic!("{}", e));
+    }
+    let mut events = EventHub::new().with_log(log::stdout());
+    if let Some(node) = start_node(&exchange).await {
+        events = events.with_node(node);
+    }
+    let exchange = Arc::new(Mutex::new(exchange));
+
+    if let Some((fix_addr, config)) = fix {
+        let listener = tokio::net::TcpListener::bind(&fix_addr)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to bind {}: {}", fix_addr, e));
+        println!("FIX gateway listening on {}", fix_addr);
+        let gateway =
+            FixGateway::new(exchange.clone(), events.clone(), config).with_log(log::stdout());
+        tokio::spawn(async move { gateway.serve(listener).await.unwrap() });
+    }
+
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind {}: {}", addr, e));
+    println!("Exchange API listening on http://{}", addr);
+    let app = api::router_with_events(exchange.clone(), events, &operator_key);
+
+    let saved = exchange.clone();
+    let path = snapshot_path.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = save_exchange(&saved, &path).await {
+                println!("{}", e);
+            }
+        }
+    });
+
+    tokio::select! {
+        served = axum::serve(listener, app) => served.unwrap(),
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    match save_exchange(&exchange, &snapshot_path).await {
+        Ok(()) => println!("Saved the exchange to {}", snapshot_path.display()),
+        Err(e) => panic!("{}", e),
+    }
+}
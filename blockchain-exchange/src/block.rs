@@ -1,8 +1,31 @@
 use chrono::Utc;
+use ed25519_dalek::VerifyingKey;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use uuid::Uuid;
 
-use crate::transaction::Transaction;
+use crate::amount::Amount;
+use crate::mempool::{Ledger, Mempool};
+use crate::merkle::{self, InclusionProof};
+use crate::target::Target;
+use crate::transaction::{Transaction, TransactionType, REWARD_CURRENCY, SYSTEM_ADDRESS};
+
+/// Most transactions a block holds, the mining reward included, unless changed
+pub const DEFAULT_MAX_BLOCK_TRANSACTIONS: usize = 1000;
+
+/// Seconds the chain aims to take per block, unless changed
+pub const DEFAULT_BLOCK_TIME: i64 = 60;
+
+/// Blocks between target adjustments, unless changed
+pub const DEFAULT_RETARGET_INTERVAL: u64 = 10;
+
+/// Latest blocks whose median timestamp a new block must be later than
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Seconds a block's timestamp may be ahead of the local clock
+pub const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
 
 /// Represents a block in the blockchain
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -10,35 +33,66 @@ pub struct Block {
     pub index: u64,
     pub timestamp: i64,
     pub transactions: Vec<Transaction>,
+    /// Root of the Merkle tree over the [hashes](Transaction::hash) of `transactions`
+    pub merkle_root: String,
     pub previous_hash: String,
+    /// Proof-of-work target the hash must meet
+    pub target: Target,
     pub hash: String,
     pub nonce: u64,
 }
 
 impl Block {
     /// Creates a new block with the given transactions
-    pub fn new(index: u64, transactions: Vec<Transaction>, previous_hash: String) -> Self {
-        let timestamp = Utc::now().timestamp();
+    pub fn new(
+        index: u64,
+        transactions: Vec<Transaction>,
+        previous_hash: String,
+        target: Target,
+    ) -> Self {
+        Self::with_timestamp(
+            index,
+            transactions,
+            previous_hash,
+            target,
+            Utc::now().timestamp(),
+        )
+    }
+
+    /// Creates a new block with the given transactions and timestamp
+    pub fn with_timestamp(
+        index: u64,
+        transactions: Vec<Transaction>,
+        previous_hash: String,
+        target: Target,
+        timestamp: i64,
+    ) -> Self {
         let mut block = Block {
             index,
             timestamp,
             transactions,
+            merkle_root: String::new(),
             previous_hash,
+            target,
             hash: String::new(),
             nonce: 0,
         };
+        block.merkle_root = block.calculate_merkle_root();
         block.hash = block.calculate_hash();
         block
     }
 
     /// Calculates the hash of the block
+    ///
+    /// Only the header is hashed; the transactions count through the Merkle root.
     pub fn calculate_hash(&self) -> String {
         let data = format!(
-            "{}{}{}{}{}",
+            "{}{}{}{}{}{}",
             self.index,
             self.timestamp,
-            serde_json::to_string(&self.transactions).unwrap_or_default(),
+            self.merkle_root,
             self.previous_hash,
+            self.target,
             self.nonce
         );
         let mut hasher = Sha256::new();
@@ -46,10 +100,35 @@ impl Block {
         format!("{:x}", hasher.finalize())
     }
 
-    /// Mines the block with the given difficulty (number of leading zeros)
-    pub fn mine(&mut self, difficulty: usize) {
-        let target = "0".repeat(difficulty);
-        while &self.hash[..difficulty] != target {
+    /// Calculates the Merkle root of the block's transactions
+    pub fn calculate_merkle_root(&self) -> String {
+        merkle::root(&self.transaction_hashes())
+    }
+
+    fn transaction_hashes(&self) -> Vec<String> {
+        self.transactions.iter().map(Transaction::hash).collect()
+    }
+
+    /// Proves that the transaction with the given id is in this block
+    pub fn prove(&self, transaction_id: &str) -> Option<InclusionProof> {
+        let index = self
+            .transactions
+            .iter()
+            .position(|tx| tx.id == transaction_id)?;
+        let hashes = self.transaction_hashes();
+        Some(InclusionProof {
+            transaction_id: transaction_id.to_string(),
+            transaction_hash: hashes[index].clone(),
+            block_index: self.index,
+            block_hash: self.hash.clone(),
+            merkle_root: self.merkle_root.clone(),
+            path: merkle::path(&hashes, index)?,
+        })
+    }
+
+    /// Mines the block until its hash meets its target
+    pub fn mine(&mut self) {
+        while !self.target.is_met_by(&self.hash) {
             self.nonce += 1;
             self.hash = self.calculate_hash();
         }
@@ -57,126 +136,1311 @@ impl Block {
     }
 }
 
+/// What [`Blockchain::add_block`] did with a block
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockOutcome {
+    /// The block extended the active chain
+    Extended,
+    /// The block joined a branch with no more work than the active chain
+    SideBranch,
+    /// The block's branch became the active chain, replacing its last blocks
+    Reorganized { disconnected: usize },
+}
+
 /// Represents the blockchain
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Blockchain {
+    /// The active chain: the valid branch with the most work
     pub chain: Vec<Block>,
-    pub difficulty: usize,
-    pub pending_transactions: Vec<Transaction>,
-    pub mining_reward: f64,
+    /// Valid blocks on other branches, by hash
+    #[serde(default)]
+    pub side_blocks: HashMap<String, Block>,
+    /// Seconds the chain aims to take per block
+    pub block_time: i64,
+    /// Blocks between target adjustments; values below 2 count as 2
+    pub retarget_interval: u64,
+    /// Transactions waiting for a block
+    pub mempool: Mempool,
+    pub mining_reward: Amount,
+    /// Most transactions mined into one block, the mining reward included
+    pub max_block_transactions: usize,
+    /// Hex public key that must sign every deposit; without one, the chain
+    /// takes no deposits
+    #[serde(default)]
+    pub custodian: Option<String>,
+    /// Height up to which active blocks keep only their headers
+    #[serde(default)]
+    pub pruned_height: u64,
+    /// Ledger after the pruned blocks
+    #[serde(default)]
+    pruned_ledger: Ledger,
+    /// Ledger of the active chain, built the first time it is needed and
+    /// then updated as blocks connect and disconnect
+    #[serde(skip)]
+    ledger: OnceLock<Ledger>,
+    /// Heights of the active blocks by hash, built the first time they are
+    /// needed and then updated as blocks connect and disconnect
+    #[serde(skip)]
+    heights: OnceLock<HashMap<String, usize>>,
 }
 
 impl Blockchain {
     /// Creates a new blockchain with a genesis block
-    pub fn new(difficulty: usize, mining_reward: f64) -> Self {
-        let genesis_block = Block::new(0, vec![], String::from("0"));
+    ///
+    /// Blocks start out at `target`, which then follows the retarget rule,
+    /// see [`Blockchain::next_target`].
+    pub fn new(target: Target, mining_reward: Amount) -> Self {
+        Self::with_genesis_time(target, mining_reward, Utc::now().timestamp())
+    }
+
+    /// Creates a new blockchain whose genesis block has the given timestamp
+    pub fn with_genesis_time(target: Target, mining_reward: Amount, timestamp: i64) -> Self {
+        let genesis_block = Block::with_timestamp(0, vec![], String::from("0"), target, timestamp);
         Blockchain {
             chain: vec![genesis_block],
-            difficulty,
-            pending_transactions: vec![],
+            side_blocks: HashMap::new(),
+            block_time: DEFAULT_BLOCK_TIME,
+            retarget_interval: DEFAULT_RETARGET_INTERVAL,
+            mempool: Mempool::new(),
             mining_reward,
+            max_block_transactions: DEFAULT_MAX_BLOCK_TRANSACTIONS,
+            custodian: None,
+            pruned_height: 0,
+            pruned_ledger: Ledger::default(),
+            ledger: OnceLock::new(),
+            heights: OnceLock::new(),
         }
     }
 
+    /// Takes deposits signed by `custodian`
+    pub fn with_custodian(mut self, custodian: &VerifyingKey) -> Self {
+        self.custodian = Some(hex::encode(custodian.as_bytes()));
+        self
+    }
+
     /// Returns the latest block in the chain
     pub fn get_latest_block(&self) -> &Block {
         self.chain.last().expect("Blockchain should have at least one block")
     }
 
-    /// Adds a transaction to the pending transactions
+    /// Target the next block must meet
+    pub fn next_target(&self) -> Target {
+        self.target_after(&self.chain)
+    }
+
+    /// Target of the block that follows `blocks`
+    ///
+    /// Every `retarget_interval` blocks, the target is scaled by how long the
+    /// last `retarget_interval` blocks took against `block_time` per block,
+    /// see [`Target::retarget`]. In between, blocks keep the previous target.
+    fn target_after(&self, blocks: &[Block]) -> Target {
+        let last = blocks.last().expect("Blockchain should have at least one block");
+        let interval = self.retarget_interval.max(2);
+        if !(blocks.len() as u64).is_multiple_of(interval) {
+            return last.target;
+        }
+        let first = &blocks[blocks.len() - interval as usize];
+        let actual = last.timestamp - first.timestamp;
+        let expected = self.block_time * (interval as i64 - 1);
+        last.target.retarget(actual, expected)
+    }
+
+    /// Median timestamp of the last [`MEDIAN_TIME_SPAN`] blocks of `blocks`
+    ///
+    /// A block that follows `blocks` must be stamped later than this, so
+    /// timestamps keep moving forward even if a few blocks lie about theirs.
+    fn median_time_past(blocks: &[Block]) -> i64 {
+        let start = blocks.len().saturating_sub(MEDIAN_TIME_SPAN);
+        let mut timestamps: Vec<i64> = blocks[start..].iter().map(|b| b.timestamp).collect();
+        timestamps.sort_unstable();
+        timestamps[timestamps.len() / 2]
+    }
+
+    /// Earliest timestamp the next block may carry
+    pub fn min_next_timestamp(&self) -> i64 {
+        Self::median_time_past(&self.chain) + 1
+    }
+
+    /// Balances, nonces and transaction ids of the mined blocks
+    pub fn ledger(&self) -> &Ledger {
+        self.ledger.get_or_init(|| {
+            let mut ledger = self.pruned_ledger.clone();
+            for tx in self
+                .chain
+                .iter()
+                .skip(self.pruned_height as usize + 1)
+                .flat_map(|block| &block.transactions)
+            {
+                ledger.apply(tx);
+            }
+            ledger
+        })
+    }
+
+    /// The ledger of the mined blocks alongside the mempool, to check
+    /// transactions against it
+    fn ledger_and_mempool(&mut self) -> (&Ledger, &mut Mempool) {
+        self.ledger();
+        let ledger = self.ledger.get().expect("ledger was just built");
+        (ledger, &mut self.mempool)
+    }
+
+    /// Height of the active block with the given hash
+    fn height_of(&self, hash: &str) -> Option<usize> {
+        self.heights
+            .get_or_init(|| {
+                self.chain
+                    .iter()
+                    .enumerate()
+                    .map(|(height, block)| (block.hash.clone(), height))
+                    .collect()
+            })
+            .get(hash)
+            .copied()
+    }
+
+    /// Records the transactions of blocks added to the tip in the ledger
+    ///
+    /// Called before they are pushed to the chain, so a ledger that has not
+    /// been built yet is left to be built from the chain later.
+    fn connect(&mut self, blocks: &[Block]) {
+        if let Some(ledger) = self.ledger.get_mut() {
+            for tx in blocks.iter().flat_map(|block| &block.transactions) {
+                ledger.apply(tx);
+            }
+        }
+        self.index(blocks);
+    }
+
+    /// Records the heights of blocks added to the tip
+    fn index(&mut self, blocks: &[Block]) {
+        if let Some(heights) = self.heights.get_mut() {
+            for block in blocks {
+                heights.insert(block.hash.clone(), block.index as usize);
+            }
+        }
+    }
+
+    /// Undoes the transactions of blocks taken off the tip, in chain order
+    fn disconnect(&mut self, blocks: &[Block]) {
+        if let Some(ledger) = self.ledger.get_mut() {
+            for tx in blocks.iter().rev().flat_map(|block| block.transactions.iter().rev()) {
+                ledger.revert(tx);
+            }
+        }
+        if let Some(heights) = self.heights.get_mut() {
+            for block in blocks {
+                heights.remove(&block.hash);
+            }
+        }
+    }
+
+    /// Drops the transactions of the active blocks up to `height`
+    ///
+    /// The blocks keep their headers and the pruned ledger keeps their effect,
+    /// so balances and nonces are unchanged. Their transactions can no longer
+    /// be proven, though, and the chain can no longer reorganize at or below
+    /// `height`.
+    pub fn prune(&mut self, height: u64) {
+        let height = height.min(self.get_latest_block().index);
+        if height <= self.pruned_height {
+            return;
+        }
+        let mut ledger = self.pruned_ledger.clone();
+        for tx in self.chain[self.pruned_height as usize + 1..=height as usize]
+            .iter()
+            .flat_map(|block| &block.transactions)
+        {
+            ledger.apply(tx);
+        }
+        // Pruning leaves the ledger of the whole chain as it was
+        let confirmed = std::mem::take(&mut self.ledger);
+        self.restore_pruned(height, ledger);
+        self.ledger = confirmed;
+    }
+
+    /// Ledger after the pruned blocks
+    pub fn pruned_ledger(&self) -> &Ledger {
+        &self.pruned_ledger
+    }
+
+    /// Marks the blocks up to `height` as pruned, with `ledger` as their effect
+    ///
+    /// Side blocks at or below `height` are dropped, as they could only join
+    /// the chain by reorganizing below it. The ledger and the heights of the
+    /// active chain are built again the next time they are needed.
+    pub(crate) fn restore_pruned(&mut self, height: u64, ledger: Ledger) {
+        let end = (height as usize + 1).min(self.chain.len());
+        for block in &mut self.chain[1..end] {
+            block.transactions.clear();
+        }
+        self.side_blocks.retain(|_, block| block.index > height);
+        self.pruned_height = height;
+        self.pruned_ledger = ledger;
+        self.ledger = OnceLock::new();
+        self.heights = OnceLock::new();
+    }
+
+    /// Nonce the next transaction from `address` must carry, counting pending ones
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        self.mempool
+            .pending_ledger(self.ledger())
+            .next_nonce(address)
+    }
+
+    /// Balance of one currency at an address, counting pending transactions
+    pub fn pending_balance(&self, address: &str, currency: &str) -> Amount {
+        self.mempool
+            .pending_ledger(self.ledger())
+            .balance(address, currency)
+    }
+
+    /// Adds a transaction to the mempool
+    ///
+    /// The transaction must be signed by its sender, unless it is an
+    /// `EXTERNAL` deposit. Mining rewards are only created by mining. Signed
+    /// transactions must carry the sender's next nonce and may not spend more
+    /// than the sender's balance after the pending transactions.
     pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), String> {
         if transaction.from_address.is_empty() || transaction.to_address.is_empty() {
             return Err("Transaction must include from and to address".to_string());
         }
-        if transaction.amount <= 0.0 {
+        if !transaction.amount.is_positive() {
             return Err("Transaction amount must be positive".to_string());
         }
-        self.pending_transactions.push(transaction);
-        Ok(())
+        if transaction.from_address == SYSTEM_ADDRESS {
+            return Err("Mining rewards cannot be submitted as transactions".to_string());
+        }
+        self.authorize(&transaction)?;
+        let (ledger, mempool) = self.ledger_and_mempool();
+        mempool.add(transaction, ledger)
     }
 
     /// Mines pending transactions and rewards the miner
     pub fn mine_pending_transactions(&mut self, miner_address: &str) {
+        let reward_id = Uuid::new_v4().to_string();
+        self.mine_pending_transactions_at(miner_address, reward_id, Utc::now().timestamp());
+    }
+
+    /// Mines pending transactions into a block stamped `timestamp`
+    ///
+    /// The rest stay pending unless the block made them invalid.
+    pub fn mine_pending_transactions_at(
+        &mut self,
+        miner_address: &str,
+        reward_id: String,
+        timestamp: i64,
+    ) {
+        let mut block = self.candidate_block(miner_address, reward_id, timestamp);
+        block.mine();
+        self.connect(std::slice::from_ref(&block));
+        self.chain.push(block);
+        let (ledger, mempool) = self.ledger_and_mempool();
+        mempool.evict(ledger);
+    }
+
+    /// Builds the next block from pending transactions, without mining it
+    ///
+    /// The block takes the highest-fee transactions that fit, see
+    /// [`Mempool::select`]. The miner's reward transaction gets `reward_id`
+    /// and the block's timestamp, which is moved up to
+    /// [`Blockchain::min_next_timestamp`] if it is earlier.
+    pub fn candidate_block(&self, miner_address: &str, reward_id: String, timestamp: i64) -> Block {
+        let timestamp = timestamp.max(self.min_next_timestamp());
+        let limit = self.max_block_transactions.saturating_sub(1);
+        let mut transactions = self.mempool.select(self.ledger(), limit);
+
         // Create reward transaction for miner
-        let reward_tx = Transaction::new(
-            String::from("SYSTEM"),
-            miner_address.to_string(),
-            self.mining_reward,
-        );
-        self.pending_transactions.push(reward_tx);
+        let reward_tx =
+            Transaction::new_mining_reward(miner_address.to_string(), self.mining_reward)
+                .with_id_and_timestamp(reward_id, timestamp);
+        transactions.push(reward_tx);
 
-        // Create new block with pending transactions
+        // Create new block with the selected transactions
         let previous_hash = self.get_latest_block().hash.clone();
-        let mut block = Block::new(
+        Block::with_timestamp(
             self.chain.len() as u64,
-            self.pending_transactions.clone(),
+            transactions,
             previous_hash,
-        );
+            self.next_target(),
+            timestamp,
+        )
+    }
+
+    /// Gets the mined balance of one currency at an address
+    pub fn get_balance(&self, address: &str, currency: &str) -> Amount {
+        self.ledger().balance(address, currency)
+    }
+
+    /// Proves that a mined transaction is on the chain
+    pub fn prove_transaction(&self, transaction_id: &str) -> Option<InclusionProof> {
+        self.chain
+            .iter()
+            .find_map(|block| block.prove(transaction_id))
+    }
+
+    /// Checks an inclusion proof for `transaction` against this chain
+    ///
+    /// The proof must lead to the Merkle root of the block it names, as
+    /// recorded in that block's header.
+    pub fn verify_proof(
+        &self,
+        proof: &InclusionProof,
+        transaction: &Transaction,
+    ) -> Result<(), String> {
+        proof.verify(transaction)?;
+        let block = self
+            .chain
+            .get(proof.block_index as usize)
+            .ok_or_else(|| format!("Block {} not found", proof.block_index))?;
+        if block.hash != proof.block_hash || block.merkle_root != proof.merkle_root {
+            return Err(format!(
+                "Proof does not match block {} of the chain",
+                proof.block_index
+            ));
+        }
+        Ok(())
+    }
+
+    /// Total work of the active chain, see [`Target::work`]
+    pub fn total_work(&self) -> u128 {
+        Self::work(&self.chain)
+    }
+
+    fn work(blocks: &[Block]) -> u128 {
+        blocks.iter().fold(0u128, |work, block| {
+            work.saturating_add(block.target.work())
+        })
+    }
 
-        block.mine(self.difficulty);
+    /// Returns true if the block is on the active chain or a side branch
+    pub fn contains_block(&self, hash: &str) -> bool {
+        self.side_blocks.contains_key(hash) || self.height_of(hash).is_some()
+    }
+
+    /// Adds a block mined elsewhere, on top of any block already known
+    ///
+    /// A block on the tip is checked against the ledger of the active chain.
+    /// Any other block's branch is validated from the genesis block. If it
+    /// ends up with more work than the active chain, it becomes the active
+    /// chain, see [`Blockchain::replace_chain`]; otherwise the block is kept
+    /// as a side block in case its branch grows.
+    pub fn add_block(&mut self, block: Block) -> Result<BlockOutcome, String> {
+        self.add_block_at(block, Utc::now().timestamp())
+    }
+
+    /// Adds a block mined elsewhere, with `now` as the local time
+    ///
+    /// Blocks stamped more than [`MAX_FUTURE_BLOCK_TIME`] after `now` are
+    /// refused; they may be accepted once the local clock catches up.
+    pub fn add_block_at(&mut self, block: Block, now: i64) -> Result<BlockOutcome, String> {
+        Self::check_not_ahead(std::slice::from_ref(&block), now)?;
+        if self.contains_block(&block.hash) {
+            return Err(format!("Block {} is already known", block.hash));
+        }
+        if block.previous_hash == self.get_latest_block().hash {
+            self.extend(block)?;
+            return Ok(BlockOutcome::Extended);
+        }
+        let mut branch = self.branch_to(&block.previous_hash).ok_or_else(|| {
+            format!(
+                "Parent {} of block {} is unknown",
+                block.previous_hash, block.hash
+            )
+        })?;
+        branch.push(block);
+        self.validate_chain(&branch)?;
+
+        if Self::work(&branch) > self.total_work() {
+            let disconnected = self.reorganize(branch);
+            if disconnected == 0 {
+                return Ok(BlockOutcome::Extended);
+            }
+            return Ok(BlockOutcome::Reorganized { disconnected });
+        }
+        let block = branch.pop().expect("branch ends with the new block");
+        self.side_blocks.insert(block.hash.clone(), block);
+        Ok(BlockOutcome::SideBranch)
+    }
+
+    /// Adds a block on the tip, checking its transactions against the ledger
+    fn extend(&mut self, block: Block) -> Result<(), String> {
+        self.check_header(&self.chain, &block)?;
+        self.ledger();
+        let mut ledger = self.ledger.take().expect("ledger was just built");
+        let applied = self.apply_transactions(&block, &mut ledger);
+        self.ledger = OnceLock::from(ledger);
+        applied?;
+
+        self.index(std::slice::from_ref(&block));
         self.chain.push(block);
-        self.pending_transactions = vec![];
+        let (ledger, mempool) = self.ledger_and_mempool();
+        mempool.evict(ledger);
+        Ok(())
     }
 
-    /// Gets the balance of an address
-    pub fn get_balance(&self, address: &str) -> f64 {
-        let mut balance = 0.0;
+    /// Hashes of active chain blocks from which a peer can tell where its chain forks off
+    ///
+    /// Lists the ten latest blocks, then steps back twice as far each time,
+    /// and always ends with the genesis block.
+    pub fn locator(&self) -> Vec<String> {
+        let mut hashes = vec![];
+        let mut height = self.chain.len() - 1;
+        let mut step = 1;
+        loop {
+            hashes.push(self.chain[height].hash.clone());
+            if height == 0 {
+                return hashes;
+            }
+            if hashes.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+    }
 
-        for block in &self.chain {
-            for tx in &block.transactions {
-                if tx.from_address == address {
-                    balance -= tx.amount;
-                }
-                if tx.to_address == address {
-                    balance += tx.amount;
-                }
+    /// Up to `limit` active chain blocks after the first locator hash on it
+    ///
+    /// Starts after the genesis block if no hash of the locator is on the
+    /// active chain.
+    pub fn blocks_after(&self, locator: &[String], limit: usize) -> &[Block] {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.height_of(hash))
+            .map_or(1, |fork| fork + 1);
+        let end = start.saturating_add(limit).min(self.chain.len());
+        &self.chain[start.min(end)..end]
+    }
+
+    /// Blocks from the genesis block up to the known block with the given hash
+    fn branch_to(&self, hash: &str) -> Option<Vec<Block>> {
+        let mut side = vec![];
+        let mut hash = hash;
+        loop {
+            if let Some(fork) = self.height_of(hash) {
+                let mut branch = self.chain[..=fork].to_vec();
+                branch.extend(side.into_iter().rev());
+                return Some(branch);
             }
+            let block = self.side_blocks.get(hash)?;
+            side.push(block.clone());
+            hash = &block.previous_hash;
+        }
+    }
+
+    /// Replaces the active chain with a candidate that has more work
+    ///
+    /// The candidate must start from the same genesis block and be valid end
+    /// to end. Blocks of the active chain that the candidate leaves out are
+    /// kept as side blocks, and their transactions return to the mempool
+    /// unless the candidate already has them or they can no longer follow it.
+    pub fn replace_chain(&mut self, candidate: Vec<Block>) -> Result<(), String> {
+        Self::check_not_ahead(&candidate, Utc::now().timestamp())?;
+        self.validate_chain(&candidate)?;
+        if Self::work(&candidate) <= self.total_work() {
+            return Err("Candidate chain has no more work than the active chain".to_string());
+        }
+        self.reorganize(candidate);
+        Ok(())
+    }
+
+    /// Refuses blocks stamped more than [`MAX_FUTURE_BLOCK_TIME`] after `now`
+    fn check_not_ahead(blocks: &[Block], now: i64) -> Result<(), String> {
+        match blocks
+            .iter()
+            .find(|block| block.timestamp > now.saturating_add(MAX_FUTURE_BLOCK_TIME))
+        {
+            Some(block) => Err(format!(
+                "Block {} is stamped {} seconds ahead of the local clock",
+                block.index,
+                block.timestamp - now
+            )),
+            None => Ok(()),
         }
+    }
 
-        balance
+    /// Switches to a valid chain and returns how many blocks were disconnected
+    fn reorganize(&mut self, chain: Vec<Block>) -> usize {
+        let fork = self
+            .chain
+            .iter()
+            .zip(&chain)
+            .take_while(|(active, new)| active.hash == new.hash)
+            .count();
+        let disconnected = self.chain.split_off(fork);
+        self.disconnect(&disconnected);
+        self.connect(&chain[fork..]);
+        for block in &chain[fork..] {
+            self.side_blocks.remove(&block.hash);
+        }
+        self.chain = chain;
+
+        let orphaned = disconnected
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .filter(|tx| tx.transaction_type != TransactionType::MiningReward)
+            .cloned()
+            .collect();
+        let count = disconnected.len();
+        for block in disconnected {
+            self.side_blocks.insert(block.hash.clone(), block);
+        }
+        let (ledger, mempool) = self.ledger_and_mempool();
+        mempool.readmit(orphaned, ledger);
+        count
     }
 
     /// Validates the blockchain integrity
     pub fn is_valid(&self) -> bool {
-        for i in 1..self.chain.len() {
-            let current = &self.chain[i];
-            let previous = &self.chain[i - 1];
+        self.validate_chain(&self.chain).is_ok()
+    }
+
+    /// Checks a chain from its genesis block on, under this chain's rules
+    ///
+    /// The chain must start from this chain's genesis block, and every block
+    /// must link to the one before it, be stamped later than the median time
+    /// past of the blocks before it, meet the target the retarget rule gives
+    /// it and hold only valid transactions. Up to the pruned height it
+    /// must match this chain, as only the block headers are left there.
+    pub fn validate_chain(&self, blocks: &[Block]) -> Result<(), String> {
+        let genesis = blocks.first().ok_or("Chain is empty")?;
+        if genesis.hash != self.chain[0].hash {
+            return Err("Chain starts from a different genesis block".to_string());
+        }
+        let mut ledger = self.pruned_ledger.clone();
+        for i in 1..blocks.len() {
+            let current = &blocks[i];
+            self.check_header(&blocks[..i], current)?;
+            if current.index > self.pruned_height {
+                self.apply_transactions(current, &mut ledger)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks everything about a block that follows `blocks` but its transactions
+    fn check_header(&self, blocks: &[Block], current: &Block) -> Result<(), String> {
+        let i = blocks.len();
+        let previous = &blocks[i - 1];
+
+        if current.index != i as u64 {
+            return Err(format!("Block {} is at height {}", current.index, i));
+        }
+
+        // Check if the hash is correct
+        if current.hash != current.calculate_hash() {
+            return Err(format!("Hash of block {} is incorrect", i));
+        }
+
+        // Pruned blocks only have their headers left to check
+        let pruned = current.index <= self.pruned_height;
+        if pruned && self.chain.get(i).map(|block| &block.hash) != Some(&current.hash) {
+            return Err(format!(
+                "Block {} forks off at or below the pruned height {}",
+                i, self.pruned_height
+            ));
+        }
+
+        // Check that the header commits to the transactions
+        if !pruned && current.merkle_root != current.calculate_merkle_root() {
+            return Err(format!("Merkle root of block {} is incorrect", i));
+        }
+
+        // Check if the previous hash reference is correct
+        if current.previous_hash != previous.hash {
+            return Err(format!("Block {} does not follow block {}", i, i - 1));
+        }
+
+        if current.timestamp <= Self::median_time_past(blocks) {
+            return Err(format!(
+                "Block {} is not later than the median time of the blocks before it",
+                i
+            ));
+        }
+
+        // Check the proof of work against the target the chain called for
+        if current.target != self.target_after(blocks) {
+            return Err(format!("Block {} has the wrong target", i));
+        }
+        if !current.target.is_met_by(&current.hash) {
+            return Err(format!("Hash of block {} does not meet its target", i));
+        }
+        Ok(())
+    }
 
-            // Check if the hash is correct
-            if current.hash != current.calculate_hash() {
-                return false;
+    /// Checks that every transaction in a block is authorized and applies them
+    ///
+    /// Apart from signed transactions, a block holds its deposits and at most
+    /// one mining reward, which comes last and pays no more than
+    /// `mining_reward`, in [`REWARD_CURRENCY`]. Each transaction must be able
+    /// to follow the ones before it in `ledger`: no replays and no
+    /// overspends. If one cannot, `ledger` is left as it was.
+    fn apply_transactions(&self, block: &Block, ledger: &mut Ledger) -> Result<(), String> {
+        for (i, tx) in block.transactions.iter().enumerate() {
+            if let Err(e) = self.check_transaction(block, i, ledger) {
+                for applied in block.transactions[..i].iter().rev() {
+                    ledger.revert(applied);
+                }
+                return Err(e);
             }
+            ledger.apply(tx);
+        }
+        Ok(())
+    }
 
-            // Check if the previous hash reference is correct
-            if current.previous_hash != previous.hash {
-                return false;
+    /// Checks that the `i`th transaction of a block can follow `ledger`
+    fn check_transaction(&self, block: &Block, i: usize, ledger: &Ledger) -> Result<(), String> {
+        let tx = &block.transactions[i];
+        if tx.transaction_type == TransactionType::MiningReward {
+            if i + 1 != block.transactions.len() {
+                return Err(format!(
+                    "Mining reward of block {} is not last",
+                    block.index
+                ));
+            }
+            if tx.currency != REWARD_CURRENCY {
+                return Err(format!(
+                    "Mining reward of block {} is not paid in {}",
+                    block.index, REWARD_CURRENCY
+                ));
             }
+            if tx.amount > self.mining_reward {
+                return Err(format!(
+                    "Mining reward of block {} exceeds {}",
+                    block.index, self.mining_reward
+                ));
+            }
+        }
+        self.authorize(tx)
+            .and_then(|_| ledger.check(tx))
+            .map_err(|e| format!("Block {}: {}", block.index, e))
+    }
+
+    /// Checks a transaction's signature, see [`Transaction::verify`]
+    ///
+    /// Deposits bring funds from outside the chain, so only the custodian
+    /// can sign them.
+    fn authorize(&self, transaction: &Transaction) -> Result<(), String> {
+        transaction.verify()?;
+        if transaction.transaction_type != TransactionType::Deposit {
+            return Ok(());
+        }
+        match &self.custodian {
+            Some(custodian) if transaction.public_key.as_ref() == Some(custodian) => Ok(()),
+            Some(_) => Err(format!(
+                "Deposit {} is not signed by the custodian",
+                transaction.id
+            )),
+            None => Err("Chain has no custodian to take deposits from".to_string()),
         }
-        true
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::keys;
+    use crate::wallet::Wallet;
+    use ed25519_dalek::SigningKey;
+
+    /// Key the test chains take deposits from
+    fn custodian() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    /// A chain with a one-zero target that takes deposits from [`custodian`]
+    fn test_chain() -> Blockchain {
+        Blockchain::new(Target::with_leading_zeros(1), Amount::from(100))
+            .with_custodian(&custodian().verifying_key())
+    }
+
+    fn deposit(address: &str, amount: i64) -> Transaction {
+        Transaction::new_deposit(address.to_string(), "BTC", Amount::from(amount))
+            .sign(&custodian())
+    }
 
     #[test]
     fn test_blockchain_creation() {
-        let blockchain = Blockchain::new(2, 100.0);
+        let blockchain = Blockchain::new(Target::with_leading_zeros(2), Amount::from(100));
         assert_eq!(blockchain.chain.len(), 1);
         assert!(blockchain.is_valid());
     }
 
     #[test]
     fn test_mining() {
-        let mut blockchain = Blockchain::new(1, 100.0);
-        blockchain.add_transaction(Transaction::new(
-            "Alice".to_string(),
+        let mut blockchain = test_chain();
+        let alice = Wallet::new("Alice");
+        let deposit = deposit(&alice.address, 50);
+        blockchain.add_transaction(deposit).unwrap();
+        let tx = Transaction::new(
+            alice.address.clone(),
             "Bob".to_string(),
-            50.0,
-        )).unwrap();
+            "BTC",
+            Amount::from(50),
+        );
+        blockchain.add_transaction(alice.sign(tx).unwrap()).unwrap();
         blockchain.mine_pending_transactions("Miner");
         assert_eq!(blockchain.chain.len(), 2);
         assert!(blockchain.is_valid());
+        assert!(blockchain.mempool.is_empty());
+        assert_eq!(
+            blockchain.get_balance("Miner", REWARD_CURRENCY),
+            Amount::from(100)
+        );
+        assert_eq!(blockchain.get_balance("Bob", "BTC"), Amount::from(50));
+    }
+
+    #[test]
+    fn test_full_blocks_take_highest_fees_and_replays_are_invalid() {
+        let mut blockchain = test_chain();
+        blockchain.max_block_transactions = 3;
+        let alice = Wallet::new("Alice");
+        let bob = Wallet::new("Bob");
+        for wallet in [&alice, &bob] {
+            let deposit = deposit(&wallet.address, 10);
+            blockchain.add_transaction(deposit).unwrap();
+        }
+        blockchain.mine_pending_transactions("Miner");
+
+        let cheap = Transaction::new(
+            alice.address.clone(),
+            "Carol".to_string(),
+            "BTC",
+            Amount::from(5),
+        )
+        .with_nonce(blockchain.next_nonce(&alice.address));
+        blockchain
+            .add_transaction(alice.sign(cheap).unwrap())
+            .unwrap();
+        let pricey = Transaction::new(
+            bob.address.clone(),
+            "Carol".to_string(),
+            "BTC",
+            Amount::from(5),
+        )
+        .with_nonce(blockchain.next_nonce(&bob.address))
+        .with_fee(Amount::from(1));
+        blockchain
+            .add_transaction(bob.sign(pricey).unwrap())
+            .unwrap();
+        let rest = Transaction::new(
+            alice.address.clone(),
+            "Carol".to_string(),
+            "BTC",
+            Amount::from(5),
+        )
+        .with_nonce(blockchain.next_nonce(&alice.address));
+        blockchain
+            .add_transaction(alice.sign(rest).unwrap())
+            .unwrap();
+        assert_eq!(blockchain.next_nonce(&alice.address), 2);
+
+        // Two transactions and the reward fit; Alice's second one waits
+        blockchain.mine_pending_transactions("Miner");
+        let block = blockchain.get_latest_block();
+        assert_eq!(block.transactions.len(), 3);
+        assert_eq!(block.transactions[0].from_address, bob.address);
+        assert_eq!(blockchain.mempool.len(), 1);
+        assert_eq!(blockchain.mempool.transactions()[0].nonce, 1);
+        assert_eq!(blockchain.get_balance("Carol", "BTC"), Amount::from(10));
+
+        // A block that replays a mined transfer breaks the chain
+        let replayed = block.transactions[0].clone();
+        let mut tampered = blockchain.clone();
+        let previous_hash = tampered.get_latest_block().hash.clone();
+        let mut block = Block::new(3, vec![replayed], previous_hash, tampered.next_target());
+        block.mine();
+        tampered.chain.push(block);
+        assert!(!tampered.is_valid());
+    }
+
+    #[test]
+    fn test_unsigned_and_forged_transactions_are_rejected() {
+        let mut blockchain = test_chain();
+        let alice = Wallet::new("Alice");
+        let mallory = Wallet::new("Mallory");
+
+        let unsigned = Transaction::new(
+            alice.address.clone(),
+            "Bob".to_string(),
+            "BTC",
+            Amount::from(5),
+        );
+        assert!(blockchain.add_transaction(unsigned).is_err());
+        let to_mallory = Transaction::new(
+            alice.address.clone(),
+            mallory.address.clone(),
+            "BTC",
+            Amount::from(5),
+        );
+        let forged = to_mallory.sign(&keys::generate_signing_key());
+        assert!(blockchain.add_transaction(forged).is_err());
+        let reward = Transaction::new_mining_reward("Mallory".to_string(), Amount::from(100));
+        assert!(blockchain.add_transaction(reward).is_err());
+        // Only the custodian can vouch for deposits
+        let unsigned = Transaction::new_deposit(alice.address.clone(), "BTC", Amount::from(5));
+        assert!(blockchain
+            .add_transaction(unsigned)
+            .unwrap_err()
+            .contains("not signed"));
+        let minted = Transaction::new_deposit(mallory.address.clone(), "BTC", Amount::from(5));
+        let minted = minted.sign(&keys::generate_signing_key());
+        assert!(blockchain
+            .add_transaction(minted)
+            .unwrap_err()
+            .contains("custodian"));
+        let mut without_custodian =
+            Blockchain::new(Target::with_leading_zeros(1), Amount::from(100));
+        assert!(without_custodian
+            .add_transaction(deposit(&alice.address, 5))
+            .is_err());
+        assert!(blockchain.add_transaction(deposit(&alice.address, 5)).is_ok());
+
+        let tx = Transaction::new(
+            alice.address.clone(),
+            "Bob".to_string(),
+            "BTC",
+            Amount::from(5),
+        );
+        blockchain.add_transaction(alice.sign(tx).unwrap()).unwrap();
+        blockchain.mine_pending_transactions("Miner");
+        assert!(blockchain.is_valid());
+
+        // Redirecting a signed transfer and re-mining the block still breaks the chain
+        let mut tampered = blockchain.clone();
+        tampered.chain[1].transactions[1].to_address = mallory.address.clone();
+        tampered.chain[1].merkle_root = tampered.chain[1].calculate_merkle_root();
+        tampered.chain[1].nonce = 0;
+        tampered.chain[1].hash = tampered.chain[1].calculate_hash();
+        tampered.chain[1].mine();
+        assert!(!tampered.is_valid());
+
+        // So does a reward slipped in ahead of other transactions
+        let mut tampered = blockchain.clone();
+        let reward = tampered.chain[1].transactions.pop().unwrap();
+        tampered.chain[1].transactions.insert(0, reward);
+        tampered.chain[1].merkle_root = tampered.chain[1].calculate_merkle_root();
+        tampered.chain[1].hash = tampered.chain[1].calculate_hash();
+        tampered.chain[1].mine();
+        assert!(!tampered.is_valid());
+    }
+
+    #[test]
+    fn test_inclusion_proofs() {
+        let mut blockchain = test_chain();
+        let alice = Wallet::new("Alice");
+        let mut deposits = vec![];
+        for amount in 1..=5 {
+            let deposit =
+                deposit(&alice.address, amount);
+            deposits.push(deposit.clone());
+            blockchain.add_transaction(deposit).unwrap();
+        }
+        blockchain.mine_pending_transactions("Miner");
+        let withdrawal = Transaction::new_withdrawal(alice.address.clone(), "BTC", Amount::from(2))
+            .with_nonce(blockchain.next_nonce(&alice.address));
+        let withdrawal = alice.sign(withdrawal).unwrap();
+        blockchain.add_transaction(withdrawal.clone()).unwrap();
+        blockchain.mine_pending_transactions("Miner");
+
+        for tx in deposits.iter().chain([&withdrawal]) {
+            let proof = blockchain.prove_transaction(&tx.id).unwrap();
+            assert!(blockchain.verify_proof(&proof, tx).is_ok());
+        }
+        let proof = blockchain.prove_transaction(&withdrawal.id).unwrap();
+        assert_eq!(proof.block_index, 2);
+        assert!(blockchain.prove_transaction("unknown").is_none());
+
+        // An altered transaction, or a proof for another one, does not verify
+        let mut altered = withdrawal.clone();
+        altered.amount = Amount::from(1);
+        assert!(proof.verify(&altered).is_err());
+        assert!(proof.verify(&deposits[0]).is_err());
+        let mut forged = blockchain.prove_transaction(&deposits[0].id).unwrap();
+        forged.path[0].hash = deposits[2].hash();
+        assert!(forged.verify(&deposits[0]).is_err());
+
+        // Nor does a proof against a root the chain never recorded
+        let mut elsewhere = proof.clone();
+        elsewhere.block_index = 1;
+        assert!(elsewhere.verify(&withdrawal).is_ok());
+        assert!(blockchain.verify_proof(&elsewhere, &withdrawal).is_err());
+    }
+
+    #[test]
+    fn test_edited_transactions_break_the_merkle_root() {
+        let mut blockchain = test_chain();
+        let deposit = deposit("Alice", 5);
+        blockchain.add_transaction(deposit).unwrap();
+        blockchain.mine_pending_transactions("Miner");
+        assert!(blockchain.is_valid());
+
+        // The header hash stays intact, but no longer matches the transactions
+        let mut tampered = blockchain.clone();
+        tampered.chain[1].transactions[0].amount = Amount::from(500);
+        assert_eq!(tampered.chain[1].hash, tampered.chain[1].calculate_hash());
+        assert!(!tampered.is_valid());
+    }
+
+    #[test]
+    fn test_target_follows_block_times() {
+        fn mine_at(blockchain: &mut Blockchain, timestamp: i64) {
+            let reward_id = Uuid::new_v4().to_string();
+            blockchain.mine_pending_transactions_at("Miner", reward_id, timestamp);
+        }
+        let start = Target::with_leading_zeros(1);
+        let mut blockchain = Blockchain::with_genesis_time(start, Amount::from(100), 0);
+        blockchain.retarget_interval = 3;
+
+        // Two blocks in 20 seconds instead of 120: as much harder as one step allows
+        mine_at(&mut blockchain, 10);
+        mine_at(&mut blockchain, 20);
+        assert_eq!(blockchain.chain[2].target, start);
+        let harder = blockchain.next_target();
+        assert_eq!(harder, start.retarget(30, 120));
+        assert!(harder.difficulty() > start.difficulty());
+
+        // 400 seconds instead of 120 eases it by that much
+        for timestamp in [100, 300, 500] {
+            mine_at(&mut blockchain, timestamp);
+        }
+        assert_eq!(blockchain.chain[5].target, harder);
+        let eased = blockchain.next_target();
+        assert_eq!(eased, harder.retarget(400, 120));
+        assert!(eased.difficulty() < harder.difficulty());
+        assert!(blockchain.is_valid());
+
+        // A block that skips the retarget breaks the chain, even with its work done
+        let mut skipped = blockchain.clone();
+        skipped.chain.truncate(3);
+        let previous_hash = skipped.get_latest_block().hash.clone();
+        let mut block = Block::with_timestamp(3, vec![], previous_hash, start, 100);
+        block.mine();
+        skipped.chain.push(block);
+        assert!(!skipped.is_valid());
+
+        // So does a block whose hash misses its target
+        let mut unmined = blockchain.clone();
+        let block = unmined.chain.last_mut().unwrap();
+        while block.target.is_met_by(&block.hash) {
+            block.nonce += 1;
+            block.hash = block.calculate_hash();
+        }
+        assert!(!unmined.is_valid());
+    }
+
+    /// A chain with a one-zero target and a fixed genesis block
+    fn shared_genesis() -> Blockchain {
+        Blockchain::with_genesis_time(Target::with_leading_zeros(1), Amount::from(100), 0)
+            .with_custodian(&custodian().verifying_key())
+    }
+
+    /// The ledger replayed from the blocks, instead of the one kept up to date
+    fn replayed_ledger(blockchain: &Blockchain) -> Ledger {
+        let mut replay = blockchain.clone();
+        replay.ledger = OnceLock::new();
+        replay.ledger().clone()
+    }
+
+    fn mine_at(blockchain: &mut Blockchain, miner: &str, timestamp: i64) -> Block {
+        let reward_id = Uuid::new_v4().to_string();
+        blockchain.mine_pending_transactions_at(miner, reward_id, timestamp);
+        blockchain.get_latest_block().clone()
+    }
+
+    #[test]
+    fn test_block_times_move_forward_and_stay_near_the_clock() {
+        let mut blockchain = shared_genesis();
+        let mut peer = blockchain.clone();
+        for timestamp in [10, 20, 30] {
+            mine_at(&mut peer, "Miner", timestamp);
+        }
+
+        // The median of 0, 10, 20 and 30 is 20, so a block must be stamped later
+        let stale = |peer: &Blockchain, timestamp| {
+            let previous_hash = peer.get_latest_block().hash.clone();
+            let index = peer.chain.len() as u64;
+            let mut block =
+                Block::with_timestamp(index, vec![], previous_hash, peer.next_target(), timestamp);
+            block.mine();
+            block
+        };
+        let err = peer.add_block_at(stale(&peer, 20), 100).unwrap_err();
+        assert!(err.contains("median"));
+        peer.add_block_at(stale(&peer, 21), 100).unwrap();
+
+        // Mining moves an early timestamp up instead
+        assert_eq!(mine_at(&mut peer, "Miner", 5).timestamp, 21);
+        assert!(peer.is_valid());
+
+        // A block too far ahead of the local clock waits for the clock to catch up
+        for block in &peer.chain[1..] {
+            blockchain.add_block_at(block.clone(), 100).unwrap();
+        }
+        let ahead = stale(&blockchain, 100 + MAX_FUTURE_BLOCK_TIME + 1);
+        let err = blockchain.add_block_at(ahead.clone(), 100).unwrap_err();
+        assert!(err.contains("ahead"));
+        assert!(blockchain.add_block_at(ahead, 101).is_ok());
+    }
+
+    #[test]
+    fn test_heaviest_branch_wins_and_orphans_return() {
+        let mut blockchain = shared_genesis();
+        let mut rival = blockchain.clone();
+        let alice = Wallet::new("Alice");
+        let deposit = deposit(&alice.address, 10);
+        blockchain.add_transaction(deposit.clone()).unwrap();
+        let transfer = Transaction::new(
+            alice.address.clone(),
+            "Bob".to_string(),
+            "BTC",
+            Amount::from(4),
+        );
+        let transfer = alice.sign(transfer).unwrap();
+        blockchain.add_transaction(transfer.clone()).unwrap();
+        let ours = mine_at(&mut blockchain, "Miner", 10);
+
+        // A rival miner finds a block at the same height: no more work, so no switch
+        let first = mine_at(&mut rival, "Rival", 11);
+        assert_eq!(
+            blockchain.add_block(first.clone()),
+            Ok(BlockOutcome::SideBranch)
+        );
+        assert_eq!(blockchain.get_latest_block().hash, ours.hash);
+        assert!(blockchain
+            .add_block(first)
+            .unwrap_err()
+            .contains("already known"));
+
+        // Its next block makes the rival branch heavier
+        let second = mine_at(&mut rival, "Rival", 20);
+        assert_eq!(
+            blockchain.add_block(second.clone()),
+            Ok(BlockOutcome::Reorganized { disconnected: 1 })
+        );
+        assert_eq!(blockchain.get_latest_block().hash, second.hash);
+        assert_eq!(blockchain.total_work(), rival.total_work());
+        assert!(blockchain.is_valid());
+        assert!(blockchain.contains_block(&ours.hash));
+
+        // The orphaned transactions are pending again, in their old order
+        let pending: Vec<_> = blockchain
+            .mempool
+            .transactions()
+            .iter()
+            .map(|tx| &tx.id)
+            .collect();
+        assert_eq!(pending, [&deposit.id, &transfer.id]);
+        assert_eq!(blockchain.get_balance("Bob", "BTC"), Amount::ZERO);
+        // The kept ledger dropped the disconnected block and took the new ones
+        let replayed = replayed_ledger(&blockchain);
+        for address in ["Miner", "Rival", "Bob", alice.address.as_str()] {
+            for currency in ["BTC", REWARD_CURRENCY] {
+                assert_eq!(
+                    blockchain.ledger().balance(address, currency),
+                    replayed.balance(address, currency)
+                );
+            }
+        }
+        let rival_rewards = blockchain.ledger().balance("Rival", REWARD_CURRENCY);
+        assert_eq!(rival_rewards, Amount::from(200));
+        assert_eq!(blockchain.ledger().next_nonce(&alice.address), 0);
+        mine_at(&mut blockchain, "Miner", 30);
+        assert_eq!(blockchain.get_balance("Bob", "BTC"), Amount::from(4));
+
+        // A block on the tip just extends it
+        let next = {
+            let mut copy = blockchain.clone();
+            mine_at(&mut copy, "Miner", 40)
+        };
+        assert_eq!(blockchain.add_block(next), Ok(BlockOutcome::Extended));
+        assert_eq!(blockchain.chain.len(), 5);
+    }
+
+    #[test]
+    fn test_invalid_and_unconnected_blocks_are_refused() {
+        let mut blockchain = shared_genesis();
+        let mut rival = blockchain.clone();
+        mine_at(&mut rival, "Rival", 10);
+        let orphan = mine_at(&mut rival, "Rival", 20);
+        assert!(blockchain
+            .add_block(orphan)
+            .unwrap_err()
+            .contains("unknown"));
+
+        // A side block that pays itself too much is not kept
+        let previous_hash = blockchain.get_latest_block().hash.clone();
+        let reward = Transaction::new_mining_reward("Greedy".to_string(), Amount::from(1000));
+        let mut greedy =
+            Block::with_timestamp(1, vec![reward], previous_hash, blockchain.next_target(), 10);
+        greedy.mine();
+        let err = blockchain.add_block(greedy.clone()).unwrap_err();
+        assert!(err.contains("exceeds"));
+        assert!(!blockchain.contains_block(&greedy.hash));
+
+        // Or one that pays its reward in a currency the exchange trades
+        let previous_hash = blockchain.get_latest_block().hash.clone();
+        let mut reward = Transaction::new_mining_reward("Greedy".to_string(), Amount::from(10));
+        reward.currency = "BTC".to_string();
+        let mut tradable =
+            Block::with_timestamp(1, vec![reward], previous_hash, blockchain.next_target(), 10);
+        tradable.mine();
+        let err = blockchain.add_block(tradable.clone()).unwrap_err();
+        assert!(err.contains(REWARD_CURRENCY));
+        assert!(!blockchain.contains_block(&tradable.hash));
+
+        // So is one that mints itself a deposit
+        let previous_hash = blockchain.get_latest_block().hash.clone();
+        let minted = Transaction::new_deposit("Mallory".to_string(), "BTC", Amount::from(1000));
+        let mut minting =
+            Block::with_timestamp(1, vec![minted], previous_hash, blockchain.next_target(), 10);
+        minting.mine();
+        let err = blockchain.add_block(minting).unwrap_err();
+        assert!(err.contains("not signed"));
+    }
+
+    #[test]
+    fn test_tip_blocks_are_checked_against_the_kept_ledger() {
+        let mut blockchain = shared_genesis();
+        let alice = Wallet::new("Alice");
+        blockchain.add_transaction(deposit(&alice.address, 10)).unwrap();
+        mine_at(&mut blockchain, "Miner", 10);
+        let transfer = |amount: i64, nonce: u64| {
+            let tx = Transaction::new(
+                alice.address.clone(),
+                "Bob".to_string(),
+                "BTC",
+                Amount::from(amount),
+            );
+            alice.sign(tx.with_nonce(nonce)).unwrap()
+        };
+        let tip_block = |blockchain: &Blockchain, transactions| {
+            let previous_hash = blockchain.get_latest_block().hash.clone();
+            let mut block =
+                Block::with_timestamp(2, transactions, previous_hash, blockchain.next_target(), 20);
+            block.mine();
+            block
+        };
+
+        // A block whose second transfer overspends leaves the ledger as it was
+        let overspent = tip_block(&blockchain, vec![transfer(4, 0), transfer(20, 1)]);
+        let err = blockchain.add_block(overspent.clone()).unwrap_err();
+        assert!(err.contains("Block 2"));
+        assert!(!blockchain.contains_block(&overspent.hash));
+        assert_eq!(blockchain.chain.len(), 2);
+        assert_eq!(blockchain.get_balance(&alice.address, "BTC"), Amount::from(10));
+        assert_eq!(blockchain.ledger().next_nonce(&alice.address), 0);
+
+        // A valid one connects to the kept ledger and the hash index
+        let valid = tip_block(&blockchain, vec![transfer(4, 0), transfer(6, 1)]);
+        assert_eq!(blockchain.add_block(valid.clone()), Ok(BlockOutcome::Extended));
+        assert!(blockchain.contains_block(&valid.hash));
+        assert!(blockchain.blocks_after(std::slice::from_ref(&valid.hash), 10).is_empty());
+        assert_eq!(blockchain.get_balance("Bob", "BTC"), Amount::from(10));
+        let replayed = replayed_ledger(&blockchain);
+        assert_eq!(replayed.balance("Bob", "BTC"), Amount::from(10));
+        assert_eq!(replayed.next_nonce(&alice.address), 2);
+        assert!(blockchain.is_valid());
+    }
+
+    #[test]
+    fn test_replace_chain_validates_end_to_end() {
+        let mut blockchain = shared_genesis();
+        mine_at(&mut blockchain, "Miner", 10);
+        let mut rival = shared_genesis();
+        for timestamp in [10, 20, 30] {
+            mine_at(&mut rival, "Rival", timestamp);
+        }
+
+        let lighter = rival.chain[..2].to_vec();
+        assert!(blockchain
+            .replace_chain(lighter)
+            .unwrap_err()
+            .contains("no more work"));
+
+        let mut tampered = rival.chain.clone();
+        tampered[2].transactions[0].to_address = "Mallory".to_string();
+        assert!(blockchain
+            .replace_chain(tampered)
+            .unwrap_err()
+            .contains("Merkle root"));
+
+        let elsewhere =
+            Blockchain::with_genesis_time(Target::with_leading_zeros(1), Amount::from(100), 1);
+        let mut foreign = elsewhere.clone();
+        for timestamp in [10, 20, 30] {
+            mine_at(&mut foreign, "Rival", timestamp);
+        }
+        let err = blockchain.replace_chain(foreign.chain).unwrap_err();
+        assert!(err.contains("different genesis"));
+
+        let ours = blockchain.get_latest_block().hash.clone();
+        blockchain.replace_chain(rival.chain.clone()).unwrap();
+        assert_eq!(blockchain.chain.len(), 4);
+        assert!(blockchain.is_valid());
+        assert!(blockchain.side_blocks.contains_key(&ours));
+    }
+
+    #[test]
+    fn test_locator_finds_the_fork() {
+        let mut blockchain = shared_genesis();
+        for timestamp in 1..=14 {
+            mine_at(&mut blockchain, "Miner", timestamp * 60);
+        }
+        let heights: Vec<u64> = blockchain
+            .locator()
+            .iter()
+            .map(|hash| blockchain.chain.iter().position(|b| &b.hash == hash).unwrap() as u64)
+            .collect();
+        assert_eq!(heights, [14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 3, 0]);
+
+        let mut behind = shared_genesis();
+        let blocks = blockchain.blocks_after(&behind.locator(), 5);
+        assert_eq!(blocks.len(), 5);
+        for block in blocks {
+            behind.add_block(block.clone()).unwrap();
+        }
+        let rest = blockchain.blocks_after(&behind.locator(), 100);
+        assert_eq!(rest.len(), 9);
+        assert_eq!(rest[0].index, 6);
+        assert!(blockchain.blocks_after(&blockchain.locator(), 100).is_empty());
+    }
+
+    #[test]
+    fn test_pruned_chain_keeps_balances_and_refuses_deep_forks() {
+        let mut blockchain = shared_genesis();
+        let rival = blockchain.clone();
+        let deposit = deposit("Alice", 10);
+        blockchain.add_transaction(deposit.clone()).unwrap();
+        for timestamp in [10, 20, 30] {
+            mine_at(&mut blockchain, "Miner", timestamp);
+        }
+        let ledger = blockchain.ledger().clone();
+        assert!(blockchain.prove_transaction(&deposit.id).is_some());
+
+        blockchain.prune(2);
+        assert_eq!(blockchain.pruned_height, 2);
+        assert!(blockchain.chain[1..=2].iter().all(|b| b.transactions.is_empty()));
+        assert!(!blockchain.chain[3].transactions.is_empty());
+        assert!(blockchain.is_valid());
+        assert!(blockchain.prove_transaction(&deposit.id).is_none());
+        assert_eq!(blockchain.get_balance("Alice", "BTC"), Amount::from(10));
+        assert_eq!(blockchain.get_balance("Miner", "BTC"), ledger.balance("Miner", "BTC"));
+        // The deposit still counts as mined
+        assert!(blockchain.add_transaction(deposit).is_err());
+
+        // Pruning again below the pruned height changes nothing
+        blockchain.prune(1);
+        assert_eq!(blockchain.pruned_height, 2);
+        mine_at(&mut blockchain, "Miner", 40);
+        assert!(blockchain.is_valid());
+
+        // Even a heavier chain cannot replace the pruned blocks
+        let mut heavier = rival;
+        for timestamp in [10, 20, 30, 40, 50, 60] {
+            mine_at(&mut heavier, "Rival", timestamp);
+        }
+        let err = blockchain.replace_chain(heavier.chain).unwrap_err();
+        assert!(err.contains("pruned height"));
     }
 }
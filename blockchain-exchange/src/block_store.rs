@@ -0,0 +1,724 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::block::{Block, Blockchain};
+use crate::mempool::Ledger;
+
+/// Bytes of the length that comes before each block in the block file
+const LENGTH_BYTES: u64 = 4;
+
+/// Where a stored block sits in the block file
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Location {
+    height: u64,
+    /// Offset of the block's record, its length included
+    offset: u64,
+    /// Length of the block's JSON
+    length: u64,
+}
+
+impl Location {
+    /// Offset just past the record
+    fn end(&self) -> u64 {
+        self.offset + LENGTH_BYTES + self.length
+    }
+}
+
+/// What the pruned blocks leave behind, see [`Blockchain::prune`]
+#[derive(Serialize, Deserialize)]
+struct Pruned {
+    height: u64,
+    ledger: Ledger,
+}
+
+/// Blocks of a chain that the store does not have yet, see [`BlockStore::unsaved`]
+pub struct UnsavedBlocks {
+    /// Height from which the active chain differs from the stored one
+    fork: usize,
+    /// Hashes of the active blocks from `fork` on
+    active: Vec<String>,
+    blocks: Vec<Block>,
+}
+
+/// The blocks of a [`Blockchain`] on disk
+///
+/// `blocks.dat` holds every block the chain accepted, on any branch, each
+/// appended once as its JSON preceded by its length in 4 little-endian
+/// bytes. `blocks.idx` has a line per block with its hash, height, offset
+/// and length. A crash can leave a torn record, or a block without its index
+/// line, at the end; opening the store truncates the one and indexes the
+/// other.
+///
+/// With pruning, active blocks more than a set depth below the tip keep only
+/// their headers, and `pruned.json` holds the ledger they leave behind.
+pub struct BlockStore {
+    blocks_path: PathBuf,
+    index_path: PathBuf,
+    pruned_path: PathBuf,
+    /// Every stored block, by hash
+    locations: HashMap<String, Location>,
+    /// Length of the block file
+    end: u64,
+    /// Hashes of the active chain by height, as of the last load or save
+    heights: Vec<String>,
+    /// How many of the latest blocks keep their transactions, if pruning
+    keep_recent: Option<u64>,
+}
+
+impl BlockStore {
+    /// Opens the store in `dir`, repairing what a crash left at its end
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, String> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        let mut store = BlockStore {
+            blocks_path: dir.join("blocks.dat"),
+            index_path: dir.join("blocks.idx"),
+            pruned_path: dir.join("pruned.json"),
+            locations: HashMap::new(),
+            end: 0,
+            heights: vec![],
+            keep_recent: None,
+        };
+        store.recover()?;
+        Ok(store)
+    }
+
+    /// Prunes blocks more than `keep_recent` blocks below the tip on save
+    ///
+    /// Pruning happens in batches of `keep_recent` blocks, or of one block if
+    /// it is 0, as each prune rewrites the block file.
+    pub fn with_pruning(mut self, keep_recent: u64) -> Self {
+        self.keep_recent = Some(keep_recent);
+        self
+    }
+
+    /// Number of stored blocks, on any branch
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    /// Reads a stored block by its hash
+    pub fn block_by_hash(&self, hash: &str) -> Result<Option<Block>, String> {
+        let Some(location) = self.locations.get(hash) else {
+            return Ok(None);
+        };
+        let mut file = File::open(&self.blocks_path)
+            .map_err(|e| format!("Failed to open {}: {}", self.blocks_path.display(), e))?;
+        self.read_block(&mut file, hash, location).map(Some)
+    }
+
+    /// Reads the active chain's block at `height`
+    pub fn block_by_height(&self, height: u64) -> Result<Option<Block>, String> {
+        match self.heights.get(height as usize) {
+            Some(hash) => self.block_by_hash(hash),
+            None => Ok(None),
+        }
+    }
+
+    /// Replaces the blocks of `chain` with the stored ones
+    ///
+    /// The stored branch with the most work becomes the active chain and the
+    /// others its side blocks, which must pass [`Blockchain::is_valid`] under
+    /// `chain`'s rules. Pending transactions that the stored blocks already
+    /// hold leave the mempool. An empty store leaves `chain` as it is.
+    pub fn load(&mut self, chain: &mut Blockchain) -> Result<(), String> {
+        if self.locations.is_empty() {
+            return Ok(());
+        }
+        let blocks = self.read_all()?;
+        let genesis = &blocks[0];
+        if genesis.hash != chain.chain[0].hash {
+            return Err("Stored blocks start from a different genesis block".to_string());
+        }
+
+        // Work up to each block that links back to the genesis block; blocks
+        // are stored after their parents
+        let mut work = HashMap::from([(genesis.hash.clone(), genesis.target.work())]);
+        let mut tip = (genesis.hash.clone(), genesis.target.work());
+        for block in &blocks[1..] {
+            let Some(&parent) = work.get(&block.previous_hash) else {
+                continue;
+            };
+            let total = parent.saturating_add(block.target.work());
+            work.insert(block.hash.clone(), total);
+            // As with Blockchain::add_block, the first branch keeps ties
+            if total > tip.1 {
+                tip = (block.hash.clone(), total);
+            }
+        }
+        let mut side_blocks: HashMap<String, Block> = blocks
+            .into_iter()
+            .filter(|block| work.contains_key(&block.hash))
+            .map(|block| (block.hash.clone(), block))
+            .collect();
+        let mut active = vec![];
+        let mut hash = tip.0;
+        while let Some(block) = side_blocks.remove(&hash) {
+            hash = block.previous_hash.clone();
+            active.push(block);
+        }
+        active.reverse();
+
+        let pruned = self.read_pruned()?.unwrap_or(Pruned {
+            height: 0,
+            ledger: Ledger::default(),
+        });
+        if pruned.height >= active.len() as u64 {
+            return Err(format!(
+                "Stored chain ends below its pruned height {}",
+                pruned.height
+            ));
+        }
+        let mut loaded = chain.clone();
+        loaded.chain = active;
+        loaded.side_blocks = side_blocks;
+        loaded.restore_pruned(pruned.height, pruned.ledger);
+        if !loaded.is_valid() {
+            let reason = loaded.validate_chain(&loaded.chain).unwrap_err();
+            return Err(format!("Stored chain is invalid: {}", reason));
+        }
+        let ledger = loaded.ledger().clone();
+        loaded.mempool.evict(&ledger);
+
+        self.heights = loaded
+            .chain
+            .iter()
+            .map(|block| block.hash.clone())
+            .collect();
+        *chain = loaded;
+        Ok(())
+    }
+
+    /// Appends the blocks of `chain` that are not stored yet
+    ///
+    /// When pruning, the blocks deep enough below the tip are then pruned
+    /// from `chain` and the store, see [`BlockStore::with_pruning`].
+    pub fn save(&mut self, chain: &mut Blockchain) -> Result<(), String> {
+        self.write(self.unsaved(chain))?;
+        if let Some(height) = self.prune_height(chain) {
+            chain.prune(height);
+            self.compact(chain)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the blocks of `chain` that are not stored yet
+    ///
+    /// Only the active blocks above the last one the store has at the same
+    /// height are looked at, besides the side blocks, so a chain that grew
+    /// by a block costs a block to copy.
+    pub fn unsaved(&self, chain: &Blockchain) -> UnsavedBlocks {
+        let mut fork = chain.chain.len();
+        while fork > 0 && self.heights.get(fork - 1) != Some(&chain.chain[fork - 1].hash) {
+            fork -= 1;
+        }
+        // Parents go before their children
+        let mut side: Vec<&Block> = chain.side_blocks.values().collect();
+        side.sort_by_key(|block| block.index);
+        let blocks = chain.chain[fork..]
+            .iter()
+            .chain(side)
+            .filter(|block| !self.locations.contains_key(&block.hash))
+            .cloned()
+            .collect();
+        UnsavedBlocks {
+            fork,
+            active: chain.chain[fork..]
+                .iter()
+                .map(|block| block.hash.clone())
+                .collect(),
+            blocks,
+        }
+    }
+
+    /// Appends blocks copied by [`BlockStore::unsaved`]
+    ///
+    /// Needs no lock on the chain they came from, so a node can write them
+    /// while it keeps taking blocks.
+    pub fn write(&mut self, unsaved: UnsavedBlocks) -> Result<(), String> {
+        for block in &unsaved.blocks {
+            self.append(block)?;
+        }
+        self.heights.truncate(unsaved.fork);
+        self.heights.extend(unsaved.active);
+        Ok(())
+    }
+
+    /// Height to prune `chain` to before the next [`BlockStore::compact`], if pruning
+    ///
+    /// Pruning waits until another `keep_recent` blocks are due, so the
+    /// store is rewritten once every `keep_recent` blocks.
+    pub fn prune_height(&self, chain: &Blockchain) -> Option<u64> {
+        let keep_recent = self.keep_recent?;
+        let height = chain.get_latest_block().index.saturating_sub(keep_recent);
+        (height >= chain.pruned_height + keep_recent.max(1)).then_some(height)
+    }
+
+    fn append(&mut self, block: &Block) -> Result<(), String> {
+        let record = encode(block)?;
+        let location = Location {
+            height: block.index,
+            offset: self.end,
+            length: record.len() as u64 - LENGTH_BYTES,
+        };
+        // The block goes to disk before its index line, so a crash can only
+        // leave a block the index does not know about yet
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.blocks_path)
+            .and_then(|mut file| {
+                file.write_all(&record)?;
+                file.sync_data()
+            })
+            .map_err(|e| format!("Failed to store block {}: {}", block.index, e))?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.index_path)
+            .and_then(|mut file| file.write_all(index_line(&block.hash, &location).as_bytes()))
+            .map_err(|e| format!("Failed to index block {}: {}", block.index, e))?;
+        self.locations.insert(block.hash.clone(), location);
+        self.end = location.end();
+        Ok(())
+    }
+
+    /// Rewrites the store with the blocks of `chain` as they are now
+    ///
+    /// Called once `chain` is pruned, to drop the pruned transactions from
+    /// the store too. The pruned ledger is written first. The index is
+    /// removed until the new block file is in place, so a crash in between
+    /// leaves a store that reopens by scanning the block file.
+    pub fn compact(&mut self, chain: &Blockchain) -> Result<(), String> {
+        let pruned = Pruned {
+            height: chain.pruned_height,
+            ledger: chain.pruned_ledger().clone(),
+        };
+        let json = serde_json::to_vec(&pruned)
+            .map_err(|e| format!("Failed to serialize the pruned ledger: {}", e))?;
+        let tmp = self.pruned_path.with_extension("json.tmp");
+        fs::write(&tmp, json)
+            .and_then(|()| fs::rename(&tmp, &self.pruned_path))
+            .map_err(|e| format!("Failed to save the pruned ledger: {}", e))?;
+
+        let mut side: Vec<&Block> = chain.side_blocks.values().collect();
+        side.sort_by_key(|block| block.index);
+        let mut data = vec![];
+        let mut entries = vec![];
+        for block in chain.chain.iter().chain(side) {
+            let record = encode(block)?;
+            let location = Location {
+                height: block.index,
+                offset: data.len() as u64,
+                length: record.len() as u64 - LENGTH_BYTES,
+            };
+            entries.push((block.hash.clone(), location));
+            data.extend(record);
+        }
+        let tmp = self.blocks_path.with_extension("dat.tmp");
+        match fs::remove_file(&self.index_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(format!("Failed to remove the block index: {}", e))
+            }
+            _ => {}
+        }
+        fs::write(&tmp, &data)
+            .and_then(|()| fs::rename(&tmp, &self.blocks_path))
+            .map_err(|e| format!("Failed to rewrite the block file: {}", e))?;
+        self.write_index(&entries)?;
+        self.locations = entries.into_iter().collect();
+        self.end = data.len() as u64;
+        self.heights = chain.chain.iter().map(|block| block.hash.clone()).collect();
+        Ok(())
+    }
+
+    /// Reads the index and repairs what a crash left at the end of the files
+    fn recover(&mut self) -> Result<(), String> {
+        let size = match fs::metadata(&self.blocks_path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => {
+                return Err(format!(
+                    "Failed to read {}: {}",
+                    self.blocks_path.display(),
+                    e
+                ))
+            }
+        };
+        let (mut entries, clean) = self.read_index(size)?;
+        let indexed_end = entries.last().map_or(0, |(_, location)| location.end());
+        let (found, end) = self.scan(indexed_end, size)?;
+        if end < size {
+            OpenOptions::new()
+                .write(true)
+                .open(&self.blocks_path)
+                .and_then(|file| file.set_len(end))
+                .map_err(|e| format!("Failed to truncate the torn block: {}", e))?;
+        }
+        if !clean || !found.is_empty() {
+            entries.extend(found);
+            self.write_index(&entries)?;
+        }
+        self.locations = entries.into_iter().collect();
+        self.end = end;
+        Ok(())
+    }
+
+    /// Entries of the index that fit the block file, and whether all of them did
+    ///
+    /// Entries must follow one another from the start of the block file. The
+    /// first one that does not, or that is torn, ends the usable index.
+    fn read_index(&self, size: u64) -> Result<(Vec<(String, Location)>, bool), String> {
+        let text = match fs::read_to_string(&self.index_path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((vec![], true)),
+            Err(e) => {
+                return Err(format!(
+                    "Failed to read {}: {}",
+                    self.index_path.display(),
+                    e
+                ))
+            }
+        };
+        let mut entries: Vec<(String, Location)> = vec![];
+        for line in text.split_inclusive('\n') {
+            let expected = entries.last().map_or(0, |(_, location)| location.end());
+            match line.strip_suffix('\n').and_then(parse_index_line) {
+                Some((hash, location)) if location.offset == expected && location.end() <= size => {
+                    entries.push((hash, location))
+                }
+                _ => return Ok((entries, false)),
+            }
+        }
+        Ok((entries, true))
+    }
+
+    /// Blocks in the block file from `offset` on, and where the last whole one ends
+    ///
+    /// A torn record can only be the last one; any other that does not parse
+    /// means the file is corrupt.
+    fn scan(&self, mut offset: u64, size: u64) -> Result<(Vec<(String, Location)>, u64), String> {
+        if offset >= size {
+            return Ok((vec![], offset));
+        }
+        let read_error = |e: io::Error| format!("Failed to read the block file: {}", e);
+        let mut file = File::open(&self.blocks_path).map_err(read_error)?;
+        file.seek(SeekFrom::Start(offset)).map_err(read_error)?;
+        let mut reader = BufReader::new(file);
+        let mut found = vec![];
+        while size - offset >= LENGTH_BYTES {
+            let mut length = [0; LENGTH_BYTES as usize];
+            reader.read_exact(&mut length).map_err(read_error)?;
+            let location = Location {
+                height: 0,
+                offset,
+                length: u32::from_le_bytes(length) as u64,
+            };
+            if location.end() > size {
+                break;
+            }
+            let mut json = vec![0; location.length as usize];
+            reader.read_exact(&mut json).map_err(read_error)?;
+            match serde_json::from_slice::<Block>(&json) {
+                Ok(block) => found.push((
+                    block.hash,
+                    Location {
+                        height: block.index,
+                        ..location
+                    },
+                )),
+                Err(_) if location.end() == size => break,
+                Err(e) => return Err(format!("Corrupt block at offset {}: {}", offset, e)),
+            }
+            offset = location.end();
+        }
+        Ok((found, offset))
+    }
+
+    fn write_index(&self, entries: &[(String, Location)]) -> Result<(), String> {
+        let text: String = entries
+            .iter()
+            .map(|(hash, location)| index_line(hash, location))
+            .collect();
+        let tmp = self.index_path.with_extension("idx.tmp");
+        fs::write(&tmp, text)
+            .and_then(|()| fs::rename(&tmp, &self.index_path))
+            .map_err(|e| format!("Failed to write the block index: {}", e))
+    }
+
+    /// Every stored block, in the order they were stored
+    fn read_all(&self) -> Result<Vec<Block>, String> {
+        let mut locations: Vec<_> = self.locations.iter().collect();
+        locations.sort_by_key(|(_, location)| location.offset);
+        let mut file = File::open(&self.blocks_path)
+            .map_err(|e| format!("Failed to open {}: {}", self.blocks_path.display(), e))?;
+        locations
+            .into_iter()
+            .map(|(hash, location)| self.read_block(&mut file, hash, location))
+            .collect()
+    }
+
+    fn read_block(
+        &self,
+        file: &mut File,
+        hash: &str,
+        location: &Location,
+    ) -> Result<Block, String> {
+        let mut json = vec![0; location.length as usize];
+        file.seek(SeekFrom::Start(location.offset + LENGTH_BYTES))
+            .and_then(|_| file.read_exact(&mut json))
+            .map_err(|e| format!("Failed to read block {}: {}", hash, e))?;
+        let block: Block = serde_json::from_slice(&json)
+            .map_err(|e| format!("Corrupt block at offset {}: {}", location.offset, e))?;
+        if block.hash != hash {
+            return Err(format!(
+                "Block at offset {} is not {}",
+                location.offset, hash
+            ));
+        }
+        Ok(block)
+    }
+
+    fn read_pruned(&self) -> Result<Option<Pruned>, String> {
+        match fs::read(&self.pruned_path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .map_err(|e| format!("Corrupt {}: {}", self.pruned_path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!(
+                "Failed to read {}: {}",
+                self.pruned_path.display(),
+                e
+            )),
+        }
+    }
+}
+
+/// A block's record in the block file: its length, then its JSON
+fn encode(block: &Block) -> Result<Vec<u8>, String> {
+    let json = serde_json::to_vec(block)
+        .map_err(|e| format!("Failed to serialize block {}: {}", block.index, e))?;
+    let mut record = (json.len() as u32).to_le_bytes().to_vec();
+    record.extend(json);
+    Ok(record)
+}
+
+fn index_line(hash: &str, location: &Location) -> String {
+    format!(
+        "{} {} {} {}\n",
+        hash, location.height, location.offset, location.length
+    )
+}
+
+fn parse_index_line(line: &str) -> Option<(String, Location)> {
+    let mut fields = line.split(' ');
+    let hash = fields.next()?.to_string();
+    let mut numbers = fields.map(|field| field.parse::<u64>().ok());
+    let location = Location {
+        height: numbers.next()??,
+        offset: numbers.next()??,
+        length: numbers.next()??,
+    };
+    if numbers.next().is_some() {
+        return None;
+    }
+    Some((hash, location))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::target::Target;
+    use crate::transaction::{Transaction, REWARD_CURRENCY};
+    use ed25519_dalek::SigningKey;
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("block-store-{}", Uuid::new_v4()))
+    }
+
+    /// Key the test chains take deposits from
+    fn custodian() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn shared_genesis() -> Blockchain {
+        Blockchain::with_genesis_time(Target::with_leading_zeros(1), Amount::from(100), 0)
+            .with_custodian(&custodian().verifying_key())
+    }
+
+    fn mine_at(blockchain: &mut Blockchain, miner: &str, timestamp: i64) -> Block {
+        let reward_id = Uuid::new_v4().to_string();
+        blockchain.mine_pending_transactions_at(miner, reward_id, timestamp);
+        blockchain.get_latest_block().clone()
+    }
+
+    fn hashes(blockchain: &Blockchain) -> Vec<&String> {
+        blockchain.chain.iter().map(|block| &block.hash).collect()
+    }
+
+    #[test]
+    fn test_blocks_survive_a_restart() {
+        let dir = temp_dir();
+        let mut blockchain = shared_genesis();
+        let mut rival = blockchain.clone();
+        let deposit = Transaction::new_deposit("Alice".to_string(), "BTC", Amount::from(10));
+        blockchain
+            .add_transaction(deposit.sign(&custodian()))
+            .unwrap();
+        for timestamp in [10, 20, 30] {
+            mine_at(&mut blockchain, "Miner", timestamp);
+        }
+        let side = mine_at(&mut rival, "Rival", 11);
+        blockchain.add_block(side.clone()).unwrap();
+
+        let mut store = BlockStore::open(&dir).unwrap();
+        store.save(&mut blockchain).unwrap();
+        store.save(&mut blockchain).unwrap();
+        assert_eq!(store.len(), 5);
+        assert_eq!(
+            store.block_by_height(2).unwrap().unwrap().hash,
+            blockchain.chain[2].hash
+        );
+        assert_eq!(
+            store.block_by_hash(&side.hash).unwrap().unwrap().hash,
+            side.hash
+        );
+        assert!(store.block_by_height(4).unwrap().is_none());
+
+        let mut restored = shared_genesis();
+        let mut store = BlockStore::open(&dir).unwrap();
+        store.load(&mut restored).unwrap();
+        assert_eq!(hashes(&restored), hashes(&blockchain));
+        assert!(restored.side_blocks.contains_key(&side.hash));
+        assert_eq!(restored.get_balance("Alice", "BTC"), Amount::from(10));
+        assert!(restored.is_valid());
+
+        let mut elsewhere =
+            Blockchain::with_genesis_time(Target::with_leading_zeros(1), Amount::from(100), 1);
+        assert!(store
+            .load(&mut elsewhere)
+            .unwrap_err()
+            .contains("different genesis"));
+
+        // Blocks edited on disk fail validation
+        let path = dir.join("blocks.dat");
+        let mut data = fs::read(&path).unwrap();
+        let at = data
+            .windows(5)
+            .position(|window| window == b"Alice")
+            .unwrap();
+        data[at..at + 5].copy_from_slice(b"Malle");
+        fs::write(&path, data).unwrap();
+        let err = BlockStore::open(&dir)
+            .unwrap()
+            .load(&mut shared_genesis())
+            .unwrap_err();
+        assert!(err.contains("invalid"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_writes_are_recovered() {
+        let dir = temp_dir();
+        let mut blockchain = shared_genesis();
+        let mut store = BlockStore::open(&dir).unwrap();
+        for timestamp in [10, 20, 30] {
+            mine_at(&mut blockchain, "Miner", timestamp);
+            store.save(&mut blockchain).unwrap();
+        }
+        let blocks_path = dir.join("blocks.dat");
+        let index_path = dir.join("blocks.idx");
+        let size = fs::metadata(&blocks_path).unwrap().len();
+
+        // The last block made it to disk but its index line did not, and the
+        // next block was torn halfway through
+        let index = fs::read_to_string(&index_path).unwrap();
+        let lines: Vec<&str> = index.lines().collect();
+        let torn_line = &lines[3][..10];
+        fs::write(
+            &index_path,
+            format!("{}\n{}", lines[..3].join("\n"), torn_line),
+        )
+        .unwrap();
+        let next = mine_at(&mut blockchain.clone(), "Miner", 40);
+        let record = encode(&next).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&blocks_path).unwrap();
+        file.write_all(&record[..record.len() / 2]).unwrap();
+
+        let mut restored = shared_genesis();
+        let mut store = BlockStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 4);
+        assert_eq!(fs::metadata(&blocks_path).unwrap().len(), size);
+        assert_eq!(fs::read_to_string(&index_path).unwrap(), index);
+        store.load(&mut restored).unwrap();
+        assert_eq!(hashes(&restored), hashes(&blockchain));
+
+        // The store carries on from the recovered end
+        mine_at(&mut restored, "Miner", 40);
+        store.save(&mut restored).unwrap();
+        let mut reopened = shared_genesis();
+        BlockStore::open(&dir).unwrap().load(&mut reopened).unwrap();
+        assert_eq!(reopened.chain.len(), 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pruning_keeps_recent_bodies() {
+        let dir = temp_dir();
+        let mut blockchain = shared_genesis();
+        let deposit = Transaction::new_deposit("Alice".to_string(), "BTC", Amount::from(10));
+        blockchain
+            .add_transaction(deposit.sign(&custodian()))
+            .unwrap();
+        let mut store = BlockStore::open(&dir).unwrap().with_pruning(2);
+        for timestamp in 1..=7 {
+            mine_at(&mut blockchain, "Miner", timestamp * 10);
+            store.save(&mut blockchain).unwrap();
+        }
+        // Pruned at heights 4 and 6, each time to two blocks below the tip
+        assert_eq!(blockchain.pruned_height, 4);
+        assert!(blockchain.chain[4].transactions.is_empty());
+        assert!(!blockchain.chain[5].transactions.is_empty());
+        assert!(store
+            .block_by_height(1)
+            .unwrap()
+            .unwrap()
+            .transactions
+            .is_empty());
+        let stored = store.block_by_height(5).unwrap().unwrap();
+        assert_eq!(
+            stored.transactions.len(),
+            blockchain.chain[5].transactions.len()
+        );
+        assert_eq!(blockchain.get_balance("Alice", "BTC"), Amount::from(10));
+        assert_eq!(
+            blockchain.get_balance("Miner", REWARD_CURRENCY),
+            Amount::from(700)
+        );
+
+        // A crash while compacting can leave the store without its index
+        fs::remove_file(dir.join("blocks.idx")).unwrap();
+        let mut restored = shared_genesis();
+        BlockStore::open(&dir).unwrap().load(&mut restored).unwrap();
+        assert_eq!(hashes(&restored), hashes(&blockchain));
+        assert_eq!(restored.pruned_height, 4);
+        assert_eq!(restored.get_balance("Alice", "BTC"), Amount::from(10));
+        assert_eq!(
+            restored.get_balance("Miner", REWARD_CURRENCY),
+            Amount::from(700)
+        );
+        mine_at(&mut restored, "Miner", 80);
+        assert!(restored.is_valid());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::amount::{Amount, Rounding};
+use crate::order::TradingPair;
+
+/// Length of a candle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CandleInterval {
+    OneMinute,
+    FiveMinutes,
+    OneHour,
+    OneDay,
+}
+
+impl CandleInterval {
+    /// Every interval candles are kept for
+    pub const ALL: [CandleInterval; 4] = [
+        CandleInterval::OneMinute,
+        CandleInterval::FiveMinutes,
+        CandleInterval::OneHour,
+        CandleInterval::OneDay,
+    ];
+
+    /// Length of the interval in seconds
+    pub fn seconds(&self) -> i64 {
+        match self {
+            CandleInterval::OneMinute => 60,
+            CandleInterval::FiveMinutes => 5 * 60,
+            CandleInterval::OneHour => 60 * 60,
+            CandleInterval::OneDay => 24 * 60 * 60,
+        }
+    }
+
+    /// Start of the candle containing `timestamp`
+    pub fn open_time(&self, timestamp: i64) -> i64 {
+        timestamp - timestamp.rem_euclid(self.seconds())
+    }
+}
+
+/// Open, high, low, close and volume of the trades in one interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Candle {
+    /// Unix timestamp the interval starts at
+    pub open_time: i64,
+    pub open: Amount,
+    pub high: Amount,
+    pub low: Amount,
+    pub close: Amount,
+    /// Traded quantity in the base currency
+    pub volume: Amount,
+    pub trade_count: u64,
+}
+
+impl Candle {
+    fn new(open_time: i64, price: Amount, quantity: Amount) -> Self {
+        Candle {
+            open_time,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: quantity,
+            trade_count: 1,
+        }
+    }
+
+    fn add(&mut self, price: Amount, quantity: Amount) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += quantity;
+        self.trade_count += 1;
+    }
+}
+
+/// Summary of a pair's trading over the last 24 hours
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ticker {
+    pub pair: TradingPair,
+    /// Price of the most recent trade
+    pub last: Amount,
+    /// Price of the first trade in the window
+    pub open: Amount,
+    /// Change from `open` to `last`, in percent to two decimal places
+    pub change_percent: Amount,
+    pub high: Amount,
+    pub low: Amount,
+    /// Traded quantity in the base currency
+    pub volume: Amount,
+    pub trade_count: u64,
+}
+
+/// Candles of one trading pair at every interval, built trade by trade
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CandleSeries {
+    pub pair: TradingPair,
+    candles: HashMap<CandleInterval, BTreeMap<i64, Candle>>,
+}
+
+impl CandleSeries {
+    pub fn new(pair: TradingPair) -> Self {
+        CandleSeries {
+            pair,
+            candles: HashMap::new(),
+        }
+    }
+
+    /// Adds a trade to the candle of each interval that contains `timestamp`
+    ///
+    /// Trades are expected in time order; a late trade updates its candle's
+    /// high, low and volume and becomes its close.
+    pub fn record(&mut self, price: Amount, quantity: Amount, timestamp: i64) {
+        for interval in CandleInterval::ALL {
+            let open_time = interval.open_time(timestamp);
+            self.candles
+                .entry(interval)
+                .or_default()
+                .entry(open_time)
+                .and_modify(|candle| candle.add(price, quantity))
+                .or_insert_with(|| Candle::new(open_time, price, quantity));
+        }
+    }
+
+    /// Candles of `interval` that open at or after `from` and before `to`, oldest first
+    ///
+    /// Intervals without trades have no candle.
+    pub fn range(&self, interval: CandleInterval, from: i64, to: i64) -> Vec<Candle> {
+        if from >= to {
+            return vec![];
+        }
+        self.candles
+            .get(&interval)
+            .map(|candles| candles.range(from..to).map(|(_, c)| *c).collect())
+            .unwrap_or_default()
+    }
+
+    /// 24 hour summary ending at `now`, or `None` if nothing traded in that time
+    ///
+    /// The window is built from one-minute candles, so it starts at the
+    /// minute boundary 24 hours before `now`.
+    pub fn ticker(&self, now: i64) -> Option<Ticker> {
+        let from = CandleInterval::OneMinute.open_time(now - CandleInterval::OneDay.seconds());
+        let candles = self.range(CandleInterval::OneMinute, from, now + 1);
+        let first = candles.first()?;
+        let last = candles.last()?;
+
+        let change = (last.close - first.open) * Amount::from(100);
+        Some(Ticker {
+            pair: self.pair.clone(),
+            last: last.close,
+            open: first.open,
+            change_percent: change.div_round(first.open, 2, Rounding::Down),
+            high: candles.iter().map(|c| c.high).max()?,
+            low: candles.iter().map(|c| c.low).min()?,
+            volume: candles.iter().map(|c| c.volume).sum(),
+            trade_count: candles.iter().map(|c| c.trade_count).sum(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series_with(trades: &[(i64, i64, i64)]) -> CandleSeries {
+        let mut series = CandleSeries::new(TradingPair::new("BTC", "USDT"));
+        for &(timestamp, price, quantity) in trades {
+            series.record(Amount::from(price), Amount::from(quantity), timestamp);
+        }
+        series
+    }
+
+    #[test]
+    fn test_interval_open_time() {
+        assert_eq!(CandleInterval::OneMinute.open_time(125), 120);
+        assert_eq!(CandleInterval::FiveMinutes.open_time(899), 600);
+        assert_eq!(CandleInterval::OneHour.open_time(7300), 7200);
+        assert_eq!(CandleInterval::OneDay.open_time(86_399), 0);
+    }
+
+    #[test]
+    fn test_candles_by_interval() {
+        let series = series_with(&[
+            (0, 100, 1),
+            (30, 105, 2),
+            (59, 98, 1),
+            (61, 101, 3),
+            (400, 99, 1),
+        ]);
+
+        let minutes = series.range(CandleInterval::OneMinute, 0, 3600);
+        assert_eq!(minutes.len(), 3);
+        assert_eq!(
+            minutes[0],
+            Candle {
+                open_time: 0,
+                open: Amount::from(100),
+                high: Amount::from(105),
+                low: Amount::from(98),
+                close: Amount::from(98),
+                volume: Amount::from(4),
+                trade_count: 3,
+            }
+        );
+        assert_eq!(minutes[1].open_time, 60);
+        assert_eq!(minutes[2].open_time, 360);
+
+        let five = series.range(CandleInterval::FiveMinutes, 0, 3600);
+        assert_eq!(five.len(), 2);
+        assert_eq!(five[0].close, Amount::from(101));
+        assert_eq!(five[0].volume, Amount::from(7));
+
+        let hour = series.range(CandleInterval::OneHour, 0, 3600);
+        assert_eq!(hour.len(), 1);
+        assert_eq!(hour[0].trade_count, 5);
+
+        // The range is half-open on the candles' open time
+        assert_eq!(series.range(CandleInterval::OneMinute, 60, 360).len(), 1);
+        assert!(series.range(CandleInterval::OneDay, 10, 10).is_empty());
+    }
+
+    #[test]
+    fn test_ticker_covers_last_24_hours() {
+        let day = CandleInterval::OneDay.seconds();
+        let series = series_with(&[
+            (0, 90, 5),
+            (day, 100, 1),
+            (day + 3600, 120, 2),
+            (2 * day, 110, 1),
+        ]);
+        let now = 2 * day + 30;
+
+        let ticker = series.ticker(now).unwrap();
+        assert_eq!(ticker.open, Amount::from(100));
+        assert_eq!(ticker.last, Amount::from(110));
+        assert_eq!(ticker.change_percent, Amount::from(10));
+        assert_eq!(ticker.high, Amount::from(120));
+        assert_eq!(ticker.low, Amount::from(100));
+        assert_eq!(ticker.volume, Amount::from(4));
+        assert_eq!(ticker.trade_count, 3);
+
+        assert!(series.ticker(4 * day).is_none());
+    }
+}
@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::amount::Amount;
+use crate::order::{OrderSide, TradingPair};
+
+/// Aggregated orders at one price
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DepthLevel {
+    pub price: Amount,
+    /// Sum of the remaining quantity of the orders at this price
+    pub quantity: Amount,
+    pub order_count: usize,
+}
+
+/// Top price levels of an order book
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DepthSnapshot {
+    pub pair: TradingPair,
+    /// Sequence number of the last level update included in the snapshot
+    pub sequence: u64,
+    /// Best (highest) bid first
+    pub bids: Vec<DepthLevel>,
+    /// Best (lowest) ask first
+    pub asks: Vec<DepthLevel>,
+}
+
+/// How a price level changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LevelAction {
+    /// The first order arrived at a new price
+    Insert,
+    /// The quantity or order count at an existing price changed
+    Update,
+    /// The last order at a price left the book
+    Delete,
+}
+
+/// One change to a price level, numbered per order book
+///
+/// For deletes, `quantity` and `order_count` are zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelUpdate {
+    pub sequence: u64,
+    pub side: OrderSide,
+    pub action: LevelAction,
+    pub level: DepthLevel,
+}
+
+/// Local copy of an order book's depth, kept current from level updates
+#[derive(Debug, Clone, Default)]
+pub struct DepthReplica {
+    sequence: u64,
+    bids: BTreeMap<Amount, DepthLevel>,
+    asks: BTreeMap<Amount, DepthLevel>,
+}
+
+impl DepthReplica {
+    /// Starts a replica from a full snapshot (one taken with all levels)
+    pub fn from_snapshot(snapshot: &DepthSnapshot) -> Self {
+        let by_price = |levels: &[DepthLevel]| levels.iter().map(|l| (l.price, *l)).collect();
+        DepthReplica {
+            sequence: snapshot.sequence,
+            bids: by_price(&snapshot.bids),
+            asks: by_price(&snapshot.asks),
+        }
+    }
+
+    /// Sequence number of the last update applied
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Applies an update
+    ///
+    /// Updates already contained in the replica are ignored. A gap in the
+    /// sequence is an error; the replica must then be rebuilt from a snapshot.
+    pub fn apply(&mut self, update: &LevelUpdate) -> Result<(), String> {
+        if update.sequence <= self.sequence {
+            return Ok(());
+        }
+        if update.sequence != self.sequence + 1 {
+            return Err(format!(
+                "Missed level updates {} to {}",
+                self.sequence + 1,
+                update.sequence - 1
+            ));
+        }
+
+        let levels = match update.side {
+            OrderSide::Buy => &mut self.bids,
+            OrderSide::Sell => &mut self.asks,
+        };
+        match update.action {
+            LevelAction::Insert | LevelAction::Update => {
+                levels.insert(update.level.price, update.level);
+            }
+            LevelAction::Delete => {
+                levels.remove(&update.level.price);
+            }
+        }
+        self.sequence = update.sequence;
+        Ok(())
+    }
+
+    /// Bid levels, best first
+    pub fn bids(&self) -> Vec<DepthLevel> {
+        self.bids.values().rev().copied().collect()
+    }
+
+    /// Ask levels, best first
+    pub fn asks(&self) -> Vec<DepthLevel> {
+        self.asks.values().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(price: i64, quantity: i64, order_count: usize) -> DepthLevel {
+        DepthLevel {
+            price: Amount::from(price),
+            quantity: Amount::from(quantity),
+            order_count,
+        }
+    }
+
+    fn update(sequence: u64, action: LevelAction, level: DepthLevel) -> LevelUpdate {
+        LevelUpdate {
+            sequence,
+            side: OrderSide::Sell,
+            action,
+            level,
+        }
+    }
+
+    #[test]
+    fn test_replica_applies_updates_in_sequence() {
+        let snapshot = DepthSnapshot {
+            pair: TradingPair::new("BTC", "USDT"),
+            sequence: 4,
+            bids: vec![level(99, 1, 1)],
+            asks: vec![level(101, 2, 1)],
+        };
+        let mut replica = DepthReplica::from_snapshot(&snapshot);
+
+        // Updates up to the snapshot's sequence are already included
+        replica
+            .apply(&update(4, LevelAction::Insert, level(105, 9, 9)))
+            .unwrap();
+        replica
+            .apply(&update(5, LevelAction::Insert, level(100, 1, 1)))
+            .unwrap();
+        replica
+            .apply(&update(6, LevelAction::Delete, level(101, 0, 0)))
+            .unwrap();
+        assert_eq!(replica.sequence(), 6);
+        assert_eq!(replica.asks(), vec![level(100, 1, 1)]);
+        assert_eq!(replica.bids(), vec![level(99, 1, 1)]);
+
+        let gap = replica.apply(&update(8, LevelAction::Update, level(100, 3, 2)));
+        assert!(gap.is_err());
+        assert_eq!(replica.sequence(), 6);
+    }
+}
@@ -1,33 +1,162 @@
-use std::collections::HashMap;
-
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+
+use crate::amount::{Amount, AssetScales, Rounding};
 use crate::block::Blockchain;
-use crate::order::{Order, OrderBook, OrderSide, OrderStatus, Trade, TradingPair};
-use crate::transaction::Transaction;
-use crate::wallet::WalletManager;
+use crate::candle::{Candle, CandleInterval, CandleSeries, Ticker};
+use crate::clock::{Clock, IdGenerator, RandomIds, SystemClock};
+use crate::depth::{DepthSnapshot, LevelUpdate};
+use crate::fee::{FeeSchedule, Liquidity};
+use crate::keys;
+use crate::market_data::{BookTicker, MarketEvent, MarketFeed};
+use crate::order::{
+    MarketOrderSize, Order, OrderBook, OrderOptions, OrderSide, OrderStatus, PostOnlyAction,
+    PreventedSelfTrade, SelfTradePrevention, StopOrderKind, StopTrigger, TimeInForce, Trade,
+    TradingPair,
+};
+use crate::snapshot::{ExchangeSnapshot, SNAPSHOT_VERSION};
+use crate::target::Target;
+use crate::transaction::{Transaction, EXTERNAL_ADDRESS};
+use crate::user_data::{BalanceReason, OrderEvent, UserEvent, UserFeed};
+use crate::wallet::{Wallet, WalletManager};
 
 /// The main exchange struct that handles trading operations
 #[derive(Debug)]
 pub struct Exchange {
     pub name: String,
     pub order_books: HashMap<String, OrderBook>,
+    /// Pending stop orders per trading pair, in placement order
+    pub stop_orders: HashMap<String, Vec<Order>>,
     pub wallet_manager: WalletManager,
     pub blockchain: Blockchain,
     pub trades: Vec<Trade>,
+    /// Candles per trading pair, updated as trades are processed
+    pub candles: HashMap<String, CandleSeries>,
+    /// Matches stopped by self-trade prevention
+    pub prevented_self_trades: Vec<PreventedSelfTrade>,
+    /// Self-trade prevention mode of each account that has set one
+    pub self_trade_prevention: HashMap<String, SelfTradePrevention>,
     pub supported_pairs: Vec<TradingPair>,
+    /// Decimal places allowed per asset
+    pub asset_scales: AssetScales,
+    /// Maker and taker fee rates and volume tiers
+    pub fee_schedule: FeeSchedule,
+    /// Traded volume per account and quote currency, for fee tiers
+    pub trading_volumes: HashMap<String, HashMap<String, Amount>>,
+    /// Default slippage guard for market orders, as a fraction of the best price
+    pub max_slippage: Amount,
+    /// Pair symbol and id of each good-till-date order put on a book, by expiry
+    ///
+    /// Entries of orders that left the book are skipped when they come due.
+    expiries: BTreeMap<i64, Vec<(String, String)>>,
+    /// Market data events not yet taken
+    market_feed: MarketFeed,
+    /// Account events not yet taken
+    user_feed: UserFeed,
+    /// Transactions added to the chain's mempool and not yet taken
+    recorded_transactions: Vec<Transaction>,
+    clock: Box<dyn Clock>,
+    ids: Box<dyn IdGenerator>,
+}
+
+/// Secret keys an exchange is created with
+///
+/// They are generated at random, so an exchange rebuilt from its journal
+/// must be given the same ones back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExchangeKeys {
+    /// Key of the wallet that collects trading fees
+    #[serde(with = "keys::signing_key_hex")]
+    pub fee_wallet: SigningKey,
+    /// Key that signs deposits, which the chain takes from it alone
+    #[serde(with = "keys::signing_key_hex")]
+    pub custodian: SigningKey,
+}
+
+impl ExchangeKeys {
+    pub fn generate() -> Self {
+        ExchangeKeys {
+            fee_wallet: keys::generate_signing_key(),
+            custodian: keys::generate_signing_key(),
+        }
+    }
+}
+
+/// What matching one incoming order produced
+#[derive(Default)]
+struct MatchResult {
+    trades: Vec<Trade>,
+    /// Buy and sell order as each trade left them, in trade order
+    filled: Vec<(Order, Order)>,
+    prevented: Vec<PreventedSelfTrade>,
+    /// Resting orders cancelled by self-trade prevention
+    cancelled: Vec<Order>,
 }
 
 impl Exchange {
     /// Creates a new exchange
     pub fn new(name: &str) -> Self {
+        Self::with_sources(name, Box::new(SystemClock), Box::new(RandomIds))
+    }
+
+    /// Creates a new exchange that takes its time from `clock` and its ids from `ids`
+    pub fn with_sources(name: &str, clock: Box<dyn Clock>, ids: Box<dyn IdGenerator>) -> Self {
+        Self::with_keys(name, clock, ids, ExchangeKeys::generate())
+    }
+
+    /// Creates a new exchange with the given sources and secret keys
+    ///
+    /// Everything the exchange creates (orders, trades, transactions and
+    /// blocks) is stamped from `clock` and `ids`, so two exchanges with
+    /// equivalent sources and the same keys that receive the same commands
+    /// end up identical.
+    pub fn with_keys(
+        name: &str,
+        clock: Box<dyn Clock>,
+        ids: Box<dyn IdGenerator>,
+        keys: ExchangeKeys,
+    ) -> Self {
+        let fee_wallet = Wallet::with_key("Exchange fees", keys.fee_wallet);
+        let custodian = keys.custodian.verifying_key();
+        let genesis_time = clock.now();
         let mut exchange = Exchange {
             name: name.to_string(),
             order_books: HashMap::new(),
-            wallet_manager: WalletManager::new(),
-            blockchain: Blockchain::new(2, 10.0), // difficulty: 2, reward: 10
+            stop_orders: HashMap::new(),
+            wallet_manager: WalletManager::with_keys(fee_wallet, keys.custodian),
+            // two leading hex zeros to start with, reward: 10
+            blockchain: Blockchain::with_genesis_time(
+                Target::with_leading_zeros(2),
+                Amount::from(10),
+                genesis_time,
+            )
+            .with_custodian(&custodian),
             trades: vec![],
+            candles: HashMap::new(),
+            prevented_self_trades: vec![],
+            self_trade_prevention: HashMap::new(),
             supported_pairs: vec![],
+            asset_scales: AssetScales::new(),
+            fee_schedule: FeeSchedule::new(),
+            trading_volumes: HashMap::new(),
+            max_slippage: Amount::new(5, 2), // 5%
+            expiries: BTreeMap::new(),
+            market_feed: MarketFeed::default(),
+            user_feed: UserFeed::default(),
+            recorded_transactions: vec![],
+            clock,
+            ids,
         };
 
+        // Default asset precision
+        exchange.asset_scales.set_scale("BTC", 8);
+        exchange.asset_scales.set_scale("ETH", 8);
+        exchange.asset_scales.set_scale("USDT", 6);
+
         // Add default trading pairs
         let default_pairs = vec![
             TradingPair::new("BTC", "USDT"),
@@ -44,89 +173,460 @@ impl Exchange {
 
     /// Adds a new trading pair to the exchange
     pub fn add_trading_pair(&mut self, pair: TradingPair) {
-        let symbol = pair.symbol();
-        if !self.order_books.contains_key(&symbol) {
-            self.order_books.insert(symbol, OrderBook::new(pair.clone()));
+        if let Entry::Vacant(entry) = self.order_books.entry(pair.symbol()) {
+            entry.insert(OrderBook::new(pair.clone()));
+            self.candles
+                .insert(pair.symbol(), CandleSeries::new(pair.clone()));
             self.supported_pairs.push(pair);
         }
     }
 
     /// Creates a new user wallet
     pub fn create_wallet(&mut self, owner: &str) -> String {
-        self.wallet_manager.create_wallet(owner)
+        self.create_wallet_with_key(owner, keys::generate_signing_key())
+    }
+
+    /// Creates a new user wallet around an existing key
+    pub fn create_wallet_with_key(&mut self, owner: &str, signing_key: SigningKey) -> String {
+        self.wallet_manager
+            .add_wallet(Wallet::with_key(owner, signing_key))
     }
 
     /// Deposits funds to a user's wallet
-    pub fn deposit(&mut self, address: &str, currency: &str, amount: f64) -> Result<(), String> {
+    pub fn deposit(
+        &mut self,
+        address: &str,
+        currency: &str,
+        amount: Amount,
+    ) -> Result<(), String> {
+        self.asset_scales.validate(currency, amount)?;
         self.wallet_manager.deposit(address, currency, amount)?;
 
         // Record the deposit transaction on the blockchain
-        let tx = Transaction::new_deposit(address.to_string(), amount);
-        let _ = self.blockchain.add_transaction(tx);
+        let tx = Transaction::new_deposit(address.to_string(), currency, amount)
+            .with_id_and_timestamp(self.next_id(), self.now());
+        if let Err(e) = self.record_transactions(vec![tx]) {
+            self.wallet_manager.withdraw(address, currency, amount)?;
+            self.wallet_manager.take_changed_balances();
+            return Err(e);
+        }
+        self.record_balances(BalanceReason::Deposit);
 
         Ok(())
     }
 
     /// Withdraws funds from a user's wallet
-    pub fn withdraw(&mut self, address: &str, currency: &str, amount: f64) -> Result<(), String> {
+    pub fn withdraw(
+        &mut self,
+        address: &str,
+        currency: &str,
+        amount: Amount,
+    ) -> Result<(), String> {
+        self.asset_scales.validate(currency, amount)?;
         self.wallet_manager.withdraw(address, currency, amount)?;
 
-        // Record the withdrawal transaction on the blockchain
-        let tx = Transaction::new_withdrawal(address.to_string(), amount);
-        let _ = self.blockchain.add_transaction(tx);
+        // Record the withdrawal transaction on the blockchain, leaving what
+        // the wallet holds for orders there
+        let tx = Transaction::new_withdrawal(address.to_string(), currency, amount)
+            .with_id_and_timestamp(self.next_id(), self.now());
+        let recorded =
+            Self::check_backed(&self.wallet_manager, &self.blockchain, address, currency, amount)
+                .and_then(|_| self.record_transactions(vec![tx]));
+        if let Err(e) = recorded {
+            self.wallet_manager.deposit(address, currency, amount)?;
+            self.wallet_manager.take_changed_balances();
+            return Err(e);
+        }
+        self.record_balances(BalanceReason::Withdrawal);
 
         Ok(())
     }
 
-    /// Gets the balance of a user's wallet
-    pub fn get_balance(&self, address: &str, currency: &str) -> f64 {
+    /// Gets the available balance of a user's wallet
+    pub fn get_balance(&self, address: &str, currency: &str) -> Amount {
         self.wallet_manager
             .get_wallet(address)
             .map(|w| w.get_balance(currency))
-            .unwrap_or(0.0)
+            .unwrap_or_default()
+    }
+
+    /// Gets the balance of a user's wallet that is held for open orders
+    pub fn get_locked_balance(&self, address: &str, currency: &str) -> Amount {
+        self.wallet_manager
+            .get_wallet(address)
+            .map(|w| w.get_locked_balance(currency))
+            .unwrap_or_default()
+    }
+
+    /// Volume an account has traded in pairs quoted in `currency`
+    pub fn trading_volume(&self, address: &str, currency: &str) -> Amount {
+        self.trading_volumes
+            .get(address)
+            .and_then(|volumes| volumes.get(currency))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sets the self-trade prevention mode for an account's orders
+    ///
+    /// Orders placed with an explicit mode in [`OrderOptions`] override it.
+    pub fn set_self_trade_prevention(&mut self, address: &str, mode: SelfTradePrevention) {
+        self.self_trade_prevention.insert(address.to_string(), mode);
+    }
+
+    /// Self-trade prevention mode used for an account's orders
+    pub fn account_self_trade_prevention(&self, address: &str) -> SelfTradePrevention {
+        self.self_trade_prevention
+            .get(address)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Quantity of an order that self-trade prevention kept from trading
+    ///
+    /// Counts matches where the order was either the incoming or the resting side.
+    pub fn prevented_quantity(&self, order_id: &str) -> Amount {
+        self.prevented_self_trades
+            .iter()
+            .filter(|p| p.taker_order_id == order_id || p.maker_order_id == order_id)
+            .map(|p| p.quantity)
+            .sum()
     }
 
-    /// Places a limit order
+    /// Places a good-till-cancelled limit order
     pub fn place_order(
         &mut self,
         user_address: String,
         pair: TradingPair,
         side: OrderSide,
-        price: f64,
-        quantity: f64,
+        price: Amount,
+        quantity: Amount,
+    ) -> Result<String, String> {
+        self.place_order_with_options(
+            user_address,
+            pair,
+            side,
+            price,
+            quantity,
+            OrderOptions::default(),
+        )
+    }
+
+    /// Places a limit order with the given time in force
+    ///
+    /// Fill-or-kill orders that cannot fill completely and post-only orders
+    /// that would match (with [`PostOnlyAction::Reject`]) are rejected before
+    /// any funds are locked.
+    pub fn place_order_with_options(
+        &mut self,
+        user_address: String,
+        pair: TradingPair,
+        side: OrderSide,
+        price: Amount,
+        quantity: Amount,
+        options: OrderOptions,
     ) -> Result<String, String> {
+        let now = self.now();
+        self.expire_orders(now)?;
+
         // Validate the trading pair
         let symbol = pair.symbol();
         if !self.order_books.contains_key(&symbol) {
             return Err(format!("Trading pair {} not supported", symbol));
         }
 
-        // Check user balance
-        let required_currency = match side {
-            OrderSide::Buy => &pair.quote,  // Need quote currency to buy
-            OrderSide::Sell => &pair.base,  // Need base currency to sell
+        // Validate price and quantity against the asset precision
+        if !price.is_positive() || !quantity.is_positive() {
+            return Err("Price and quantity must be positive".to_string());
+        }
+        self.asset_scales.validate(&pair.quote, price)?;
+        self.asset_scales.validate(&pair.base, quantity)?;
+
+        let order_book = &self.order_books[&symbol];
+        let mut price = price;
+        match options.time_in_force {
+            TimeInForce::GoodTillDate(expires_at) if expires_at <= now => {
+                return Err("Good-till-date expiry must be in the future".to_string());
+            }
+            TimeInForce::FillOrKill if order_book.matchable_quantity(side, price) < quantity => {
+                return Err("Fill-or-kill order cannot be filled completely".to_string());
+            }
+            TimeInForce::PostOnly(action) => {
+                let opposite = match side {
+                    OrderSide::Buy => order_book.best_ask().filter(|&ask| price >= ask),
+                    OrderSide::Sell => order_book.best_bid().filter(|&bid| price <= bid),
+                };
+                if let Some(opposite) = opposite {
+                    if action == PostOnlyAction::Reject {
+                        return Err("Post-only order would match immediately".to_string());
+                    }
+                    let tick = Amount::new(1, self.asset_scales.scale(&pair.quote));
+                    price = match side {
+                        OrderSide::Buy => opposite - tick,
+                        OrderSide::Sell => opposite + tick,
+                    };
+                    if !price.is_positive() {
+                        return Err("Post-only order cannot be repriced".to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        let mut order = Order::new(user_address, pair, side, price, quantity);
+        order.time_in_force = options.time_in_force;
+        order.self_trade_prevention = options
+            .self_trade_prevention
+            .unwrap_or_else(|| self.account_self_trade_prevention(&order.user_address));
+        self.submit_order(order)
+    }
+
+    /// Places a market order
+    ///
+    /// The order sweeps the other side of the book until it is filled, the book
+    /// runs out, or prices move more than `max_slippage` (a fraction; defaults to
+    /// `self.max_slippage`) away from the best price at placement. Whatever is
+    /// left unfilled is cancelled rather than rested.
+    pub fn place_market_order(
+        &mut self,
+        user_address: String,
+        pair: TradingPair,
+        side: OrderSide,
+        size: MarketOrderSize,
+        max_slippage: Option<Amount>,
+    ) -> Result<String, String> {
+        self.expire_orders(self.now())?;
+
+        let symbol = pair.symbol();
+        let order_book = self
+            .order_books
+            .get(&symbol)
+            .ok_or_else(|| format!("Trading pair {} not supported", symbol))?;
+
+        let slippage = max_slippage.unwrap_or(self.max_slippage);
+        if slippage < Amount::ZERO || slippage >= Amount::from(1) {
+            return Err("Slippage must be at least 0 and less than 1".to_string());
+        }
+
+        // Worst acceptable price, derived from the best price on the other side
+        let quote_scale = self.asset_scales.scale(&pair.quote);
+        let best_price = match side {
+            OrderSide::Buy => order_book.best_ask(),
+            OrderSide::Sell => order_book.best_bid(),
+        }
+        .ok_or_else(|| format!("No liquidity in {}", symbol))?;
+        let price_limit = Self::slippage_limit(side, best_price, slippage, quote_scale);
+
+        let mut order = match size {
+            MarketOrderSize::Base(quantity) => {
+                if !quantity.is_positive() {
+                    return Err("Quantity must be positive".to_string());
+                }
+                self.asset_scales.validate(&pair.base, quantity)?;
+                Order::new_market(user_address, pair, side, price_limit, quantity)
+            }
+            MarketOrderSize::Quote(budget) => {
+                if side == OrderSide::Sell {
+                    return Err("Only market buys can be sized in the quote currency".to_string());
+                }
+                if !budget.is_positive() {
+                    return Err("Quote amount must be positive".to_string());
+                }
+                self.asset_scales.validate(&pair.quote, budget)?;
+
+                // Fills never beat the best ask, so this bounds the quantity
+                let base_scale = self.asset_scales.scale(&pair.base);
+                let quantity = budget.div_round(best_price, base_scale, Rounding::Down);
+                if quantity.is_zero() {
+                    return Err(format!("Quote amount too small to buy any {}", pair.base));
+                }
+
+                let mut order = Order::new_market(user_address, pair, side, price_limit, quantity);
+                order.quote_budget = Some(budget);
+                order
+            }
+        };
+        order.self_trade_prevention = self.account_self_trade_prevention(&order.user_address);
+
+        self.submit_order(order)
+    }
+
+    /// Places a stop order
+    ///
+    /// The order waits off-book as `Pending`, with its funds held, until a trade
+    /// reaches the stop price. It then enters the matching engine as a limit
+    /// order (stop-limit) or as a market order guarded by `self.max_slippage`
+    /// from the stop price at placement (stop-loss and trailing stop).
+    pub fn place_stop_order(
+        &mut self,
+        user_address: String,
+        pair: TradingPair,
+        side: OrderSide,
+        kind: StopOrderKind,
+        quantity: Amount,
+    ) -> Result<String, String> {
+        self.expire_orders(self.now())?;
+
+        let symbol = pair.symbol();
+        if !self.order_books.contains_key(&symbol) {
+            return Err(format!("Trading pair {} not supported", symbol));
+        }
+        if !quantity.is_positive() {
+            return Err("Quantity must be positive".to_string());
+        }
+        self.asset_scales.validate(&pair.base, quantity)?;
+
+        let last_price = self.last_price(&pair);
+        let quote_scale = self.asset_scales.scale(&pair.quote);
+        let (stop_price, trigger) = match kind {
+            StopOrderKind::StopLoss { stop_price }
+            | StopOrderKind::StopLimit { stop_price, .. } => {
+                (stop_price, StopTrigger::Price(stop_price))
+            }
+            StopOrderKind::TrailingStop { offset } => {
+                if !offset.is_positive() {
+                    return Err("Trailing offset must be positive".to_string());
+                }
+                self.asset_scales.validate(&pair.quote, offset)?;
+                let best_price =
+                    last_price.ok_or_else(|| format!("No trades in {} to trail", symbol))?;
+                let stop_price = match side {
+                    OrderSide::Buy => best_price + offset,
+                    OrderSide::Sell => best_price - offset,
+                };
+                (stop_price, StopTrigger::Trailing { offset, best_price })
+            }
         };
+        if !stop_price.is_positive() {
+            return Err("Stop price must be positive".to_string());
+        }
+        self.asset_scales.validate(&pair.quote, stop_price)?;
 
-        let required_amount = match side {
-            OrderSide::Buy => price * quantity,
-            OrderSide::Sell => quantity,
+        let mut order = match kind {
+            StopOrderKind::StopLimit { limit_price, .. } => {
+                if !limit_price.is_positive() {
+                    return Err("Limit price must be positive".to_string());
+                }
+                self.asset_scales.validate(&pair.quote, limit_price)?;
+                Order::new(user_address, pair, side, limit_price, quantity)
+            }
+            _ => {
+                let price_limit =
+                    Self::slippage_limit(side, stop_price, self.max_slippage, quote_scale);
+                Order::new_market(user_address, pair, side, price_limit, quantity)
+            }
         };
+        order.stop = Some(trigger);
+        order.status = OrderStatus::Pending;
+        order.self_trade_prevention = self.account_self_trade_prevention(&order.user_address);
 
-        let balance = self.get_balance(&user_address, required_currency);
-        if balance < required_amount {
+        if last_price.is_some_and(|price| order.stop_reached(price)) {
+            return Err("Stop order would trigger immediately".to_string());
+        }
+
+        // Hold the funds now so the order can execute when it triggers
+        let required_amount = order.required_hold(quote_scale)?;
+        Self::hold(
+            &mut self.wallet_manager,
+            &self.blockchain,
+            &order.user_address,
+            order.hold_currency(),
+            required_amount,
+        )?;
+        order.locked_amount = required_amount;
+
+        order.id = self.next_id();
+        order.timestamp = self.now();
+        let order_id = order.id.clone();
+        self.user_feed
+            .order(&order, OrderEvent::Accepted, None, order.timestamp);
+        self.record_balances(BalanceReason::Order);
+        self.stop_orders.entry(symbol).or_default().push(order);
+        Ok(order_id)
+    }
+
+    /// Worst price a market order may reach, `slippage` away from `reference`
+    fn slippage_limit(
+        side: OrderSide,
+        reference: Amount,
+        slippage: Amount,
+        quote_scale: u32,
+    ) -> Amount {
+        match side {
+            OrderSide::Buy => {
+                (reference * (Amount::from(1) + slippage)).round_to(quote_scale, Rounding::Down)
+            }
+            OrderSide::Sell => {
+                (reference * (Amount::from(1) - slippage)).round_to(quote_scale, Rounding::Up)
+            }
+        }
+    }
+
+    /// Moves funds from available to locked for an order
+    ///
+    /// Trades move held funds on the chain, so the chain must carry
+    /// everything an account holds: were it short, it would refuse a trade
+    /// that the book has already matched.
+    fn hold(
+        wallet_manager: &mut WalletManager,
+        blockchain: &Blockchain,
+        address: &str,
+        currency: &str,
+        amount: Amount,
+    ) -> Result<(), String> {
+        wallet_manager.hold(address, currency, amount)?;
+        // The new hold already counts as locked
+        let backed =
+            Self::check_backed(wallet_manager, blockchain, address, currency, Amount::ZERO);
+        if let Err(e) = backed {
+            wallet_manager.release(address, currency, amount)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Checks that the chain carries an account's locked funds, plus
+    /// `spending` that is about to leave its available balance
+    fn check_backed(
+        wallet_manager: &WalletManager,
+        blockchain: &Blockchain,
+        address: &str,
+        currency: &str,
+        spending: Amount,
+    ) -> Result<(), String> {
+        let locked = wallet_manager
+            .get_wallet(address)
+            .map(|w| w.get_locked_balance(currency))
+            .unwrap_or_default();
+        let needed = locked.checked_add(spending)?;
+        let on_chain = blockchain.pending_balance(address, currency);
+        if on_chain < needed {
             return Err(format!(
-                "Insufficient {} balance. Required: {}, Available: {}",
-                required_currency, required_amount, balance
+                "Blockchain holds {} {} for {}, less than the {} needed",
+                on_chain, currency, address, needed
             ));
         }
+        Ok(())
+    }
 
-        // Lock the funds (withdraw from available balance)
-        self.wallet_manager
-            .withdraw(&user_address, required_currency, required_amount)?;
-
-        // Create and add the order
-        let order = Order::new(user_address, pair.clone(), side, price, quantity);
+    /// Locks the funds an order needs and sends it to the matching engine
+    fn submit_order(&mut self, mut order: Order) -> Result<String, String> {
+        order.id = self.next_id();
+        order.timestamp = self.now();
+        let quote_scale = self.asset_scales.scale(&order.pair.quote);
+        let required_amount = order.required_hold(quote_scale)?;
+        Self::hold(
+            &mut self.wallet_manager,
+            &self.blockchain,
+            &order.user_address,
+            order.hold_currency(),
+            required_amount,
+        )?;
+        order.locked_amount = required_amount;
         let order_id = order.id.clone();
+        self.user_feed
+            .order(&order, OrderEvent::Accepted, None, order.timestamp);
+        self.record_balances(BalanceReason::Order);
 
         // Try to match the order
         self.match_order(order)?;
@@ -134,160 +634,428 @@ impl Exchange {
         Ok(order_id)
     }
 
-    /// Matches an incoming order against the order book
-    fn match_order(&mut self, mut incoming: Order) -> Result<(), String> {
+    /// Matches an incoming order, then the stop orders its trades trigger
+    ///
+    /// Stops run in the order of the trades that reach them and, within one
+    /// trade, in placement order. Each triggered stop is matched in turn and may
+    /// trigger further stops.
+    fn match_order(&mut self, incoming: Order) -> Result<(), String> {
+        let mut queue = VecDeque::from([incoming]);
+        while let Some(order) = queue.pop_front() {
+            let triggered = self.execute_order(order)?;
+            queue.extend(triggered);
+        }
+        Ok(())
+    }
+
+    /// Matches one order against the order book and returns the stops it triggered
+    fn execute_order(&mut self, mut incoming: Order) -> Result<Vec<Order>, String> {
         let symbol = incoming.pair.symbol();
+        let quote_scale = self.asset_scales.scale(&incoming.pair.quote);
 
         // Get order book and perform matching
-        let trades = {
+        let reported = incoming.status;
+        let MatchResult {
+            trades,
+            filled,
+            prevented,
+            cancelled,
+        } = {
             let order_book = self
                 .order_books
                 .get_mut(&symbol)
                 .ok_or("Order book not found")?;
+            let wallet_manager = &mut self.wallet_manager;
+            let scales = &self.asset_scales;
 
             match incoming.side {
-                OrderSide::Buy => Self::match_buy_order(&mut incoming, order_book),
-                OrderSide::Sell => Self::match_sell_order(&mut incoming, order_book),
+                OrderSide::Buy => {
+                    Self::match_buy_order(&mut incoming, order_book, wallet_manager, scales)?
+                }
+                OrderSide::Sell => {
+                    Self::match_sell_order(&mut incoming, order_book, wallet_manager, scales)?
+                }
             }
         };
-
-        // Process trades
-        for trade in trades {
-            self.process_trade(&trade)?;
+        let now = self.now();
+        for mut prevented in prevented {
+            prevented.timestamp = now;
+            self.prevented_self_trades.push(prevented);
+        }
+        for order in &cancelled {
+            self.user_feed
+                .order(order, OrderEvent::Cancelled, None, now);
+        }
+        self.record_balances(BalanceReason::Cancel);
+
+        // Process trades, checking the pending stops after each one
+        let mut triggered = vec![];
+        let mut reported = reported;
+        for (mut trade, (buy_order, sell_order)) in trades.into_iter().zip(filled) {
+            trade.id = self.next_id();
+            trade.timestamp = now;
+            self.process_trade(&mut trade)?;
+            self.user_feed.fill(&buy_order, &trade, now);
+            self.user_feed.fill(&sell_order, &trade, now);
+            self.record_balances(BalanceReason::Trade);
+            reported = match incoming.side {
+                OrderSide::Buy => buy_order.status,
+                OrderSide::Sell => sell_order.status,
+            };
+            triggered.extend(self.trigger_stops(&symbol, trade.price));
             self.trades.push(trade);
         }
 
+        // Market and immediate-or-cancel orders never rest
+        if incoming.is_active() && !incoming.can_rest() {
+            Self::close_order(&mut self.wallet_manager, &mut incoming, OrderStatus::Cancelled)?;
+        }
+
+        // Release funds the order no longer needs: price improvement on a
+        // resting remainder, or everything left once it is filled
+        let keep = if incoming.is_active() {
+            incoming.required_hold(quote_scale)?
+        } else {
+            Amount::ZERO
+        };
+        let excess = incoming.locked_amount - keep;
+        if excess.is_positive() {
+            self.wallet_manager
+                .release(&incoming.user_address, incoming.hold_currency(), excess)?;
+            incoming.locked_amount = keep;
+        }
+
+        // Report how the order ended if no fill already did: a cancelled
+        // remainder or an exhausted quote budget
+        if !incoming.is_active() && incoming.status != reported {
+            let event = match incoming.status {
+                OrderStatus::Filled => OrderEvent::Filled,
+                _ => OrderEvent::Cancelled,
+            };
+            self.user_feed.order(&incoming, event, None, now);
+        }
+        let reason = match incoming.status {
+            OrderStatus::Cancelled => BalanceReason::Cancel,
+            _ => BalanceReason::Order,
+        };
+        self.record_balances(reason);
+
         // If order is not fully filled, add to order book
-        if incoming.status == OrderStatus::Open || incoming.status == OrderStatus::PartiallyFilled {
+        if incoming.is_active() {
+            if let TimeInForce::GoodTillDate(expires_at) = incoming.time_in_force {
+                let entry = (symbol.clone(), incoming.id.clone());
+                self.expiries.entry(expires_at).or_default().push(entry);
+            }
             let order_book = self.order_books.get_mut(&symbol).unwrap();
-            match incoming.side {
-                OrderSide::Buy => order_book.add_buy_order(incoming),
-                OrderSide::Sell => order_book.add_sell_order(incoming),
+            order_book.add_order(incoming);
+        }
+
+        Ok(triggered)
+    }
+
+    /// Feeds a trade price to the pending stops of a pair and takes out the ones that fire
+    fn trigger_stops(&mut self, symbol: &str, price: Amount) -> Vec<Order> {
+        let Some(stops) = self.stop_orders.get_mut(symbol) else {
+            return vec![];
+        };
+
+        for order in stops.iter_mut() {
+            if order.observe_price(price) {
+                order.status = OrderStatus::Triggered;
             }
         }
 
-        Ok(())
+        let (triggered, pending) = std::mem::take(stops)
+            .into_iter()
+            .partition(|o| o.status == OrderStatus::Triggered);
+        *stops = pending;
+        triggered
     }
 
     /// Matches a buy order against sell orders
-    fn match_buy_order(buy_order: &mut Order, order_book: &mut OrderBook) -> Vec<Trade> {
-        let mut trades = vec![];
-
-        for sell_order in order_book.sell_orders.iter_mut() {
-            if sell_order.status != OrderStatus::Open
-                && sell_order.status != OrderStatus::PartiallyFilled
-            {
-                continue;
-            }
+    fn match_buy_order(
+        buy_order: &mut Order,
+        order_book: &mut OrderBook,
+        wallet_manager: &mut WalletManager,
+        scales: &AssetScales,
+    ) -> Result<MatchResult, String> {
+        let mut result = MatchResult::default();
+        let base_scale = scales.scale(&buy_order.pair.base);
+        let quote_scale = scales.scale(&buy_order.pair.quote);
+
+        while buy_order.is_active() {
+            let step = order_book.with_best_match(OrderSide::Buy, |sell_order| {
+                // Check if prices match (buy price >= sell price)
+                if buy_order.price < sell_order.price {
+                    return None;
+                }
 
-            // Check if prices match (buy price >= sell price)
-            if buy_order.price < sell_order.price {
-                break; // No more matching orders (sorted by price)
-            }
+                // Calculate trade quantity
+                let price = sell_order.price;
+                let trade_quantity = buy_order
+                    .fillable_quantity(price, base_scale)
+                    .min(sell_order.remaining_quantity());
+                if trade_quantity.is_zero() {
+                    // The quote budget cannot buy any more
+                    buy_order.complete();
+                    return None;
+                }
 
-            // Calculate trade quantity
-            let trade_quantity = buy_order
-                .remaining_quantity()
-                .min(sell_order.remaining_quantity());
-
-            // Execute trade at sell order's price (price-time priority)
-            let trade = Trade::new(
-                buy_order.pair.clone(),
-                sell_order.price,
-                trade_quantity,
-                buy_order.user_address.clone(),
-                sell_order.user_address.clone(),
-                buy_order.id.clone(),
-                sell_order.id.clone(),
-            );
-
-            // Update orders
-            buy_order.fill(trade_quantity);
-            sell_order.fill(trade_quantity);
-
-            trades.push(trade);
-
-            if buy_order.status == OrderStatus::Filled {
-                break;
+                // Never trade with the same account
+                if buy_order.user_address == sell_order.user_address {
+                    let prevented = Self::prevent_self_trade(
+                        buy_order,
+                        sell_order,
+                        price,
+                        trade_quantity,
+                        wallet_manager,
+                        quote_scale,
+                    );
+                    if sell_order.status == OrderStatus::Cancelled {
+                        result.cancelled.push(sell_order.clone());
+                    }
+                    return Some(prevented.map(|p| result.prevented.push(p)));
+                }
+
+                // Execute trade at sell order's price (price-time priority)
+                result.trades.push(Self::execute_trade(
+                    buy_order,
+                    sell_order,
+                    price,
+                    trade_quantity,
+                    quote_scale,
+                    OrderSide::Buy,
+                ));
+                result.filled.push((buy_order.clone(), sell_order.clone()));
+                Some(Ok(()))
+            });
+
+            match step.flatten() {
+                Some(outcome) => outcome?,
+                None => break, // Book exhausted or best ask out of range
             }
         }
 
-        // Clean up filled orders
-        order_book.clean_orders();
-
-        trades
+        Ok(result)
     }
 
     /// Matches a sell order against buy orders
     fn match_sell_order(
         sell_order: &mut Order,
         order_book: &mut OrderBook,
-    ) -> Vec<Trade> {
-        let mut trades = vec![];
-
-        for buy_order in order_book.buy_orders.iter_mut() {
-            if buy_order.status != OrderStatus::Open
-                && buy_order.status != OrderStatus::PartiallyFilled
-            {
-                continue;
+        wallet_manager: &mut WalletManager,
+        scales: &AssetScales,
+    ) -> Result<MatchResult, String> {
+        let mut result = MatchResult::default();
+        let quote_scale = scales.scale(&sell_order.pair.quote);
+
+        while sell_order.is_active() {
+            let step = order_book.with_best_match(OrderSide::Sell, |buy_order| {
+                // Check if prices match (sell price <= buy price)
+                if sell_order.price > buy_order.price {
+                    return None;
+                }
+
+                // Calculate trade quantity
+                let price = buy_order.price;
+                let trade_quantity = sell_order
+                    .remaining_quantity()
+                    .min(buy_order.remaining_quantity());
+
+                // Never trade with the same account
+                if sell_order.user_address == buy_order.user_address {
+                    let prevented = Self::prevent_self_trade(
+                        sell_order,
+                        buy_order,
+                        price,
+                        trade_quantity,
+                        wallet_manager,
+                        quote_scale,
+                    );
+                    if buy_order.status == OrderStatus::Cancelled {
+                        result.cancelled.push(buy_order.clone());
+                    }
+                    return Some(prevented.map(|p| result.prevented.push(p)));
+                }
+
+                // Execute trade at buy order's price (price-time priority)
+                result.trades.push(Self::execute_trade(
+                    buy_order,
+                    sell_order,
+                    price,
+                    trade_quantity,
+                    quote_scale,
+                    OrderSide::Sell,
+                ));
+                result.filled.push((buy_order.clone(), sell_order.clone()));
+                Some(Ok(()))
+            });
+
+            match step.flatten() {
+                Some(outcome) => outcome?,
+                None => break, // Book exhausted or best bid out of range
             }
+        }
+
+        Ok(result)
+    }
 
-            // Check if prices match (sell price <= buy price)
-            if sell_order.price > buy_order.price {
-                break; // No more matching orders (sorted by price)
+    /// Applies the incoming order's self-trade prevention mode instead of trading
+    ///
+    /// Cancelled orders release their holds straight away, and a resting order
+    /// that is only decremented releases what it no longer needs. The incoming
+    /// order's excess hold is released once its matching is done.
+    fn prevent_self_trade(
+        taker: &mut Order,
+        maker: &mut Order,
+        price: Amount,
+        quantity: Amount,
+        wallet_manager: &mut WalletManager,
+        quote_scale: u32,
+    ) -> Result<PreventedSelfTrade, String> {
+        let mode = taker.self_trade_prevention;
+        let (cancel_taker, cancel_maker) = match mode {
+            SelfTradePrevention::CancelNewest => (true, false),
+            SelfTradePrevention::CancelOldest => (false, true),
+            SelfTradePrevention::CancelBoth => (true, true),
+            SelfTradePrevention::DecrementAndCancel => {
+                taker.quantity -= quantity;
+                maker.quantity -= quantity;
+                (
+                    taker.remaining_quantity().is_zero(),
+                    maker.remaining_quantity().is_zero(),
+                )
             }
+        };
 
-            // Calculate trade quantity
-            let trade_quantity = sell_order
-                .remaining_quantity()
-                .min(buy_order.remaining_quantity());
-
-            // Execute trade at buy order's price (price-time priority)
-            let trade = Trade::new(
-                sell_order.pair.clone(),
-                buy_order.price,
-                trade_quantity,
-                buy_order.user_address.clone(),
-                sell_order.user_address.clone(),
-                buy_order.id.clone(),
-                sell_order.id.clone(),
-            );
-
-            // Update orders
-            sell_order.fill(trade_quantity);
-            buy_order.fill(trade_quantity);
-
-            trades.push(trade);
-
-            if sell_order.status == OrderStatus::Filled {
-                break;
+        if cancel_taker {
+            Self::close_order(wallet_manager, taker, OrderStatus::Cancelled)?;
+        }
+        if cancel_maker {
+            Self::close_order(wallet_manager, maker, OrderStatus::Cancelled)?;
+        } else {
+            let excess = maker.locked_amount - maker.required_hold(quote_scale)?;
+            if excess.is_positive() {
+                wallet_manager.release(&maker.user_address, maker.hold_currency(), excess)?;
+                maker.locked_amount -= excess;
             }
         }
 
-        // Clean up filled orders
-        order_book.clean_orders();
+        Ok(PreventedSelfTrade {
+            pair: taker.pair.clone(),
+            user_address: taker.user_address.clone(),
+            taker_order_id: taker.id.clone(),
+            maker_order_id: maker.id.clone(),
+            mode,
+            price,
+            quantity,
+            timestamp: 0, // stamped by execute_order
+        })
+    }
+
+    /// Fills both orders and builds the resulting trade
+    ///
+    /// The buyer is charged, and the seller paid, the change in their rounded
+    /// running quote totals, and both holds are reduced accordingly.
+    fn execute_trade(
+        buy_order: &mut Order,
+        sell_order: &mut Order,
+        price: Amount,
+        quantity: Amount,
+        quote_scale: u32,
+        taker_side: OrderSide,
+    ) -> Trade {
+        let paid_before = buy_order.settled_quote(quote_scale);
+        let received_before = sell_order.settled_quote(quote_scale);
+
+        buy_order.fill(quantity, price);
+        sell_order.fill(quantity, price);
+
+        let mut trade = Trade::new(
+            buy_order.pair.clone(),
+            price,
+            quantity,
+            buy_order.user_address.clone(),
+            sell_order.user_address.clone(),
+            buy_order.id.clone(),
+            sell_order.id.clone(),
+        );
+        trade.buyer_quote = buy_order.settled_quote(quote_scale) - paid_before;
+        trade.seller_quote = sell_order.settled_quote(quote_scale) - received_before;
+        trade.taker_side = taker_side;
+
+        buy_order.locked_amount -= trade.buyer_quote;
+        sell_order.locked_amount -= quantity;
 
-        trades
+        trade
     }
 
-    /// Processes a trade by updating wallets
-    fn process_trade(&mut self, trade: &Trade) -> Result<(), String> {
-        // Buyer receives base currency
+    /// Processes a trade by settling both holds and crediting the proceeds
+    ///
+    /// Each side pays its fee in the currency it receives; fees go to the fee
+    /// wallet and are recorded on the trade.
+    fn process_trade(&mut self, trade: &mut Trade) -> Result<(), String> {
+        let pair = trade.pair.clone();
+        let (buyer_liquidity, seller_liquidity) = match trade.taker_side {
+            OrderSide::Buy => (Liquidity::Taker, Liquidity::Maker),
+            OrderSide::Sell => (Liquidity::Maker, Liquidity::Taker),
+        };
+        let buyer_volume = self.trading_volume(&trade.buyer_address, &pair.quote);
+        let buyer_rate = self.fee_schedule.rate(&pair, buyer_liquidity, buyer_volume);
+        let seller_volume = self.trading_volume(&trade.seller_address, &pair.quote);
+        let seller_rate = self.fee_schedule.rate(&pair, seller_liquidity, seller_volume);
+        let base_scale = self.asset_scales.scale(&pair.base);
+        let quote_scale = self.asset_scales.scale(&pair.quote);
+        trade.buyer_fee = FeeSchedule::fee(trade.quantity, buyer_rate, base_scale);
+        trade.seller_fee = FeeSchedule::fee(trade.seller_quote, seller_rate, quote_scale);
+
+        // The chain must take the trade before any wallet moves. Both sides'
+        // holds are backed on the chain, see `Exchange::hold`, so it does.
+        self.record_trade(trade)?;
+
+        // Buyer pays quote currency from the hold and receives base currency
         self.wallet_manager
-            .deposit(&trade.buyer_address, &trade.pair.base, trade.quantity)?;
+            .settle(&trade.buyer_address, &pair.quote, trade.buyer_quote)?;
+        let bought = trade.quantity - trade.buyer_fee;
+        if bought.is_positive() {
+            self.wallet_manager
+                .deposit(&trade.buyer_address, &pair.base, bought)?;
+        }
 
-        // Seller receives quote currency
-        let quote_amount = trade.price * trade.quantity;
+        // Seller delivers base currency from the hold and receives quote currency
         self.wallet_manager
-            .deposit(&trade.seller_address, &trade.pair.quote, quote_amount)?;
+            .settle(&trade.seller_address, &pair.base, trade.quantity)?;
+        let proceeds = trade.seller_quote - trade.seller_fee;
+        if proceeds.is_positive() {
+            self.wallet_manager
+                .deposit(&trade.seller_address, &pair.quote, proceeds)?;
+        }
 
-        // Record the trade on the blockchain
-        let tx = Transaction::new_trade(
-            trade.seller_address.clone(),
-            trade.buyer_address.clone(),
-            trade.quantity,
-        );
-        let _ = self.blockchain.add_transaction(tx);
+        // Collect the fees
+        if trade.buyer_fee.is_positive() {
+            self.wallet_manager.collect_fee(&pair.base, trade.buyer_fee)?;
+        }
+        if trade.seller_fee.is_positive() {
+            self.wallet_manager.collect_fee(&pair.quote, trade.seller_fee)?;
+        }
+
+        // Count the traded volume towards both accounts' fee tiers
+        for (address, volume) in [
+            (&trade.buyer_address, trade.buyer_quote),
+            (&trade.seller_address, trade.seller_quote),
+        ] {
+            *self
+                .trading_volumes
+                .entry(address.clone())
+                .or_default()
+                .entry(pair.quote.clone())
+                .or_default() += volume;
+        }
+
+        if let Some(series) = self.candles.get_mut(&pair.symbol()) {
+            series.record(trade.price, trade.quantity, trade.timestamp);
+        }
+        self.market_feed.record_trade(trade);
 
         println!(
             "Trade executed: {} {} @ {} {} (Buyer: {}, Seller: {})",
@@ -302,47 +1070,345 @@ impl Exchange {
         Ok(())
     }
 
+    /// Records both legs of a trade on the blockchain
+    ///
+    /// Fees reach the fee wallet in transfers of their own. Buyers pay their
+    /// quote rounded up and sellers receive theirs rounded down, so any
+    /// difference leaves or enters the exchange. That way the chain moves
+    /// exactly what the wallets do.
+    fn record_trade(&mut self, trade: &Trade) -> Result<(), String> {
+        let buyer = &trade.buyer_address;
+        let seller = &trade.seller_address;
+        let fee_address = self.wallet_manager.fee_address().to_string();
+        let (base, quote) = (&trade.pair.base, &trade.pair.quote);
+
+        let mut transactions = vec![
+            Transaction::new_trade(seller.clone(), buyer.clone(), base, trade.quantity)
+                .with_fee(trade.buyer_fee),
+            Transaction::new_trade(buyer.clone(), fee_address.clone(), base, trade.buyer_fee),
+            Transaction::new_trade(buyer.clone(), seller.clone(), quote, trade.seller_quote)
+                .with_fee(trade.seller_fee),
+            Transaction::new_trade(seller.clone(), fee_address, quote, trade.seller_fee),
+        ];
+        match trade.buyer_quote.cmp(&trade.seller_quote) {
+            Ordering::Greater => transactions.push(Transaction::new_trade(
+                buyer.clone(),
+                EXTERNAL_ADDRESS.to_string(),
+                quote,
+                trade.buyer_quote - trade.seller_quote,
+            )),
+            Ordering::Less => transactions.push(Transaction::new_deposit(
+                seller.clone(),
+                quote,
+                trade.seller_quote - trade.buyer_quote,
+            )),
+            Ordering::Equal => {}
+        }
+
+        let transactions = transactions
+            .into_iter()
+            .filter(|tx| tx.amount.is_positive())
+            .map(|tx| tx.with_id_and_timestamp(self.next_id(), trade.timestamp))
+            .collect();
+        self.record_transactions(transactions)
+    }
+
+    /// Adds transactions to the mempool, all or none
+    ///
+    /// The exchange holds its users' keys and signs for the sender; deposits
+    /// from `EXTERNAL` it signs as the chain's custodian. If the chain refuses
+    /// one, those added before it are taken back out.
+    fn record_transactions(&mut self, transactions: Vec<Transaction>) -> Result<(), String> {
+        let pending = self.blockchain.mempool.len();
+        let mut recorded = vec![];
+        for tx in transactions {
+            let tx = if tx.is_exempt() {
+                Ok(self.wallet_manager.sign_deposit(tx))
+            } else {
+                let nonce = self.blockchain.next_nonce(&tx.from_address);
+                self.wallet_manager.sign(tx.with_nonce(nonce))
+            };
+            let added = tx.and_then(|tx| {
+                recorded.push(tx.clone());
+                self.blockchain.add_transaction(tx)
+            });
+            if let Err(e) = added {
+                self.blockchain.mempool.truncate(pending);
+                return Err(format!("Blockchain rejected transaction: {}", e));
+            }
+        }
+        self.recorded_transactions.extend(recorded);
+        Ok(())
+    }
+
     /// Cancels an order
     pub fn cancel_order(&mut self, order_id: &str, pair: &TradingPair) -> Result<(), String> {
+        self.expire_orders(self.now())?;
+
         let symbol = pair.symbol();
         let order_book = self
             .order_books
             .get_mut(&symbol)
             .ok_or("Order book not found")?;
 
-        let order = order_book
-            .get_order_mut(order_id)
-            .ok_or("Order not found")?;
+        // Only active orders rest on the book
+        let mut order = match order_book.remove_order(order_id) {
+            Some(order) => order,
+            None => {
+                // Pending stop orders are kept off-book
+                let stops = self.stop_orders.get_mut(&symbol).ok_or("Order not found")?;
+                let index = stops
+                    .iter()
+                    .position(|o| o.id == order_id)
+                    .ok_or("Order not found")?;
+                stops.remove(index)
+            }
+        };
+        Self::close_order(&mut self.wallet_manager, &mut order, OrderStatus::Cancelled)?;
+        self.user_feed
+            .order(&order, OrderEvent::Cancelled, None, self.now());
+        self.record_balances(BalanceReason::Cancel);
+        Ok(())
+    }
 
-        if order.status == OrderStatus::Filled || order.status == OrderStatus::Cancelled {
-            return Err("Order cannot be cancelled".to_string());
+    /// Amends the price and quantity of a resting order
+    ///
+    /// `quantity` is the new total quantity, including what has already
+    /// filled. Lowering the quantity keeps the order's place in the queue;
+    /// changing the price or raising the quantity sends it to the back of the
+    /// queue at its new price, where it may match straight away. The hold is
+    /// adjusted before the book changes, so a rejected amend leaves both the
+    /// order and the balances as they were.
+    pub fn amend_order(
+        &mut self,
+        order_id: &str,
+        pair: &TradingPair,
+        price: Amount,
+        quantity: Amount,
+    ) -> Result<(), String> {
+        self.expire_orders(self.now())?;
+
+        if !price.is_positive() || !quantity.is_positive() {
+            return Err("Price and quantity must be positive".to_string());
         }
+        self.asset_scales.validate(&pair.quote, price)?;
+        self.asset_scales.validate(&pair.base, quantity)?;
+        let quote_scale = self.asset_scales.scale(&pair.quote);
 
-        // Refund remaining funds
-        let refund_currency = match order.side {
-            OrderSide::Buy => &pair.quote,
-            OrderSide::Sell => &pair.base,
-        };
+        let order_book = self
+            .order_books
+            .get_mut(&pair.symbol())
+            .ok_or("Order book not found")?;
 
-        let refund_amount = match order.side {
-            OrderSide::Buy => order.price * order.remaining_quantity(),
-            OrderSide::Sell => order.remaining_quantity(),
-        };
+        // Filled, cancelled and expired orders have already left the book
+        let order = order_book
+            .get_order(order_id)
+            .ok_or("Order not found or no longer open")?;
+        if quantity <= order.filled_quantity {
+            return Err("Amended quantity must exceed the filled quantity".to_string());
+        }
+        if let TimeInForce::PostOnly(_) = order.time_in_force {
+            if order_book.matchable_quantity(order.side, price).is_positive() {
+                return Err("Post-only order would match immediately".to_string());
+            }
+        }
 
-        self.wallet_manager
-            .deposit(&order.user_address, refund_currency, refund_amount)?;
+        let keep_priority = price == order.price && quantity <= order.quantity;
+        let mut amended = order.clone();
+        amended.price = price;
+        amended.quantity = quantity;
+
+        // Re-hold for the amended order; the extra hold is the step that can fail
+        let required = amended.required_hold(quote_scale)?;
+        let extra = required - order.locked_amount;
+        if extra.is_positive() {
+            Self::hold(
+                &mut self.wallet_manager,
+                &self.blockchain,
+                &amended.user_address,
+                amended.hold_currency(),
+                extra,
+            )?;
+        } else if (-extra).is_positive() {
+            self.wallet_manager
+                .release(&amended.user_address, amended.hold_currency(), -extra)?;
+        }
+        amended.locked_amount = required;
 
-        order.cancel();
-        order_book.clean_orders();
+        if keep_priority {
+            order_book.update_order(order_id, |order| *order = amended);
+            self.record_balances(BalanceReason::Order);
+            return Ok(());
+        }
+
+        order_book.remove_order(order_id);
+        self.record_balances(BalanceReason::Order);
+        amended.timestamp = self.now();
+        self.match_order(amended)
+    }
+
+    /// Expires good-till-date orders whose expiry is at or before `now`
+    ///
+    /// Runs automatically before every order placement and cancellation, and
+    /// only looks at the orders that have come due.
+    /// Returns the ids of the expired orders.
+    pub fn expire_orders(&mut self, now: i64) -> Result<Vec<String>, String> {
+        let mut expired = vec![];
+        while let Some(entry) = self.expiries.first_entry() {
+            if *entry.key() > now {
+                break;
+            }
+            for (symbol, order_id) in entry.remove() {
+                let Some(order_book) = self.order_books.get_mut(&symbol) else {
+                    continue;
+                };
+                // The order may have filled or been cancelled since
+                if !order_book
+                    .get_order(&order_id)
+                    .is_some_and(|order| order.is_expired(now))
+                {
+                    continue;
+                }
+                if let Some(mut order) = order_book.remove_order(&order_id) {
+                    Self::close_order(&mut self.wallet_manager, &mut order, OrderStatus::Expired)?;
+                    self.user_feed
+                        .order(&order, OrderEvent::Expired, None, now);
+                    expired.push(order_id);
+                }
+            }
+        }
+        self.record_balances(BalanceReason::Cancel);
+        Ok(expired)
+    }
 
+    /// Releases everything still held for an order and closes it with `status`
+    ///
+    /// This is the refund path shared by cancellations, expiries and
+    /// remainders that are not allowed to rest.
+    fn close_order(
+        wallet_manager: &mut WalletManager,
+        order: &mut Order,
+        status: OrderStatus,
+    ) -> Result<(), String> {
+        wallet_manager.release(&order.user_address, order.hold_currency(), order.locked_amount)?;
+        order.locked_amount = Amount::ZERO;
+        order.status = status;
         Ok(())
     }
 
+    /// Records a balance event for every balance changed since the last call
+    fn record_balances(&mut self, reason: BalanceReason) {
+        let now = self.now();
+        for (address, currency) in self.wallet_manager.take_changed_balances() {
+            if let Some(wallet) = self.wallet_manager.get_wallet(&address) {
+                let balance = wallet.balance(&currency);
+                self.user_feed
+                    .balance(&address, &currency, balance, reason, now);
+            }
+        }
+    }
+
+    /// Current Unix timestamp, from the exchange's clock
+    pub fn now(&self) -> i64 {
+        self.clock.now()
+    }
+
+    /// Next unique id, from the exchange's id generator
+    fn next_id(&mut self) -> String {
+        self.ids.next_id()
+    }
+
+    /// Aggregated depth of the top `levels` price levels on each side of a pair
+    pub fn depth(&self, pair: &TradingPair, levels: usize) -> Option<DepthSnapshot> {
+        self.get_order_book(pair).map(|book| book.depth(levels))
+    }
+
+    /// Takes the level updates of a pair recorded since the last call
+    ///
+    /// Applying them in order to a [`DepthReplica`](crate::depth::DepthReplica)
+    /// built from a full [`Exchange::depth`] snapshot keeps it in step with the book.
+    pub fn take_level_updates(&mut self, pair: &TradingPair) -> Vec<LevelUpdate> {
+        self.order_books
+            .get_mut(&pair.symbol())
+            .map(|book| book.take_level_updates())
+            .unwrap_or_default()
+    }
+
+    /// Takes the market data events of every pair recorded since the last call
+    ///
+    /// Trades, depth and ticker events come out per pair, in that order, each
+    /// channel oldest first. This drains the order books' level updates, so it
+    /// should not be combined with [`Exchange::take_level_updates`].
+    pub fn take_market_events(&mut self) -> Vec<MarketEvent> {
+        let mut events = vec![];
+        for pair in &self.supported_pairs {
+            if let Some(book) = self.order_books.get_mut(&pair.symbol()) {
+                events.extend(self.market_feed.take_events(book));
+            }
+        }
+        events
+    }
+
+    /// Takes the account events recorded since the last call, oldest first
+    ///
+    /// Each account's events come out in sequence order; events of different
+    /// accounts are interleaved as they happened.
+    pub fn take_user_events(&mut self) -> Vec<UserEvent> {
+        self.user_feed.take_events()
+    }
+
+    /// Takes the transactions recorded on the chain since the last call, oldest first
+    ///
+    /// These are the signed transactions of deposits, withdrawals and trades,
+    /// for relaying to a chain node; see [`EventHub::with_node`](crate::api::EventHub::with_node).
+    pub fn take_recorded_transactions(&mut self) -> Vec<Transaction> {
+        std::mem::take(&mut self.recorded_transactions)
+    }
+
+    /// Best bid, best ask and last price of a pair
+    pub fn book_ticker(&self, pair: &TradingPair) -> Option<BookTicker> {
+        let book = self.get_order_book(pair)?;
+        Some(self.market_feed.book_ticker(book))
+    }
+
+    /// Number of trades in a pair so far, the sequence of its latest trade event
+    pub fn trade_sequence(&self, pair: &TradingPair) -> u64 {
+        self.market_feed.trade_sequence(pair)
+    }
+
+    /// Candles of a pair that open at or after `from` and before `to`, oldest first
+    pub fn candles(
+        &self,
+        pair: &TradingPair,
+        interval: CandleInterval,
+        from: i64,
+        to: i64,
+    ) -> Vec<Candle> {
+        self.candles
+            .get(&pair.symbol())
+            .map(|series| series.range(interval, from, to))
+            .unwrap_or_default()
+    }
+
+    /// 24 hour ticker of a pair, or `None` if it has not traded in that time
+    pub fn ticker(&self, pair: &TradingPair) -> Option<Ticker> {
+        self.candles.get(&pair.symbol())?.ticker(self.now())
+    }
+
     /// Gets the order book for a trading pair
     pub fn get_order_book(&self, pair: &TradingPair) -> Option<&OrderBook> {
         self.order_books.get(&pair.symbol())
     }
 
+    /// Gets the price of the most recent trade in a pair
+    pub fn last_price(&self, pair: &TradingPair) -> Option<Amount> {
+        self.trades
+            .iter()
+            .rev()
+            .find(|t| t.pair == *pair)
+            .map(|t| t.price)
+    }
+
     /// Gets recent trades
     pub fn get_recent_trades(&self, limit: usize) -> Vec<&Trade> {
         self.trades.iter().rev().take(limit).collect()
@@ -350,36 +1416,108 @@ impl Exchange {
 
     /// Mines pending transactions
     pub fn mine_transactions(&mut self, miner_address: &str) {
-        self.blockchain.mine_pending_transactions(miner_address);
+        let reward_id = self.next_id();
+        let now = self.now();
+        self.blockchain
+            .mine_pending_transactions_at(miner_address, reward_id, now);
     }
 
-    /// Prints the current state of the order book
-    pub fn print_order_book(&self, pair: &TradingPair) {
-        if let Some(order_book) = self.get_order_book(pair) {
-            println!("\n=== Order Book: {} ===", pair.symbol());
-            println!("--- SELL ORDERS ---");
-            for order in order_book.sell_orders.iter().rev() {
-                if order.status == OrderStatus::Open || order.status == OrderStatus::PartiallyFilled
-                {
-                    println!(
-                        "  Price: {:.2}, Qty: {:.4}, Remaining: {:.4}",
-                        order.price,
-                        order.quantity,
-                        order.remaining_quantity()
-                    );
+    /// Captures the exchange's state
+    pub fn snapshot(&self) -> ExchangeSnapshot {
+        ExchangeSnapshot {
+            version: SNAPSHOT_VERSION,
+            taken_at: self.now(),
+            name: self.name.clone(),
+            supported_pairs: self.supported_pairs.clone(),
+            order_books: self.order_books.clone(),
+            stop_orders: self.stop_orders.clone(),
+            wallet_manager: self.wallet_manager.clone(),
+            blockchain: self.blockchain.clone(),
+            trades: self.trades.clone(),
+            candles: self.candles.clone(),
+            prevented_self_trades: self.prevented_self_trades.clone(),
+            self_trade_prevention: self.self_trade_prevention.clone(),
+            asset_scales: self.asset_scales.clone(),
+            fee_schedule: self.fee_schedule.clone(),
+            trading_volumes: self.trading_volumes.clone(),
+            max_slippage: self.max_slippage,
+        }
+    }
+
+    /// Rebuilds an exchange from a snapshot, after validating it
+    pub fn from_snapshot(
+        snapshot: ExchangeSnapshot,
+        clock: Box<dyn Clock>,
+        ids: Box<dyn IdGenerator>,
+    ) -> Result<Self, String> {
+        snapshot.validate()?;
+        let market_feed = MarketFeed::resume(&snapshot.trades);
+        let mut expiries: BTreeMap<i64, Vec<(String, String)>> = BTreeMap::new();
+        for (symbol, book) in &snapshot.order_books {
+            for order in book.buy_orders().chain(book.sell_orders()) {
+                if let TimeInForce::GoodTillDate(expires_at) = order.time_in_force {
+                    let entry = (symbol.clone(), order.id.clone());
+                    expiries.entry(expires_at).or_default().push(entry);
                 }
             }
+        }
+        Ok(Exchange {
+            name: snapshot.name,
+            order_books: snapshot.order_books,
+            stop_orders: snapshot.stop_orders,
+            wallet_manager: snapshot.wallet_manager,
+            blockchain: snapshot.blockchain,
+            trades: snapshot.trades,
+            candles: snapshot.candles,
+            prevented_self_trades: snapshot.prevented_self_trades,
+            self_trade_prevention: snapshot.self_trade_prevention,
+            supported_pairs: snapshot.supported_pairs,
+            asset_scales: snapshot.asset_scales,
+            fee_schedule: snapshot.fee_schedule,
+            trading_volumes: snapshot.trading_volumes,
+            max_slippage: snapshot.max_slippage,
+            expiries,
+            market_feed,
+            user_feed: UserFeed::default(),
+            recorded_transactions: vec![],
+            clock,
+            ids,
+        })
+    }
+
+    /// Writes a snapshot of the exchange to a file
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        self.snapshot().save(path)
+    }
+
+    /// Restores an exchange from a snapshot file, using the system clock and random ids
+    pub fn restore_snapshot(path: impl AsRef<Path>) -> Result<Self, String> {
+        let snapshot = ExchangeSnapshot::load(path)?;
+        Self::from_snapshot(snapshot, Box::new(SystemClock), Box::new(RandomIds))
+    }
+
+    /// Prints the current state of the order book
+    pub fn print_order_book(&self, pair: &TradingPair) {
+        if let Some(order_book) = self.get_order_book(pair) {
+            println!("\n=== Order Book: {} ===", pair.symbol());
+            println!("--- SELL ORDERS ---");
+            let sell_orders: Vec<&Order> = order_book.sell_orders().collect();
+            for order in sell_orders.iter().rev() {
+                println!(
+                    "  Price: {:.2}, Qty: {:.4}, Remaining: {:.4}",
+                    order.price,
+                    order.quantity,
+                    order.remaining_quantity()
+                );
+            }
             println!("--- BUY ORDERS ---");
-            for order in &order_book.buy_orders {
-                if order.status == OrderStatus::Open || order.status == OrderStatus::PartiallyFilled
-                {
-                    println!(
-                        "  Price: {:.2}, Qty: {:.4}, Remaining: {:.4}",
-                        order.price,
-                        order.quantity,
-                        order.remaining_quantity()
-                    );
-                }
+            for order in order_book.buy_orders() {
+                println!(
+                    "  Price: {:.2}, Qty: {:.4}, Remaining: {:.4}",
+                    order.price,
+                    order.quantity,
+                    order.remaining_quantity()
+                );
             }
             if let (Some(bid), Some(ask)) = (order_book.best_bid(), order_book.best_ask()) {
                 println!("Spread: {:.2}", ask - bid);
@@ -392,6 +1530,14 @@ impl Exchange {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::Utc;
+    use crate::depth::{DepthReplica, LevelAction};
+    use crate::fee::{FeeRates, FeeTier};
+    use crate::user_data::UserUpdate;
+
+    fn amt(s: &str) -> Amount {
+        s.parse().unwrap()
+    }
 
     #[test]
     fn test_exchange_creation() {
@@ -409,24 +1555,36 @@ mod tests {
         let bob = exchange.create_wallet("Bob");
 
         // Deposit funds
-        exchange.deposit(&alice, "USDT", 100000.0).unwrap();
-        exchange.deposit(&bob, "BTC", 2.0).unwrap();
+        exchange.deposit(&alice, "USDT", Amount::from(100000)).unwrap();
+        exchange.deposit(&bob, "BTC", Amount::from(2)).unwrap();
 
         let pair = TradingPair::new("BTC", "USDT");
 
         // Alice places a buy order
         exchange
-            .place_order(alice.clone(), pair.clone(), OrderSide::Buy, 50000.0, 1.0)
+            .place_order(
+                alice.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(50000),
+                Amount::from(1),
+            )
             .unwrap();
 
         // Bob places a matching sell order
         exchange
-            .place_order(bob.clone(), pair.clone(), OrderSide::Sell, 50000.0, 1.0)
+            .place_order(
+                bob.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                Amount::from(50000),
+                Amount::from(1),
+            )
             .unwrap();
 
         // Check balances after trade
-        assert_eq!(exchange.get_balance(&alice, "BTC"), 1.0);
-        assert_eq!(exchange.get_balance(&bob, "USDT"), 50000.0);
+        assert_eq!(exchange.get_balance(&alice, "BTC"), Amount::from(1));
+        assert_eq!(exchange.get_balance(&bob, "USDT"), Amount::from(50000));
     }
 
     #[test]
@@ -436,28 +1594,1390 @@ mod tests {
         let alice = exchange.create_wallet("Alice");
         let bob = exchange.create_wallet("Bob");
 
-        exchange.deposit(&alice, "USDT", 100000.0).unwrap();
-        exchange.deposit(&bob, "BTC", 1.0).unwrap();
+        exchange.deposit(&alice, "USDT", Amount::from(100000)).unwrap();
+        exchange.deposit(&bob, "BTC", Amount::from(1)).unwrap();
 
         let pair = TradingPair::new("BTC", "USDT");
 
         // Alice wants to buy 2 BTC
         exchange
-            .place_order(alice.clone(), pair.clone(), OrderSide::Buy, 50000.0, 2.0)
+            .place_order(
+                alice.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(50000),
+                Amount::from(2),
+            )
             .unwrap();
 
         // Bob only sells 1 BTC
         exchange
-            .place_order(bob.clone(), pair.clone(), OrderSide::Sell, 50000.0, 1.0)
+            .place_order(
+                bob.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                Amount::from(50000),
+                Amount::from(1),
+            )
             .unwrap();
 
         // Check that only 1 BTC was traded
-        assert_eq!(exchange.get_balance(&alice, "BTC"), 1.0);
-        assert_eq!(exchange.get_balance(&bob, "USDT"), 50000.0);
+        assert_eq!(exchange.get_balance(&alice, "BTC"), Amount::from(1));
+        assert_eq!(exchange.get_balance(&bob, "USDT"), Amount::from(50000));
 
         // Alice should have a remaining buy order for 1 BTC
         let order_book = exchange.get_order_book(&pair).unwrap();
-        assert_eq!(order_book.buy_orders.len(), 1);
-        assert_eq!(order_book.buy_orders[0].remaining_quantity(), 1.0);
+        assert_eq!(order_book.buy_orders().count(), 1);
+        assert_eq!(
+            order_book.buy_orders().next().unwrap().remaining_quantity(),
+            Amount::from(1)
+        );
+    }
+
+    #[test]
+    fn test_quote_rounding_on_fill_and_cancel() {
+        let mut exchange = Exchange::new("TestExchange");
+
+        let alice = exchange.create_wallet("Alice");
+        let bob = exchange.create_wallet("Bob");
+
+        exchange.deposit(&alice, "USDT", Amount::from(10000)).unwrap();
+        exchange.deposit(&bob, "BTC", Amount::from(1)).unwrap();
+
+        let pair = TradingPair::new("BTC", "USDT");
+        let price = amt("33333.333333");
+
+        // 0.3 * price = 9999.9999999, locked rounded up to USDT's 6 decimals
+        let buy_id = exchange
+            .place_order(alice.clone(), pair.clone(), OrderSide::Buy, price, amt("0.3"))
+            .unwrap();
+        assert_eq!(exchange.get_balance(&alice, "USDT"), Amount::ZERO);
+
+        exchange
+            .place_order(bob.clone(), pair.clone(), OrderSide::Sell, price, amt("0.1"))
+            .unwrap();
+
+        // Seller proceeds round down
+        assert_eq!(exchange.get_balance(&bob, "USDT"), amt("3333.333333"));
+
+        // Buyer is charged the rounded-up cost of the filled part and gets the rest back
+        exchange.cancel_order(&buy_id, &pair).unwrap();
+        assert_eq!(exchange.get_balance(&alice, "USDT"), amt("6666.666666"));
+        assert_eq!(exchange.get_balance(&alice, "BTC"), amt("0.1"));
+    }
+
+    #[test]
+    fn test_rejects_excess_precision() {
+        let mut exchange = Exchange::new("TestExchange");
+        let alice = exchange.create_wallet("Alice");
+
+        assert!(exchange.deposit(&alice, "USDT", amt("1.0000001")).is_err());
+        exchange.deposit(&alice, "USDT", Amount::from(100)).unwrap();
+
+        let pair = TradingPair::new("BTC", "USDT");
+        let result = exchange.place_order(
+            alice,
+            pair,
+            OrderSide::Buy,
+            Amount::from(10),
+            amt("0.000000001"),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_rejects_amounts_above_the_limit() {
+        let mut exchange = Exchange::new("TestExchange");
+        let alice = exchange.create_wallet("Alice");
+        let too_large = Amount::MAX + Amount::from(1);
+
+        assert!(exchange.deposit(&alice, "USDT", too_large).is_err());
+        exchange.deposit(&alice, "USDT", Amount::MAX).unwrap();
+        exchange.deposit(&alice, "USDT", Amount::MAX).unwrap();
+        assert!(exchange.withdraw(&alice, "USDT", too_large).is_err());
+
+        let pair = TradingPair::new("BTC", "USDT");
+        for (price, quantity) in [(too_large, Amount::from(1)), (Amount::from(1), too_large)] {
+            let side = OrderSide::Buy;
+            let result = exchange.place_order(alice.clone(), pair.clone(), side, price, quantity);
+            assert!(result.unwrap_err().contains("limit"));
+        }
+        // The largest order is refused for its funds, not by overflowing
+        let result =
+            exchange.place_order(alice.clone(), pair, OrderSide::Buy, Amount::MAX, Amount::MAX);
+        assert!(result.unwrap_err().contains("Insufficient"));
+        assert_eq!(exchange.get_balance(&alice, "USDT"), Amount::MAX + Amount::MAX);
+    }
+
+    #[test]
+    fn test_operations_fail_when_the_chain_refuses_their_transactions() {
+        let mut exchange = Exchange::new("TestExchange");
+        let alice = exchange.create_wallet("Alice");
+        let bob = exchange.create_wallet("Bob");
+        exchange.deposit(&bob, "BTC", Amount::from(1)).unwrap();
+        // Alice's USDT reaches her wallet but not the chain
+        exchange
+            .wallet_manager
+            .deposit(&alice, "USDT", Amount::from(100000))
+            .unwrap();
+        let pending = exchange.blockchain.mempool.len();
+
+        let err = exchange
+            .withdraw(&alice, "USDT", Amount::from(100))
+            .unwrap_err();
+        assert!(err.contains("Blockchain"));
+        assert_eq!(exchange.get_balance(&alice, "USDT"), Amount::from(100000));
+
+        // Alice's bid is refused before it can match, as the chain could not settle it
+        let pair = TradingPair::new("BTC", "USDT");
+        let (price, quantity) = (Amount::from(30000), Amount::from(1));
+        let ask = exchange
+            .place_order(bob.clone(), pair.clone(), OrderSide::Sell, price, quantity)
+            .unwrap();
+        let err = exchange
+            .place_order(alice.clone(), pair.clone(), OrderSide::Buy, price, quantity)
+            .unwrap_err();
+        assert!(err.contains("Blockchain"));
+        assert_eq!(exchange.blockchain.mempool.len(), pending);
+        assert_eq!(exchange.get_balance(&alice, "USDT"), Amount::from(100000));
+        assert_eq!(exchange.get_locked_balance(&alice, "USDT"), Amount::ZERO);
+        assert_eq!(exchange.get_balance(&alice, "BTC"), Amount::ZERO);
+        assert_eq!(exchange.get_locked_balance(&bob, "BTC"), Amount::from(1));
+        assert_eq!(exchange.get_balance(&bob, "USDT"), Amount::ZERO);
+        let book = exchange.get_order_book(&pair).unwrap();
+        assert_eq!(book.buy_orders().count(), 0);
+        let asks: Vec<&str> = book.sell_orders().map(|o| o.id.as_str()).collect();
+        assert_eq!(asks, [ask.as_str()]);
+
+        // Bob's ask is untouched and can still be cancelled
+        exchange.cancel_order(&ask, &pair).unwrap();
+        assert_eq!(exchange.get_locked_balance(&bob, "BTC"), Amount::ZERO);
+        assert_eq!(exchange.get_balance(&bob, "BTC"), Amount::from(1));
+
+        // Funds the chain carries cannot be withdrawn out from under a hold
+        exchange
+            .place_order(bob.clone(), pair, OrderSide::Sell, price, quantity)
+            .unwrap();
+        exchange.wallet_manager.deposit(&bob, "BTC", Amount::from(1)).unwrap();
+        assert!(exchange.withdraw(&bob, "BTC", Amount::from(1)).is_err());
+        assert_eq!(exchange.get_balance(&bob, "BTC"), Amount::from(1));
+
+        exchange.blockchain.custodian = None;
+        assert!(exchange.deposit(&bob, "BTC", Amount::from(1)).is_err());
+        assert_eq!(exchange.get_balance(&bob, "BTC"), Amount::from(1));
+        assert_eq!(exchange.blockchain.mempool.len(), pending);
+    }
+
+    #[test]
+    fn test_funds_locked_while_order_rests() {
+        let mut exchange = Exchange::new("TestExchange");
+        let alice = exchange.create_wallet("Alice");
+        exchange.deposit(&alice, "USDT", Amount::from(100000)).unwrap();
+
+        let pair = TradingPair::new("BTC", "USDT");
+        let order_id = exchange
+            .place_order(
+                alice.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(40000),
+                Amount::from(2),
+            )
+            .unwrap();
+
+        assert_eq!(exchange.get_balance(&alice, "USDT"), Amount::from(20000));
+        assert_eq!(exchange.get_locked_balance(&alice, "USDT"), Amount::from(80000));
+        assert!(exchange.withdraw(&alice, "USDT", Amount::from(30000)).is_err());
+
+        exchange.cancel_order(&order_id, &pair).unwrap();
+        assert_eq!(exchange.get_balance(&alice, "USDT"), Amount::from(100000));
+        assert_eq!(exchange.get_locked_balance(&alice, "USDT"), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_price_improvement_is_released() {
+        let mut exchange = Exchange::new("TestExchange");
+        let alice = exchange.create_wallet("Alice");
+        let bob = exchange.create_wallet("Bob");
+        exchange.deposit(&alice, "BTC", Amount::from(1)).unwrap();
+        exchange.deposit(&bob, "USDT", Amount::from(100000)).unwrap();
+
+        let pair = TradingPair::new("BTC", "USDT");
+        exchange
+            .place_order(
+                alice.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                Amount::from(49000),
+                Amount::from(1),
+            )
+            .unwrap();
+
+        // Bob bids 50000 for 2 BTC; 1 BTC fills at 49000 and 1 BTC rests at 50000
+        let buy_id = exchange
+            .place_order(
+                bob.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(50000),
+                Amount::from(2),
+            )
+            .unwrap();
+
+        assert_eq!(exchange.get_balance(&bob, "BTC"), Amount::from(1));
+        assert_eq!(exchange.get_balance(&bob, "USDT"), Amount::from(1000));
+        assert_eq!(exchange.get_locked_balance(&bob, "USDT"), Amount::from(50000));
+        assert_eq!(exchange.get_balance(&alice, "USDT"), Amount::from(49000));
+        assert_eq!(exchange.get_locked_balance(&alice, "BTC"), Amount::ZERO);
+
+        exchange.cancel_order(&buy_id, &pair).unwrap();
+        assert_eq!(exchange.get_balance(&bob, "USDT"), Amount::from(51000));
+        assert_eq!(exchange.get_locked_balance(&bob, "USDT"), Amount::ZERO);
+    }
+
+    /// Creates an exchange where a seller offers 1 BTC at each of `prices`
+    fn exchange_with_asks(prices: &[i64]) -> (Exchange, String, String) {
+        let mut exchange = Exchange::new("TestExchange");
+        let seller = exchange.create_wallet("Seller");
+        let buyer = exchange.create_wallet("Buyer");
+        exchange.deposit(&seller, "BTC", Amount::from(10)).unwrap();
+        exchange.deposit(&buyer, "USDT", Amount::from(100000)).unwrap();
+
+        let pair = TradingPair::new("BTC", "USDT");
+        for &price in prices {
+            exchange
+                .place_order(
+                    seller.clone(),
+                    pair.clone(),
+                    OrderSide::Sell,
+                    Amount::from(price),
+                    Amount::from(1),
+                )
+                .unwrap();
+        }
+        (exchange, seller, buyer)
+    }
+
+    #[test]
+    fn test_market_buy_sweeps_book() {
+        let (mut exchange, seller, buyer) = exchange_with_asks(&[100, 101, 102]);
+        let pair = TradingPair::new("BTC", "USDT");
+
+        exchange
+            .place_market_order(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                MarketOrderSize::Base(amt("2.5")),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(exchange.trades.len(), 3);
+        assert_eq!(exchange.get_balance(&buyer, "BTC"), amt("2.5"));
+        assert_eq!(exchange.get_balance(&buyer, "USDT"), Amount::from(99748));
+        assert_eq!(exchange.get_locked_balance(&buyer, "USDT"), Amount::ZERO);
+        assert_eq!(exchange.get_balance(&seller, "USDT"), Amount::from(252));
+
+        let order_book = exchange.get_order_book(&pair).unwrap();
+        assert!(order_book.buy_orders().next().is_none());
+        assert_eq!(order_book.best_ask(), Some(Amount::from(102)));
+    }
+
+    #[test]
+    fn test_market_remainder_never_rests() {
+        let (mut exchange, _, buyer) = exchange_with_asks(&[100]);
+        let pair = TradingPair::new("BTC", "USDT");
+
+        exchange
+            .place_market_order(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                MarketOrderSize::Base(Amount::from(3)),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(exchange.get_balance(&buyer, "BTC"), Amount::from(1));
+        assert_eq!(exchange.get_balance(&buyer, "USDT"), Amount::from(99900));
+        assert_eq!(exchange.get_locked_balance(&buyer, "USDT"), Amount::ZERO);
+        let order_book = exchange.get_order_book(&pair).unwrap();
+        assert!(order_book.buy_orders().next().is_none());
+        assert!(order_book.sell_orders().next().is_none());
+    }
+
+    #[test]
+    fn test_market_buy_with_quote_budget() {
+        let (mut exchange, _, buyer) = exchange_with_asks(&[100, 104]);
+        let pair = TradingPair::new("BTC", "USDT");
+
+        exchange
+            .place_market_order(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                MarketOrderSize::Quote(Amount::from(152)),
+                None,
+            )
+            .unwrap();
+
+        // 1 BTC at 100, then 52 USDT buys 0.5 BTC at 104
+        assert_eq!(exchange.get_balance(&buyer, "BTC"), amt("1.5"));
+        assert_eq!(exchange.get_balance(&buyer, "USDT"), Amount::from(99848));
+        assert_eq!(exchange.get_locked_balance(&buyer, "USDT"), Amount::ZERO);
+        assert!(exchange.get_order_book(&pair).unwrap().buy_orders().next().is_none());
+    }
+
+    #[test]
+    fn test_market_slippage_guard() {
+        let (mut exchange, _, buyer) = exchange_with_asks(&[100, 101, 120]);
+        let pair = TradingPair::new("BTC", "USDT");
+
+        // 5% above the best ask of 100 allows 105, so the 120 level is untouched
+        exchange
+            .place_market_order(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                MarketOrderSize::Base(Amount::from(3)),
+                Some(amt("0.05")),
+            )
+            .unwrap();
+
+        assert_eq!(exchange.get_balance(&buyer, "BTC"), Amount::from(2));
+        assert_eq!(exchange.get_balance(&buyer, "USDT"), Amount::from(99799));
+        let order_book = exchange.get_order_book(&pair).unwrap();
+        assert_eq!(order_book.best_ask(), Some(Amount::from(120)));
+        assert!(order_book.buy_orders().next().is_none());
+    }
+
+    #[test]
+    fn test_market_order_rejections() {
+        let mut exchange = Exchange::new("TestExchange");
+        let alice = exchange.create_wallet("Alice");
+        exchange.deposit(&alice, "BTC", Amount::from(1)).unwrap();
+        let pair = TradingPair::new("BTC", "USDT");
+
+        let no_liquidity = exchange.place_market_order(
+            alice.clone(),
+            pair.clone(),
+            OrderSide::Sell,
+            MarketOrderSize::Base(Amount::from(1)),
+            None,
+        );
+        assert!(no_liquidity.is_err());
+
+        let (mut exchange, seller, _) = exchange_with_asks(&[100]);
+        let quote_sell = exchange.place_market_order(
+            seller,
+            pair,
+            OrderSide::Sell,
+            MarketOrderSize::Quote(Amount::from(100)),
+            None,
+        );
+        assert!(quote_sell.is_err());
+    }
+
+    fn with_tif(time_in_force: TimeInForce) -> OrderOptions {
+        OrderOptions {
+            time_in_force,
+            ..OrderOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_immediate_or_cancel_remainder_is_refunded() {
+        let (mut exchange, _, buyer) = exchange_with_asks(&[100]);
+        let pair = TradingPair::new("BTC", "USDT");
+
+        exchange
+            .place_order_with_options(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(100),
+                Amount::from(3),
+                with_tif(TimeInForce::ImmediateOrCancel),
+            )
+            .unwrap();
+
+        assert_eq!(exchange.get_balance(&buyer, "BTC"), Amount::from(1));
+        assert_eq!(exchange.get_balance(&buyer, "USDT"), Amount::from(99900));
+        assert_eq!(exchange.get_locked_balance(&buyer, "USDT"), Amount::ZERO);
+        assert!(exchange.get_order_book(&pair).unwrap().buy_orders().next().is_none());
+    }
+
+    #[test]
+    fn test_fill_or_kill() {
+        let (mut exchange, _, buyer) = exchange_with_asks(&[100, 101]);
+        let pair = TradingPair::new("BTC", "USDT");
+
+        // Only 1 BTC is offered at or below 100, so nothing trades
+        let killed = exchange.place_order_with_options(
+            buyer.clone(),
+            pair.clone(),
+            OrderSide::Buy,
+            Amount::from(100),
+            Amount::from(2),
+            with_tif(TimeInForce::FillOrKill),
+        );
+        assert!(killed.is_err());
+        assert!(exchange.trades.is_empty());
+        assert_eq!(exchange.get_balance(&buyer, "USDT"), Amount::from(100000));
+
+        exchange
+            .place_order_with_options(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(101),
+                Amount::from(2),
+                with_tif(TimeInForce::FillOrKill),
+            )
+            .unwrap();
+        assert_eq!(exchange.get_balance(&buyer, "BTC"), Amount::from(2));
+        assert_eq!(exchange.get_balance(&buyer, "USDT"), Amount::from(99799));
+    }
+
+    #[test]
+    fn test_post_only() {
+        let (mut exchange, _, buyer) = exchange_with_asks(&[100]);
+        let pair = TradingPair::new("BTC", "USDT");
+
+        let rejected = exchange.place_order_with_options(
+            buyer.clone(),
+            pair.clone(),
+            OrderSide::Buy,
+            Amount::from(100),
+            Amount::from(1),
+            with_tif(TimeInForce::PostOnly(PostOnlyAction::Reject)),
+        );
+        assert!(rejected.is_err());
+
+        exchange
+            .place_order_with_options(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(105),
+                Amount::from(1),
+                with_tif(TimeInForce::PostOnly(PostOnlyAction::Reprice)),
+            )
+            .unwrap();
+
+        // Repriced one USDT tick below the best ask instead of matching
+        assert!(exchange.trades.is_empty());
+        let order_book = exchange.get_order_book(&pair).unwrap();
+        assert_eq!(order_book.best_bid(), Some(amt("99.999999")));
+        assert_eq!(exchange.get_locked_balance(&buyer, "USDT"), amt("99.999999"));
+    }
+
+    #[test]
+    fn test_good_till_date_expiry() {
+        let mut exchange = Exchange::new("TestExchange");
+        let alice = exchange.create_wallet("Alice");
+        exchange.deposit(&alice, "BTC", Amount::from(1)).unwrap();
+        let pair = TradingPair::new("BTC", "USDT");
+        let expires_at = Utc::now().timestamp() + 3600;
+
+        let past = exchange.place_order_with_options(
+            alice.clone(),
+            pair.clone(),
+            OrderSide::Sell,
+            Amount::from(100),
+            Amount::from(1),
+            with_tif(TimeInForce::GoodTillDate(expires_at - 7200)),
+        );
+        assert!(past.is_err());
+
+        let order_id = exchange
+            .place_order_with_options(
+                alice.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                Amount::from(100),
+                Amount::from(1),
+                with_tif(TimeInForce::GoodTillDate(expires_at)),
+            )
+            .unwrap();
+        assert_eq!(exchange.get_locked_balance(&alice, "BTC"), Amount::from(1));
+        // Repricing puts the order back on the book under the same expiry
+        exchange
+            .amend_order(&order_id, &pair, Amount::from(101), Amount::from(1))
+            .unwrap();
+
+        assert!(exchange.expire_orders(expires_at - 1).unwrap().is_empty());
+        assert_eq!(exchange.expire_orders(expires_at).unwrap(), vec![order_id]);
+        assert_eq!(exchange.get_balance(&alice, "BTC"), Amount::from(1));
+        assert_eq!(exchange.get_locked_balance(&alice, "BTC"), Amount::ZERO);
+        assert!(exchange.get_order_book(&pair).unwrap().sell_orders().next().is_none());
+
+        // Cancelled orders are skipped when their expiry comes, and a restored
+        // exchange still expires the orders it was given
+        let gtd = |exchange: &mut Exchange, expires_at| {
+            exchange.place_order_with_options(
+                alice.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                Amount::new(1, 1),
+                Amount::new(1, 1),
+                with_tif(TimeInForce::GoodTillDate(expires_at)),
+            )
+        };
+        let cancelled = gtd(&mut exchange, expires_at + 10).unwrap();
+        exchange.cancel_order(&cancelled, &pair).unwrap();
+        let restored_id = gtd(&mut exchange, expires_at + 20).unwrap();
+        let clock = Box::new(SystemClock);
+        let mut restored =
+            Exchange::from_snapshot(exchange.snapshot(), clock, Box::new(RandomIds)).unwrap();
+        assert!(restored.expire_orders(expires_at + 10).unwrap().is_empty());
+        assert_eq!(restored.expire_orders(expires_at + 20).unwrap(), vec![restored_id]);
+    }
+
+    /// Trades 1 BTC between `buyer` and `seller` at `price`
+    fn trade_at(exchange: &mut Exchange, buyer: &str, seller: &str, price: i64) {
+        let pair = TradingPair::new("BTC", "USDT");
+        for (user, side) in [(seller, OrderSide::Sell), (buyer, OrderSide::Buy)] {
+            exchange
+                .place_order(
+                    user.to_string(),
+                    pair.clone(),
+                    side,
+                    Amount::from(price),
+                    Amount::from(1),
+                )
+                .unwrap();
+        }
+    }
+
+    /// Creates an exchange with a funded buyer and seller, plus Alice holding 1 BTC
+    fn exchange_for_stops() -> (Exchange, String, String, String) {
+        let mut exchange = Exchange::new("TestExchange");
+        let buyer = exchange.create_wallet("Buyer");
+        let seller = exchange.create_wallet("Seller");
+        let alice = exchange.create_wallet("Alice");
+        exchange.deposit(&buyer, "USDT", Amount::from(100000)).unwrap();
+        exchange.deposit(&seller, "BTC", Amount::from(10)).unwrap();
+        exchange.deposit(&alice, "BTC", Amount::from(1)).unwrap();
+        (exchange, buyer, seller, alice)
+    }
+
+    #[test]
+    fn test_stop_loss_triggers_market_sell() {
+        let (mut exchange, buyer, seller, alice) = exchange_for_stops();
+        exchange.max_slippage = amt("0.2");
+        let pair = TradingPair::new("BTC", "USDT");
+
+        let stop_id = exchange
+            .place_stop_order(
+                alice.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                StopOrderKind::StopLoss {
+                    stop_price: Amount::from(95),
+                },
+                Amount::from(1),
+            )
+            .unwrap();
+        assert_eq!(exchange.stop_orders[&pair.symbol()][0].status, OrderStatus::Pending);
+        assert_eq!(exchange.get_locked_balance(&alice, "BTC"), Amount::from(1));
+
+        // A bid for 2 BTC at 90 waits below the stop
+        exchange
+            .place_order(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(90),
+                Amount::from(2),
+            )
+            .unwrap();
+
+        trade_at(&mut exchange, &buyer, &seller, 100);
+        assert_eq!(exchange.stop_orders[&pair.symbol()].len(), 1);
+
+        // Selling into the bid at 90 crosses the stop; Alice then sells into the rest
+        exchange
+            .place_order(
+                seller.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                Amount::from(90),
+                Amount::from(1),
+            )
+            .unwrap();
+
+        assert!(exchange.stop_orders[&pair.symbol()].is_empty());
+        let last = exchange.trades.last().unwrap();
+        assert_eq!(last.sell_order_id, stop_id);
+        assert_eq!(last.price, Amount::from(90));
+        assert_eq!(exchange.get_balance(&alice, "USDT"), Amount::from(90));
+        assert_eq!(exchange.get_locked_balance(&alice, "BTC"), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_stop_limit_rests_as_triggered() {
+        let (mut exchange, buyer, seller, _) = exchange_for_stops();
+        let pair = TradingPair::new("BTC", "USDT");
+        let bob = exchange.create_wallet("Bob");
+        exchange.deposit(&bob, "USDT", Amount::from(1000)).unwrap();
+
+        let stop_id = exchange
+            .place_stop_order(
+                bob.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                StopOrderKind::StopLimit {
+                    stop_price: Amount::from(100),
+                    limit_price: Amount::from(105),
+                },
+                Amount::from(1),
+            )
+            .unwrap();
+        assert_eq!(exchange.get_locked_balance(&bob, "USDT"), Amount::from(105));
+
+        exchange
+            .place_order(
+                seller.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                Amount::from(110),
+                Amount::from(1),
+            )
+            .unwrap();
+        trade_at(&mut exchange, &buyer, &seller, 100);
+
+        // The limit at 105 cannot reach the ask at 110, so it rests
+        let order_book = exchange.get_order_book(&pair).unwrap();
+        let resting = order_book.buy_orders().next().unwrap();
+        assert_eq!(resting.id, stop_id);
+        assert_eq!(resting.status, OrderStatus::Triggered);
+        assert_eq!(order_book.best_bid(), Some(Amount::from(105)));
+        assert_eq!(exchange.get_locked_balance(&bob, "USDT"), Amount::from(105));
+    }
+
+    #[test]
+    fn test_trailing_stop_follows_best_price() {
+        let (mut exchange, buyer, seller, alice) = exchange_for_stops();
+        exchange.max_slippage = amt("0.6");
+        let pair = TradingPair::new("BTC", "USDT");
+
+        trade_at(&mut exchange, &buyer, &seller, 100);
+        exchange
+            .place_stop_order(
+                alice.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                StopOrderKind::TrailingStop {
+                    offset: Amount::from(10),
+                },
+                Amount::from(1),
+            )
+            .unwrap();
+        assert_eq!(
+            exchange.stop_orders[&pair.symbol()][0].stop_price(),
+            Some(Amount::from(90))
+        );
+
+        // Resting bid for the stop to sell into
+        exchange
+            .place_order(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(50),
+                Amount::from(1),
+            )
+            .unwrap();
+
+        trade_at(&mut exchange, &buyer, &seller, 120);
+        trade_at(&mut exchange, &buyer, &seller, 115);
+        assert_eq!(
+            exchange.stop_orders[&pair.symbol()][0].stop_price(),
+            Some(Amount::from(110))
+        );
+
+        trade_at(&mut exchange, &buyer, &seller, 110);
+        assert!(exchange.stop_orders[&pair.symbol()].is_empty());
+        assert_eq!(exchange.get_balance(&alice, "USDT"), Amount::from(50));
+    }
+
+    #[test]
+    fn test_stop_order_cancel_and_rejection() {
+        let (mut exchange, buyer, seller, alice) = exchange_for_stops();
+        let pair = TradingPair::new("BTC", "USDT");
+        trade_at(&mut exchange, &buyer, &seller, 100);
+
+        let immediate = exchange.place_stop_order(
+            alice.clone(),
+            pair.clone(),
+            OrderSide::Sell,
+            StopOrderKind::StopLoss {
+                stop_price: Amount::from(100),
+            },
+            Amount::from(1),
+        );
+        assert!(immediate.is_err());
+
+        let stop_id = exchange
+            .place_stop_order(
+                alice.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                StopOrderKind::StopLoss {
+                    stop_price: Amount::from(80),
+                },
+                Amount::from(1),
+            )
+            .unwrap();
+        exchange.cancel_order(&stop_id, &pair).unwrap();
+        assert_eq!(exchange.get_balance(&alice, "BTC"), Amount::from(1));
+        assert_eq!(exchange.get_locked_balance(&alice, "BTC"), Amount::ZERO);
+        assert!(exchange.stop_orders[&pair.symbol()].is_empty());
+    }
+
+    /// Places a 1 BTC ask at 100 for each seller and returns their order ids
+    fn place_asks(exchange: &mut Exchange, sellers: &[&String]) -> Vec<String> {
+        let pair = TradingPair::new("BTC", "USDT");
+        sellers
+            .iter()
+            .map(|seller| {
+                exchange.deposit(seller, "BTC", Amount::from(2)).unwrap();
+                exchange
+                    .place_order(
+                        seller.to_string(),
+                        pair.clone(),
+                        OrderSide::Sell,
+                        Amount::from(100),
+                        Amount::from(1),
+                    )
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn buy_one_at(exchange: &mut Exchange, buyer: &str, price: i64) {
+        exchange
+            .place_order(
+                buyer.to_string(),
+                TradingPair::new("BTC", "USDT"),
+                OrderSide::Buy,
+                Amount::from(price),
+                Amount::new(5, 1),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_amend_quantity_decrease_keeps_priority() {
+        let mut exchange = Exchange::new("TestExchange");
+        let first = exchange.create_wallet("First");
+        let second = exchange.create_wallet("Second");
+        let buyer = exchange.create_wallet("Buyer");
+        exchange.deposit(&buyer, "USDT", Amount::from(1000)).unwrap();
+        let pair = TradingPair::new("BTC", "USDT");
+        let ids = place_asks(&mut exchange, &[&first, &second]);
+
+        exchange
+            .amend_order(&ids[0], &pair, Amount::from(100), amt("0.6"))
+            .unwrap();
+        assert_eq!(exchange.get_locked_balance(&first, "BTC"), amt("0.6"));
+        assert_eq!(exchange.get_balance(&first, "BTC"), amt("1.4"));
+        let level = exchange.get_order_book(&pair).unwrap().ask_levels().next().unwrap().1;
+        assert_eq!(level.total_quantity, amt("1.6"));
+
+        // The first seller is still at the front of the queue
+        buy_one_at(&mut exchange, &buyer, 100);
+        assert_eq!(exchange.get_balance(&first, "USDT"), Amount::from(50));
+        assert_eq!(exchange.get_balance(&second, "USDT"), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_amend_quantity_increase_loses_priority() {
+        let mut exchange = Exchange::new("TestExchange");
+        let first = exchange.create_wallet("First");
+        let second = exchange.create_wallet("Second");
+        let buyer = exchange.create_wallet("Buyer");
+        exchange.deposit(&buyer, "USDT", Amount::from(1000)).unwrap();
+        let pair = TradingPair::new("BTC", "USDT");
+        let ids = place_asks(&mut exchange, &[&first, &second]);
+
+        exchange
+            .amend_order(&ids[0], &pair, Amount::from(100), Amount::from(2))
+            .unwrap();
+        assert_eq!(exchange.get_locked_balance(&first, "BTC"), Amount::from(2));
+        assert_eq!(exchange.get_balance(&first, "BTC"), Amount::ZERO);
+
+        // The second seller now fills first
+        buy_one_at(&mut exchange, &buyer, 100);
+        assert_eq!(exchange.get_balance(&first, "USDT"), Amount::ZERO);
+        assert_eq!(exchange.get_balance(&second, "USDT"), Amount::from(50));
+    }
+
+    #[test]
+    fn test_amend_price_rematches_and_reholds() {
+        let mut exchange = Exchange::new("TestExchange");
+        let seller = exchange.create_wallet("Seller");
+        let buyer = exchange.create_wallet("Buyer");
+        exchange.deposit(&buyer, "USDT", Amount::from(1000)).unwrap();
+        let pair = TradingPair::new("BTC", "USDT");
+        place_asks(&mut exchange, &[&seller]);
+
+        let bid_id = exchange
+            .place_order(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(90),
+                Amount::from(2),
+            )
+            .unwrap();
+        assert_eq!(exchange.get_locked_balance(&buyer, "USDT"), Amount::from(180));
+
+        // Raising the bid to 110 crosses the ask at 100; the rest rests at 110
+        exchange
+            .amend_order(&bid_id, &pair, Amount::from(110), Amount::from(2))
+            .unwrap();
+        assert_eq!(exchange.get_balance(&buyer, "BTC"), Amount::from(1));
+        assert_eq!(exchange.get_locked_balance(&buyer, "USDT"), Amount::from(110));
+        assert_eq!(exchange.get_balance(&buyer, "USDT"), Amount::from(790));
+        let book = exchange.get_order_book(&pair).unwrap();
+        assert_eq!(book.best_bid(), Some(Amount::from(110)));
+        assert_eq!(book.get_order(&bid_id).unwrap().remaining_quantity(), Amount::from(1));
+
+        // Lowering the price releases the difference
+        exchange
+            .amend_order(&bid_id, &pair, Amount::from(80), Amount::from(2))
+            .unwrap();
+        assert_eq!(exchange.get_locked_balance(&buyer, "USDT"), Amount::from(80));
+        assert_eq!(exchange.get_balance(&buyer, "USDT"), Amount::from(820));
+    }
+
+    #[test]
+    fn test_amend_rejections() {
+        let mut exchange = Exchange::new("TestExchange");
+        let seller = exchange.create_wallet("Seller");
+        let buyer = exchange.create_wallet("Buyer");
+        exchange.deposit(&buyer, "USDT", Amount::from(1000)).unwrap();
+        let pair = TradingPair::new("BTC", "USDT");
+        let ids = place_asks(&mut exchange, &[&seller, &seller]);
+
+        // Not enough BTC for the larger order; nothing changes
+        let result = exchange.amend_order(&ids[0], &pair, Amount::from(100), Amount::from(4));
+        assert!(result.is_err());
+        assert_eq!(exchange.get_locked_balance(&seller, "BTC"), Amount::from(2));
+        let book = exchange.get_order_book(&pair).unwrap();
+        assert_eq!(book.get_order(&ids[0]).unwrap().quantity, Amount::from(1));
+
+        // Cannot amend below what has already filled
+        buy_one_at(&mut exchange, &buyer, 100);
+        let result = exchange.amend_order(&ids[0], &pair, Amount::from(100), amt("0.5"));
+        assert!(result.is_err());
+
+        // Filled and cancelled orders cannot be amended
+        buy_one_at(&mut exchange, &buyer, 100);
+        let result = exchange.amend_order(&ids[0], &pair, Amount::from(100), Amount::from(2));
+        assert!(result.is_err());
+        exchange.cancel_order(&ids[1], &pair).unwrap();
+        let result = exchange.amend_order(&ids[1], &pair, Amount::from(100), Amount::from(2));
+        assert!(result.is_err());
+        assert_eq!(exchange.get_balance(&seller, "BTC"), Amount::from(3));
+        assert_eq!(exchange.get_locked_balance(&seller, "BTC"), Amount::ZERO);
+    }
+
+    fn with_stp(mode: SelfTradePrevention) -> OrderOptions {
+        OrderOptions {
+            self_trade_prevention: Some(mode),
+            ..OrderOptions::default()
+        }
+    }
+
+    /// Creates an exchange where `trader` rests a 3 BTC ask at 100 and another
+    /// seller a 1 BTC ask at 101
+    fn exchange_for_self_trades() -> (Exchange, String, String, String) {
+        let mut exchange = Exchange::new("TestExchange");
+        let trader = exchange.create_wallet("Trader");
+        let other = exchange.create_wallet("Other");
+        exchange.deposit(&trader, "BTC", Amount::from(3)).unwrap();
+        exchange.deposit(&trader, "USDT", Amount::from(1000)).unwrap();
+        exchange.deposit(&other, "BTC", Amount::from(1)).unwrap();
+
+        let pair = TradingPair::new("BTC", "USDT");
+        let ask_id = exchange
+            .place_order(
+                trader.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                Amount::from(100),
+                Amount::from(3),
+            )
+            .unwrap();
+        exchange
+            .place_order(
+                other.clone(),
+                pair,
+                OrderSide::Sell,
+                Amount::from(101),
+                Amount::from(1),
+            )
+            .unwrap();
+        (exchange, trader, other, ask_id)
+    }
+
+    fn self_bid(exchange: &mut Exchange, trader: &str, options: OrderOptions) -> String {
+        exchange
+            .place_order_with_options(
+                trader.to_string(),
+                TradingPair::new("BTC", "USDT"),
+                OrderSide::Buy,
+                Amount::from(101),
+                Amount::from(4),
+                options,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_self_trade_cancel_newest_by_default() {
+        let (mut exchange, trader, _, ask_id) = exchange_for_self_trades();
+        let pair = TradingPair::new("BTC", "USDT");
+
+        let bid_id = self_bid(&mut exchange, &trader, OrderOptions::default());
+        assert!(exchange.trades.is_empty());
+        assert_eq!(exchange.prevented_quantity(&bid_id), Amount::from(3));
+        assert_eq!(exchange.prevented_quantity(&ask_id), Amount::from(3));
+        assert_eq!(exchange.prevented_self_trades[0].mode, SelfTradePrevention::CancelNewest);
+
+        // The bid is cancelled and refunded; the resting ask is untouched
+        let book = exchange.get_order_book(&pair).unwrap();
+        assert!(book.get_order(&bid_id).is_none());
+        assert_eq!(book.get_order(&ask_id).unwrap().remaining_quantity(), Amount::from(3));
+        assert_eq!(exchange.get_balance(&trader, "USDT"), Amount::from(1000));
+        assert_eq!(exchange.get_locked_balance(&trader, "USDT"), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_self_trade_cancel_oldest_per_account() {
+        let (mut exchange, trader, other, ask_id) = exchange_for_self_trades();
+        let pair = TradingPair::new("BTC", "USDT");
+        exchange.set_self_trade_prevention(&trader, SelfTradePrevention::CancelOldest);
+
+        // The trader's ask is cancelled and the bid trades with the other seller
+        let bid_id = self_bid(&mut exchange, &trader, OrderOptions::default());
+        assert_eq!(exchange.prevented_quantity(&bid_id), Amount::from(3));
+        assert_eq!(exchange.trades.len(), 1);
+        assert_eq!(exchange.get_balance(&other, "USDT"), Amount::from(101));
+        assert_eq!(exchange.get_balance(&trader, "BTC"), Amount::from(4));
+        assert_eq!(exchange.get_locked_balance(&trader, "BTC"), Amount::ZERO);
+
+        let book = exchange.get_order_book(&pair).unwrap();
+        assert!(book.get_order(&ask_id).is_none());
+        assert_eq!(book.get_order(&bid_id).unwrap().remaining_quantity(), Amount::from(3));
+        assert_eq!(exchange.get_locked_balance(&trader, "USDT"), Amount::from(303));
+    }
+
+    #[test]
+    fn test_self_trade_cancel_both_per_order() {
+        let (mut exchange, trader, _, ask_id) = exchange_for_self_trades();
+        let pair = TradingPair::new("BTC", "USDT");
+        exchange.set_self_trade_prevention(&trader, SelfTradePrevention::CancelOldest);
+
+        // The order's own mode overrides the account's
+        let bid_id = self_bid(&mut exchange, &trader, with_stp(SelfTradePrevention::CancelBoth));
+        assert!(exchange.trades.is_empty());
+        assert_eq!(exchange.prevented_quantity(&bid_id), Amount::from(3));
+
+        let book = exchange.get_order_book(&pair).unwrap();
+        assert!(book.get_order(&ask_id).is_none());
+        assert!(book.get_order(&bid_id).is_none());
+        assert_eq!(book.best_ask(), Some(Amount::from(101)));
+        assert_eq!(exchange.get_balance(&trader, "BTC"), Amount::from(3));
+        assert_eq!(exchange.get_balance(&trader, "USDT"), Amount::from(1000));
+    }
+
+    #[test]
+    fn test_self_trade_decrement_and_cancel() {
+        let (mut exchange, trader, _, ask_id) = exchange_for_self_trades();
+        let pair = TradingPair::new("BTC", "USDT");
+        let options = with_stp(SelfTradePrevention::DecrementAndCancel);
+
+        // A 1 BTC bid is used up against the 3 BTC ask, which keeps 2 BTC
+        let bid_id = exchange
+            .place_order_with_options(
+                trader.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(100),
+                Amount::from(1),
+                options,
+            )
+            .unwrap();
+        assert!(exchange.trades.is_empty());
+        assert_eq!(exchange.prevented_quantity(&bid_id), Amount::from(1));
+
+        let book = exchange.get_order_book(&pair).unwrap();
+        assert!(book.get_order(&bid_id).is_none());
+        assert_eq!(book.get_order(&ask_id).unwrap().remaining_quantity(), Amount::from(2));
+        assert_eq!(book.ask_levels().next().unwrap().1.total_quantity, Amount::from(2));
+        assert_eq!(exchange.get_locked_balance(&trader, "BTC"), Amount::from(2));
+        assert_eq!(exchange.get_balance(&trader, "BTC"), Amount::from(1));
+
+        // A 4 BTC bid cancels the 2 BTC left and buys from the other seller
+        let bid_id = self_bid(&mut exchange, &trader, options);
+        assert_eq!(exchange.prevented_quantity(&bid_id), Amount::from(2));
+        assert_eq!(exchange.trades.len(), 1);
+        let book = exchange.get_order_book(&pair).unwrap();
+        assert!(book.get_order(&ask_id).is_none());
+        assert_eq!(book.get_order(&bid_id).unwrap().remaining_quantity(), Amount::from(1));
+        assert_eq!(exchange.get_balance(&trader, "BTC"), Amount::from(4));
+        assert_eq!(exchange.get_locked_balance(&trader, "USDT"), Amount::from(101));
+    }
+
+    /// Creates an exchange charging 0.1% maker and 0.2% taker on BTC/USDT
+    fn exchange_with_fees() -> (Exchange, String, String) {
+        let mut exchange = Exchange::new("TestExchange");
+        let pair = TradingPair::new("BTC", "USDT");
+        exchange
+            .fee_schedule
+            .set_pair_rates(&pair, FeeRates::new(amt("0.001"), amt("0.002")));
+        let alice = exchange.create_wallet("Alice");
+        let bob = exchange.create_wallet("Bob");
+        exchange.deposit(&alice, "USDT", Amount::from(200000)).unwrap();
+        exchange.deposit(&bob, "BTC", Amount::from(4)).unwrap();
+        (exchange, alice, bob)
+    }
+
+    /// Alice rests a bid for 1 BTC at 50000 and Bob sells into it
+    fn maker_bid_taker_ask(exchange: &mut Exchange, alice: &str, bob: &str) {
+        let pair = TradingPair::new("BTC", "USDT");
+        for (user, side) in [(alice, OrderSide::Buy), (bob, OrderSide::Sell)] {
+            exchange
+                .place_order(
+                    user.to_string(),
+                    pair.clone(),
+                    side,
+                    Amount::from(50000),
+                    Amount::from(1),
+                )
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_maker_taker_fees() {
+        let (mut exchange, alice, bob) = exchange_with_fees();
+        maker_bid_taker_ask(&mut exchange, &alice, &bob);
+
+        // Alice made liquidity and pays 0.1% of the BTC she receives;
+        // Bob took it and pays 0.2% of the USDT he receives
+        let trade = &exchange.trades[0];
+        assert_eq!(trade.taker_side, OrderSide::Sell);
+        assert_eq!(trade.buyer_fee, amt("0.001"));
+        assert_eq!(trade.seller_fee, Amount::from(100));
+        assert_eq!(exchange.get_balance(&alice, "BTC"), amt("0.999"));
+        assert_eq!(exchange.get_balance(&bob, "USDT"), Amount::from(49900));
+
+        let fee_address = exchange.wallet_manager.fee_address().to_string();
+        assert_eq!(exchange.get_balance(&fee_address, "BTC"), amt("0.001"));
+        assert_eq!(exchange.get_balance(&fee_address, "USDT"), Amount::from(100));
+
+        // The BTC leg carries the buyer's fee
+        let tx = exchange
+            .blockchain
+            .mempool
+            .transactions()
+            .iter()
+            .find(|tx| tx.from_address == bob && tx.currency == "BTC")
+            .unwrap();
+        assert_eq!(tx.to_address, alice);
+        assert_eq!(tx.amount, Amount::from(1));
+        assert_eq!(tx.fee, amt("0.001"));
+        assert!(tx.verify().is_ok());
+    }
+
+    /// Asserts that the mined chain holds what the wallets hold
+    fn assert_chain_matches_wallets(exchange: &Exchange, addresses: &[&str]) {
+        for address in addresses {
+            let wallet = exchange.wallet_manager.get_wallet(address).unwrap();
+            for currency in ["BTC", "USDT"] {
+                assert_eq!(
+                    exchange.blockchain.get_balance(address, currency),
+                    wallet.balance(currency).total(),
+                    "{} of {}",
+                    currency,
+                    wallet.owner
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_chain_balances_match_wallets() {
+        let (mut exchange, alice, bob) = exchange_with_fees();
+        let pair = TradingPair::new("BTC", "USDT");
+        let price = amt("33333.333333");
+
+        // The buyer pays 0.000001 USDT more than the seller receives
+        exchange
+            .place_order(alice.clone(), pair.clone(), OrderSide::Buy, price, amt("0.3"))
+            .unwrap();
+        exchange
+            .place_order(bob.clone(), pair.clone(), OrderSide::Sell, price, amt("0.1"))
+            .unwrap();
+        let trade = &exchange.trades[0];
+        assert_ne!(trade.buyer_quote, trade.seller_quote);
+        exchange.withdraw(&bob, "USDT", Amount::from(1000)).unwrap();
+        exchange.withdraw(&alice, "BTC", amt("0.05")).unwrap();
+
+        let fee_address = exchange.wallet_manager.fee_address().to_string();
+        exchange.mine_transactions(&fee_address);
+        assert!(exchange.blockchain.mempool.is_empty());
+        assert!(exchange.blockchain.is_valid());
+        // Alice's open order keeps funds locked; the chain counts them as hers
+        let locked = exchange.wallet_manager.get_wallet(&alice).unwrap();
+        assert!(locked.get_locked_balance("USDT").is_positive());
+        assert_chain_matches_wallets(&exchange, &[&alice, &bob, &fee_address]);
+    }
+
+    #[test]
+    fn test_fee_volume_tiers() {
+        let (mut exchange, alice, bob) = exchange_with_fees();
+        exchange
+            .fee_schedule
+            .add_tier(FeeTier {
+                min_volume: Amount::from(50000),
+                discount: amt("0.5"),
+            })
+            .unwrap();
+
+        maker_bid_taker_ask(&mut exchange, &alice, &bob);
+        assert_eq!(exchange.trading_volume(&alice, "USDT"), Amount::from(50000));
+        assert_eq!(exchange.trading_volume(&bob, "USDT"), Amount::from(50000));
+        assert_eq!(exchange.trading_volume(&bob, "BTC"), Amount::ZERO);
+
+        // Both accounts have reached the tier, so the second trade costs half
+        maker_bid_taker_ask(&mut exchange, &alice, &bob);
+        let trade = &exchange.trades[1];
+        assert_eq!(trade.buyer_fee, amt("0.0005"));
+        assert_eq!(trade.seller_fee, Amount::from(50));
+        assert_eq!(exchange.get_balance(&alice, "BTC"), amt("1.9985"));
+        assert_eq!(exchange.get_balance(&bob, "USDT"), Amount::from(99850));
+    }
+
+    #[test]
+    fn test_depth_snapshot() {
+        let (mut exchange, seller, _) = exchange_with_asks(&[100, 101, 102]);
+        let pair = TradingPair::new("BTC", "USDT");
+        exchange
+            .place_order(
+                seller.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                Amount::from(100),
+                amt("0.5"),
+            )
+            .unwrap();
+
+        let depth = exchange.depth(&pair, 2).unwrap();
+        assert!(depth.bids.is_empty());
+        assert_eq!(depth.asks.len(), 2);
+        assert_eq!(depth.asks[0].price, Amount::from(100));
+        assert_eq!(depth.asks[0].quantity, amt("1.5"));
+        assert_eq!(depth.asks[0].order_count, 2);
+        assert_eq!(depth.asks[1].price, Amount::from(101));
+        assert_eq!(depth.sequence, 4);
+        assert!(exchange.depth(&TradingPair::new("XRP", "USDT"), 2).is_none());
+    }
+
+    #[test]
+    fn test_level_updates_keep_replica_in_step() {
+        let (mut exchange, seller, buyer) = exchange_with_asks(&[100, 101]);
+        let pair = TradingPair::new("BTC", "USDT");
+        let mut replica = DepthReplica::from_snapshot(&exchange.depth(&pair, usize::MAX).unwrap());
+        exchange.take_level_updates(&pair);
+
+        // Sweep the 100 level, partly fill 101, rest a bid, then cancel and amend
+        let bid_id = exchange
+            .place_order(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(101),
+                amt("1.5"),
+            )
+            .unwrap();
+        let bid_id_2 = exchange
+            .place_order(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(99),
+                Amount::from(2),
+            )
+            .unwrap();
+        let ask_id = exchange
+            .place_order(
+                seller.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                Amount::from(103),
+                Amount::from(1),
+            )
+            .unwrap();
+        exchange.cancel_order(&ask_id, &pair).unwrap();
+        exchange
+            .amend_order(&bid_id_2, &pair, Amount::from(99), Amount::from(1))
+            .unwrap();
+        assert!(exchange.get_order_book(&pair).unwrap().get_order(&bid_id).is_none());
+
+        let updates = exchange.take_level_updates(&pair);
+        let actions: Vec<LevelAction> = updates.iter().map(|u| u.action).collect();
+        assert_eq!(
+            actions,
+            vec![
+                LevelAction::Delete, // 100 ask filled
+                LevelAction::Update, // 101 ask half filled
+                LevelAction::Insert, // 99 bid
+                LevelAction::Insert, // 103 ask
+                LevelAction::Delete, // 103 ask cancelled
+                LevelAction::Update, // 99 bid reduced
+            ]
+        );
+        for update in &updates {
+            replica.apply(update).unwrap();
+        }
+
+        let depth = exchange.depth(&pair, usize::MAX).unwrap();
+        assert_eq!(replica.sequence(), depth.sequence);
+        assert_eq!(replica.bids(), depth.bids);
+        assert_eq!(replica.asks(), depth.asks);
+        assert_eq!(depth.asks[0].quantity, amt("0.5"));
+        assert_eq!(depth.bids[0].quantity, Amount::from(1));
+    }
+
+    #[test]
+    fn test_candles_and_ticker_follow_trades() {
+        let (mut exchange, _, buyer) = exchange_with_asks(&[100, 101]);
+        let pair = TradingPair::new("BTC", "USDT");
+        assert!(exchange.ticker(&pair).is_none());
+
+        exchange
+            .place_market_order(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                MarketOrderSize::Base(Amount::from(2)),
+                None,
+            )
+            .unwrap();
+
+        let timestamp = exchange.trades[0].timestamp;
+        let candles = exchange.candles(&pair, CandleInterval::OneDay, 0, timestamp + 1);
+        assert_eq!(candles.len(), 1);
+        assert_eq!(candles[0].open, Amount::from(100));
+        assert_eq!(candles[0].close, Amount::from(101));
+        assert_eq!(candles[0].volume, Amount::from(2));
+        assert_eq!(candles[0].trade_count, 2);
+
+        let ticker = exchange.ticker(&pair).unwrap();
+        assert_eq!(ticker.last, Amount::from(101));
+        assert_eq!(ticker.change_percent, Amount::from(1));
+        assert_eq!(ticker.high, Amount::from(101));
+        assert_eq!(ticker.low, Amount::from(100));
+        assert!(exchange.ticker(&TradingPair::new("ETH", "USDT")).is_none());
+    }
+
+    /// Order events and balance reasons of one account, in sequence order
+    fn user_updates(events: &[UserEvent], address: &str) -> Vec<String> {
+        let events: Vec<_> = events.iter().filter(|e| e.address == address).collect();
+        for pair in events.windows(2) {
+            assert_eq!(pair[1].sequence, pair[0].sequence + 1);
+        }
+        events
+            .iter()
+            .map(|event| match &event.update {
+                UserUpdate::Order { event, .. } => format!("{:?}", event),
+                UserUpdate::Balance {
+                    currency, reason, ..
+                } => format!("{} {:?}", currency, reason),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_user_events_follow_order_lifecycle() {
+        let (mut exchange, seller, buyer) = exchange_with_asks(&[100]);
+        let pair = TradingPair::new("BTC", "USDT");
+        exchange.take_user_events();
+
+        exchange
+            .place_order_with_options(
+                buyer.clone(),
+                pair.clone(),
+                OrderSide::Buy,
+                Amount::from(100),
+                Amount::from(3),
+                with_tif(TimeInForce::ImmediateOrCancel),
+            )
+            .unwrap();
+        let events = exchange.take_user_events();
+        assert_eq!(
+            user_updates(&events, &buyer),
+            [
+                "Accepted",
+                "USDT Order",
+                "PartiallyFilled",
+                "BTC Trade",
+                "USDT Trade",
+                "Cancelled",
+                "USDT Cancel",
+            ]
+        );
+        assert_eq!(
+            user_updates(&events, &seller),
+            ["Filled", "BTC Trade", "USDT Trade"]
+        );
+        let fill = events.iter().find_map(|event| match &event.update {
+            UserUpdate::Order {
+                fill: Some(fill), ..
+            } if event.address == buyer => Some(fill.clone()),
+            _ => None,
+        });
+        let fill = fill.unwrap();
+        assert_eq!(fill.quantity, Amount::from(1));
+        assert_eq!(fill.liquidity, Liquidity::Taker);
+        assert_eq!(fill.fee_currency, "BTC");
+        let last = events.iter().rev().find(|e| e.address == buyer).unwrap();
+        let UserUpdate::Balance {
+            available, locked, ..
+        } = &last.update
+        else {
+            panic!("expected a balance event");
+        };
+        assert_eq!(*available, Amount::from(99900));
+        assert_eq!(*locked, Amount::ZERO);
+
+        let order_id = exchange
+            .place_order(
+                seller.clone(),
+                pair.clone(),
+                OrderSide::Sell,
+                Amount::from(200),
+                Amount::from(1),
+            )
+            .unwrap();
+        exchange.cancel_order(&order_id, &pair).unwrap();
+        exchange.withdraw(&seller, "BTC", Amount::from(1)).unwrap();
+        assert_eq!(
+            user_updates(&exchange.take_user_events(), &seller),
+            [
+                "Accepted",
+                "BTC Order",
+                "Cancelled",
+                "BTC Cancel",
+                "BTC Withdrawal"
+            ]
+        );
     }
 }
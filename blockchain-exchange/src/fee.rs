@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::amount::{Amount, Rounding};
+use crate::order::TradingPair;
+
+/// Which side of a trade an order was on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Liquidity {
+    /// The resting order
+    Maker,
+    /// The incoming order
+    Taker,
+}
+
+/// Maker and taker fee rates, as fractions of the amount received
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeRates {
+    pub maker: Amount,
+    pub taker: Amount,
+}
+
+impl FeeRates {
+    pub fn new(maker: Amount, taker: Amount) -> Self {
+        FeeRates { maker, taker }
+    }
+
+    /// Rate for the given side of a trade
+    pub fn rate(&self, liquidity: Liquidity) -> Amount {
+        match liquidity {
+            Liquidity::Maker => self.maker,
+            Liquidity::Taker => self.taker,
+        }
+    }
+}
+
+/// Discount on the pair rates for accounts that have traded at least `min_volume`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeTier {
+    /// Traded volume, in the pair's quote currency, needed to reach the tier
+    pub min_volume: Amount,
+    /// Fraction taken off both the maker and the taker rate
+    pub discount: Amount,
+}
+
+/// Fee rates per trading pair and the volume tiers that discount them
+///
+/// Pairs without their own rates use the default rates, which are zero unless
+/// set. Volumes are counted per quote currency, so an account reaches tiers
+/// separately on USDT and BTC quoted pairs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FeeSchedule {
+    default_rates: FeeRates,
+    pair_rates: HashMap<String, FeeRates>,
+    /// Sorted by `min_volume`, lowest first
+    tiers: Vec<FeeTier>,
+}
+
+impl FeeSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the rates used by pairs that have none of their own
+    pub fn set_default_rates(&mut self, rates: FeeRates) {
+        self.default_rates = rates;
+    }
+
+    /// Sets the rates for a trading pair
+    pub fn set_pair_rates(&mut self, pair: &TradingPair, rates: FeeRates) {
+        self.pair_rates.insert(pair.symbol(), rates);
+    }
+
+    /// Adds a volume tier, replacing any tier with the same threshold
+    pub fn add_tier(&mut self, tier: FeeTier) -> Result<(), String> {
+        if tier.discount < Amount::ZERO || tier.discount > Amount::from(1) {
+            return Err("Fee tier discount must be between 0 and 1".to_string());
+        }
+        self.tiers.retain(|t| t.min_volume != tier.min_volume);
+        self.tiers.push(tier);
+        self.tiers.sort_by_key(|t| t.min_volume);
+        Ok(())
+    }
+
+    /// Rates for a trading pair, before any tier discount
+    pub fn pair_rates(&self, pair: &TradingPair) -> FeeRates {
+        self.pair_rates
+            .get(&pair.symbol())
+            .copied()
+            .unwrap_or(self.default_rates)
+    }
+
+    /// Discount of the highest tier that `volume` reaches
+    pub fn discount(&self, volume: Amount) -> Amount {
+        self.tiers
+            .iter()
+            .rev()
+            .find(|t| volume >= t.min_volume)
+            .map_or(Amount::ZERO, |t| t.discount)
+    }
+
+    /// Fee rate for one side of a trade on `pair` by an account with `volume`
+    pub fn rate(&self, pair: &TradingPair, liquidity: Liquidity, volume: Amount) -> Amount {
+        let rate = self.pair_rates(pair).rate(liquidity);
+        rate * (Amount::from(1) - self.discount(volume))
+    }
+
+    /// Fee on `amount` at `rate`, rounded up to `scale` but never more than `amount`
+    pub fn fee(amount: Amount, rate: Amount, scale: u32) -> Amount {
+        amount.mul_round(rate, scale, Rounding::Up).min(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amt(s: &str) -> Amount {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_pair_and_default_rates() {
+        let btc = TradingPair::new("BTC", "USDT");
+        let eth = TradingPair::new("ETH", "USDT");
+        let mut schedule = FeeSchedule::new();
+        assert_eq!(schedule.rate(&btc, Liquidity::Taker, Amount::ZERO), Amount::ZERO);
+
+        schedule.set_default_rates(FeeRates::new(amt("0.001"), amt("0.002")));
+        schedule.set_pair_rates(&btc, FeeRates::new(Amount::ZERO, amt("0.0005")));
+        assert_eq!(schedule.rate(&btc, Liquidity::Maker, Amount::ZERO), Amount::ZERO);
+        assert_eq!(schedule.rate(&btc, Liquidity::Taker, Amount::ZERO), amt("0.0005"));
+        assert_eq!(schedule.rate(&eth, Liquidity::Taker, Amount::ZERO), amt("0.002"));
+    }
+
+    #[test]
+    fn test_volume_tiers() {
+        let pair = TradingPair::new("BTC", "USDT");
+        let mut schedule = FeeSchedule::new();
+        schedule.set_default_rates(FeeRates::new(amt("0.001"), amt("0.002")));
+        schedule
+            .add_tier(FeeTier {
+                min_volume: Amount::from(100000),
+                discount: amt("0.5"),
+            })
+            .unwrap();
+        schedule
+            .add_tier(FeeTier {
+                min_volume: Amount::from(10000),
+                discount: amt("0.25"),
+            })
+            .unwrap();
+        assert!(schedule
+            .add_tier(FeeTier {
+                min_volume: Amount::from(1),
+                discount: amt("1.5"),
+            })
+            .is_err());
+
+        let rate = |volume: i64| schedule.rate(&pair, Liquidity::Taker, Amount::from(volume));
+        assert_eq!(rate(9999), amt("0.002"));
+        assert_eq!(rate(10000), amt("0.0015"));
+        assert_eq!(rate(250000), amt("0.001"));
+    }
+
+    #[test]
+    fn test_fee_rounds_up_within_amount() {
+        assert_eq!(FeeSchedule::fee(amt("10.01"), amt("0.001"), 2), amt("0.02"));
+        assert_eq!(FeeSchedule::fee(amt("0.01"), amt("2"), 2), amt("0.01"));
+        assert_eq!(FeeSchedule::fee(amt("10"), Amount::ZERO, 2), Amount::ZERO);
+    }
+}
@@ -0,0 +1,363 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Field delimiter of the FIX tag-value encoding
+pub const SOH: u8 = 0x01;
+
+/// BeginString of every message the gateway sends and accepts
+pub const BEGIN_STRING: &str = "FIX.4.4";
+
+/// Longest frame accepted, from BeginString through CheckSum
+pub const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// FIX tag numbers used by the gateway
+pub mod tag {
+    pub const AVG_PX: u32 = 6;
+    pub const BEGIN_SEQ_NO: u32 = 7;
+    pub const BEGIN_STRING: u32 = 8;
+    pub const BODY_LENGTH: u32 = 9;
+    pub const CHECK_SUM: u32 = 10;
+    pub const CL_ORD_ID: u32 = 11;
+    pub const CUM_QTY: u32 = 14;
+    pub const END_SEQ_NO: u32 = 16;
+    pub const EXEC_ID: u32 = 17;
+    pub const LAST_PX: u32 = 31;
+    pub const LAST_QTY: u32 = 32;
+    pub const MSG_SEQ_NUM: u32 = 34;
+    pub const MSG_TYPE: u32 = 35;
+    pub const NEW_SEQ_NO: u32 = 36;
+    pub const ORDER_ID: u32 = 37;
+    pub const ORDER_QTY: u32 = 38;
+    pub const ORD_STATUS: u32 = 39;
+    pub const ORD_TYPE: u32 = 40;
+    pub const ORIG_CL_ORD_ID: u32 = 41;
+    pub const POSS_DUP_FLAG: u32 = 43;
+    pub const PRICE: u32 = 44;
+    pub const REF_SEQ_NUM: u32 = 45;
+    pub const SENDER_COMP_ID: u32 = 49;
+    pub const SENDING_TIME: u32 = 52;
+    pub const SIDE: u32 = 54;
+    pub const SYMBOL: u32 = 55;
+    pub const TARGET_COMP_ID: u32 = 56;
+    pub const TEXT: u32 = 58;
+    pub const TIME_IN_FORCE: u32 = 59;
+    pub const TRANSACT_TIME: u32 = 60;
+    pub const ENCRYPT_METHOD: u32 = 98;
+    pub const CXL_REJ_REASON: u32 = 102;
+    pub const HEART_BT_INT: u32 = 108;
+    pub const TEST_REQ_ID: u32 = 112;
+    pub const ORIG_SENDING_TIME: u32 = 122;
+    pub const GAP_FILL_FLAG: u32 = 123;
+    pub const EXPIRE_TIME: u32 = 126;
+    pub const RESET_SEQ_NUM_FLAG: u32 = 141;
+    pub const EXEC_TYPE: u32 = 150;
+    pub const LEAVES_QTY: u32 = 151;
+    pub const REF_MSG_TYPE: u32 = 372;
+    pub const SESSION_REJECT_REASON: u32 = 373;
+    pub const CXL_REJ_RESPONSE_TO: u32 = 434;
+    pub const PASSWORD: u32 = 554;
+}
+
+/// FIX MsgType values used by the gateway
+pub mod msg_type {
+    pub const HEARTBEAT: &str = "0";
+    pub const TEST_REQUEST: &str = "1";
+    pub const RESEND_REQUEST: &str = "2";
+    pub const REJECT: &str = "3";
+    pub const SEQUENCE_RESET: &str = "4";
+    pub const LOGOUT: &str = "5";
+    pub const EXECUTION_REPORT: &str = "8";
+    pub const ORDER_CANCEL_REJECT: &str = "9";
+    pub const LOGON: &str = "A";
+    pub const NEW_ORDER_SINGLE: &str = "D";
+    pub const ORDER_CANCEL_REQUEST: &str = "F";
+    pub const ORDER_CANCEL_REPLACE_REQUEST: &str = "G";
+}
+
+/// Tags of the standard header that a session fills in when it sends a message
+const HEADER_TAGS: [u32; 7] = [
+    tag::MSG_TYPE,
+    tag::SENDER_COMP_ID,
+    tag::TARGET_COMP_ID,
+    tag::MSG_SEQ_NUM,
+    tag::SENDING_TIME,
+    tag::POSS_DUP_FLAG,
+    tag::ORIG_SENDING_TIME,
+];
+
+/// A FIX message: its fields between BodyLength and CheckSum, in order
+///
+/// MsgType always comes first. BeginString, BodyLength and CheckSum are
+/// computed when encoding and checked when decoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixMessage {
+    fields: Vec<(u32, String)>,
+}
+
+impl FixMessage {
+    /// Creates a message of the given MsgType with no other fields
+    pub fn new(msg_type: &str) -> Self {
+        FixMessage {
+            fields: vec![(tag::MSG_TYPE, msg_type.to_string())],
+        }
+    }
+
+    /// Appends a field
+    pub fn with(mut self, tag: u32, value: impl ToString) -> Self {
+        self.fields.push((tag, value.to_string()));
+        self
+    }
+
+    /// Replaces the first field with `tag`, or appends it
+    pub fn set(&mut self, tag: u32, value: impl ToString) {
+        match self.fields.iter_mut().find(|(t, _)| *t == tag) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.fields.push((tag, value.to_string())),
+        }
+    }
+
+    pub fn msg_type(&self) -> &str {
+        &self.fields[0].1
+    }
+
+    /// Value of the first field with `tag`
+    pub fn get(&self, tag: u32) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Value of a field the message must have
+    pub fn require(&self, tag: u32) -> Result<&str, String> {
+        self.get(tag)
+            .ok_or_else(|| format!("Missing required tag {}", tag))
+    }
+
+    /// Parses an optional field
+    pub fn parse<T: FromStr>(&self, tag: u32) -> Result<Option<T>, String> {
+        self.get(tag)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("Invalid value {:?} for tag {}", value, tag))
+            })
+            .transpose()
+    }
+
+    /// Parses a field the message must have
+    pub fn parse_required<T: FromStr>(&self, tag: u32) -> Result<T, String> {
+        self.parse(tag)?
+            .ok_or_else(|| format!("Missing required tag {}", tag))
+    }
+
+    /// Returns true if a Y/N field is `Y`
+    pub fn flag(&self, tag: u32) -> bool {
+        self.get(tag) == Some("Y")
+    }
+
+    /// MsgSeqNum of the message, if present and valid
+    pub fn seq_num(&self) -> Option<u64> {
+        self.parse(tag::MSG_SEQ_NUM).ok().flatten()
+    }
+
+    /// The message without its standard header, keeping MsgType
+    pub fn body(&self) -> FixMessage {
+        let fields = self
+            .fields
+            .iter()
+            .filter(|(t, _)| *t == tag::MSG_TYPE || !HEADER_TAGS.contains(t))
+            .cloned()
+            .collect();
+        FixMessage { fields }
+    }
+
+    /// The message's body under a new standard header
+    ///
+    /// `header` holds the header fields that follow MsgType; any header fields
+    /// the message already had are dropped.
+    pub fn with_header(&self, header: Vec<(u32, String)>) -> FixMessage {
+        let mut fields = vec![self.fields[0].clone()];
+        fields.extend(header);
+        fields.extend(self.body().fields.into_iter().skip(1));
+        FixMessage { fields }
+    }
+
+    /// Encodes the message with BeginString, BodyLength and CheckSum
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (tag, value) in &self.fields {
+            body.extend_from_slice(format!("{}={}", tag, value).as_bytes());
+            body.push(SOH);
+        }
+
+        let mut frame = format!("8={}\x019={}\x01", BEGIN_STRING, body.len()).into_bytes();
+        frame.extend_from_slice(&body);
+        let checksum = checksum(&frame);
+        frame.extend_from_slice(format!("10={:03}\x01", checksum).as_bytes());
+        frame
+    }
+
+    /// Decodes one complete frame, checking its BodyLength and CheckSum
+    pub fn decode(frame: &[u8]) -> Result<Self, String> {
+        let text = std::str::from_utf8(frame).map_err(|_| "Message is not valid UTF-8")?;
+        let text = text
+            .strip_suffix('\x01')
+            .ok_or("Message does not end with a delimiter")?;
+        let mut fields = text
+            .split('\x01')
+            .map(|field| {
+                let (tag, value) = field
+                    .split_once('=')
+                    .ok_or_else(|| format!("Malformed field {:?}", field))?;
+                let tag = tag
+                    .parse::<u32>()
+                    .map_err(|_| format!("Malformed tag {:?}", tag))?;
+                Ok((tag, value.to_string()))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        if fields.len() < 4 || fields[0] != (tag::BEGIN_STRING, BEGIN_STRING.to_string()) {
+            return Err(format!("Expected BeginString {}", BEGIN_STRING));
+        }
+        if fields[1].0 != tag::BODY_LENGTH || fields[2].0 != tag::MSG_TYPE {
+            return Err("Expected BodyLength and MsgType to follow BeginString".to_string());
+        }
+        let (checksum_tag, checksum_value) = fields.pop().unwrap_or_default();
+        if checksum_tag != tag::CHECK_SUM {
+            return Err("Expected CheckSum to end the message".to_string());
+        }
+
+        let trailer_start = frame.len() - (checksum_value.len() + 4);
+        let expected = format!("{:03}", checksum(&frame[..trailer_start]));
+        if checksum_value != expected {
+            return Err(format!(
+                "CheckSum {} does not match {}",
+                checksum_value, expected
+            ));
+        }
+        let body_start = fields[0].1.len() + fields[1].1.len() + 6;
+        if fields[1].1 != (trailer_start - body_start).to_string() {
+            return Err("BodyLength does not match the message".to_string());
+        }
+
+        Ok(FixMessage {
+            fields: fields.split_off(2),
+        })
+    }
+}
+
+/// Renders the message with `|` in place of the delimiter, for logs
+impl fmt::Display for FixMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (tag, value) in &self.fields {
+            write!(f, "{}={}|", tag, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Length of the first complete frame at the start of `buffer`, if there is one
+///
+/// Only the framing is checked; [`FixMessage::decode`] validates the content.
+/// Frames longer than [`MAX_MESSAGE_BYTES`] are refused as soon as their
+/// BodyLength is read, so a reader never buffers more than that.
+pub fn frame_length(buffer: &[u8]) -> Result<Option<usize>, String> {
+    let prefix = format!("8={}\x019=", BEGIN_STRING);
+    let compared = buffer.len().min(prefix.len());
+    if buffer[..compared] != prefix.as_bytes()[..compared] {
+        return Err("Stream is not at the start of a FIX message".to_string());
+    }
+    if buffer.len() == compared {
+        return Ok(None);
+    }
+
+    let rest = &buffer[prefix.len()..];
+    let digits = MAX_MESSAGE_BYTES.to_string().len();
+    let Some(end) = rest.iter().take(digits + 1).position(|&b| b == SOH) else {
+        if rest.len() > digits {
+            return Err("BodyLength is too long".to_string());
+        }
+        return Ok(None);
+    };
+    let body_length: usize = std::str::from_utf8(&rest[..end])
+        .ok()
+        .and_then(|length| length.parse().ok())
+        .ok_or("Malformed BodyLength")?;
+
+    // BeginString and BodyLength, the body, then "10=nnn" and a delimiter
+    let length = prefix.len() + end + 1 + body_length.min(MAX_MESSAGE_BYTES) + 7;
+    if length > MAX_MESSAGE_BYTES {
+        return Err(format!(
+            "Message of {} bytes is longer than the limit of {}",
+            length, MAX_MESSAGE_BYTES
+        ));
+    }
+    Ok((buffer.len() >= length).then_some(length))
+}
+
+/// Sum of the bytes modulo 256
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let message = FixMessage::new(msg_type::HEARTBEAT)
+            .with(tag::SENDER_COMP_ID, "CLIENT")
+            .with(tag::TARGET_COMP_ID, "EXCHANGE")
+            .with(tag::MSG_SEQ_NUM, 7)
+            .with(tag::TEST_REQ_ID, "ping");
+        let frame = message.encode();
+        let text = String::from_utf8(frame.clone()).unwrap().replace('\x01', "|");
+        assert!(text.starts_with("8=FIX.4.4|9=41|35=0|49=CLIENT|"));
+        assert!(text.ends_with("|112=ping|10=133|"));
+
+        assert_eq!(frame_length(&frame[..10]), Ok(None));
+        assert_eq!(frame_length(&frame[..frame.len() - 1]), Ok(None));
+        let mut stream = frame.clone();
+        stream.extend_from_slice(&frame[..5]);
+        assert_eq!(frame_length(&stream), Ok(Some(frame.len())));
+
+        let decoded = FixMessage::decode(&frame).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.seq_num(), Some(7));
+        assert_eq!(
+            decoded.body(),
+            FixMessage::new(msg_type::HEARTBEAT).with(tag::TEST_REQ_ID, "ping")
+        );
+    }
+
+    #[test]
+    fn test_decode_rejects_corruption() {
+        let frame = FixMessage::new(msg_type::LOGON)
+            .with(tag::HEART_BT_INT, 30)
+            .encode();
+
+        let mut corrupted = frame.clone();
+        let index = corrupted.len() - 10;
+        corrupted[index] = b'9';
+        let error = FixMessage::decode(&corrupted).unwrap_err();
+        assert!(error.contains("CheckSum"));
+
+        assert!(frame_length(b"8=FIX.4.2\x019=5\x01").is_err());
+        let oversized = format!("8=FIX.4.4\x019={}\x01", MAX_MESSAGE_BYTES);
+        assert!(frame_length(oversized.as_bytes()).is_err());
+        let overflowing = format!("8=FIX.4.4\x019={}\x01", usize::MAX);
+        assert!(frame_length(overflowing.as_bytes()).is_err());
+        assert!(frame_length(b"8=FIX.4.4\x019=0000000000").is_err());
+        assert!(FixMessage::decode(&frame[..frame.len() - 1]).is_err());
+
+        let message = FixMessage::decode(&frame).unwrap();
+        assert_eq!(message.parse::<u64>(tag::HEART_BT_INT), Ok(Some(30)));
+        assert!(message.require(tag::TEST_REQ_ID).is_err());
+        assert!(FixMessage::new("A")
+            .with(tag::HEART_BT_INT, "x")
+            .parse::<u64>(tag::HEART_BT_INT)
+            .is_err());
+    }
+}
@@ -0,0 +1,1529 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{self, Instant};
+use uuid::Uuid;
+
+use crate::amount::Amount;
+use crate::api::{lock, EventHub, SharedExchange};
+use crate::exchange::Exchange;
+use crate::fix::{frame_length, msg_type, tag, FixMessage};
+use crate::keys;
+use crate::log::{self, Log};
+use crate::order::{
+    MarketOrderSize, OrderOptions, OrderSide, OrderStatus, OrderType, TimeInForce, TradingPair,
+};
+use crate::user_data::{OrderEvent, OrderUpdate, UserEvent, UserUpdate};
+
+/// Time a new connection has to send its Logon
+const LOGON_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often sessions check their heartbeat timers
+const TIMER_TICK: Duration = Duration::from_millis(100);
+
+/// SessionRejectReason for rejects that have no more specific code
+const OTHER_REJECT_REASON: u32 = 99;
+
+/// Message types resent as they were; the others are replaced by gap fills
+const RESENT_TYPES: [&str; 3] = [
+    msg_type::EXECUTION_REPORT,
+    msg_type::ORDER_CANCEL_REJECT,
+    msg_type::REJECT,
+];
+
+/// How the gateway identifies itself and its clients
+#[derive(Debug, Clone)]
+pub struct FixConfig {
+    /// SenderCompID of the gateway, and the TargetCompID clients must use
+    pub comp_id: String,
+    /// Directory holding each session's sequence numbers and sent messages
+    pub store_dir: PathBuf,
+    /// Clients allowed to log on, by SenderCompID
+    pub clients: HashMap<String, FixClient>,
+}
+
+impl FixConfig {
+    /// Checks that every client trades for a wallet of `exchange`
+    pub fn check_clients(&self, exchange: &Exchange) -> Result<(), String> {
+        let mut comp_ids: Vec<&String> = self.clients.keys().collect();
+        comp_ids.sort();
+        for comp_id in comp_ids {
+            let address = &self.clients[comp_id].address;
+            if exchange.wallet_manager.get_wallet(address).is_none() {
+                return Err(format!(
+                    "FIX client {} trades for unknown wallet {}",
+                    comp_id, address
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A client of the gateway
+#[derive(Debug, Clone)]
+pub struct FixClient {
+    /// Wallet address the client trades for
+    pub address: String,
+    /// Secret the client must send as Password (554) on its Logon
+    pub password: String,
+}
+
+/// Sequence numbers and sent messages of one FIX session, kept on disk
+///
+/// `<client>.seqnums` holds the next outgoing and the next expected incoming
+/// sequence number, and `<client>.messages` every message sent, one encoded
+/// frame per line, so they can be resent after a reconnect or a restart.
+pub struct SessionStore {
+    seqnums_path: PathBuf,
+    messages_path: PathBuf,
+    next_sender_seq: u64,
+    next_target_seq: u64,
+    sent: BTreeMap<u64, FixMessage>,
+}
+
+impl SessionStore {
+    /// Opens the store of a client's session, starting at 1 if it has none
+    pub fn open(dir: impl AsRef<Path>, client: &str) -> Result<Self, String> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        let seqnums_path = dir.join(format!("{}.seqnums", client));
+        let messages_path = dir.join(format!("{}.messages", client));
+
+        let (next_sender_seq, next_target_seq) = match fs::read_to_string(&seqnums_path) {
+            Ok(text) => {
+                let mut numbers = text.split_whitespace().map(str::parse::<u64>);
+                match (numbers.next(), numbers.next()) {
+                    (Some(Ok(sender)), Some(Ok(target))) => (sender, target),
+                    _ => {
+                        return Err(format!(
+                            "Corrupt sequence numbers in {}",
+                            seqnums_path.display()
+                        ))
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (1, 1),
+            Err(e) => return Err(format!("Failed to read {}: {}", seqnums_path.display(), e)),
+        };
+
+        let mut sent = BTreeMap::new();
+        match File::open(&messages_path) {
+            Ok(file) => {
+                for line in BufReader::new(file).split(b'\n') {
+                    let line = line.map_err(|e| format!("Failed to read messages: {}", e))?;
+                    // A line torn by a crash fails its checksum and is skipped
+                    if let Ok(message) = FixMessage::decode(&line) {
+                        if let Some(seq) = message.seq_num() {
+                            sent.insert(seq, message);
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to open {}: {}", messages_path.display(), e)),
+        }
+
+        Ok(SessionStore {
+            seqnums_path,
+            messages_path,
+            next_sender_seq,
+            next_target_seq,
+            sent,
+        })
+    }
+
+    /// Sequence number of the next message sent
+    pub fn next_sender_seq(&self) -> u64 {
+        self.next_sender_seq
+    }
+
+    /// Sequence number expected on the next message received
+    pub fn next_target_seq(&self) -> u64 {
+        self.next_target_seq
+    }
+
+    /// Stores a message sent with the next outgoing sequence number
+    pub fn record_sent(&mut self, message: &FixMessage) -> Result<(), String> {
+        let mut line = message.encode();
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.messages_path)
+            .and_then(|mut file| {
+                file.write_all(&line)?;
+                file.sync_data()
+            })
+            .map_err(|e| format!("Failed to store sent message: {}", e))?;
+        self.sent.insert(self.next_sender_seq, message.clone());
+        self.next_sender_seq += 1;
+        self.save()
+    }
+
+    pub fn set_next_target_seq(&mut self, seq: u64) -> Result<(), String> {
+        self.next_target_seq = seq;
+        self.save()
+    }
+
+    /// Starts both sequences again at 1 and forgets the sent messages
+    pub fn reset(&mut self) -> Result<(), String> {
+        match fs::remove_file(&self.messages_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to reset messages: {}", e)),
+        }
+        self.sent.clear();
+        self.next_sender_seq = 1;
+        self.next_target_seq = 1;
+        self.save()
+    }
+
+    /// The sent message with a sequence number, if it is stored
+    pub fn sent(&self, seq: u64) -> Option<&FixMessage> {
+        self.sent.get(&seq)
+    }
+
+    /// Writes the sequence numbers to a temporary file, then renames it
+    fn save(&self) -> Result<(), String> {
+        let tmp_path = self.seqnums_path.with_extension("seqnums.tmp");
+        let text = format!("{} {}\n", self.next_sender_seq, self.next_target_seq);
+        fs::write(&tmp_path, text)
+            .and_then(|()| fs::rename(&tmp_path, &self.seqnums_path))
+            .map_err(|e| format!("Failed to save sequence numbers: {}", e))
+    }
+}
+
+/// An order a client placed over FIX
+struct FixOrder {
+    /// ClOrdID of the latest accepted request for the order
+    cl_ord_id: String,
+    /// ClOrdID and OrigClOrdID of a cancel request awaiting its report
+    pending_cancel: Option<(String, String)>,
+    /// Status in the latest execution report
+    status: OrderStatus,
+}
+
+/// Orders a client placed over FIX
+#[derive(Default)]
+struct ClientOrders {
+    /// Orders by order id
+    orders: HashMap<String, FixOrder>,
+    /// Order id of every ClOrdID the client has used
+    cl_ord_ids: HashMap<String, String>,
+}
+
+#[derive(Default)]
+struct GatewayState {
+    /// Clients with a session logged on
+    logged_on: HashSet<String>,
+    orders: HashMap<String, ClientOrders>,
+}
+
+/// FIX 4.4 order-entry acceptor for an exchange
+///
+/// Clients log on with the SenderCompID and Password they are configured
+/// under and trade for its wallet: NewOrderSingle places limit (OrdType 2) and market
+/// (OrdType 1) orders, OrderCancelRequest cancels and
+/// OrderCancelReplaceRequest amends them. ExecutionReports follow every
+/// change to an order placed through the session, whoever caused it. Sequence
+/// numbers and sent messages persist in `FixConfig::store_dir`, so sessions
+/// continue across reconnects and restarts and can answer ResendRequests.
+/// The ClOrdIDs of orders are kept in memory only.
+///
+/// The gateway publishes the exchange's events through `events`, which must
+/// be the hub of any HTTP API serving the same exchange.
+#[derive(Clone)]
+pub struct FixGateway {
+    exchange: SharedExchange,
+    events: EventHub,
+    config: Arc<FixConfig>,
+    state: Arc<Mutex<GatewayState>>,
+    /// Where refused logons and ended sessions are reported
+    log: Log,
+}
+
+impl FixGateway {
+    pub fn new(exchange: SharedExchange, events: EventHub, config: FixConfig) -> Self {
+        FixGateway {
+            exchange,
+            events,
+            config: Arc::new(config),
+            state: Arc::default(),
+            log: log::discard(),
+        }
+    }
+
+    /// Reports refused logons, ended sessions and garbled messages to `log`
+    pub fn with_log(mut self, log: Log) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// Accepts FIX connections until the listener fails
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let gateway = self.clone();
+            tokio::spawn(async move { gateway.accept(stream).await });
+        }
+    }
+
+    /// Waits for a Logon, then runs the session until it ends
+    async fn accept(self, mut stream: TcpStream) {
+        let mut buffer = vec![];
+        let logon = time::timeout(LOGON_TIMEOUT, read_message(&mut stream, &mut buffer)).await;
+        let Ok(Ok(Some(logon))) = logon else {
+            return;
+        };
+        if logon.msg_type() != msg_type::LOGON
+            || logon.get(tag::TARGET_COMP_ID) != Some(self.config.comp_id.as_str())
+        {
+            return;
+        }
+        let Some(client) = logon.get(tag::SENDER_COMP_ID).map(str::to_string) else {
+            return;
+        };
+        let Some(config) = self.config.clients.get(&client) else {
+            (self.log)(&format!("FIX logon from unknown client {}", client));
+            return;
+        };
+        let password = logon.get(tag::PASSWORD).unwrap_or_default();
+        if !keys::secrets_match(password, &config.password) {
+            (self.log)(&format!("FIX logon from {} with a wrong password", client));
+            return;
+        }
+        let address = config.address.clone();
+        if !self.state().logged_on.insert(client.clone()) {
+            (self.log)(&format!("FIX client {} is already logged on", client));
+            return;
+        }
+
+        let result = self
+            .run(stream, buffer, client.clone(), address, logon)
+            .await;
+        self.state().logged_on.remove(&client);
+        if let Err(e) = result {
+            (self.log)(&format!("FIX session {} ended: {}", client, e));
+        }
+    }
+
+    async fn run(
+        &self,
+        stream: TcpStream,
+        buffer: Vec<u8>,
+        client: String,
+        address: String,
+        logon: FixMessage,
+    ) -> Result<(), String> {
+        let mut store = SessionStore::open(&self.config.store_dir, &client)?;
+        let reset = logon.flag(tag::RESET_SEQ_NUM_FLAG);
+        if reset {
+            store.reset()?;
+        }
+        let heartbeat: u64 = logon.parse_required(tag::HEART_BT_INT)?;
+        // Subscribe before anything can happen to the session's orders
+        let mut events = self.events.user_data.subscribe();
+        let mut session = Session {
+            gateway: self.clone(),
+            stream,
+            buffer,
+            client,
+            address,
+            store,
+            heartbeat: (heartbeat > 0).then(|| Duration::from_secs(heartbeat)),
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            test_request: None,
+            resend_until: 0,
+        };
+
+        let seq = logon.seq_num().ok_or("Logon without MsgSeqNum")?;
+        let expected = session.store.next_target_seq();
+        if seq < expected {
+            let text = format!(
+                "MsgSeqNum too low, expecting {} but received {}",
+                expected, seq
+            );
+            session.logout(&text).await?;
+            return Err(text);
+        }
+        let mut reply = FixMessage::new(msg_type::LOGON)
+            .with(tag::ENCRYPT_METHOD, 0)
+            .with(tag::HEART_BT_INT, heartbeat);
+        if reset {
+            reply = reply.with(tag::RESET_SEQ_NUM_FLAG, "Y");
+        }
+        session.send(reply).await?;
+        if seq > expected {
+            session.request_resend(seq).await?;
+        } else {
+            session.store.set_next_target_seq(seq + 1)?;
+        }
+
+        let mut timer = time::interval(TIMER_TICK);
+        let mut chunk = [0u8; 4096];
+        loop {
+            // Handle every complete message already read
+            while let Some(length) = frame_length(&session.buffer)? {
+                let frame: Vec<u8> = session.buffer.drain(..length).collect();
+                session.last_received = Instant::now();
+                session.test_request = None;
+                match FixMessage::decode(&frame) {
+                    Ok(message) => {
+                        if !session.receive(message).await? {
+                            return Ok(());
+                        }
+                    }
+                    // Garbled messages are ignored; a resend recovers them
+                    Err(e) => (self.log)(&format!("Ignoring garbled FIX message: {}", e)),
+                }
+            }
+
+            let input = tokio::select! {
+                read = session.stream.read(&mut chunk) => Input::Read(read),
+                event = events.recv() => Input::Event(event.map(Box::new)),
+                _ = timer.tick() => Input::Tick,
+            };
+            match input {
+                Input::Read(Ok(0)) => return Err("Connection closed".to_string()),
+                Input::Read(Ok(n)) => session.buffer.extend_from_slice(&chunk[..n]),
+                Input::Read(Err(e)) => return Err(e.to_string()),
+                Input::Event(Ok(event)) => session.report(*event).await?,
+                Input::Event(Err(RecvError::Lagged(_))) => {
+                    let text = "Execution reports fell behind";
+                    session.logout(text).await?;
+                    return Err(text.to_string());
+                }
+                Input::Event(Err(RecvError::Closed)) => return Ok(()),
+                Input::Tick => {
+                    if !session.check_heartbeat().await? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, GatewayState> {
+        // The state stays consistent even if a session panicked while holding it
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// What woke a session up
+enum Input {
+    Read(io::Result<usize>),
+    Event(Result<Box<UserEvent>, RecvError>),
+    Tick,
+}
+
+/// A logged-on FIX session
+struct Session {
+    gateway: FixGateway,
+    stream: TcpStream,
+    /// Bytes read but not yet handled
+    buffer: Vec<u8>,
+    /// SenderCompID of the client
+    client: String,
+    /// Wallet the client trades for
+    address: String,
+    store: SessionStore,
+    heartbeat: Option<Duration>,
+    last_sent: Instant,
+    last_received: Instant,
+    /// TestReqID of an unanswered TestRequest
+    test_request: Option<String>,
+    /// Highest sequence number covered by the last ResendRequest
+    resend_until: u64,
+}
+
+impl Session {
+    /// Handles a received message and returns false once the session is over
+    async fn receive(&mut self, message: FixMessage) -> Result<bool, String> {
+        if message.get(tag::SENDER_COMP_ID) != Some(self.client.as_str())
+            || message.get(tag::TARGET_COMP_ID) != Some(self.gateway.config.comp_id.as_str())
+        {
+            self.logout("CompID problem").await?;
+            return Ok(false);
+        }
+
+        let msg_type = message.msg_type().to_string();
+        // A SequenceReset in reset mode applies whatever its sequence number
+        if msg_type == msg_type::SEQUENCE_RESET && !message.flag(tag::GAP_FILL_FLAG) {
+            return match message.parse_required::<u64>(tag::NEW_SEQ_NO) {
+                Ok(new_seq) if new_seq >= self.store.next_target_seq() => {
+                    self.store.set_next_target_seq(new_seq)?;
+                    Ok(true)
+                }
+                Ok(_) => self.reject(&message, "NewSeqNo is too low").await,
+                Err(text) => self.reject(&message, &text).await,
+            };
+        }
+
+        let Some(seq) = message.seq_num() else {
+            self.logout("MsgSeqNum missing").await?;
+            return Ok(false);
+        };
+        let expected = self.store.next_target_seq();
+        if seq > expected {
+            // Resend requests and logouts are acted on despite the gap
+            match msg_type.as_str() {
+                msg_type::RESEND_REQUEST => self.resend(&message).await?,
+                msg_type::LOGOUT => {
+                    self.send(FixMessage::new(msg_type::LOGOUT)).await?;
+                    return Ok(false);
+                }
+                _ => {}
+            }
+            if expected > self.resend_until {
+                self.request_resend(seq).await?;
+            }
+            return Ok(true);
+        }
+        if seq < expected {
+            if message.flag(tag::POSS_DUP_FLAG) {
+                return Ok(true);
+            }
+            let text = format!(
+                "MsgSeqNum too low, expecting {} but received {}",
+                expected, seq
+            );
+            self.logout(&text).await?;
+            return Ok(false);
+        }
+        self.store.set_next_target_seq(seq + 1)?;
+
+        match msg_type.as_str() {
+            msg_type::HEARTBEAT => Ok(true),
+            msg_type::TEST_REQUEST => match message.require(tag::TEST_REQ_ID) {
+                Ok(id) => {
+                    let heartbeat = FixMessage::new(msg_type::HEARTBEAT).with(tag::TEST_REQ_ID, id);
+                    self.send(heartbeat).await?;
+                    Ok(true)
+                }
+                Err(text) => self.reject(&message, &text).await,
+            },
+            msg_type::RESEND_REQUEST => {
+                self.resend(&message).await?;
+                Ok(true)
+            }
+            msg_type::SEQUENCE_RESET => match message.parse_required::<u64>(tag::NEW_SEQ_NO) {
+                Ok(new_seq) if new_seq > seq => {
+                    self.store.set_next_target_seq(new_seq)?;
+                    Ok(true)
+                }
+                Ok(_) => self.reject(&message, "NewSeqNo is too low").await,
+                Err(text) => self.reject(&message, &text).await,
+            },
+            msg_type::LOGOUT => {
+                self.send(FixMessage::new(msg_type::LOGOUT)).await?;
+                Ok(false)
+            }
+            msg_type::LOGON => self.reject(&message, "Already logged on").await,
+            msg_type::NEW_ORDER_SINGLE => self.new_order(&message).await,
+            msg_type::ORDER_CANCEL_REQUEST => self.cancel(&message).await,
+            msg_type::ORDER_CANCEL_REPLACE_REQUEST => self.replace(&message).await,
+            _ => self.reject(&message, "Unsupported MsgType").await,
+        }
+    }
+
+    /// Places the order of a NewOrderSingle
+    ///
+    /// Its ExecutionReports follow from the exchange's events; only a rejection
+    /// is reported here.
+    async fn new_order(&mut self, message: &FixMessage) -> Result<bool, String> {
+        let request = match NewOrder::parse(message) {
+            Ok(request) => request,
+            Err(text) => return self.reject(message, &text).await,
+        };
+        if self.client_orders_contain(&request.cl_ord_id) {
+            let report = request.rejection("Duplicate ClOrdID");
+            self.send(report).await?;
+            return Ok(true);
+        }
+
+        let result = {
+            let mut exchange = lock(&self.gateway.exchange).map_err(|e| e.message)?;
+            let address = self.address.clone();
+            let pair = request.pair.clone();
+            let result = match request.price {
+                Some(price) => exchange.place_order_with_options(
+                    address,
+                    pair,
+                    request.side,
+                    price,
+                    request.quantity,
+                    request.options,
+                ),
+                None => exchange.place_market_order(
+                    address,
+                    pair,
+                    request.side,
+                    MarketOrderSize::Base(request.quantity),
+                    None,
+                ),
+            };
+            if let Ok(order_id) = &result {
+                let mut state = self.gateway.state();
+                let orders = state.orders.entry(self.client.clone()).or_default();
+                orders
+                    .cl_ord_ids
+                    .insert(request.cl_ord_id.clone(), order_id.clone());
+                orders.orders.insert(
+                    order_id.clone(),
+                    FixOrder {
+                        cl_ord_id: request.cl_ord_id.clone(),
+                        pending_cancel: None,
+                        status: OrderStatus::Open,
+                    },
+                );
+            }
+            self.gateway.events.publish(exchange);
+            result
+        };
+
+        if let Err(e) = result {
+            self.send(request.rejection(&e)).await?;
+        }
+        Ok(true)
+    }
+
+    /// Cancels an order; the ExecutionReport follows from the exchange's events
+    async fn cancel(&mut self, message: &FixMessage) -> Result<bool, String> {
+        let fields = (|| {
+            Ok::<_, String>((
+                message.require(tag::CL_ORD_ID)?.to_string(),
+                message.require(tag::ORIG_CL_ORD_ID)?.to_string(),
+                parse_symbol(message.require(tag::SYMBOL)?)?,
+            ))
+        })();
+        let (cl_ord_id, orig_cl_ord_id, pair) = match fields {
+            Ok(fields) => fields,
+            Err(text) => return self.reject(message, &text).await,
+        };
+        let reject = CancelReject {
+            cl_ord_id: cl_ord_id.clone(),
+            orig_cl_ord_id: orig_cl_ord_id.clone(),
+            response_to: "1",
+        };
+        if self.client_orders_contain(&cl_ord_id) {
+            self.send(reject.message(None, "Duplicate ClOrdID")).await?;
+            return Ok(true);
+        }
+        let Some((order_id, status)) = self.find_order(&orig_cl_ord_id) else {
+            self.send(reject.message(None, "Unknown order")).await?;
+            return Ok(true);
+        };
+
+        let result = {
+            let mut exchange = lock(&self.gateway.exchange).map_err(|e| e.message)?;
+            let result = exchange.cancel_order(&order_id, &pair);
+            if result.is_ok() {
+                let mut state = self.gateway.state();
+                let orders = state.orders.entry(self.client.clone()).or_default();
+                orders
+                    .cl_ord_ids
+                    .insert(cl_ord_id.clone(), order_id.clone());
+                if let Some(order) = orders.orders.get_mut(&order_id) {
+                    order.pending_cancel = Some((cl_ord_id, orig_cl_ord_id));
+                }
+            }
+            self.gateway.events.publish(exchange);
+            result
+        };
+
+        if let Err(e) = result {
+            let report = reject.message(Some((&order_id, status)), &e);
+            self.send(report).await?;
+        }
+        Ok(true)
+    }
+
+    /// Amends the price and quantity of a resting limit order
+    async fn replace(&mut self, message: &FixMessage) -> Result<bool, String> {
+        let fields = (|| {
+            Ok::<_, String>((
+                message.require(tag::CL_ORD_ID)?.to_string(),
+                message.require(tag::ORIG_CL_ORD_ID)?.to_string(),
+                parse_symbol(message.require(tag::SYMBOL)?)?,
+                message.parse_required::<Amount>(tag::PRICE)?,
+                message.parse_required::<Amount>(tag::ORDER_QTY)?,
+            ))
+        })();
+        let (cl_ord_id, orig_cl_ord_id, pair, price, quantity) = match fields {
+            Ok(fields) => fields,
+            Err(text) => return self.reject(message, &text).await,
+        };
+        let reject = CancelReject {
+            cl_ord_id: cl_ord_id.clone(),
+            orig_cl_ord_id: orig_cl_ord_id.clone(),
+            response_to: "2",
+        };
+        if self.client_orders_contain(&cl_ord_id) {
+            self.send(reject.message(None, "Duplicate ClOrdID")).await?;
+            return Ok(true);
+        }
+        let Some((order_id, status)) = self.find_order(&orig_cl_ord_id) else {
+            self.send(reject.message(None, "Unknown order")).await?;
+            return Ok(true);
+        };
+
+        let result = {
+            let mut exchange = lock(&self.gateway.exchange).map_err(|e| e.message)?;
+            let resting = exchange
+                .get_order_book(&pair)
+                .and_then(|book| book.get_order(&order_id))
+                .cloned();
+            let result = exchange
+                .amend_order(&order_id, &pair, price, quantity)
+                .and_then(|()| resting.ok_or_else(|| "Order not found".to_string()));
+            if result.is_ok() {
+                let mut state = self.gateway.state();
+                let orders = state.orders.entry(self.client.clone()).or_default();
+                orders
+                    .cl_ord_ids
+                    .insert(cl_ord_id.clone(), order_id.clone());
+                if let Some(order) = orders.orders.get_mut(&order_id) {
+                    order.cl_ord_id = cl_ord_id.clone();
+                }
+            }
+            // Publishing queues any fills of the amended order behind the report below
+            self.gateway.events.publish(exchange);
+            result
+        };
+
+        match result {
+            Ok(mut order) => {
+                order.price = price;
+                order.quantity = quantity;
+                let report = execution_report(&OrderUpdate::from(&order), &cl_ord_id, "5")
+                    .with(tag::EXEC_ID, Uuid::new_v4().simple())
+                    .with(tag::ORIG_CL_ORD_ID, orig_cl_ord_id);
+                self.send(report).await?;
+            }
+            Err(e) => {
+                let report = reject.message(Some((&order_id, status)), &e);
+                self.send(report).await?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Sends the ExecutionReport for an event of one of the session's orders
+    async fn report(&mut self, event: UserEvent) -> Result<(), String> {
+        if event.address != self.address {
+            return Ok(());
+        }
+        let UserUpdate::Order {
+            event: order_event,
+            order,
+            fill,
+        } = event.update
+        else {
+            return Ok(());
+        };
+
+        let (cl_ord_id, orig_cl_ord_id) = {
+            let mut state = self.gateway.state();
+            let Some(fix_order) = state
+                .orders
+                .get_mut(&self.client)
+                .and_then(|orders| orders.orders.get_mut(&order.id))
+            else {
+                return Ok(());
+            };
+            fix_order.status = order.status;
+            match fix_order.pending_cancel.take() {
+                Some((cl_ord_id, orig)) if order_event == OrderEvent::Cancelled => {
+                    (cl_ord_id, Some(orig))
+                }
+                pending => {
+                    fix_order.pending_cancel = pending;
+                    (fix_order.cl_ord_id.clone(), None)
+                }
+            }
+        };
+
+        let exec_type = match order_event {
+            _ if fill.is_some() => "F",
+            OrderEvent::Accepted => "0",
+            OrderEvent::Cancelled => "4",
+            OrderEvent::Expired => "C",
+            OrderEvent::PartiallyFilled | OrderEvent::Filled => "I",
+        };
+        let exec_id = match &fill {
+            Some(fill) => fill.trade_id.clone(),
+            None => format!("{}-{}", order.id, event.sequence),
+        };
+        let mut report = execution_report(&order, &cl_ord_id, exec_type)
+            .with(tag::EXEC_ID, exec_id)
+            .with(tag::TRANSACT_TIME, fix_time(event.timestamp));
+        if let Some(orig) = orig_cl_ord_id {
+            report = report.with(tag::ORIG_CL_ORD_ID, orig);
+        }
+        if let Some(fill) = fill {
+            report = report
+                .with(tag::LAST_QTY, fill.quantity)
+                .with(tag::LAST_PX, fill.price);
+        }
+        self.send(report).await
+    }
+
+    /// Returns true if the client has used a ClOrdID before
+    fn client_orders_contain(&self, cl_ord_id: &str) -> bool {
+        self.gateway
+            .state()
+            .orders
+            .get(&self.client)
+            .is_some_and(|orders| orders.cl_ord_ids.contains_key(cl_ord_id))
+    }
+
+    /// Order id and last reported status of the order a ClOrdID refers to
+    ///
+    /// Only the latest ClOrdID of an order refers to it.
+    fn find_order(&self, cl_ord_id: &str) -> Option<(String, OrderStatus)> {
+        let state = self.gateway.state();
+        let orders = state.orders.get(&self.client)?;
+        let order_id = orders.cl_ord_ids.get(cl_ord_id)?;
+        let order = orders.orders.get(order_id)?;
+        (order.cl_ord_id == cl_ord_id).then(|| (order_id.clone(), order.status))
+    }
+
+    /// Resends the stored messages a ResendRequest asks for
+    ///
+    /// ExecutionReports and rejects go out again with PossDupFlag set; session
+    /// messages, and anything no longer stored, are skipped with gap fills.
+    async fn resend(&mut self, request: &FixMessage) -> Result<(), String> {
+        let range = request
+            .parse_required::<u64>(tag::BEGIN_SEQ_NO)
+            .and_then(|begin| Ok((begin, request.parse_required::<u64>(tag::END_SEQ_NO)?)));
+        let (begin, end) = match range {
+            Ok(range) => range,
+            Err(text) => {
+                self.reject(request, &text).await?;
+                return Ok(());
+            }
+        };
+        let last = self.store.next_sender_seq() - 1;
+        let end = if end == 0 { last } else { end.min(last) };
+
+        let mut gap_start = None;
+        for seq in begin.max(1)..=end {
+            let original = self
+                .store
+                .sent(seq)
+                .filter(|message| RESENT_TYPES.contains(&message.msg_type()))
+                .cloned();
+            let Some(original) = original else {
+                gap_start.get_or_insert(seq);
+                continue;
+            };
+            if let Some(start) = gap_start.take() {
+                self.gap_fill(start, seq).await?;
+            }
+            let mut header = self.header(seq);
+            header.push((tag::POSS_DUP_FLAG, "Y".to_string()));
+            if let Some(sent_at) = original.get(tag::SENDING_TIME) {
+                header.push((tag::ORIG_SENDING_TIME, sent_at.to_string()));
+            }
+            self.write(&original.with_header(header)).await?;
+        }
+        if let Some(start) = gap_start {
+            self.gap_fill(start, end + 1).await?;
+        }
+        Ok(())
+    }
+
+    /// Tells the client to skip sequence numbers `seq` to `new_seq - 1`
+    async fn gap_fill(&mut self, seq: u64, new_seq: u64) -> Result<(), String> {
+        let mut header = self.header(seq);
+        header.push((tag::POSS_DUP_FLAG, "Y".to_string()));
+        header.push((tag::ORIG_SENDING_TIME, fix_now()));
+        let message = FixMessage::new(msg_type::SEQUENCE_RESET)
+            .with(tag::GAP_FILL_FLAG, "Y")
+            .with(tag::NEW_SEQ_NO, new_seq)
+            .with_header(header);
+        self.write(&message).await
+    }
+
+    /// Asks for everything from the next expected message on, after `seq` arrived early
+    async fn request_resend(&mut self, seq: u64) -> Result<(), String> {
+        self.resend_until = seq;
+        let request = FixMessage::new(msg_type::RESEND_REQUEST)
+            .with(tag::BEGIN_SEQ_NO, self.store.next_target_seq())
+            .with(tag::END_SEQ_NO, 0);
+        self.send(request).await
+    }
+
+    /// Sends a Heartbeat when the session has been quiet, a TestRequest when
+    /// the client has, and logs out a client that does not answer
+    async fn check_heartbeat(&mut self) -> Result<bool, String> {
+        let Some(interval) = self.heartbeat else {
+            return Ok(true);
+        };
+        if self.last_sent.elapsed() >= interval {
+            self.send(FixMessage::new(msg_type::HEARTBEAT)).await?;
+        }
+
+        let silence = self.last_received.elapsed();
+        if self.test_request.is_some() {
+            if silence >= interval * 2 {
+                self.logout("Heartbeat timeout").await?;
+                return Ok(false);
+            }
+        } else if silence >= interval + interval / 5 {
+            let id = Uuid::new_v4().simple().to_string();
+            let request = FixMessage::new(msg_type::TEST_REQUEST).with(tag::TEST_REQ_ID, &id);
+            self.send(request).await?;
+            self.test_request = Some(id);
+        }
+        Ok(true)
+    }
+
+    /// Rejects a message that cannot be processed and keeps the session going
+    async fn reject(&mut self, message: &FixMessage, text: &str) -> Result<bool, String> {
+        let mut reject = FixMessage::new(msg_type::REJECT);
+        if let Some(seq) = message.seq_num() {
+            reject = reject.with(tag::REF_SEQ_NUM, seq);
+        }
+        let reject = reject
+            .with(tag::REF_MSG_TYPE, message.msg_type())
+            .with(tag::SESSION_REJECT_REASON, OTHER_REJECT_REASON)
+            .with(tag::TEXT, text);
+        self.send(reject).await?;
+        Ok(true)
+    }
+
+    async fn logout(&mut self, text: &str) -> Result<(), String> {
+        let logout = FixMessage::new(msg_type::LOGOUT).with(tag::TEXT, text);
+        self.send(logout).await
+    }
+
+    /// Sends a message with the next sequence number, storing it first
+    async fn send(&mut self, body: FixMessage) -> Result<(), String> {
+        let message = body.with_header(self.header(self.store.next_sender_seq()));
+        self.store.record_sent(&message)?;
+        self.write(&message).await
+    }
+
+    async fn write(&mut self, message: &FixMessage) -> Result<(), String> {
+        self.stream
+            .write_all(&message.encode())
+            .await
+            .map_err(|e| e.to_string())?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Standard header fields after MsgType for a message with sequence number `seq`
+    fn header(&self, seq: u64) -> Vec<(u32, String)> {
+        vec![
+            (tag::SENDER_COMP_ID, self.gateway.config.comp_id.clone()),
+            (tag::TARGET_COMP_ID, self.client.clone()),
+            (tag::MSG_SEQ_NUM, seq.to_string()),
+            (tag::SENDING_TIME, fix_now()),
+        ]
+    }
+}
+
+/// The fields of a NewOrderSingle
+struct NewOrder {
+    cl_ord_id: String,
+    pair: TradingPair,
+    side: OrderSide,
+    quantity: Amount,
+    /// Limit price; market orders have none
+    price: Option<Amount>,
+    options: OrderOptions,
+}
+
+impl NewOrder {
+    fn parse(message: &FixMessage) -> Result<Self, String> {
+        let cl_ord_id = message.require(tag::CL_ORD_ID)?.to_string();
+        let pair = parse_symbol(message.require(tag::SYMBOL)?)?;
+        let side = match message.require(tag::SIDE)? {
+            "1" => OrderSide::Buy,
+            "2" => OrderSide::Sell,
+            other => return Err(format!("Unsupported Side {}", other)),
+        };
+        let quantity = message.parse_required(tag::ORDER_QTY)?;
+        let price = match message.require(tag::ORD_TYPE)? {
+            "1" => None,
+            "2" => Some(message.parse_required(tag::PRICE)?),
+            other => return Err(format!("Unsupported OrdType {}", other)),
+        };
+        // Day orders rest until cancelled: the exchange trades around the clock
+        let time_in_force = match message.get(tag::TIME_IN_FORCE) {
+            None | Some("0") | Some("1") => TimeInForce::GoodTillCancelled,
+            Some("3") => TimeInForce::ImmediateOrCancel,
+            Some("4") => TimeInForce::FillOrKill,
+            Some("6") => {
+                let expire_time = message.require(tag::EXPIRE_TIME)?;
+                TimeInForce::GoodTillDate(parse_fix_time(expire_time)?)
+            }
+            Some(other) => return Err(format!("Unsupported TimeInForce {}", other)),
+        };
+        Ok(NewOrder {
+            cl_ord_id,
+            pair,
+            side,
+            quantity,
+            price,
+            options: OrderOptions {
+                time_in_force,
+                ..OrderOptions::default()
+            },
+        })
+    }
+
+    /// ExecutionReport rejecting the order
+    fn rejection(&self, text: &str) -> FixMessage {
+        let mut report = FixMessage::new(msg_type::EXECUTION_REPORT)
+            .with(tag::ORDER_ID, "NONE")
+            .with(tag::CL_ORD_ID, &self.cl_ord_id)
+            .with(tag::EXEC_ID, Uuid::new_v4().simple())
+            .with(tag::EXEC_TYPE, "8")
+            .with(tag::ORD_STATUS, "8")
+            .with(tag::SYMBOL, fix_symbol(&self.pair))
+            .with(tag::SIDE, fix_side(self.side))
+            .with(tag::ORD_TYPE, if self.price.is_some() { "2" } else { "1" })
+            .with(tag::ORDER_QTY, self.quantity);
+        if let Some(price) = self.price {
+            report = report.with(tag::PRICE, price);
+        }
+        report
+            .with(tag::LEAVES_QTY, 0)
+            .with(tag::CUM_QTY, 0)
+            .with(tag::AVG_PX, 0)
+            .with(tag::TRANSACT_TIME, fix_now())
+            .with(tag::TEXT, text)
+    }
+}
+
+/// Builds the OrderCancelReject for a cancel or replace request
+struct CancelReject {
+    cl_ord_id: String,
+    orig_cl_ord_id: String,
+    /// CxlRejResponseTo: 1 for a cancel, 2 for a cancel/replace
+    response_to: &'static str,
+}
+
+impl CancelReject {
+    /// `order` is the order id and last reported status, if the order is known
+    fn message(&self, order: Option<(&str, OrderStatus)>, text: &str) -> FixMessage {
+        let (order_id, status, reason) = match order {
+            Some((order_id, status)) => (order_id, ord_status(status), "0"), // too late
+            None => ("NONE", "8", "1"),                                      // unknown order
+        };
+        FixMessage::new(msg_type::ORDER_CANCEL_REJECT)
+            .with(tag::ORDER_ID, order_id)
+            .with(tag::CL_ORD_ID, &self.cl_ord_id)
+            .with(tag::ORIG_CL_ORD_ID, &self.orig_cl_ord_id)
+            .with(tag::ORD_STATUS, status)
+            .with(tag::CXL_REJ_RESPONSE_TO, self.response_to)
+            .with(tag::CXL_REJ_REASON, reason)
+            .with(tag::TEXT, text)
+    }
+}
+
+/// ExecutionReport of an order's current state, without ExecID
+fn execution_report(order: &OrderUpdate, cl_ord_id: &str, exec_type: &str) -> FixMessage {
+    let leaves = match order.status {
+        OrderStatus::Open | OrderStatus::PartiallyFilled => order.remaining_quantity,
+        _ => Amount::ZERO,
+    };
+    let mut report = FixMessage::new(msg_type::EXECUTION_REPORT)
+        .with(tag::ORDER_ID, &order.id)
+        .with(tag::CL_ORD_ID, cl_ord_id)
+        .with(tag::EXEC_TYPE, exec_type)
+        .with(tag::ORD_STATUS, ord_status(order.status))
+        .with(tag::SYMBOL, fix_symbol(&order.pair))
+        .with(tag::SIDE, fix_side(order.side))
+        .with(tag::ORDER_QTY, order.quantity);
+    report = match order.order_type {
+        OrderType::Limit => report
+            .with(tag::ORD_TYPE, "2")
+            .with(tag::PRICE, order.price),
+        OrderType::Market => report.with(tag::ORD_TYPE, "1"),
+    };
+    report
+        .with(tag::LEAVES_QTY, leaves)
+        .with(tag::CUM_QTY, order.filled_quantity)
+        .with(tag::AVG_PX, order.average_price)
+}
+
+/// OrdStatus of an order status
+fn ord_status(status: OrderStatus) -> &'static str {
+    match status {
+        OrderStatus::Open | OrderStatus::Triggered => "0",
+        OrderStatus::PartiallyFilled => "1",
+        OrderStatus::Filled => "2",
+        OrderStatus::Cancelled => "4",
+        OrderStatus::Pending => "A",
+        OrderStatus::Expired => "C",
+    }
+}
+
+fn fix_side(side: OrderSide) -> &'static str {
+    match side {
+        OrderSide::Buy => "1",
+        OrderSide::Sell => "2",
+    }
+}
+
+/// A pair as FIX clients name it, e.g. `BTC/USDT`
+fn fix_symbol(pair: &TradingPair) -> String {
+    format!("{}/{}", pair.base, pair.quote)
+}
+
+fn parse_symbol(symbol: &str) -> Result<TradingPair, String> {
+    let (base, quote) = symbol
+        .split_once('/')
+        .ok_or_else(|| format!("Invalid Symbol {}", symbol))?;
+    Ok(TradingPair::new(base, quote))
+}
+
+/// Format of FIX UTCTimestamp fields
+const FIX_TIME_FORMAT: &str = "%Y%m%d-%H:%M:%S%.3f";
+
+fn fix_now() -> String {
+    Utc::now().format(FIX_TIME_FORMAT).to_string()
+}
+
+/// A Unix timestamp as a UTCTimestamp
+fn fix_time(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .format(FIX_TIME_FORMAT)
+        .to_string()
+}
+
+/// A UTCTimestamp, with or without milliseconds, as a Unix timestamp
+fn parse_fix_time(value: &str) -> Result<i64, String> {
+    NaiveDateTime::parse_from_str(value, "%Y%m%d-%H:%M:%S%.f")
+        .map(|time| time.and_utc().timestamp())
+        .map_err(|_| format!("Invalid UTCTimestamp {}", value))
+}
+
+/// Reads from the stream until a whole message has arrived
+///
+/// Returns `None` if the connection closes first. Fails as soon as the
+/// message turns out longer than [`crate::fix::MAX_MESSAGE_BYTES`], before buffering it.
+async fn read_message(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+) -> Result<Option<FixMessage>, String> {
+    loop {
+        if let Some(length) = frame_length(buffer)? {
+            let frame: Vec<u8> = buffer.drain(..length).collect();
+            return FixMessage::decode(&frame).map(Some);
+        }
+        let read = stream.read_buf(buffer).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GATEWAY: &str = "EXCHANGE";
+
+    /// FIX client driving a session over TCP
+    struct Initiator {
+        stream: TcpStream,
+        buffer: Vec<u8>,
+        comp_id: String,
+        password: String,
+        next_seq: u64,
+    }
+
+    impl Initiator {
+        async fn connect(address: &str, comp_id: &str, next_seq: u64) -> Self {
+            Initiator {
+                stream: TcpStream::connect(address).await.unwrap(),
+                buffer: vec![],
+                comp_id: comp_id.to_string(),
+                password: password(comp_id),
+                next_seq,
+            }
+        }
+
+        /// Logs on and returns the gateway's Logon
+        async fn logon(&mut self, heartbeat: u64, reset: bool) -> FixMessage {
+            let mut logon = FixMessage::new(msg_type::LOGON)
+                .with(tag::ENCRYPT_METHOD, 0)
+                .with(tag::HEART_BT_INT, heartbeat);
+            if reset {
+                logon = logon.with(tag::RESET_SEQ_NUM_FLAG, "Y");
+            }
+            self.send(logon.with(tag::PASSWORD, self.password.clone())).await;
+            let reply = self.receive().await;
+            assert_eq!(reply.msg_type(), msg_type::LOGON);
+            reply
+        }
+
+        async fn send(&mut self, body: FixMessage) {
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            self.send_with_seq(body, seq).await;
+        }
+
+        async fn send_with_seq(&mut self, body: FixMessage, seq: u64) {
+            let message = body.with_header(vec![
+                (tag::SENDER_COMP_ID, self.comp_id.clone()),
+                (tag::TARGET_COMP_ID, GATEWAY.to_string()),
+                (tag::MSG_SEQ_NUM, seq.to_string()),
+                (tag::SENDING_TIME, fix_now()),
+            ]);
+            self.stream.write_all(&message.encode()).await.unwrap();
+        }
+
+        async fn receive(&mut self) -> FixMessage {
+            let read = read_message(&mut self.stream, &mut self.buffer);
+            time::timeout(Duration::from_secs(5), read)
+                .await
+                .expect("no message from the gateway")
+                .unwrap()
+                .expect("connection closed")
+        }
+    }
+
+    fn new_order(cl_ord_id: &str, side: &str, price: Option<i64>, quantity: i64) -> FixMessage {
+        let message = FixMessage::new(msg_type::NEW_ORDER_SINGLE)
+            .with(tag::CL_ORD_ID, cl_ord_id)
+            .with(tag::SYMBOL, "BTC/USDT")
+            .with(tag::SIDE, side)
+            .with(tag::ORDER_QTY, quantity);
+        match price {
+            Some(price) => message.with(tag::ORD_TYPE, 2).with(tag::PRICE, price),
+            None => message.with(tag::ORD_TYPE, 1),
+        }
+    }
+
+    fn cancel(cl_ord_id: &str, orig_cl_ord_id: &str) -> FixMessage {
+        FixMessage::new(msg_type::ORDER_CANCEL_REQUEST)
+            .with(tag::CL_ORD_ID, cl_ord_id)
+            .with(tag::ORIG_CL_ORD_ID, orig_cl_ord_id)
+            .with(tag::SYMBOL, "BTC/USDT")
+            .with(tag::SIDE, 1)
+    }
+
+    fn amount(message: &FixMessage, tag: u32) -> Amount {
+        message.parse_required(tag).unwrap()
+    }
+
+    /// Password a test client is configured with
+    fn password(comp_id: &str) -> String {
+        format!("{}-secret", comp_id.to_lowercase())
+    }
+
+    fn temp_store() -> PathBuf {
+        std::env::temp_dir().join(format!("fix-store-{}", Uuid::new_v4()))
+    }
+
+    /// An exchange where ALICE holds USDT and BOB holds BTC
+    fn setup(store_dir: PathBuf) -> (SharedExchange, FixConfig) {
+        let mut exchange = Exchange::new("FixExchange");
+        let alice = exchange.create_wallet("Alice");
+        let bob = exchange.create_wallet("Bob");
+        exchange
+            .deposit(&alice, "USDT", Amount::from(100000))
+            .unwrap();
+        exchange.deposit(&bob, "BTC", Amount::from(10)).unwrap();
+        let config = FixConfig {
+            comp_id: GATEWAY.to_string(),
+            store_dir,
+            clients: [("ALICE", alice), ("BOB", bob)]
+                .into_iter()
+                .map(|(comp_id, address)| {
+                    let client = FixClient {
+                        address,
+                        password: password(comp_id),
+                    };
+                    (comp_id.to_string(), client)
+                })
+                .collect(),
+        };
+        (Arc::new(Mutex::new(exchange)), config)
+    }
+
+    /// Serves a gateway on a free port and returns its address
+    async fn start(gateway: FixGateway) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { gateway.serve(listener).await.unwrap() });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_orders_fills_cancels_and_replaces() {
+        let store_dir = temp_store();
+        let (exchange, config) = setup(store_dir.clone());
+        let address = start(FixGateway::new(exchange, EventHub::new(), config)).await;
+
+        let mut alice = Initiator::connect(&address, "ALICE", 1).await;
+        let logon = alice.logon(30, true).await;
+        assert_eq!(logon.get(tag::MSG_SEQ_NUM), Some("1"));
+        assert!(logon.flag(tag::RESET_SEQ_NUM_FLAG));
+
+        alice.send(new_order("a1", "1", Some(100), 2)).await;
+        let report = alice.receive().await;
+        assert_eq!(report.msg_type(), msg_type::EXECUTION_REPORT);
+        assert_eq!(report.get(tag::CL_ORD_ID), Some("a1"));
+        assert_eq!(report.get(tag::EXEC_TYPE), Some("0"));
+        assert_eq!(report.get(tag::ORD_STATUS), Some("0"));
+        assert_eq!(amount(&report, tag::LEAVES_QTY), Amount::from(2));
+        let order_id = report.require(tag::ORDER_ID).unwrap().to_string();
+
+        // A market sell from another session fills half of it
+        let mut bob = Initiator::connect(&address, "BOB", 1).await;
+        bob.logon(30, true).await;
+        bob.send(new_order("b1", "2", None, 1)).await;
+        let report = loop {
+            let report = bob.receive().await;
+            if report.get(tag::EXEC_TYPE) == Some("F") {
+                break report;
+            }
+        };
+        assert_eq!(report.get(tag::ORD_STATUS), Some("2"));
+        assert_eq!(report.get(tag::ORD_TYPE), Some("1"));
+
+        let fill = alice.receive().await;
+        assert_eq!(fill.get(tag::EXEC_TYPE), Some("F"));
+        assert_eq!(fill.get(tag::ORD_STATUS), Some("1"));
+        assert_eq!(fill.get(tag::ORDER_ID), Some(order_id.as_str()));
+        assert_eq!(amount(&fill, tag::LAST_QTY), Amount::from(1));
+        assert_eq!(amount(&fill, tag::LAST_PX), Amount::from(100));
+        assert_eq!(amount(&fill, tag::CUM_QTY), Amount::from(1));
+        assert_eq!(amount(&fill, tag::LEAVES_QTY), Amount::from(1));
+        assert_eq!(amount(&fill, tag::AVG_PX), Amount::from(100));
+        assert_eq!(fill.get(tag::EXEC_ID), report.get(tag::EXEC_ID));
+
+        let replace = FixMessage::new(msg_type::ORDER_CANCEL_REPLACE_REQUEST)
+            .with(tag::CL_ORD_ID, "a2")
+            .with(tag::ORIG_CL_ORD_ID, "a1")
+            .with(tag::SYMBOL, "BTC/USDT")
+            .with(tag::SIDE, 1)
+            .with(tag::ORD_TYPE, 2)
+            .with(tag::PRICE, 99)
+            .with(tag::ORDER_QTY, 3);
+        alice.send(replace).await;
+        let report = alice.receive().await;
+        assert_eq!(report.get(tag::EXEC_TYPE), Some("5"));
+        assert_eq!(report.get(tag::CL_ORD_ID), Some("a2"));
+        assert_eq!(report.get(tag::ORIG_CL_ORD_ID), Some("a1"));
+        assert_eq!(report.get(tag::ORD_STATUS), Some("1"));
+        assert_eq!(amount(&report, tag::PRICE), Amount::from(99));
+        assert_eq!(amount(&report, tag::ORDER_QTY), Amount::from(3));
+        assert_eq!(amount(&report, tag::LEAVES_QTY), Amount::from(2));
+
+        // The replaced ClOrdID no longer refers to the order
+        alice.send(cancel("a3", "a1")).await;
+        let reject = alice.receive().await;
+        assert_eq!(reject.msg_type(), msg_type::ORDER_CANCEL_REJECT);
+        assert_eq!(reject.get(tag::CXL_REJ_REASON), Some("1"));
+        assert_eq!(reject.get(tag::CXL_REJ_RESPONSE_TO), Some("1"));
+
+        alice.send(cancel("a4", "a2")).await;
+        let report = alice.receive().await;
+        assert_eq!(report.get(tag::EXEC_TYPE), Some("4"));
+        assert_eq!(report.get(tag::ORD_STATUS), Some("4"));
+        assert_eq!(report.get(tag::CL_ORD_ID), Some("a4"));
+        assert_eq!(report.get(tag::ORIG_CL_ORD_ID), Some("a2"));
+        assert_eq!(amount(&report, tag::LEAVES_QTY), Amount::ZERO);
+        assert_eq!(amount(&report, tag::CUM_QTY), Amount::from(1));
+
+        // Cancelling again is too late
+        alice.send(cancel("a5", "a2")).await;
+        let reject = alice.receive().await;
+        assert_eq!(reject.msg_type(), msg_type::ORDER_CANCEL_REJECT);
+        assert_eq!(reject.get(tag::CXL_REJ_REASON), Some("0"));
+        assert_eq!(reject.get(tag::ORD_STATUS), Some("4"));
+        assert_eq!(reject.get(tag::ORDER_ID), Some(order_id.as_str()));
+
+        let mut unknown_pair = new_order("a6", "1", Some(100), 1);
+        unknown_pair.set(tag::SYMBOL, "DOGE/USDT");
+        alice.send(unknown_pair).await;
+        let report = alice.receive().await;
+        assert_eq!(report.get(tag::EXEC_TYPE), Some("8"));
+        assert_eq!(report.get(tag::ORD_STATUS), Some("8"));
+        assert_eq!(report.get(tag::CL_ORD_ID), Some("a6"));
+        assert!(report.get(tag::TEXT).is_some());
+
+        alice.send(new_order("a1", "1", Some(100), 1)).await;
+        let report = alice.receive().await;
+        assert_eq!(report.get(tag::EXEC_TYPE), Some("8"));
+        assert_eq!(report.get(tag::TEXT), Some("Duplicate ClOrdID"));
+
+        let missing_quantity = FixMessage::new(msg_type::NEW_ORDER_SINGLE)
+            .with(tag::CL_ORD_ID, "a7")
+            .with(tag::SYMBOL, "BTC/USDT")
+            .with(tag::SIDE, 1)
+            .with(tag::ORD_TYPE, 1);
+        alice.send(missing_quantity).await;
+        let reject = alice.receive().await;
+        assert_eq!(reject.msg_type(), msg_type::REJECT);
+        assert_eq!(
+            reject.get(tag::REF_MSG_TYPE),
+            Some(msg_type::NEW_ORDER_SINGLE)
+        );
+        assert_eq!(
+            reject.parse_required::<u64>(tag::REF_SEQ_NUM).unwrap(),
+            alice.next_seq - 1
+        );
+
+        alice.send(FixMessage::new(msg_type::LOGOUT)).await;
+        assert_eq!(alice.receive().await.msg_type(), msg_type::LOGOUT);
+        fs::remove_dir_all(store_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sequence_numbers_survive_restart_and_gaps_are_recovered() {
+        let store_dir = temp_store();
+        let (exchange, config) = setup(store_dir.clone());
+        let events = EventHub::new();
+        let gateway = FixGateway::new(exchange.clone(), events.clone(), config.clone());
+        let address = start(gateway).await;
+
+        let mut alice = Initiator::connect(&address, "ALICE", 1).await;
+        alice.logon(30, true).await;
+        alice.send(new_order("a1", "1", Some(100), 1)).await;
+        let report = alice.receive().await;
+        assert_eq!(report.get(tag::MSG_SEQ_NUM), Some("2"));
+        alice.send(FixMessage::new(msg_type::LOGOUT)).await;
+        let logout = alice.receive().await;
+        assert_eq!(logout.get(tag::MSG_SEQ_NUM), Some("3"));
+
+        // A new gateway on the same store continues the session
+        let address = start(FixGateway::new(exchange, events, config)).await;
+        let mut alice = Initiator::connect(&address, "ALICE", alice.next_seq).await;
+        let logon = alice.logon(30, false).await;
+        assert_eq!(logon.get(tag::MSG_SEQ_NUM), Some("4"));
+
+        let resend = FixMessage::new(msg_type::RESEND_REQUEST)
+            .with(tag::BEGIN_SEQ_NO, 1)
+            .with(tag::END_SEQ_NO, 0);
+        alice.send(resend).await;
+        let gap_fill = alice.receive().await;
+        assert_eq!(gap_fill.msg_type(), msg_type::SEQUENCE_RESET);
+        assert_eq!(gap_fill.get(tag::MSG_SEQ_NUM), Some("1"));
+        assert_eq!(gap_fill.get(tag::NEW_SEQ_NO), Some("2"));
+        assert!(gap_fill.flag(tag::GAP_FILL_FLAG));
+        let resent = alice.receive().await;
+        assert_eq!(resent.msg_type(), msg_type::EXECUTION_REPORT);
+        assert_eq!(resent.get(tag::MSG_SEQ_NUM), Some("2"));
+        assert!(resent.flag(tag::POSS_DUP_FLAG));
+        assert_eq!(
+            resent.get(tag::ORIG_SENDING_TIME),
+            report.get(tag::SENDING_TIME)
+        );
+        assert_eq!(resent.get(tag::EXEC_ID), report.get(tag::EXEC_ID));
+        let gap_fill = alice.receive().await;
+        assert_eq!(gap_fill.get(tag::MSG_SEQ_NUM), Some("3"));
+        assert_eq!(gap_fill.get(tag::NEW_SEQ_NO), Some("5"));
+
+        // Messages 6 and 7 are lost, so the gateway asks for them again
+        let test_request = FixMessage::new(msg_type::TEST_REQUEST).with(tag::TEST_REQ_ID, "t1");
+        alice.send_with_seq(test_request.clone(), 8).await;
+        let request = alice.receive().await;
+        assert_eq!(request.msg_type(), msg_type::RESEND_REQUEST);
+        assert_eq!(request.get(tag::BEGIN_SEQ_NO), Some("6"));
+        assert_eq!(request.get(tag::END_SEQ_NO), Some("0"));
+
+        let gap_fill = FixMessage::new(msg_type::SEQUENCE_RESET)
+            .with(tag::GAP_FILL_FLAG, "Y")
+            .with(tag::NEW_SEQ_NO, 8);
+        alice.send_with_seq(gap_fill, 6).await;
+        alice.send_with_seq(test_request, 8).await;
+        let heartbeat = alice.receive().await;
+        assert_eq!(heartbeat.msg_type(), msg_type::HEARTBEAT);
+        assert_eq!(heartbeat.get(tag::TEST_REQ_ID), Some("t1"));
+
+        let store = SessionStore::open(&store_dir, "ALICE").unwrap();
+        assert_eq!(store.next_sender_seq(), 7);
+        assert_eq!(store.next_target_seq(), 9);
+        fs::remove_dir_all(store_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_logon_requires_the_clients_password() {
+        let store_dir = temp_store();
+        let (exchange, config) = setup(store_dir.clone());
+        let lines = Arc::new(Mutex::new(Vec::<String>::new()));
+        let logged = lines.clone();
+        let gateway = FixGateway::new(exchange, EventHub::new(), config)
+            .with_log(Arc::new(move |line| logged.lock().unwrap().push(line.to_string())));
+        let address = start(gateway).await;
+
+        for password in ["", "bob-secret", "alice-secre"] {
+            let mut alice = Initiator::connect(&address, "ALICE", 1).await;
+            let logon = FixMessage::new(msg_type::LOGON)
+                .with(tag::ENCRYPT_METHOD, 0)
+                .with(tag::HEART_BT_INT, 30);
+            alice.send(logon.with(tag::PASSWORD, password)).await;
+            // The gateway hangs up without answering
+            let read = read_message(&mut alice.stream, &mut alice.buffer);
+            let reply = time::timeout(Duration::from_secs(5), read).await.unwrap();
+            assert_eq!(reply, Ok(None));
+        }
+
+        let mut alice = Initiator::connect(&address, "ALICE", 1).await;
+        alice.logon(30, true).await;
+        // Each refused logon was reported
+        assert_eq!(
+            *lines.lock().unwrap(),
+            ["FIX logon from ALICE with a wrong password"; 3]
+        );
+        fs::remove_dir_all(store_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_is_tested_then_logged_out() {
+        let store_dir = temp_store();
+        let (exchange, config) = setup(store_dir.clone());
+        let address = start(FixGateway::new(exchange, EventHub::new(), config)).await;
+
+        let mut bob = Initiator::connect(&address, "BOB", 1).await;
+        bob.logon(1, true).await;
+        assert_eq!(bob.receive().await.msg_type(), msg_type::HEARTBEAT);
+        let test_request = bob.receive().await;
+        assert_eq!(test_request.msg_type(), msg_type::TEST_REQUEST);
+        assert!(test_request.get(tag::TEST_REQ_ID).is_some());
+
+        // Without an answer the gateway gives up on the session
+        let logout = bob.receive().await;
+        assert_eq!(logout.msg_type(), msg_type::LOGOUT);
+        assert_eq!(logout.get(tag::TEXT), Some("Heartbeat timeout"));
+        fs::remove_dir_all(store_dir).unwrap();
+    }
+
+    #[test]
+    fn test_clients_must_trade_for_known_wallets() {
+        let store_dir = temp_store();
+        let (exchange, mut config) = setup(store_dir);
+        let exchange = exchange.lock().unwrap();
+        assert!(config.check_clients(&exchange).is_ok());
+
+        let client = FixClient {
+            address: "nowhere".to_string(),
+            password: password("CAROL"),
+        };
+        config.clients.insert("CAROL".to_string(), client);
+        assert_eq!(
+            config.check_clients(&exchange),
+            Err("FIX client CAROL trades for unknown wallet nowhere".to_string())
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use crate::amount::Amount;
+use crate::clock::{Clock, ManualClock, SeededIds};
+use crate::exchange::{Exchange, ExchangeKeys};
+use crate::keys;
+use crate::order::{
+    MarketOrderSize, OrderOptions, OrderSide, SelfTradePrevention, StopOrderKind, TradingPair,
+};
+
+/// A state-changing exchange command, as written to the journal
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    /// Creates a wallet around a key generated when the command was made
+    CreateWallet {
+        owner: String,
+        #[serde(with = "keys::signing_key_hex")]
+        signing_key: SigningKey,
+    },
+    Deposit {
+        address: String,
+        currency: String,
+        amount: Amount,
+    },
+    Withdraw {
+        address: String,
+        currency: String,
+        amount: Amount,
+    },
+    PlaceOrder {
+        user_address: String,
+        pair: TradingPair,
+        side: OrderSide,
+        price: Amount,
+        quantity: Amount,
+        options: OrderOptions,
+    },
+    PlaceMarketOrder {
+        user_address: String,
+        pair: TradingPair,
+        side: OrderSide,
+        size: MarketOrderSize,
+        max_slippage: Option<Amount>,
+    },
+    PlaceStopOrder {
+        user_address: String,
+        pair: TradingPair,
+        side: OrderSide,
+        kind: StopOrderKind,
+        quantity: Amount,
+    },
+    AmendOrder {
+        order_id: String,
+        pair: TradingPair,
+        price: Amount,
+        quantity: Amount,
+    },
+    CancelOrder {
+        order_id: String,
+        pair: TradingPair,
+    },
+    ExpireOrders {
+        now: i64,
+    },
+    SetSelfTradePrevention {
+        address: String,
+        mode: SelfTradePrevention,
+    },
+    MineTransactions {
+        miner_address: String,
+    },
+}
+
+impl Command {
+    /// Creates a wallet for `owner` with a freshly generated key
+    pub fn create_wallet(owner: &str) -> Self {
+        Command::CreateWallet {
+            owner: owner.to_string(),
+            signing_key: keys::generate_signing_key(),
+        }
+    }
+
+    /// Runs the command against an exchange
+    ///
+    /// Returns the address or order id the command created, if any.
+    pub fn apply(&self, exchange: &mut Exchange) -> Result<Option<String>, String> {
+        match self.clone() {
+            Command::CreateWallet { owner, signing_key } => {
+                Ok(Some(exchange.create_wallet_with_key(&owner, signing_key)))
+            }
+            Command::Deposit {
+                address,
+                currency,
+                amount,
+            } => exchange.deposit(&address, &currency, amount).map(|_| None),
+            Command::Withdraw {
+                address,
+                currency,
+                amount,
+            } => exchange.withdraw(&address, &currency, amount).map(|_| None),
+            Command::PlaceOrder {
+                user_address,
+                pair,
+                side,
+                price,
+                quantity,
+                options,
+            } => exchange
+                .place_order_with_options(user_address, pair, side, price, quantity, options)
+                .map(Some),
+            Command::PlaceMarketOrder {
+                user_address,
+                pair,
+                side,
+                size,
+                max_slippage,
+            } => exchange
+                .place_market_order(user_address, pair, side, size, max_slippage)
+                .map(Some),
+            Command::PlaceStopOrder {
+                user_address,
+                pair,
+                side,
+                kind,
+                quantity,
+            } => exchange
+                .place_stop_order(user_address, pair, side, kind, quantity)
+                .map(Some),
+            Command::AmendOrder {
+                order_id,
+                pair,
+                price,
+                quantity,
+            } => exchange
+                .amend_order(&order_id, &pair, price, quantity)
+                .map(|_| None),
+            Command::CancelOrder { order_id, pair } => {
+                exchange.cancel_order(&order_id, &pair).map(|_| None)
+            }
+            Command::ExpireOrders { now } => exchange.expire_orders(now).map(|_| None),
+            Command::SetSelfTradePrevention { address, mode } => {
+                exchange.set_self_trade_prevention(&address, mode);
+                Ok(None)
+            }
+            Command::MineTransactions { miner_address } => {
+                exchange.mine_transactions(&miner_address);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// First line of a journal: what is needed to recreate the empty exchange
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalHeader {
+    pub name: String,
+    /// Seed of the exchange's id generator
+    pub seed: u64,
+    /// Time the exchange was created at
+    pub created_at: i64,
+    pub keys: ExchangeKeys,
+}
+
+/// One journaled command and the time it was received
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub timestamp: i64,
+    pub command: Command,
+}
+
+/// An exchange that writes every command to an append-only journal before running it
+///
+/// The journal is a JSON-lines file: the header, then one entry per command.
+/// The exchange runs on a seeded id generator and on a clock that is set to
+/// each entry's timestamp, so replaying the journal rebuilds it exactly, ids
+/// and timestamps included. Commands that fail are journaled too; they fail
+/// the same way on replay.
+///
+/// Changes made directly to the exchange's public fields (fee schedule, asset
+/// scales, ...) bypass the journal and are not replayed.
+///
+/// Like a snapshot, the journal holds the secret keys of the exchange and of
+/// every wallet, so it must be kept private.
+#[derive(Debug)]
+pub struct JournaledExchange {
+    exchange: Exchange,
+    file: File,
+    path: PathBuf,
+    clock: ManualClock,
+    wall_clock: Box<dyn Clock>,
+}
+
+impl JournaledExchange {
+    /// Starts a new exchange with a new journal at `path`
+    ///
+    /// Fails if the file already exists.
+    pub fn create(
+        path: impl AsRef<Path>,
+        name: &str,
+        wall_clock: Box<dyn Clock>,
+    ) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let header = JournalHeader {
+            name: name.to_string(),
+            seed: Uuid::new_v4().as_u64_pair().0,
+            created_at: wall_clock.now(),
+            keys: ExchangeKeys::generate(),
+        };
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("Failed to create journal {}: {}", path.display(), e))?;
+        write_line(&mut file, &header)?;
+
+        let (exchange, clock) = new_exchange(&header);
+        Ok(JournaledExchange {
+            exchange,
+            file,
+            path,
+            clock,
+            wall_clock,
+        })
+    }
+
+    /// Rebuilds the exchange from the journal at `path` and keeps appending to it
+    pub fn open(path: impl AsRef<Path>, wall_clock: Box<dyn Clock>) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let (header, entries, valid_len) = read_journal(&path)?;
+        let (mut exchange, clock) = new_exchange(&header);
+        for entry in &entries {
+            clock.set(entry.timestamp);
+            let _ = entry.command.apply(&mut exchange);
+        }
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("Failed to open journal {}: {}", path.display(), e))?;
+        // Drop a command that was cut off while being written
+        file.set_len(valid_len)
+            .map_err(|e| format!("Failed to repair journal {}: {}", path.display(), e))?;
+
+        Ok(JournaledExchange {
+            exchange,
+            file,
+            path,
+            clock,
+            wall_clock,
+        })
+    }
+
+    /// Journals a command, then runs it
+    pub fn execute(&mut self, command: Command) -> Result<Option<String>, String> {
+        let entry = JournalEntry {
+            timestamp: self.wall_clock.now(),
+            command,
+        };
+        write_line(&mut self.file, &entry)?;
+
+        self.clock.set(entry.timestamp);
+        entry.command.apply(&mut self.exchange)
+    }
+
+    /// The exchange, for queries
+    pub fn exchange(&self) -> &Exchange {
+        &self.exchange
+    }
+
+    /// Path of the journal file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Rebuilds the exchange recorded in the journal at `path`
+pub fn replay(path: impl AsRef<Path>) -> Result<Exchange, String> {
+    let (header, entries, _) = read_journal(path.as_ref())?;
+    let (mut exchange, clock) = new_exchange(&header);
+    for entry in &entries {
+        clock.set(entry.timestamp);
+        let _ = entry.command.apply(&mut exchange);
+    }
+    Ok(exchange)
+}
+
+/// Creates the empty exchange described by a journal header
+fn new_exchange(header: &JournalHeader) -> (Exchange, ManualClock) {
+    let clock = ManualClock::new(header.created_at);
+    let exchange = Exchange::with_keys(
+        &header.name,
+        Box::new(clock.clone()),
+        Box::new(SeededIds::new(header.seed)),
+        header.keys.clone(),
+    );
+    (exchange, clock)
+}
+
+/// Reads a journal's header and entries, and the length of its complete lines
+///
+/// A last line without a newline is a write that was cut off and is ignored.
+fn read_journal(path: &Path) -> Result<(JournalHeader, Vec<JournalEntry>, u64), String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read journal {}: {}", path.display(), e))?;
+    let valid_len = contents.rfind('\n').map_or(0, |i| i + 1);
+    let mut lines = contents[..valid_len].lines();
+
+    let header_line = lines.next().ok_or("Journal has no header")?;
+    let header: JournalHeader = serde_json::from_str(header_line)
+        .map_err(|e| format!("Invalid journal header: {}", e))?;
+    let entries = lines
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| format!("Invalid journal entry on line {}: {}", i + 2, e))
+        })
+        .collect::<Result<Vec<JournalEntry>, String>>()?;
+    Ok((header, entries, valid_len as u64))
+}
+
+/// Appends one JSON line and flushes it to disk
+fn write_line<T: Serialize>(file: &mut File, value: &T) -> Result<(), String> {
+    let mut line = serde_json::to_string(value).map_err(|e| e.to_string())?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+        .and_then(|_| file.sync_data())
+        .map_err(|e| format!("Failed to write journal: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_journal(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("journal-{}-{}.jsonl", name, Uuid::new_v4()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn json<T: Serialize>(value: &T) -> serde_json::Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    /// Checks that two exchanges hold the same state
+    fn assert_same_state(a: &Exchange, b: &Exchange) {
+        assert_eq!(json(&a.wallet_manager), json(&b.wallet_manager));
+        assert_eq!(json(&a.trades), json(&b.trades));
+        assert_eq!(json(&a.blockchain), json(&b.blockchain));
+        assert_eq!(json(&a.stop_orders), json(&b.stop_orders));
+        for pair in &a.supported_pairs {
+            let book_a = a.get_order_book(pair).unwrap();
+            let book_b = b.get_order_book(pair).unwrap();
+            assert_eq!(json(book_a), json(book_b));
+        }
+    }
+
+    fn place(user: &str, side: OrderSide, price: i64, quantity: i64) -> Command {
+        Command::PlaceOrder {
+            user_address: user.to_string(),
+            pair: TradingPair::new("BTC", "USDT"),
+            side,
+            price: Amount::from(price),
+            quantity: Amount::from(quantity),
+            options: OrderOptions::default(),
+        }
+    }
+
+    /// Runs a session with wallets, deposits, trades, a cancel and a mined block
+    fn run_session(journaled: &mut JournaledExchange, clock: &ManualClock) -> (String, String) {
+        let mut create = |owner: &str| {
+            journaled
+                .execute(Command::create_wallet(owner))
+                .unwrap()
+                .unwrap()
+        };
+        let alice = create("Alice");
+        let bob = create("Bob");
+
+        for (address, currency, amount) in [(&alice, "USDT", 100000), (&bob, "BTC", 3)] {
+            journaled
+                .execute(Command::Deposit {
+                    address: address.clone(),
+                    currency: currency.to_string(),
+                    amount: Amount::from(amount),
+                })
+                .unwrap();
+        }
+
+        clock.advance(61);
+        journaled.execute(place(&bob, OrderSide::Sell, 30000, 2)).unwrap();
+        let bid = journaled
+            .execute(place(&alice, OrderSide::Buy, 29000, 1))
+            .unwrap()
+            .unwrap();
+        clock.advance(5);
+        journaled.execute(place(&alice, OrderSide::Buy, 30000, 1)).unwrap();
+        journaled
+            .execute(Command::CancelOrder {
+                order_id: bid,
+                pair: TradingPair::new("BTC", "USDT"),
+            })
+            .unwrap();
+
+        // A failing command is journaled and fails again on replay
+        let overdraw = journaled.execute(Command::Withdraw {
+            address: bob.clone(),
+            currency: "BTC".to_string(),
+            amount: Amount::from(5),
+        });
+        assert!(overdraw.is_err());
+
+        journaled
+            .execute(Command::MineTransactions {
+                miner_address: bob.clone(),
+            })
+            .unwrap();
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_replay_rebuilds_identical_exchange() {
+        let path = temp_journal("replay");
+        let clock = ManualClock::new(1_700_000_000);
+        let mut journaled =
+            JournaledExchange::create(&path, "Journaled", Box::new(clock.clone())).unwrap();
+        let (alice, bob) = run_session(&mut journaled, &clock);
+
+        let live = journaled.exchange();
+        assert_eq!(live.trades.len(), 1);
+        assert_eq!(live.trades[0].timestamp, 1_700_000_066);
+        assert_eq!(live.get_balance(&alice, "BTC"), Amount::from(1));
+        assert_eq!(live.blockchain.chain.len(), 2);
+
+        let replayed = replay(&path).unwrap();
+        assert_same_state(live, &replayed);
+        assert_eq!(replayed.trades[0].id, live.trades[0].id);
+        assert_eq!(replayed.get_balance(&bob, "USDT"), Amount::from(30000));
+        assert!(replayed.blockchain.is_valid());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_continues_journal() {
+        let path = temp_journal("open");
+        let clock = ManualClock::new(1_700_000_000);
+        let mut journaled =
+            JournaledExchange::create(&path, "Journaled", Box::new(clock.clone())).unwrap();
+        let (alice, _) = run_session(&mut journaled, &clock);
+        drop(journaled);
+
+        // Simulate a crash in the middle of writing a command
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"timestamp":17"#).unwrap();
+
+        let mut reopened = JournaledExchange::open(&path, Box::new(clock.clone())).unwrap();
+        assert_eq!(reopened.exchange().get_balance(&alice, "BTC"), Amount::from(1));
+        let carol = reopened
+            .execute(Command::create_wallet("Carol"))
+            .unwrap()
+            .unwrap();
+
+        let replayed = replay(&path).unwrap();
+        assert_same_state(reopened.exchange(), &replayed);
+        assert!(replayed.wallet_manager.get_wallet(&carol).is_some());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_create_refuses_existing_journal() {
+        let path = temp_journal("exists");
+        JournaledExchange::create(&path, "A", Box::new(ManualClock::new(0))).unwrap();
+        assert!(JournaledExchange::create(&path, "B", Box::new(ManualClock::new(0))).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
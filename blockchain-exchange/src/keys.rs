@@ -0,0 +1,94 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+
+/// Generates a signing key from the operating system's random number generator
+///
+/// Keys are not derived from anything, so they can only be recovered from
+/// wherever they are persisted: a snapshot or a journal.
+pub fn generate_signing_key() -> SigningKey {
+    SigningKey::generate(&mut OsRng)
+}
+
+/// Address of a public key: the first 40 hex digits of its SHA-256 hash
+pub fn address_of(key: &VerifyingKey) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))[..40].to_string()
+}
+
+/// Signs a message and returns the signature as hex
+pub fn sign(key: &SigningKey, message: &[u8]) -> String {
+    hex::encode(key.sign(message).to_bytes())
+}
+
+/// Parses a hex public key
+pub fn parse_public_key(public_key: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = hex::decode(public_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("Invalid public key {}", public_key))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| format!("Invalid public key {}", public_key))
+}
+
+/// Checks a hex signature of a message by a hex public key
+pub fn verify(public_key: &str, message: &[u8], signature: &str) -> Result<(), String> {
+    let key = parse_public_key(public_key)?;
+    let bytes: [u8; 64] = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or("Malformed signature")?;
+    key.verify_strict(message, &Signature::from_bytes(&bytes))
+        .map_err(|_| "Signature does not match".to_string())
+}
+
+/// Compares secrets in time that does not depend on where they differ
+pub(crate) fn secrets_match(given: &str, expected: &str) -> bool {
+    let (given, expected) = (given.as_bytes(), expected.as_bytes());
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Serializes a signing key as the hex of its secret bytes
+pub(crate) mod signing_key_hex {
+    use ed25519_dalek::SigningKey;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(key: &SigningKey, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(key.to_bytes()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SigningKey, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let bytes: [u8; 32] = hex::decode(&text)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| D::Error::custom("invalid signing key"))?;
+        Ok(SigningKey::from_bytes(&bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = generate_signing_key();
+        let public_key = hex::encode(key.verifying_key().as_bytes());
+        assert_eq!(address_of(&key.verifying_key()).len(), 40);
+
+        let signature = sign(&key, b"message");
+        assert!(verify(&public_key, b"message", &signature).is_ok());
+        assert_eq!(
+            verify(&public_key, b"other message", &signature),
+            Err("Signature does not match".to_string())
+        );
+        let other = hex::encode(generate_signing_key().verifying_key().as_bytes());
+        assert!(verify(&other, b"message", &signature).is_err());
+        assert!(verify(&public_key, b"message", "00").is_err());
+        assert!(verify("not hex", b"message", &signature).is_err());
+    }
+}
@@ -0,0 +1,25 @@
+pub mod amount;
+pub mod api;
+pub mod block;
+pub mod block_store;
+pub mod candle;
+pub mod clock;
+pub mod depth;
+pub mod exchange;
+pub mod fee;
+pub mod fix;
+pub mod fix_gateway;
+pub mod journal;
+pub mod keys;
+pub mod log;
+pub mod market_data;
+pub mod mempool;
+pub mod merkle;
+pub mod order;
+pub mod p2p;
+pub mod snapshot;
+pub mod target;
+pub mod transaction;
+pub mod user_data;
+pub mod wallet;
+mod ws;
@@ -0,0 +1,18 @@
+use std::sync::Arc;
+
+/// Takes the lines that background tasks have to report, such as a peer
+/// that disconnected or a block that was refused
+///
+/// Library code never prints; it hands such lines to the hook its caller
+/// passed in, which can print them, count them or drop them.
+pub type Log = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// A log that drops every line, the default of every component that takes one
+pub fn discard() -> Log {
+    Arc::new(|_| {})
+}
+
+/// A log that prints every line to standard output
+pub fn stdout() -> Log {
+    Arc::new(|line| println!("{}", line))
+}
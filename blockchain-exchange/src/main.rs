@@ -1,40 +1,27 @@
-mod block;
-mod exchange;
-mod order;
-mod transaction;
-mod wallet;
-
 use std::io::{self, Write};
+use std::path::Path;
+
+use blockchain_exchange::amount::Amount;
+use blockchain_exchange::exchange::Exchange;
+use blockchain_exchange::order::{OrderSide, TradingPair};
 
-use exchange::Exchange;
-use order::{OrderSide, TradingPair};
+/// Where the demo keeps the exchange state between runs
+const SNAPSHOT_PATH: &str = "exchange_snapshot.json";
 
 fn main() {
     println!("===========================================");
     println!("   Welcome to Rust Blockchain Exchange!");
     println!("===========================================\n");
 
-    let mut exchange = Exchange::new("RustExchange");
-
-    // Create demo wallets
-    println!("Creating demo wallets...");
-    let alice = exchange.create_wallet("Alice");
-    let bob = exchange.create_wallet("Bob");
-    let charlie = exchange.create_wallet("Charlie");
+    let (mut exchange, alice, bob, charlie) = match restore_demo() {
+        Some(restored) => restored,
+        None => new_demo(),
+    };
 
     println!("  Alice's address: {}...", &alice[..16]);
     println!("  Bob's address: {}...", &bob[..16]);
     println!("  Charlie's address: {}...", &charlie[..16]);
 
-    // Deposit initial funds
-    println!("\nDepositing initial funds...");
-    exchange.deposit(&alice, "USDT", 100000.0).unwrap();
-    exchange.deposit(&alice, "ETH", 10.0).unwrap();
-    exchange.deposit(&bob, "BTC", 5.0).unwrap();
-    exchange.deposit(&bob, "ETH", 20.0).unwrap();
-    exchange.deposit(&charlie, "USDT", 50000.0).unwrap();
-    exchange.deposit(&charlie, "BTC", 2.0).unwrap();
-
     print_balances(&exchange, &alice, &bob, &charlie);
 
     // Interactive demo
@@ -68,7 +55,11 @@ fn main() {
             }
             "7" => print_blockchain_info(&exchange),
             "8" => {
-                println!("\nGoodbye!");
+                match exchange.save_snapshot(SNAPSHOT_PATH) {
+                    Ok(()) => println!("\nExchange state saved to {}", SNAPSHOT_PATH),
+                    Err(e) => println!("\nCould not save exchange state: {}", e),
+                }
+                println!("Goodbye!");
                 break;
             }
             _ => println!("Invalid option, please try again."),
@@ -76,6 +67,53 @@ fn main() {
     }
 }
 
+/// Restores the exchange and the demo wallets from the last run's snapshot, if any
+fn restore_demo() -> Option<(Exchange, String, String, String)> {
+    if !Path::new(SNAPSHOT_PATH).exists() {
+        return None;
+    }
+    let exchange = match Exchange::restore_snapshot(SNAPSHOT_PATH) {
+        Ok(exchange) => exchange,
+        Err(e) => {
+            println!("Could not restore {}: {}", SNAPSHOT_PATH, e);
+            return None;
+        }
+    };
+
+    let address_of = |owner: &str| {
+        exchange
+            .wallet_manager
+            .wallets()
+            .find(|w| w.owner == owner)
+            .map(|w| w.address.clone())
+    };
+    let (alice, bob, charlie) = (address_of("Alice")?, address_of("Bob")?, address_of("Charlie")?);
+    println!("Restored exchange state from {}", SNAPSHOT_PATH);
+    Some((exchange, alice, bob, charlie))
+}
+
+/// Starts a new exchange with funded demo wallets
+fn new_demo() -> (Exchange, String, String, String) {
+    let mut exchange = Exchange::new("RustExchange");
+
+    // Create demo wallets
+    println!("Creating demo wallets...");
+    let alice = exchange.create_wallet("Alice");
+    let bob = exchange.create_wallet("Bob");
+    let charlie = exchange.create_wallet("Charlie");
+
+    // Deposit initial funds
+    println!("Depositing initial funds...");
+    exchange.deposit(&alice, "USDT", Amount::from(100000)).unwrap();
+    exchange.deposit(&alice, "ETH", Amount::from(10)).unwrap();
+    exchange.deposit(&bob, "BTC", Amount::from(5)).unwrap();
+    exchange.deposit(&bob, "ETH", Amount::from(20)).unwrap();
+    exchange.deposit(&charlie, "USDT", Amount::from(50000)).unwrap();
+    exchange.deposit(&charlie, "BTC", Amount::from(2)).unwrap();
+
+    (exchange, alice, bob, charlie)
+}
+
 fn place_order_interactive(
     exchange: &mut Exchange,
     side: OrderSide,
@@ -131,7 +169,7 @@ fn place_order_interactive(
     io::stdout().flush().unwrap();
     input.clear();
     io::stdin().read_line(&mut input).unwrap();
-    let price: f64 = match input.trim().parse() {
+    let price: Amount = match input.trim().parse() {
         Ok(p) => p,
         Err(_) => {
             println!("Invalid price");
@@ -143,7 +181,7 @@ fn place_order_interactive(
     io::stdout().flush().unwrap();
     input.clear();
     io::stdin().read_line(&mut input).unwrap();
-    let quantity: f64 = match input.trim().parse() {
+    let quantity: Amount = match input.trim().parse() {
         Ok(q) => q,
         Err(_) => {
             println!("Invalid quantity");
@@ -215,15 +253,15 @@ fn run_demo_trades(exchange: &mut Exchange, alice: &str, bob: &str, charlie: &st
         alice.to_string(),
         btc_usdt.clone(),
         OrderSide::Buy,
-        50000.0,
-        1.0,
+        Amount::from(50000),
+        Amount::from(1),
     );
     let _ = exchange.place_order(
         bob.to_string(),
         btc_usdt.clone(),
         OrderSide::Sell,
-        50000.0,
-        1.0,
+        Amount::from(50000),
+        Amount::from(1),
     );
 
     print_balances(exchange, alice, bob, charlie);
@@ -237,15 +275,15 @@ fn run_demo_trades(exchange: &mut Exchange, alice: &str, bob: &str, charlie: &st
         alice.to_string(),
         btc_usdt.clone(),
         OrderSide::Buy,
-        49000.0,
-        0.5,
+        Amount::from(49000),
+        Amount::new(5, 1),
     );
     let _ = exchange.place_order(
         charlie.to_string(),
         btc_usdt.clone(),
         OrderSide::Buy,
-        48500.0,
-        1.0,
+        Amount::from(48500),
+        Amount::from(1),
     );
 
     // Place several sell orders
@@ -253,15 +291,15 @@ fn run_demo_trades(exchange: &mut Exchange, alice: &str, bob: &str, charlie: &st
         bob.to_string(),
         btc_usdt.clone(),
         OrderSide::Sell,
-        51000.0,
-        0.5,
+        Amount::from(51000),
+        Amount::new(5, 1),
     );
     let _ = exchange.place_order(
         bob.to_string(),
         btc_usdt.clone(),
         OrderSide::Sell,
-        52000.0,
-        1.0,
+        Amount::from(52000),
+        Amount::from(1),
     );
 
     exchange.print_order_book(&btc_usdt);
@@ -273,15 +311,15 @@ fn run_demo_trades(exchange: &mut Exchange, alice: &str, bob: &str, charlie: &st
         charlie.to_string(),
         eth_usdt.clone(),
         OrderSide::Buy,
-        3000.0,
-        5.0,
+        Amount::from(3000),
+        Amount::from(5),
     );
     let _ = exchange.place_order(
         bob.to_string(),
         eth_usdt.clone(),
         OrderSide::Sell,
-        3000.0,
-        3.0,
+        Amount::from(3000),
+        Amount::from(3),
     );
 
     exchange.print_order_book(&eth_usdt);
@@ -300,11 +338,12 @@ fn run_demo_trades(exchange: &mut Exchange, alice: &str, bob: &str, charlie: &st
 fn print_blockchain_info(exchange: &Exchange) {
     println!("\n=== Blockchain Info ===");
     println!("Chain length: {} blocks", exchange.blockchain.chain.len());
-    println!("Difficulty: {}", exchange.blockchain.difficulty);
+    let target = exchange.blockchain.next_target();
+    println!("Difficulty: {:.1} (target {})", target.difficulty(), target);
     println!("Is valid: {}", exchange.blockchain.is_valid());
     println!(
         "Pending transactions: {}",
-        exchange.blockchain.pending_transactions.len()
+        exchange.blockchain.mempool.len()
     );
 
     println!("\nRecent blocks:");
@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::amount::Amount;
+use crate::depth::{DepthLevel, LevelUpdate};
+use crate::order::{OrderBook, Trade, TradingPair};
+
+/// A market data stream of one trading pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    /// Every trade
+    Trades,
+    /// Best bid, best ask and last price
+    Ticker,
+    /// Changes to individual price levels
+    Depth,
+}
+
+/// Top of a pair's order book and its last traded price
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BookTicker {
+    pub best_bid: Option<DepthLevel>,
+    pub best_ask: Option<DepthLevel>,
+    pub last_price: Option<Amount>,
+}
+
+/// What changed in a market data event
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "channel", content = "data", rename_all = "lowercase")]
+pub enum MarketUpdate {
+    Trades(Trade),
+    Ticker(BookTicker),
+    Depth(LevelUpdate),
+}
+
+/// One change to a pair's market data
+///
+/// Sequence numbers increase per pair and channel. Trade sequences count the
+/// pair's trades, so a gap means a missed trade. Depth sequences are the order
+/// book's level sequence, and ticker events carry the level sequence at which
+/// the top of the book changed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketEvent {
+    pub pair: TradingPair,
+    pub sequence: u64,
+    #[serde(flatten)]
+    pub update: MarketUpdate,
+}
+
+impl MarketEvent {
+    pub fn channel(&self) -> Channel {
+        match self.update {
+            MarketUpdate::Trades(_) => Channel::Trades,
+            MarketUpdate::Ticker(_) => Channel::Ticker,
+            MarketUpdate::Depth(_) => Channel::Depth,
+        }
+    }
+}
+
+/// Collects market data events as the exchange trades
+///
+/// Trades are recorded as they are processed; depth and ticker events are
+/// derived from the order books when events are taken.
+#[derive(Debug, Clone, Default)]
+pub struct MarketFeed {
+    /// Number of trades per pair symbol
+    trade_sequences: HashMap<String, u64>,
+    /// Price of the latest trade per pair symbol
+    last_prices: HashMap<String, Amount>,
+    /// Last published top of book per pair symbol
+    tickers: HashMap<String, BookTicker>,
+    events: Vec<MarketEvent>,
+}
+
+impl MarketFeed {
+    /// Starts a feed that continues the trade sequences of an existing history
+    pub fn resume(trades: &[Trade]) -> Self {
+        let mut feed = MarketFeed::default();
+        for trade in trades {
+            *feed.trade_sequences.entry(trade.pair.symbol()).or_default() += 1;
+            feed.last_prices.insert(trade.pair.symbol(), trade.price);
+        }
+        feed
+    }
+
+    /// Records a processed trade
+    pub fn record_trade(&mut self, trade: &Trade) {
+        let sequence = self.trade_sequences.entry(trade.pair.symbol()).or_default();
+        *sequence += 1;
+        self.last_prices.insert(trade.pair.symbol(), trade.price);
+        self.events.push(MarketEvent {
+            pair: trade.pair.clone(),
+            sequence: *sequence,
+            update: MarketUpdate::Trades(trade.clone()),
+        });
+    }
+
+    /// Number of trades recorded for a pair
+    pub fn trade_sequence(&self, pair: &TradingPair) -> u64 {
+        self.trade_sequences
+            .get(&pair.symbol())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Takes the recorded trades, the book's pending level updates and, if the
+    /// top of the book moved, a ticker event
+    pub fn take_events(&mut self, book: &mut OrderBook) -> Vec<MarketEvent> {
+        let symbol = book.pair.symbol();
+        let (trades, others) = std::mem::take(&mut self.events)
+            .into_iter()
+            .partition(|event| event.pair == book.pair);
+        self.events = others;
+
+        let mut events: Vec<MarketEvent> = trades;
+        events.extend(
+            book.take_level_updates()
+                .into_iter()
+                .map(|update| MarketEvent {
+                    pair: book.pair.clone(),
+                    sequence: update.sequence,
+                    update: MarketUpdate::Depth(update),
+                }),
+        );
+
+        let ticker = self.book_ticker(book);
+        if self.tickers.get(&symbol) != Some(&ticker) {
+            self.tickers.insert(symbol, ticker);
+            events.push(MarketEvent {
+                pair: book.pair.clone(),
+                sequence: book.level_sequence(),
+                update: MarketUpdate::Ticker(ticker),
+            });
+        }
+        events
+    }
+
+    /// Current top of a book and the last price recorded for its pair
+    pub fn book_ticker(&self, book: &OrderBook) -> BookTicker {
+        let top = book.depth(1);
+        BookTicker {
+            best_bid: top.bids.first().copied(),
+            best_ask: top.asks.first().copied(),
+            last_price: self.last_prices.get(&book.pair.symbol()).copied(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::{Order, OrderSide};
+
+    #[test]
+    fn test_events_serialize_with_channel_tag() {
+        let pair = TradingPair::new("BTC", "USDT");
+        let mut book = OrderBook::new(pair.clone());
+        let mut feed = MarketFeed::default();
+        book.add_order(Order::new(
+            "alice".to_string(),
+            pair.clone(),
+            OrderSide::Buy,
+            Amount::from(100),
+            Amount::from(2),
+        ));
+
+        let events = feed.take_events(&mut book);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].channel(), Channel::Depth);
+        assert_eq!(events[1].channel(), Channel::Ticker);
+        let json = serde_json::to_value(&events[1]).unwrap();
+        assert_eq!(json["channel"], "ticker");
+        assert_eq!(json["sequence"], 1);
+        assert_eq!(json["data"]["best_bid"]["price"], "100");
+
+        // Nothing changed, so there is nothing to publish
+        assert!(feed.take_events(&mut book).is_empty());
+    }
+}
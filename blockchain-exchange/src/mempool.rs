@@ -0,0 +1,388 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+
+use crate::amount::Amount;
+use crate::transaction::Transaction;
+
+/// Balances, nonces and transaction ids after a sequence of transactions
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ledger {
+    /// Balances by address, then currency
+    balances: HashMap<String, HashMap<String, Amount>>,
+    /// Nonce of each address's next transaction
+    nonces: HashMap<String, u64>,
+    ids: HashSet<String>,
+}
+
+impl Ledger {
+    pub fn balance(&self, address: &str, currency: &str) -> Amount {
+        self.balances
+            .get(address)
+            .and_then(|balances| balances.get(currency))
+            .copied()
+            .unwrap_or(Amount::ZERO)
+    }
+
+    /// Nonce the next transaction from `address` must carry
+    ///
+    /// Addresses start at 0 and count up by one with every signed transaction.
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        self.nonces.get(address).copied().unwrap_or(0)
+    }
+
+    /// Checks that a transaction can follow the ones applied so far
+    ///
+    /// Its id must be new and, unless it is [exempt](Transaction::is_exempt),
+    /// it must carry the sender's next nonce and not spend more than the
+    /// sender holds.
+    /// Signatures are checked separately, by [`Transaction::verify`].
+    pub fn check(&self, transaction: &Transaction) -> Result<(), String> {
+        if self.ids.contains(&transaction.id) {
+            return Err(format!("Duplicate transaction {}", transaction.id));
+        }
+        if transaction.is_exempt() {
+            return Ok(());
+        }
+        let expected = self.next_nonce(&transaction.from_address);
+        if transaction.nonce != expected {
+            return Err(format!(
+                "Invalid nonce {} for {} (expected {})",
+                transaction.nonce, transaction.from_address, expected
+            ));
+        }
+        let balance = self.balance(&transaction.from_address, &transaction.currency);
+        if transaction.amount > balance {
+            return Err(format!(
+                "Insufficient {} balance: {} has {} but sends {}",
+                transaction.currency, transaction.from_address, balance, transaction.amount
+            ));
+        }
+        Ok(())
+    }
+
+    /// Records the effect of a transaction
+    pub fn apply(&mut self, transaction: &Transaction) {
+        for (address, change) in [
+            (&transaction.from_address, -transaction.amount),
+            (&transaction.to_address, transaction.amount),
+        ] {
+            *self
+                .balances
+                .entry(address.clone())
+                .or_default()
+                .entry(transaction.currency.clone())
+                .or_default() += change;
+        }
+        if !transaction.is_exempt() {
+            *self
+                .nonces
+                .entry(transaction.from_address.clone())
+                .or_default() += 1;
+        }
+        self.ids.insert(transaction.id.clone());
+    }
+
+    /// Undoes [`Ledger::apply`] for the last transaction applied
+    ///
+    /// Transactions must be reverted newest first.
+    pub fn revert(&mut self, transaction: &Transaction) {
+        for (address, change) in [
+            (&transaction.from_address, transaction.amount),
+            (&transaction.to_address, -transaction.amount),
+        ] {
+            *self
+                .balances
+                .entry(address.clone())
+                .or_default()
+                .entry(transaction.currency.clone())
+                .or_default() += change;
+        }
+        if !transaction.is_exempt() {
+            if let Some(nonce) = self.nonces.get_mut(&transaction.from_address) {
+                *nonce -= 1;
+                if *nonce == 0 {
+                    self.nonces.remove(&transaction.from_address);
+                }
+            }
+        }
+        self.ids.remove(&transaction.id);
+    }
+}
+
+/// Transactions waiting to be mined, in the order they arrived
+///
+/// Every transaction is checked against the chain plus the transactions that
+/// arrived before it, so together they never overspend or reuse a nonce.
+///
+/// The ledger after the pending transactions is built from the chain's
+/// ledger the first time it is needed, then kept up to date as transactions
+/// arrive. [`Mempool::evict`] and [`Mempool::readmit`] rebuild it, so they
+/// must be called whenever the chain changes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Mempool {
+    transactions: Vec<Transaction>,
+    #[serde(skip)]
+    pending: OnceLock<Ledger>,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pending transactions, oldest first
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// The ledger of `chain` with every pending transaction applied
+    pub fn pending_ledger(&self, chain: &Ledger) -> &Ledger {
+        self.pending.get_or_init(|| {
+            let mut ledger = chain.clone();
+            for transaction in &self.transactions {
+                ledger.apply(transaction);
+            }
+            ledger
+        })
+    }
+
+    /// Adds a transaction that can follow the chain and the pending transactions
+    pub fn add(&mut self, transaction: Transaction, chain: &Ledger) -> Result<(), String> {
+        self.pending_ledger(chain).check(&transaction)?;
+        if let Some(pending) = self.pending.get_mut() {
+            pending.apply(&transaction);
+        }
+        self.transactions.push(transaction);
+        Ok(())
+    }
+
+    /// Drops every transaction after the first `len`, newest first
+    ///
+    /// Lets a caller take back transactions it added together when a later
+    /// one is refused.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.transactions.len() {
+            return;
+        }
+        let mut pending = self.pending.get_mut();
+        for transaction in self.transactions.drain(len..).rev() {
+            if let Some(pending) = pending.as_mut() {
+                pending.revert(&transaction);
+            }
+        }
+    }
+
+    /// Picks up to `limit` transactions for the next block, highest fee first
+    ///
+    /// A transaction is only picked once the ones it depends on are: the
+    /// sender's earlier nonces and, if it spends unconfirmed funds, the
+    /// transactions that bring them. Ties go to the older transaction.
+    pub fn select(&self, chain: &Ledger, limit: usize) -> Vec<Transaction> {
+        let mut candidates: Vec<&Transaction> = self.transactions.iter().collect();
+        // Stable, so equal fees keep their arrival order
+        candidates.sort_by_key(|tx| std::cmp::Reverse(tx.fee));
+
+        let mut ledger = chain.clone();
+        let mut selected = vec![];
+        while selected.len() < limit {
+            let Some(index) = candidates.iter().position(|tx| ledger.check(tx).is_ok()) else {
+                break;
+            };
+            let transaction = candidates.remove(index);
+            ledger.apply(transaction);
+            selected.push(transaction.clone());
+        }
+        selected
+    }
+
+    /// Puts transactions back ahead of the pending ones
+    ///
+    /// Called when a reorganization disconnects blocks: their transactions
+    /// are older than the pending ones, so they go first. Those that are on
+    /// the new chain, or can no longer follow it, are dropped and returned.
+    pub fn readmit(&mut self, transactions: Vec<Transaction>, chain: &Ledger) -> Vec<Transaction> {
+        let pending = std::mem::take(&mut self.transactions);
+        self.transactions = transactions.into_iter().chain(pending).collect();
+        self.evict(chain)
+    }
+
+    /// Drops the transactions that can no longer follow the chain
+    ///
+    /// Called after a block is added: the transactions it included are now
+    /// duplicates, and others may have lost their funds or their nonce.
+    /// Returns the evicted transactions.
+    pub fn evict(&mut self, chain: &Ledger) -> Vec<Transaction> {
+        let mut ledger = chain.clone();
+        let mut evicted = vec![];
+        self.transactions.retain(|transaction| {
+            if ledger.check(transaction).is_ok() {
+                ledger.apply(transaction);
+                true
+            } else {
+                evicted.push(transaction.clone());
+                false
+            }
+        });
+        self.pending = OnceLock::from(ledger);
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::Wallet;
+
+    fn transfer(from: &Wallet, to: &str, amount: i64, nonce: u64, fee: i64) -> Transaction {
+        let tx = Transaction::new(
+            from.address.clone(),
+            to.to_string(),
+            "BTC",
+            Amount::from(amount),
+        )
+        .with_nonce(nonce)
+        .with_fee(Amount::from(fee));
+        from.sign(tx).unwrap()
+    }
+
+    fn funded(address: &str, amount: i64) -> Ledger {
+        let mut ledger = Ledger::default();
+        ledger.apply(&Transaction::new_deposit(
+            address.to_string(),
+            "BTC",
+            Amount::from(amount),
+        ));
+        ledger
+    }
+
+    #[test]
+    fn test_rejects_overspends_and_replays() {
+        let alice = Wallet::new("Alice");
+        let chain = funded(&alice.address, 100);
+        let mut mempool = Mempool::new();
+
+        mempool
+            .add(transfer(&alice, "Bob", 60, 0, 0), &chain)
+            .unwrap();
+        // Only 40 is left once the pending transfer is counted
+        let err = mempool
+            .add(transfer(&alice, "Bob", 50, 1, 0), &chain)
+            .unwrap_err();
+        assert!(err.contains("Insufficient BTC balance"));
+
+        let replay = mempool.transactions()[0].clone();
+        assert!(mempool
+            .add(replay, &chain)
+            .unwrap_err()
+            .contains("Duplicate"));
+        let reused_nonce = transfer(&alice, "Bob", 10, 0, 0);
+        assert!(mempool
+            .add(reused_nonce, &chain)
+            .unwrap_err()
+            .contains("nonce"));
+        let skipped_nonce = transfer(&alice, "Bob", 10, 2, 0);
+        assert!(mempool.add(skipped_nonce, &chain).is_err());
+
+        mempool
+            .add(transfer(&alice, "Bob", 40, 1, 0), &chain)
+            .unwrap();
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.pending_ledger(&chain).next_nonce(&alice.address), 2);
+    }
+
+    #[test]
+    fn test_revert_undoes_apply() {
+        let alice = Wallet::new("Alice");
+        let chain = funded(&alice.address, 100);
+        let transfer = transfer(&alice, "Bob", 60, 0, 0);
+
+        let mut ledger = chain.clone();
+        ledger.apply(&transfer);
+        assert_eq!(ledger.balance("Bob", "BTC"), Amount::from(60));
+        ledger.revert(&transfer);
+        assert_eq!(ledger.balance("Bob", "BTC"), Amount::ZERO);
+        assert_eq!(ledger.next_nonce(&alice.address), 0);
+        assert!(ledger.check(&transfer).is_ok());
+    }
+
+    #[test]
+    fn test_truncate_takes_back_the_newest_transactions() {
+        let alice = Wallet::new("Alice");
+        let chain = funded(&alice.address, 100);
+        let mut mempool = Mempool::new();
+        let kept = transfer(&alice, "Bob", 30, 0, 0);
+        mempool.add(kept.clone(), &chain).unwrap();
+        mempool
+            .add(transfer(&alice, "Bob", 70, 1, 0), &chain)
+            .unwrap();
+
+        mempool.truncate(1);
+        assert_eq!(mempool.transactions()[0].id, kept.id);
+        let pending = mempool.pending_ledger(&chain);
+        assert_eq!(pending.next_nonce(&alice.address), 1);
+        assert_eq!(pending.balance(&alice.address, "BTC"), Amount::from(70));
+    }
+
+    #[test]
+    fn test_select_orders_by_fee_and_respects_dependencies() {
+        let alice = Wallet::new("Alice");
+        let bob = Wallet::new("Bob");
+        let mut chain = funded(&alice.address, 100);
+        chain.apply(&Transaction::new_deposit(
+            bob.address.clone(),
+            "BTC",
+            Amount::from(100),
+        ));
+        let mut mempool = Mempool::new();
+
+        let alice_first = transfer(&alice, "Carol", 10, 0, 1);
+        let alice_second = transfer(&alice, "Carol", 10, 1, 9);
+        let bob_only = transfer(&bob, "Carol", 10, 0, 5);
+        for tx in [&alice_first, &alice_second, &bob_only] {
+            mempool.add(tx.clone(), &chain).unwrap();
+        }
+
+        let ids = |selected: Vec<Transaction>| -> Vec<String> {
+            selected.into_iter().map(|tx| tx.id).collect()
+        };
+        // Alice's high fee waits for her first nonce
+        assert_eq!(
+            ids(mempool.select(&chain, 10)),
+            [bob_only.id.clone(), alice_first.id, alice_second.id]
+        );
+        assert_eq!(ids(mempool.select(&chain, 1)), [bob_only.id]);
+    }
+
+    #[test]
+    fn test_evict_drops_mined_and_invalidated_transactions() {
+        let alice = Wallet::new("Alice");
+        let chain = funded(&alice.address, 100);
+        let mut mempool = Mempool::new();
+        let mined = transfer(&alice, "Bob", 30, 0, 0);
+        let pending = transfer(&alice, "Bob", 30, 1, 0);
+        mempool.add(mined.clone(), &chain).unwrap();
+        mempool.add(pending.clone(), &chain).unwrap();
+
+        let mut after_block = chain.clone();
+        after_block.apply(&mined);
+        assert_eq!(mempool.evict(&after_block)[0].id, mined.id);
+        assert_eq!(mempool.transactions()[0].id, pending.id);
+
+        // A block from elsewhere spent Alice's funds under the same nonce
+        let mut spent = chain.clone();
+        spent.apply(&mined);
+        spent.apply(&transfer(&alice, "Carol", 70, 1, 0));
+        assert_eq!(mempool.evict(&spent).len(), 1);
+        assert!(mempool.is_empty());
+    }
+}
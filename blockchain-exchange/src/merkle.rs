@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::transaction::Transaction;
+
+/// Side of the path a sibling hash sits on
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// One level of a Merkle path: the hash to combine with and where it goes
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ProofStep {
+    pub hash: String,
+    pub side: Side,
+}
+
+/// Hashes two child nodes into their parent
+///
+/// The `0x01` prefix keeps parents apart from transaction hashes, which are
+/// hashes of JSON objects.
+fn hash_pair(left: &str, right: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left.as_bytes());
+    hasher.update(right.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Parents of one level of the tree
+///
+/// An odd node out moves up unchanged rather than being paired with itself,
+/// so repeating the last transaction of a block changes its root.
+fn next_level(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_pair(left, right),
+            [single] => single.clone(),
+            _ => unreachable!("chunks of two"),
+        })
+        .collect()
+}
+
+/// Merkle root of a list of leaf hashes
+///
+/// A tree without leaves has the hash of no data as its root.
+pub fn root(leaves: &[String]) -> String {
+    if leaves.is_empty() {
+        return format!("{:x}", Sha256::digest(b""));
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+/// Path from the leaf at `index` up to the root, or `None` if there is no such leaf
+pub fn path(leaves: &[String], mut index: usize) -> Option<Vec<ProofStep>> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut steps = vec![];
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            let side = if sibling < index {
+                Side::Left
+            } else {
+                Side::Right
+            };
+            steps.push(ProofStep {
+                hash: hash.clone(),
+                side,
+            });
+        }
+        level = next_level(&level);
+        index /= 2;
+    }
+    Some(steps)
+}
+
+/// Root reached by following a path up from a leaf
+pub fn root_from_path(leaf: &str, path: &[ProofStep]) -> String {
+    path.iter()
+        .fold(leaf.to_string(), |hash, step| match step.side {
+            Side::Left => hash_pair(&step.hash, &hash),
+            Side::Right => hash_pair(&hash, &step.hash),
+        })
+}
+
+/// Evidence that a transaction is part of a mined block
+///
+/// Anyone holding the transaction can check the path with
+/// [`InclusionProof::verify`], and the block's header against its hash,
+/// without the rest of the block.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct InclusionProof {
+    pub transaction_id: String,
+    /// [`Transaction::hash`] of the proven transaction
+    pub transaction_hash: String,
+    pub block_index: u64,
+    pub block_hash: String,
+    pub merkle_root: String,
+    /// Sibling hashes from the transaction up to the root
+    pub path: Vec<ProofStep>,
+}
+
+impl InclusionProof {
+    /// Checks that the proof covers `transaction` and leads to its Merkle root
+    pub fn verify(&self, transaction: &Transaction) -> Result<(), String> {
+        if transaction.id != self.transaction_id {
+            return Err(format!(
+                "Proof is for transaction {}, not {}",
+                self.transaction_id, transaction.id
+            ));
+        }
+        if transaction.hash() != self.transaction_hash {
+            return Err(format!(
+                "Transaction {} does not match the proven hash",
+                transaction.id
+            ));
+        }
+        if root_from_path(&self.transaction_hash, &self.path) != self.merkle_root {
+            return Err("Merkle path does not lead to the root".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| format!("{:x}", Sha256::digest(i.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn test_root() {
+        let [a, b, c] = <[String; 3]>::try_from(leaves(3)).unwrap();
+        assert_eq!(root(std::slice::from_ref(&a)), a);
+        assert_eq!(root(&[a.clone(), b.clone()]), hash_pair(&a, &b));
+        // The odd leaf moves up unpaired
+        assert_eq!(
+            root(&[a.clone(), b.clone(), c.clone()]),
+            hash_pair(&hash_pair(&a, &b), &c)
+        );
+        assert_ne!(root(&[a.clone(), b.clone()]), root(&[b, a]));
+        assert_eq!(root(&[]).len(), 64);
+    }
+
+    #[test]
+    fn test_every_path_leads_to_the_root() {
+        for count in 1..=9 {
+            let leaves = leaves(count);
+            let root = root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let path = path(&leaves, index).unwrap();
+                assert_eq!(
+                    root_from_path(leaf, &path),
+                    root,
+                    "leaf {} of {}",
+                    index,
+                    count
+                );
+                // The path only fits its own leaf
+                let other = &leaves[(index + 1) % count];
+                if count > 1 {
+                    assert_ne!(root_from_path(other, &path), root);
+                }
+            }
+            assert!(super::path(&leaves, count).is_none());
+        }
+    }
+}
@@ -1,8 +1,11 @@
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use uuid::Uuid;
 
+use crate::amount::{Amount, Rounding};
+use crate::depth::{DepthLevel, DepthSnapshot, LevelAction, LevelUpdate};
+
 /// Order side (buy or sell)
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OrderSide {
@@ -17,6 +20,104 @@ pub enum OrderStatus {
     PartiallyFilled,
     Filled,
     Cancelled,
+    /// A good-till-date order that reached its expiry
+    Expired,
+    /// A stop order waiting off-book for its trigger price
+    Pending,
+    /// A stop order that has been released to the matching engine
+    Triggered,
+}
+
+/// How an order is priced
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderType {
+    /// Executes at its limit price or better; the remainder rests on the book
+    Limit,
+    /// Executes immediately against the book; the remainder is cancelled
+    Market,
+}
+
+/// What to do with a post-only order that would match on entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PostOnlyAction {
+    /// Reject the order
+    Reject,
+    /// Move the price one tick away from the other side so it rests instead
+    Reprice,
+}
+
+/// How long an order may stay on the book
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeInForce {
+    /// Rests until filled or cancelled
+    #[default]
+    GoodTillCancelled,
+    /// Fills what it can on entry and cancels the rest
+    ImmediateOrCancel,
+    /// Fills completely on entry or is rejected without trading
+    FillOrKill,
+    /// Only adds liquidity; never matches on entry
+    PostOnly(PostOnlyAction),
+    /// Rests until filled, cancelled or the given Unix timestamp
+    GoodTillDate(i64),
+}
+
+/// What to do when an incoming order would match a resting order of the same account
+///
+/// The incoming order's mode applies. "Newest" is the incoming order and
+/// "oldest" the resting one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SelfTradePrevention {
+    /// Cancel the rest of the incoming order
+    #[default]
+    CancelNewest,
+    /// Cancel the resting order and keep matching the incoming one
+    CancelOldest,
+    /// Cancel both orders
+    CancelBoth,
+    /// Reduce both orders by the quantity that would have traded and cancel
+    /// whichever has nothing left
+    DecrementAndCancel,
+}
+
+/// Optional parameters for a limit order
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderOptions {
+    pub time_in_force: TimeInForce,
+    /// Self-trade prevention mode; `None` uses the account's mode
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+}
+
+/// Kind of stop order to place
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopOrderKind {
+    /// Becomes a market order once the stop price trades
+    StopLoss { stop_price: Amount },
+    /// Becomes a limit order at `limit_price` once the stop price trades
+    StopLimit {
+        stop_price: Amount,
+        limit_price: Amount,
+    },
+    /// Market stop that follows the best price since placement at `offset`
+    TrailingStop { offset: Amount },
+}
+
+/// Trigger condition of a pending stop order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopTrigger {
+    /// Fixed stop price
+    Price(Amount),
+    /// Stop `offset` away from the best trade price seen since placement
+    Trailing { offset: Amount, best_price: Amount },
+}
+
+/// Size of a market order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MarketOrderSize {
+    /// Quantity of the base currency
+    Base(Amount),
+    /// Amount of quote currency to spend (buy orders only)
+    Quote(Amount),
 }
 
 /// Represents a trading order
@@ -26,43 +127,199 @@ pub struct Order {
     pub user_address: String,
     pub pair: TradingPair,
     pub side: OrderSide,
-    pub price: f64,
-    pub quantity: f64,
-    pub filled_quantity: f64,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    pub self_trade_prevention: SelfTradePrevention,
+    /// Limit price; for market orders, the worst price allowed by the slippage guard
+    pub price: Amount,
+    pub quantity: Amount,
+    /// Quote currency to spend, for market buys sized in the quote currency
+    pub quote_budget: Option<Amount>,
+    /// Trigger of a stop order; the order stays off-book until it fires
+    pub stop: Option<StopTrigger>,
+    pub filled_quantity: Amount,
+    /// Exact quote value of all fills so far (sum of fill price * fill quantity)
+    pub filled_quote: Amount,
+    /// Funds currently held in the owner's wallet for this order
+    pub locked_amount: Amount,
     pub status: OrderStatus,
     pub timestamp: i64,
 }
 
 impl Order {
-    /// Creates a new order
+    /// Creates a new limit order
     pub fn new(
         user_address: String,
         pair: TradingPair,
         side: OrderSide,
-        price: f64,
-        quantity: f64,
+        price: Amount,
+        quantity: Amount,
     ) -> Self {
         Order {
             id: Uuid::new_v4().to_string(),
             user_address,
             pair,
             side,
+            order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            self_trade_prevention: SelfTradePrevention::default(),
             price,
             quantity,
-            filled_quantity: 0.0,
+            quote_budget: None,
+            stop: None,
+            filled_quantity: Amount::ZERO,
+            filled_quote: Amount::ZERO,
+            locked_amount: Amount::ZERO,
             status: OrderStatus::Open,
             timestamp: Utc::now().timestamp(),
         }
     }
 
+    /// Creates a new market order limited to `price_limit`
+    pub fn new_market(
+        user_address: String,
+        pair: TradingPair,
+        side: OrderSide,
+        price_limit: Amount,
+        quantity: Amount,
+    ) -> Self {
+        let mut order = Order::new(user_address, pair, side, price_limit, quantity);
+        order.order_type = OrderType::Market;
+        order
+    }
+
     /// Returns the remaining quantity to be filled
-    pub fn remaining_quantity(&self) -> f64 {
+    pub fn remaining_quantity(&self) -> Amount {
         self.quantity - self.filled_quantity
     }
 
-    /// Fills the order with the given quantity
-    pub fn fill(&mut self, quantity: f64) {
+    /// Largest quantity that can fill at `price`, respecting the quote budget if any
+    pub fn fillable_quantity(&self, price: Amount, base_scale: u32) -> Amount {
+        let remaining = self.remaining_quantity();
+        let Some(budget) = self.quote_budget else {
+            return remaining;
+        };
+
+        let left = budget - self.filled_quote;
+        let mut affordable = left.div_round(price, base_scale, Rounding::Down);
+        // Guard against the quotient being rounded up at full precision
+        if price * affordable > left {
+            affordable -= Amount::new(1, base_scale);
+        }
+        remaining.min(affordable.max(Amount::ZERO))
+    }
+
+    /// Marks an order whose quote budget is spent as filled at its current size
+    pub fn complete(&mut self) {
+        self.quantity = self.filled_quantity;
+        self.status = OrderStatus::Filled;
+    }
+
+    /// Returns true while the order can still be matched
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self.status,
+            OrderStatus::Open | OrderStatus::PartiallyFilled | OrderStatus::Triggered
+        )
+    }
+
+    /// Current stop price of a stop order
+    pub fn stop_price(&self) -> Option<Amount> {
+        self.stop.map(|stop| match stop {
+            StopTrigger::Price(price) => price,
+            StopTrigger::Trailing { offset, best_price } => match self.side {
+                OrderSide::Buy => best_price + offset,
+                OrderSide::Sell => best_price - offset,
+            },
+        })
+    }
+
+    /// Returns true if a trade at `price` reaches the stop price
+    ///
+    /// Sell stops fire when the price falls to the stop, buy stops when it
+    /// rises to it.
+    pub fn stop_reached(&self, price: Amount) -> bool {
+        match (self.side, self.stop_price()) {
+            (OrderSide::Buy, Some(stop_price)) => price >= stop_price,
+            (OrderSide::Sell, Some(stop_price)) => price <= stop_price,
+            (_, None) => false,
+        }
+    }
+
+    /// Feeds a trade price to a pending stop order and returns true if it fires
+    ///
+    /// Trailing stops first move their best price: up for sells, down for buys.
+    pub fn observe_price(&mut self, price: Amount) -> bool {
+        if let Some(StopTrigger::Trailing { best_price, .. }) = &mut self.stop {
+            *best_price = match self.side {
+                OrderSide::Buy => (*best_price).min(price),
+                OrderSide::Sell => (*best_price).max(price),
+            };
+        }
+        self.stop_reached(price)
+    }
+
+    /// Returns true if the unfilled remainder may rest on the book
+    pub fn can_rest(&self) -> bool {
+        self.order_type == OrderType::Limit
+            && !matches!(
+                self.time_in_force,
+                TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill
+            )
+    }
+
+    /// Returns true if the order is good-till-date and its expiry has passed
+    pub fn is_expired(&self, now: i64) -> bool {
+        matches!(self.time_in_force, TimeInForce::GoodTillDate(expires_at) if expires_at <= now)
+    }
+
+    /// Currency held in the owner's wallet while the order rests
+    pub fn hold_currency(&self) -> &str {
+        match self.side {
+            OrderSide::Buy => &self.pair.quote,
+            OrderSide::Sell => &self.pair.base,
+        }
+    }
+
+    /// Quote value of the fills so far, rounded to `quote_scale`
+    ///
+    /// Buyers pay the rounded-up value and sellers receive the rounded-down
+    /// value. Rounding the running total, not each fill, keeps the error below
+    /// one unit of the quote asset however many fills there are.
+    pub fn settled_quote(&self, quote_scale: u32) -> Amount {
+        let rounding = match self.side {
+            OrderSide::Buy => Rounding::Up,
+            OrderSide::Sell => Rounding::Down,
+        };
+        self.filled_quote.round_to(quote_scale, rounding)
+    }
+
+    /// Funds that must stay locked so the rest of the order can fill at its limit
+    ///
+    /// Fails if the amount is too large to represent.
+    pub fn required_hold(&self, quote_scale: u32) -> Result<Amount, String> {
+        let settled = self.settled_quote(quote_scale);
+        if let Some(budget) = self.quote_budget {
+            return budget.checked_sub(settled);
+        }
+        match self.side {
+            OrderSide::Buy => {
+                let worst_case = self
+                    .price
+                    .checked_mul(self.remaining_quantity())?
+                    .checked_add(self.filled_quote)?;
+                worst_case
+                    .round_to(quote_scale, Rounding::Up)
+                    .checked_sub(settled)
+            }
+            OrderSide::Sell => Ok(self.remaining_quantity()),
+        }
+    }
+
+    /// Fills the order with the given quantity at the given price
+    pub fn fill(&mut self, quantity: Amount, price: Amount) {
         self.filled_quantity += quantity;
+        self.filled_quote += price * quantity;
         if self.filled_quantity >= self.quantity {
             self.status = OrderStatus::Filled;
         } else {
@@ -96,113 +353,337 @@ impl TradingPair {
     }
 }
 
+/// Orders resting at a single price, in time priority
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PriceLevel {
+    /// Queue sequence numbers, oldest first; entries of removed orders are
+    /// skipped lazily when they reach the front, or dropped all at once when
+    /// they outnumber the orders left
+    queue: VecDeque<u64>,
+    /// Sum of the remaining quantity of the orders at this level
+    pub total_quantity: Amount,
+    /// Number of orders at this level
+    pub order_count: usize,
+}
+
 /// Order book for a trading pair
+///
+/// Each side is a map of price levels with a FIFO queue per level, so inserts
+/// cost O(log levels) and the best price is found without scanning. Orders are
+/// indexed by id, so lookups and cancels don't scan the book either.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OrderBook {
     pub pair: TradingPair,
-    pub buy_orders: Vec<Order>,  // Sorted by price descending (highest first)
-    pub sell_orders: Vec<Order>, // Sorted by price ascending (lowest first)
+    /// Buy levels; the best bid is the highest key
+    bids: BTreeMap<Amount, PriceLevel>,
+    /// Sell levels; the best ask is the lowest key
+    asks: BTreeMap<Amount, PriceLevel>,
+    /// Resting orders by queue sequence number
+    orders: HashMap<u64, Order>,
+    /// Queue sequence number of each resting order id
+    index: HashMap<String, u64>,
+    next_seq: u64,
+    /// Sequence number of the last level update
+    level_sequence: u64,
+    /// Level updates not yet taken by [`OrderBook::take_level_updates`]
+    #[serde(skip)]
+    level_updates: Vec<LevelUpdate>,
 }
 
 impl OrderBook {
     pub fn new(pair: TradingPair) -> Self {
         OrderBook {
             pair,
-            buy_orders: vec![],
-            sell_orders: vec![],
+            bids: BTreeMap::new(),
+            asks: BTreeMap::new(),
+            orders: HashMap::new(),
+            index: HashMap::new(),
+            next_seq: 0,
+            level_sequence: 0,
+            level_updates: vec![],
         }
     }
 
-    /// Adds a buy order to the order book
-    pub fn add_buy_order(&mut self, order: Order) {
-        self.buy_orders.push(order);
-        // Sort by price descending, then by timestamp ascending
-        self.buy_orders.sort_by(|a, b| {
-            match b.price.partial_cmp(&a.price) {
-                Some(Ordering::Equal) => a.timestamp.cmp(&b.timestamp),
-                Some(ord) => ord,
-                None => Ordering::Equal,
-            }
-        });
+    /// Adds an order to the back of the queue at its price
+    pub fn add_order(&mut self, order: Order) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let (side, price) = (order.side, order.price);
+
+        let levels = self.levels_mut(side);
+        let existed = levels.contains_key(&price);
+        let level = levels.entry(price).or_default();
+        level.queue.push_back(seq);
+        level.total_quantity += order.remaining_quantity();
+        level.order_count += 1;
+
+        self.index.insert(order.id.clone(), seq);
+        self.orders.insert(seq, order);
+        self.record_level(side, price, existed);
     }
 
-    /// Adds a sell order to the order book
-    pub fn add_sell_order(&mut self, order: Order) {
-        self.sell_orders.push(order);
-        // Sort by price ascending, then by timestamp ascending
-        self.sell_orders.sort_by(|a, b| {
-            match a.price.partial_cmp(&b.price) {
-                Some(Ordering::Equal) => a.timestamp.cmp(&b.timestamp),
-                Some(ord) => ord,
-                None => Ordering::Equal,
+    /// Removes an order from the book and returns it
+    pub fn remove_order(&mut self, order_id: &str) -> Option<Order> {
+        let seq = self.index.remove(order_id)?;
+        let order = self.orders.remove(&seq)?;
+
+        let levels = match order.side {
+            OrderSide::Buy => &mut self.bids,
+            OrderSide::Sell => &mut self.asks,
+        };
+        if let Some(level) = levels.get_mut(&order.price) {
+            level.total_quantity -= order.remaining_quantity();
+            level.order_count -= 1;
+            if level.order_count == 0 {
+                levels.remove(&order.price);
+            } else if level.queue.len() > 2 * level.order_count {
+                // At least as many removals as orders left pay for the pass
+                level.queue.retain(|seq| self.orders.contains_key(seq));
             }
-        });
+        }
+        self.record_level(order.side, order.price, true);
+        Some(order)
+    }
+
+    /// Gets a resting order by ID
+    pub fn get_order(&self, order_id: &str) -> Option<&Order> {
+        self.index.get(order_id).and_then(|seq| self.orders.get(seq))
+    }
+
+    /// Runs `f` on a resting order without moving it in its queue
+    ///
+    /// The level total follows the order's remaining quantity. `f` must not
+    /// change the order's side or price; remove and re-add the order for that.
+    pub fn update_order<R>(
+        &mut self,
+        order_id: &str,
+        f: impl FnOnce(&mut Order) -> R,
+    ) -> Option<R> {
+        let seq = self.index.get(order_id)?;
+        let order = self.orders.get_mut(seq)?;
+        let (side, price) = (order.side, order.price);
+
+        let remaining_before = order.remaining_quantity();
+        let result = f(order);
+        debug_assert!(order.side == side && order.price == price);
+        let remaining_after = order.remaining_quantity();
+
+        let levels = match side {
+            OrderSide::Buy => &mut self.bids,
+            OrderSide::Sell => &mut self.asks,
+        };
+        if let Some(level) = levels.get_mut(&price) {
+            level.total_quantity += remaining_after - remaining_before;
+        }
+        if remaining_after != remaining_before {
+            self.record_level(side, price, true);
+        }
+        Some(result)
+    }
+
+    /// Resting buy orders, best price first and oldest first within a price
+    pub fn buy_orders(&self) -> impl Iterator<Item = &Order> + '_ {
+        self.bids.values().rev().flat_map(|level| self.level_orders(level))
+    }
+
+    /// Resting sell orders, best price first and oldest first within a price
+    pub fn sell_orders(&self) -> impl Iterator<Item = &Order> + '_ {
+        self.asks.values().flat_map(|level| self.level_orders(level))
+    }
+
+    /// Buy levels as (price, level), best first
+    pub fn bid_levels(&self) -> impl Iterator<Item = (&Amount, &PriceLevel)> + '_ {
+        self.bids.iter().rev()
+    }
+
+    /// Sell levels as (price, level), best first
+    pub fn ask_levels(&self) -> impl Iterator<Item = (&Amount, &PriceLevel)> + '_ {
+        self.asks.iter()
+    }
+
+    /// Number of resting orders on both sides
+    pub fn order_count(&self) -> usize {
+        self.orders.len()
     }
 
     /// Gets the best bid (highest buy price)
-    pub fn best_bid(&self) -> Option<f64> {
-        self.buy_orders
-            .iter()
-            .filter(|o| o.status == OrderStatus::Open || o.status == OrderStatus::PartiallyFilled)
-            .map(|o| o.price)
-            .next()
+    pub fn best_bid(&self) -> Option<Amount> {
+        self.bids.keys().next_back().copied()
     }
 
     /// Gets the best ask (lowest sell price)
-    pub fn best_ask(&self) -> Option<f64> {
-        self.sell_orders
-            .iter()
-            .filter(|o| o.status == OrderStatus::Open || o.status == OrderStatus::PartiallyFilled)
-            .map(|o| o.price)
-            .next()
+    pub fn best_ask(&self) -> Option<Amount> {
+        self.asks.keys().next().copied()
     }
 
     /// Gets the spread between best bid and ask
-    pub fn spread(&self) -> Option<f64> {
+    pub fn spread(&self) -> Option<Amount> {
         match (self.best_bid(), self.best_ask()) {
             (Some(bid), Some(ask)) => Some(ask - bid),
             _ => None,
         }
     }
 
-    /// Removes filled and cancelled orders
-    pub fn clean_orders(&mut self) {
-        self.buy_orders.retain(|o| {
-            o.status == OrderStatus::Open || o.status == OrderStatus::PartiallyFilled
-        });
-        self.sell_orders.retain(|o| {
-            o.status == OrderStatus::Open || o.status == OrderStatus::PartiallyFilled
+    /// Total remaining quantity that an order on `side` at `price` could match
+    pub fn matchable_quantity(&self, side: OrderSide, price: Amount) -> Amount {
+        match side {
+            OrderSide::Buy => self.asks.range(..=price).map(|(_, l)| l.total_quantity).sum(),
+            OrderSide::Sell => self.bids.range(price..).map(|(_, l)| l.total_quantity).sum(),
+        }
+    }
+
+    /// Runs `f` on the next order to match against an incoming order on `side`
+    ///
+    /// That is the oldest order at the best price on the other side. Level
+    /// totals are updated from the order's remaining quantity afterwards, and
+    /// the order leaves the book once it is no longer active. Returns `None`
+    /// if the other side is empty.
+    pub fn with_best_match<R>(
+        &mut self,
+        side: OrderSide,
+        f: impl FnOnce(&mut Order) -> R,
+    ) -> Option<R> {
+        let (levels, orders, index) = match side {
+            OrderSide::Buy => (&mut self.asks, &mut self.orders, &mut self.index),
+            OrderSide::Sell => (&mut self.bids, &mut self.orders, &mut self.index),
+        };
+        let mut entry = match side {
+            OrderSide::Buy => levels.first_entry()?,
+            OrderSide::Sell => levels.last_entry()?,
+        };
+        let price = *entry.key();
+        let level = entry.get_mut();
+
+        // Drop queue entries of orders that were removed
+        while let Some(seq) = level.queue.front() {
+            if orders.contains_key(seq) {
+                break;
+            }
+            level.queue.pop_front();
+        }
+        let seq = *level.queue.front()?;
+        let order = orders.get_mut(&seq)?;
+
+        let remaining_before = order.remaining_quantity();
+        let result = f(order);
+        level.total_quantity -= remaining_before - order.remaining_quantity();
+        let changed = order.remaining_quantity() != remaining_before || !order.is_active();
+
+        if !order.is_active() {
+            level.total_quantity -= order.remaining_quantity();
+            level.queue.pop_front();
+            level.order_count -= 1;
+            index.remove(&order.id);
+            orders.remove(&seq);
+            if level.order_count == 0 {
+                entry.remove();
+            }
+        }
+        if changed {
+            let resting_side = match side {
+                OrderSide::Buy => OrderSide::Sell,
+                OrderSide::Sell => OrderSide::Buy,
+            };
+            self.record_level(resting_side, price, true);
+        }
+        Some(result)
+    }
+
+    /// Top `levels` price levels of each side
+    pub fn depth(&self, levels: usize) -> DepthSnapshot {
+        let aggregate = |(price, level): (&Amount, &PriceLevel)| DepthLevel {
+            price: *price,
+            quantity: level.total_quantity,
+            order_count: level.order_count,
+        };
+        DepthSnapshot {
+            pair: self.pair.clone(),
+            sequence: self.level_sequence,
+            bids: self.bid_levels().take(levels).map(aggregate).collect(),
+            asks: self.ask_levels().take(levels).map(aggregate).collect(),
+        }
+    }
+
+    /// Sequence number of the last level update
+    pub fn level_sequence(&self) -> u64 {
+        self.level_sequence
+    }
+
+    /// Takes the level updates recorded since the last call, oldest first
+    ///
+    /// Every change to a level's total quantity or order count is recorded,
+    /// and updates accumulate until taken.
+    pub fn take_level_updates(&mut self) -> Vec<LevelUpdate> {
+        std::mem::take(&mut self.level_updates)
+    }
+
+    /// Records the current state of a level that was just changed
+    fn record_level(&mut self, side: OrderSide, price: Amount, existed: bool) {
+        let (action, quantity, order_count) = match self.levels_mut(side).get(&price) {
+            Some(level) => {
+                let action = if existed {
+                    LevelAction::Update
+                } else {
+                    LevelAction::Insert
+                };
+                (action, level.total_quantity, level.order_count)
+            }
+            None => (LevelAction::Delete, Amount::ZERO, 0),
+        };
+        self.level_sequence += 1;
+        self.level_updates.push(LevelUpdate {
+            sequence: self.level_sequence,
+            side,
+            action,
+            level: DepthLevel {
+                price,
+                quantity,
+                order_count,
+            },
         });
     }
 
-    /// Gets an order by ID
-    pub fn get_order_mut(&mut self, order_id: &str) -> Option<&mut Order> {
-        self.buy_orders
-            .iter_mut()
-            .chain(self.sell_orders.iter_mut())
-            .find(|o| o.id == order_id)
+    fn levels_mut(&mut self, side: OrderSide) -> &mut BTreeMap<Amount, PriceLevel> {
+        match side {
+            OrderSide::Buy => &mut self.bids,
+            OrderSide::Sell => &mut self.asks,
+        }
+    }
+
+    fn level_orders<'a>(&'a self, level: &'a PriceLevel) -> impl Iterator<Item = &'a Order> + 'a {
+        level.queue.iter().filter_map(|seq| self.orders.get(seq))
     }
 }
 
 /// Represents a trade execution
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Trade {
     pub id: String,
     pub pair: TradingPair,
-    pub price: f64,
-    pub quantity: f64,
+    pub price: Amount,
+    pub quantity: Amount,
     pub buyer_address: String,
     pub seller_address: String,
     pub buy_order_id: String,
     pub sell_order_id: String,
+    /// Quote amount paid by the buyer
+    pub buyer_quote: Amount,
+    /// Quote amount received by the seller, before fees
+    pub seller_quote: Amount,
+    /// Side of the incoming order; the other side was resting on the book
+    pub taker_side: OrderSide,
+    /// Fee charged to the buyer, in the base currency
+    pub buyer_fee: Amount,
+    /// Fee charged to the seller, in the quote currency
+    pub seller_fee: Amount,
     pub timestamp: i64,
 }
 
 impl Trade {
     pub fn new(
         pair: TradingPair,
-        price: f64,
-        quantity: f64,
+        price: Amount,
+        quantity: Amount,
         buyer_address: String,
         seller_address: String,
         buy_order_id: String,
@@ -217,11 +698,32 @@ impl Trade {
             seller_address,
             buy_order_id,
             sell_order_id,
+            buyer_quote: price * quantity,
+            seller_quote: price * quantity,
+            taker_side: OrderSide::Buy,
+            buyer_fee: Amount::ZERO,
+            seller_fee: Amount::ZERO,
             timestamp: Utc::now().timestamp(),
         }
     }
 }
 
+/// A match between two orders of the same account that self-trade prevention stopped
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreventedSelfTrade {
+    pub pair: TradingPair,
+    pub user_address: String,
+    /// The incoming order, whose mode applied
+    pub taker_order_id: String,
+    /// The resting order it would have matched
+    pub maker_order_id: String,
+    pub mode: SelfTradePrevention,
+    pub price: Amount,
+    /// Quantity that would have traded
+    pub quantity: Amount,
+    pub timestamp: i64,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -233,11 +735,11 @@ mod tests {
             "user123".to_string(),
             pair,
             OrderSide::Buy,
-            50000.0,
-            1.0,
+            Amount::from(50000),
+            Amount::from(1),
         );
         assert_eq!(order.status, OrderStatus::Open);
-        assert_eq!(order.remaining_quantity(), 1.0);
+        assert_eq!(order.remaining_quantity(), Amount::from(1));
     }
 
     #[test]
@@ -247,16 +749,35 @@ mod tests {
             "user123".to_string(),
             pair,
             OrderSide::Buy,
-            50000.0,
-            2.0,
+            Amount::from(50000),
+            Amount::from(2),
         );
 
-        order.fill(1.0);
+        order.fill(Amount::from(1), Amount::from(50000));
         assert_eq!(order.status, OrderStatus::PartiallyFilled);
-        assert_eq!(order.remaining_quantity(), 1.0);
+        assert_eq!(order.remaining_quantity(), Amount::from(1));
+
+        order.fill(Amount::from(1), Amount::from(50000));
+        assert_eq!(order.status, OrderStatus::Filled);
+    }
+
+    #[test]
+    fn test_fractional_fills_leave_no_dust() {
+        let pair = TradingPair::new("BTC", "USDT");
+        let tenth: Amount = "0.1".parse().unwrap();
+        let mut order = Order::new(
+            "user123".to_string(),
+            pair,
+            OrderSide::Sell,
+            Amount::from(50000),
+            "0.3".parse().unwrap(),
+        );
 
-        order.fill(1.0);
+        order.fill(tenth, Amount::from(50000));
+        order.fill(tenth, Amount::from(50000));
+        order.fill(tenth, Amount::from(50000));
         assert_eq!(order.status, OrderStatus::Filled);
+        assert_eq!(order.remaining_quantity(), Amount::ZERO);
     }
 
     #[test]
@@ -264,18 +785,125 @@ mod tests {
         let pair = TradingPair::new("ETH", "USDT");
         let mut order_book = OrderBook::new(pair.clone());
 
-        let buy1 = Order::new("buyer1".to_string(), pair.clone(), OrderSide::Buy, 2000.0, 1.0);
-        let buy2 = Order::new("buyer2".to_string(), pair.clone(), OrderSide::Buy, 2100.0, 1.0);
-        let sell1 = Order::new("seller1".to_string(), pair.clone(), OrderSide::Sell, 2200.0, 1.0);
-        let sell2 = Order::new("seller2".to_string(), pair.clone(), OrderSide::Sell, 2150.0, 1.0);
+        let buy1 = Order::new(
+            "buyer1".to_string(),
+            pair.clone(),
+            OrderSide::Buy,
+            Amount::from(2000),
+            Amount::from(1),
+        );
+        let buy2 = Order::new(
+            "buyer2".to_string(),
+            pair.clone(),
+            OrderSide::Buy,
+            Amount::from(2100),
+            Amount::from(1),
+        );
+        let sell1 = Order::new(
+            "seller1".to_string(),
+            pair.clone(),
+            OrderSide::Sell,
+            Amount::from(2200),
+            Amount::from(1),
+        );
+        let sell2 = Order::new(
+            "seller2".to_string(),
+            pair.clone(),
+            OrderSide::Sell,
+            Amount::from(2150),
+            Amount::from(1),
+        );
+
+        order_book.add_order(buy1);
+        order_book.add_order(buy2);
+        order_book.add_order(sell1);
+        order_book.add_order(sell2);
+
+        assert_eq!(order_book.best_bid(), Some(Amount::from(2100)));
+        assert_eq!(order_book.best_ask(), Some(Amount::from(2150)));
+        assert_eq!(order_book.spread(), Some(Amount::from(50)));
+    }
 
-        order_book.add_buy_order(buy1);
-        order_book.add_buy_order(buy2);
-        order_book.add_sell_order(sell1);
-        order_book.add_sell_order(sell2);
+    fn sell_at(user: &str, price: i64, quantity: i64) -> Order {
+        Order::new(
+            user.to_string(),
+            TradingPair::new("ETH", "USDT"),
+            OrderSide::Sell,
+            Amount::from(price),
+            Amount::from(quantity),
+        )
+    }
+
+    #[test]
+    fn test_order_book_fifo_within_level() {
+        let mut order_book = OrderBook::new(TradingPair::new("ETH", "USDT"));
+        order_book.add_order(sell_at("first", 2000, 1));
+        order_book.add_order(sell_at("better", 1990, 1));
+        order_book.add_order(sell_at("second", 2000, 1));
+
+        let users: Vec<&str> = order_book.sell_orders().map(|o| o.user_address.as_str()).collect();
+        assert_eq!(users, vec!["better", "first", "second"]);
+
+        // The best level is consumed first, then the oldest order at the next level
+        let matched = order_book.with_best_match(OrderSide::Buy, |o| {
+            o.fill(o.remaining_quantity(), o.price);
+            o.user_address.clone()
+        });
+        assert_eq!(matched.as_deref(), Some("better"));
+        let next = order_book.with_best_match(OrderSide::Buy, |o| o.user_address.clone());
+        assert_eq!(next.as_deref(), Some("first"));
+        assert_eq!(order_book.best_ask(), Some(Amount::from(2000)));
+    }
+
+    #[test]
+    fn test_order_book_remove_updates_levels() {
+        let mut order_book = OrderBook::new(TradingPair::new("ETH", "USDT"));
+        let first = sell_at("first", 2000, 1);
+        let first_id = first.id.clone();
+        order_book.add_order(first);
+        order_book.add_order(sell_at("second", 2000, 3));
+
+        let (price, level) = order_book.ask_levels().next().unwrap();
+        assert_eq!(*price, Amount::from(2000));
+        assert_eq!(level.total_quantity, Amount::from(4));
+        assert_eq!(level.order_count, 2);
+
+        let removed = order_book.remove_order(&first_id).unwrap();
+        assert_eq!(removed.user_address, "first");
+        assert!(order_book.get_order(&first_id).is_none());
+        assert!(order_book.remove_order(&first_id).is_none());
+
+        let (_, level) = order_book.ask_levels().next().unwrap();
+        assert_eq!(level.total_quantity, Amount::from(3));
+        assert_eq!(level.order_count, 1);
+
+        // The removed order is skipped when matching
+        let next = order_book.with_best_match(OrderSide::Buy, |o| o.user_address.clone());
+        assert_eq!(next.as_deref(), Some("second"));
+
+        order_book.with_best_match(OrderSide::Buy, |o| o.cancel());
+        assert_eq!(order_book.order_count(), 0);
+        assert_eq!(order_book.best_ask(), None);
+    }
+
+    #[test]
+    fn test_cancelled_orders_do_not_pile_up_in_their_level() {
+        let mut order_book = OrderBook::new(TradingPair::new("ETH", "USDT"));
+        order_book.add_order(sell_at("resting", 2000, 1));
+
+        // Orders placed and cancelled behind a resting one never reach the front
+        for _ in 0..100 {
+            let order = sell_at("cancelled", 2000, 1);
+            let id = order.id.clone();
+            order_book.add_order(order);
+            order_book.remove_order(&id).unwrap();
+        }
+        order_book.add_order(sell_at("last", 2000, 1));
 
-        assert_eq!(order_book.best_bid(), Some(2100.0));
-        assert_eq!(order_book.best_ask(), Some(2150.0));
-        assert_eq!(order_book.spread(), Some(50.0));
+        let level = &order_book.asks[&Amount::from(2000)];
+        assert_eq!(level.order_count, 2);
+        assert!(level.queue.len() <= 2 * level.order_count + 1);
+        let users: Vec<&str> = order_book.sell_orders().map(|o| o.user_address.as_str()).collect();
+        assert_eq!(users, vec!["resting", "last"]);
     }
 }
@@ -0,0 +1,647 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+use tokio::time;
+use uuid::Uuid;
+
+use crate::amount::Amount;
+use crate::block::{Block, BlockOutcome, Blockchain};
+use crate::block_store::BlockStore;
+use crate::log::{self, Log};
+use crate::target::Target;
+use crate::transaction::Transaction;
+
+/// Version of the message protocol; peers must speak the same one
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Timestamp of the genesis block every node starts from
+const GENESIS_TIME: i64 = 1_700_000_000;
+
+/// Time a new connection has to send its Hello
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest message accepted from a peer, newline included
+const MAX_MESSAGE_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Most blocks sent in answer to one GetBlocks
+const MAX_BLOCKS_PER_REQUEST: usize = 500;
+
+/// Most messages queued to one peer
+///
+/// Answers to a peer wait for room, which stops reading from it until its
+/// queue drains. Relayed messages are dropped for a peer whose queue is full.
+const PEER_QUEUE_SIZE: usize = 1024;
+
+/// The chain every node of the network starts from
+pub fn new_chain() -> Blockchain {
+    Blockchain::with_genesis_time(
+        Target::with_leading_zeros(2),
+        Amount::from(10),
+        GENESIS_TIME,
+    )
+}
+
+/// Message between nodes, sent as one line of JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// First message on every connection, sent by both sides
+    Hello {
+        version: u32,
+        /// Hash of the sender's genesis block, which must be ours
+        genesis: String,
+        /// Hash of the last block of the sender's active chain
+        tip: String,
+        height: u64,
+    },
+    /// Hashes of blocks the sender has
+    Inventory {
+        blocks: Vec<String>,
+    },
+    /// Asks for the active chain's blocks after the first locator hash on it,
+    /// see [`Blockchain::locator`]
+    GetBlocks {
+        locator: Vec<String>,
+    },
+    Block(Box<Block>),
+    /// Ends the blocks sent in answer to a GetBlocks
+    BlocksEnd {
+        /// The sender's active chain goes on past the last block sent
+        more: bool,
+    },
+    Tx(Box<Transaction>),
+}
+
+/// Queue of messages to one peer
+type PeerSender = mpsc::Sender<Message>;
+
+/// Blocks a session has asked its peer for
+///
+/// Only one GetBlocks is out at a time, so a peer announcing blocks while it
+/// is still sending the ones asked for is not asked for them all again.
+#[derive(Default)]
+struct BlockRequest {
+    /// A GetBlocks was sent and its BlocksEnd has not arrived yet
+    pending: bool,
+    /// Unknown blocks the peer announced while a GetBlocks was out
+    missed: HashSet<String>,
+}
+
+impl BlockRequest {
+    /// Asks for the blocks after our active chain, unless a request is out
+    ///
+    /// `unknown` are the blocks that call for it, kept to check once the
+    /// pending request is answered.
+    fn send(
+        &mut self,
+        chain: &Blockchain,
+        unknown: impl IntoIterator<Item = String>,
+    ) -> Option<Message> {
+        if self.pending {
+            self.missed.extend(unknown);
+            return None;
+        }
+        self.pending = true;
+        Some(Message::GetBlocks {
+            locator: chain.locator(),
+        })
+    }
+
+    /// Notes that the peer answered, and asks again if it has more blocks or
+    /// announced some that the answer did not bring
+    fn answered(&mut self, chain: &Blockchain, more: bool) -> Option<Message> {
+        self.pending = false;
+        let missed = std::mem::take(&mut self.missed);
+        if more || missed.iter().any(|hash| !chain.contains_block(hash)) {
+            return self.send(chain, []);
+        }
+        None
+    }
+}
+
+/// A node of the chain's peer-to-peer network
+///
+/// Nodes connect over TCP and exchange [`Message`]s. After the Hello
+/// handshake, a node that sees a tip it does not know asks for the blocks it
+/// is missing, so nodes catch up as soon as they connect. New transactions
+/// are relayed to every other peer, and new blocks announced by hash for
+/// peers to fetch. Blocks go through [`Blockchain::add_block`], so every node
+/// follows the branch with the most work.
+#[derive(Clone)]
+pub struct Node {
+    chain: Arc<Mutex<Blockchain>>,
+    peers: Arc<Mutex<HashMap<u64, PeerSender>>>,
+    next_peer: Arc<AtomicU64>,
+    /// Where accepted blocks are stored, if anywhere
+    store: Arc<Mutex<Option<BlockStore>>>,
+    /// Wakes the task that writes accepted blocks to the store
+    accepted: Arc<Notify>,
+    /// Where ended sessions, refused blocks and failed writes are reported
+    log: Log,
+}
+
+impl Node {
+    pub fn new(chain: Blockchain) -> Self {
+        Node {
+            chain: Arc::new(Mutex::new(chain)),
+            peers: Arc::default(),
+            next_peer: Arc::default(),
+            store: Arc::default(),
+            accepted: Arc::default(),
+            log: log::discard(),
+        }
+    }
+
+    /// Reports ended sessions, refused blocks and failed writes to `log`
+    ///
+    /// Set it before [`Node::with_store`], whose writer task keeps the log
+    /// the node has when it starts.
+    pub fn with_log(mut self, log: Log) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// Saves the chain's blocks to `store` now and whenever it accepts a block
+    ///
+    /// Accepted blocks are written by a background task, so this must be
+    /// called from within a Tokio runtime.
+    pub fn with_store(self, mut store: BlockStore) -> Result<Self, String> {
+        store.save(&mut self.chain())?;
+        *self.store() = Some(store);
+        tokio::spawn(self.clone().write_blocks());
+        Ok(self)
+    }
+
+    /// Writes the blocks the node accepts to its store, one save at a time
+    async fn write_blocks(self) {
+        loop {
+            self.accepted.notified().await;
+            if let Err(e) = self.save().await {
+                // The blocks stay accepted and the next save stores them
+                (self.log)(&format!("Failed to store blocks: {}", e));
+            }
+        }
+    }
+
+    /// Stores the blocks the store does not have yet, pruning when due
+    ///
+    /// The chain and the store are locked only to copy the blocks to write
+    /// and to prune; the files are written on a blocking thread, with the
+    /// chain unlocked so blocks keep coming in.
+    async fn save(&self) -> Result<(), String> {
+        let unsaved = {
+            let chain = self.chain();
+            self.store().as_ref().map(|store| store.unsaved(&chain))
+        };
+        let Some(unsaved) = unsaved else {
+            return Ok(());
+        };
+        self.on_store(move |store| store.write(unsaved)).await?;
+
+        let pruned = {
+            let mut chain = self.chain();
+            let height = self
+                .store()
+                .as_ref()
+                .and_then(|store| store.prune_height(&chain));
+            height.map(|height| {
+                chain.prune(height);
+                chain.clone()
+            })
+        };
+        match pruned {
+            Some(chain) => self.on_store(move |store| store.compact(&chain)).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Runs `write` on the store on a blocking thread
+    async fn on_store<F>(&self, write: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut BlockStore) -> Result<(), String> + Send + 'static,
+    {
+        let node = self.clone();
+        tokio::task::spawn_blocking(move || node.store().as_mut().map_or(Ok(()), write))
+            .await
+            .expect("Block writer panicked")
+    }
+
+    /// Locks the node's chain
+    pub fn chain(&self) -> MutexGuard<'_, Blockchain> {
+        // The chain stays consistent even if a session panicked while holding it
+        self.chain.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Number of peers that completed the handshake and are still connected
+    pub fn peer_count(&self) -> usize {
+        self.peers().len()
+    }
+
+    /// Accepts peer connections until the listener fails
+    pub async fn serve(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            tokio::spawn(self.clone().run(stream));
+        }
+    }
+
+    /// Connects to a peer and keeps the connection in the background
+    pub async fn connect(&self, address: &str) -> Result<(), String> {
+        let stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| format!("Failed to connect to {}: {}", address, e))?;
+        tokio::spawn(self.clone().run(stream));
+        Ok(())
+    }
+
+    /// Adds a transaction to the mempool and relays it to every peer
+    pub fn submit_transaction(&self, transaction: Transaction) -> Result<(), String> {
+        self.chain().add_transaction(transaction.clone())?;
+        self.broadcast(Message::Tx(Box::new(transaction)), None);
+        Ok(())
+    }
+
+    /// Mines a block of pending transactions and announces it
+    ///
+    /// The chain stays unlocked while mining, so blocks from peers keep
+    /// coming in; if one of them wins the race, the mined block ends up on
+    /// a side branch.
+    pub async fn mine(&self, miner_address: &str) -> Result<BlockOutcome, String> {
+        let reward_id = Uuid::new_v4().to_string();
+        let mut block =
+            self.chain()
+                .candidate_block(miner_address, reward_id, Utc::now().timestamp());
+        let block = tokio::task::spawn_blocking(move || {
+            block.mine();
+            block
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+        let hash = block.hash.clone();
+        let outcome = self.add_block(&mut self.chain(), block)?;
+        self.broadcast(Message::Inventory { blocks: vec![hash] }, None);
+        Ok(outcome)
+    }
+
+    async fn run(self, stream: TcpStream) {
+        let address = stream
+            .peer_addr()
+            .map(|address| address.to_string())
+            .unwrap_or_default();
+        if let Err(e) = self.session(stream).await {
+            (self.log)(&format!("Peer {} disconnected: {}", address, e));
+        }
+    }
+
+    /// Shakes hands with a peer, then handles its messages until it leaves
+    async fn session(&self, stream: TcpStream) -> Result<(), String> {
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let (sender, outbox) = mpsc::channel(PEER_QUEUE_SIZE);
+        let writing = tokio::spawn(write_messages(writer, outbox));
+        let _ = sender.send(self.hello()).await;
+
+        let mut request = BlockRequest::default();
+        let result = match self.handshake(&mut reader, &sender, &mut request).await {
+            Ok(peer) => {
+                let result = self
+                    .receive_all(peer, &mut reader, &sender, &mut request)
+                    .await;
+                self.peers().remove(&peer);
+                result
+            }
+            Err(e) => Err(e),
+        };
+        // Let the writer flush what is queued, such as our Hello to a peer we turn away
+        drop(sender);
+        let _ = writing.await;
+        result
+    }
+
+    /// Checks the peer's Hello and registers the peer
+    async fn handshake(
+        &self,
+        reader: &mut BufReader<OwnedReadHalf>,
+        sender: &PeerSender,
+        request: &mut BlockRequest,
+    ) -> Result<u64, String> {
+        let hello = time::timeout(HANDSHAKE_TIMEOUT, read_message(reader))
+            .await
+            .map_err(|_| "No Hello in time".to_string())??;
+        let Some(Message::Hello {
+            version,
+            genesis,
+            tip,
+            ..
+        }) = hello
+        else {
+            return Err("Expected Hello".to_string());
+        };
+        if version != PROTOCOL_VERSION {
+            return Err(format!("Unsupported protocol version {}", version));
+        }
+        let get_blocks = {
+            let chain = self.chain();
+            if genesis != chain.chain[0].hash {
+                return Err(format!(
+                    "Peer is on another chain, with genesis {}",
+                    genesis
+                ));
+            }
+            // Catch up with a peer that is ahead or on another branch
+            if chain.contains_block(&tip) {
+                None
+            } else {
+                request.send(&chain, [tip])
+            }
+        };
+        if let Some(message) = get_blocks {
+            send(sender, message).await?;
+        }
+        let peer = self.next_peer.fetch_add(1, Ordering::Relaxed);
+        self.peers().insert(peer, sender.clone());
+        Ok(peer)
+    }
+
+    async fn receive_all(
+        &self,
+        peer: u64,
+        reader: &mut BufReader<OwnedReadHalf>,
+        sender: &PeerSender,
+        request: &mut BlockRequest,
+    ) -> Result<(), String> {
+        while let Some(message) = read_message(reader).await? {
+            for reply in self.receive(peer, message, request) {
+                send(sender, reply).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a message from a peer and returns the replies to it
+    fn receive(&self, peer: u64, message: Message, request: &mut BlockRequest) -> Vec<Message> {
+        match message {
+            // Repeated handshakes change nothing
+            Message::Hello { .. } => vec![],
+            Message::Inventory { blocks } => {
+                let chain = self.chain();
+                let unknown: Vec<String> = blocks
+                    .into_iter()
+                    .filter(|hash| !chain.contains_block(hash))
+                    .collect();
+                if unknown.is_empty() {
+                    return vec![];
+                }
+                request.send(&chain, unknown).into_iter().collect()
+            }
+            Message::GetBlocks { locator } => {
+                let chain = self.chain();
+                let blocks = chain.blocks_after(&locator, MAX_BLOCKS_PER_REQUEST);
+                let last = blocks.last().map(|block| block.index as usize);
+                let more = last.is_some_and(|last| last + 1 < chain.chain.len());
+                blocks
+                    .iter()
+                    .map(|block| Message::Block(Box::new(block.clone())))
+                    .chain([Message::BlocksEnd { more }])
+                    .collect()
+            }
+            Message::Block(block) => self.receive_block(peer, *block, request),
+            Message::BlocksEnd { more } => {
+                request.answered(&self.chain(), more).into_iter().collect()
+            }
+            Message::Tx(transaction) => {
+                if self.chain().add_transaction((*transaction).clone()).is_ok() {
+                    self.broadcast(Message::Tx(transaction), Some(peer));
+                }
+                vec![]
+            }
+        }
+    }
+
+    fn receive_block(&self, peer: u64, block: Block, request: &mut BlockRequest) -> Vec<Message> {
+        let hash = block.hash.clone();
+        let added = {
+            let mut chain = self.chain();
+            if chain.contains_block(&hash) {
+                return vec![];
+            }
+            if !chain.contains_block(&block.previous_hash) {
+                // Ask the peer for the blocks in between
+                return request.send(&chain, [hash]).into_iter().collect();
+            }
+            self.add_block(&mut chain, block)
+        };
+        match added {
+            Ok(_) => self.broadcast(Message::Inventory { blocks: vec![hash] }, Some(peer)),
+            Err(e) => (self.log)(&format!("Rejected block {}: {}", hash, e)),
+        }
+        vec![]
+    }
+
+    /// Adds a block to the chain and has the store writer save it
+    fn add_block(&self, chain: &mut Blockchain, block: Block) -> Result<BlockOutcome, String> {
+        let outcome = chain.add_block(block)?;
+        self.accepted.notify_one();
+        Ok(outcome)
+    }
+
+    fn hello(&self) -> Message {
+        let chain = self.chain();
+        Message::Hello {
+            version: PROTOCOL_VERSION,
+            genesis: chain.chain[0].hash.clone(),
+            tip: chain.get_latest_block().hash.clone(),
+            height: chain.get_latest_block().index,
+        }
+    }
+
+    /// Queues a message to every peer but `except`
+    ///
+    /// A peer whose queue is full misses the message; it hears of later
+    /// blocks and asks for the ones it lacks then.
+    fn broadcast(&self, message: Message, except: Option<u64>) {
+        for (peer, sender) in self.peers().iter() {
+            if Some(*peer) != except {
+                let _ = sender.try_send(message.clone());
+            }
+        }
+    }
+
+    fn peers(&self) -> MutexGuard<'_, HashMap<u64, PeerSender>> {
+        self.peers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn store(&self) -> MutexGuard<'_, Option<BlockStore>> {
+        self.store.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Queues a message to a peer, waiting for room in its queue
+async fn send(sender: &PeerSender, message: Message) -> Result<(), String> {
+    sender
+        .send(message)
+        .await
+        .map_err(|_| "Connection closed".to_string())
+}
+
+/// Reads the next message, or `None` if the peer closed the connection
+async fn read_message(reader: &mut BufReader<OwnedReadHalf>) -> Result<Option<Message>, String> {
+    let mut line = vec![];
+    let read = (&mut *reader)
+        .take(MAX_MESSAGE_BYTES)
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(|e| e.to_string())?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err("Message too long or cut off".to_string());
+    }
+    serde_json::from_slice(&line)
+        .map(Some)
+        .map_err(|e| format!("Invalid message: {}", e))
+}
+
+/// Writes queued messages to a peer until the queue closes or writing fails
+async fn write_messages(mut writer: OwnedWriteHalf, mut outbox: mpsc::Receiver<Message>) {
+    while let Some(message) = outbox.recv().await {
+        let mut line = serde_json::to_vec(&message).expect("messages always serialize");
+        line.push(b'\n');
+        if writer.write_all(&line).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    /// Key the test nodes take deposits from
+    fn custodian() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    /// Serves a node on a free port and returns it with its address
+    async fn start() -> (Node, String) {
+        let node = Node::new(new_chain().with_custodian(&custodian().verifying_key()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(node.clone().serve(listener));
+        (node, address)
+    }
+
+    #[test]
+    fn test_one_block_request_at_a_time() {
+        let node = Node::new(new_chain());
+        let mut request = BlockRequest::default();
+        let inventory = |hash: &str| Message::Inventory {
+            blocks: vec![hash.to_string()],
+        };
+        let asks = |replies: Vec<Message>| {
+            replies
+                .iter()
+                .filter(|reply| matches!(reply, Message::GetBlocks { .. }))
+                .count()
+        };
+
+        // Announcements that arrive while blocks are on their way ask for nothing more
+        assert_eq!(asks(node.receive(0, inventory("a"), &mut request)), 1);
+        assert_eq!(asks(node.receive(0, inventory("b"), &mut request)), 0);
+        assert_eq!(asks(node.receive(0, inventory("a"), &mut request)), 0);
+
+        // The answer did not bring them, so the node asks once more
+        let end = |more| Message::BlocksEnd { more };
+        assert_eq!(asks(node.receive(0, end(false), &mut request)), 1);
+        assert_eq!(asks(node.receive(0, end(false), &mut request)), 0);
+        // A peer with more to send is asked for the rest
+        assert_eq!(asks(node.receive(0, end(true), &mut request)), 1);
+        assert!(request.pending);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_other_versions_and_chains() {
+        let (node, address) = start().await;
+        let hello = |version: u32, genesis: String| {
+            let mut line = serde_json::to_vec(&Message::Hello {
+                version,
+                genesis,
+                tip: String::new(),
+                height: 0,
+            })
+            .unwrap();
+            line.push(b'\n');
+            line
+        };
+        let genesis = node.chain().chain[0].hash.clone();
+        let other_genesis = Blockchain::new(Target::MAX, Amount::from(10)).chain[0]
+            .hash
+            .clone();
+
+        for line in [
+            hello(PROTOCOL_VERSION + 1, genesis),
+            hello(PROTOCOL_VERSION, other_genesis),
+        ] {
+            let mut stream = TcpStream::connect(&address).await.unwrap();
+            stream.write_all(&line).await.unwrap();
+            // The node sends its own Hello, then hangs up
+            let mut received = vec![];
+            stream.read_to_end(&mut received).await.unwrap();
+            let first_line = received.split(|byte| *byte == b'\n').next().unwrap();
+            let reply: Message = serde_json::from_slice(first_line).unwrap();
+            assert!(matches!(
+                reply,
+                Message::Hello {
+                    version: PROTOCOL_VERSION,
+                    ..
+                }
+            ));
+        }
+        assert_eq!(node.peer_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_accepted_blocks_are_stored_and_pruned_in_the_background() {
+        let dir = std::env::temp_dir().join(format!("node-store-{}", Uuid::new_v4()));
+        let store = BlockStore::open(&dir).unwrap().with_pruning(2);
+        let node = Node::new(new_chain()).with_store(store).unwrap();
+        for _ in 0..6 {
+            node.mine("Miner").await.unwrap();
+        }
+
+        // The writer catches up with the chain while the node goes on
+        let stored = |height| {
+            node.store()
+                .as_ref()
+                .unwrap()
+                .block_by_height(height)
+                .unwrap()
+        };
+        for _ in 0..100 {
+            let pruned_height = node.chain().pruned_height;
+            let compacted = stored(pruned_height).is_some_and(|b| b.transactions.is_empty());
+            if stored(6).is_some() && pruned_height >= 3 && compacted {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        let tip = node.chain().get_latest_block().clone();
+        assert_eq!(stored(6).map(|block| block.hash), Some(tip.hash));
+        // Pruned once no more pruning is due at this height, in the store as well
+        let pruned_height = node.chain().pruned_height;
+        assert!((3..=4).contains(&pruned_height));
+        assert!(stored(pruned_height).unwrap().transactions.is_empty());
+        assert!(!stored(pruned_height + 1).unwrap().transactions.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::amount::{Amount, AssetScales};
+use crate::block::Blockchain;
+use crate::candle::CandleSeries;
+use crate::fee::FeeSchedule;
+use crate::order::{Order, OrderBook, PreventedSelfTrade, SelfTradePrevention, Trade, TradingPair};
+use crate::wallet::WalletManager;
+
+/// Format version written by this build; other versions are refused on restore
+pub const SNAPSHOT_VERSION: u32 = 9;
+
+/// Full state of an exchange at one point in time
+///
+/// Everything but the exchange's clock and id generator is captured; a
+/// restored exchange gets fresh ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExchangeSnapshot {
+    pub version: u32,
+    /// Time the snapshot was taken, from the exchange's clock
+    pub taken_at: i64,
+    pub name: String,
+    pub supported_pairs: Vec<TradingPair>,
+    pub order_books: HashMap<String, OrderBook>,
+    pub stop_orders: HashMap<String, Vec<Order>>,
+    pub wallet_manager: WalletManager,
+    pub blockchain: Blockchain,
+    pub trades: Vec<Trade>,
+    pub candles: HashMap<String, CandleSeries>,
+    pub prevented_self_trades: Vec<PreventedSelfTrade>,
+    pub self_trade_prevention: HashMap<String, SelfTradePrevention>,
+    pub asset_scales: AssetScales,
+    pub fee_schedule: FeeSchedule,
+    pub trading_volumes: HashMap<String, HashMap<String, Amount>>,
+    pub max_slippage: Amount,
+}
+
+impl ExchangeSnapshot {
+    /// Writes the snapshot as JSON
+    ///
+    /// The file is written next to `path` and renamed over it, so a crash
+    /// never leaves a half-written snapshot behind.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let json = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, json)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| format!("Failed to write snapshot {}: {}", path.display(), e))
+    }
+
+    /// Reads a snapshot written by [`ExchangeSnapshot::save`]
+    ///
+    /// Only checks the format version; [`ExchangeSnapshot::validate`] checks the contents.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let json = fs::read(path)
+            .map_err(|e| format!("Failed to read snapshot {}: {}", path.display(), e))?;
+
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+        let version: Version =
+            serde_json::from_slice(&json).map_err(|e| format!("Invalid snapshot: {}", e))?;
+        if version.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "Unsupported snapshot version {} (expected {})",
+                version.version, SNAPSHOT_VERSION
+            ));
+        }
+        serde_json::from_slice(&json).map_err(|e| format!("Invalid snapshot: {}", e))
+    }
+
+    /// Checks that the snapshot describes a consistent exchange
+    ///
+    /// The blockchain must be valid and take deposits from the wallet
+    /// manager's custodian, every supported pair must have an order book, and
+    /// the funds locked in each wallet must equal what its resting and pending
+    /// stop orders hold.
+    pub fn validate(&self) -> Result<(), String> {
+        let custodian = hex::encode(self.wallet_manager.custodian().as_bytes());
+        if self.blockchain.custodian.as_ref() != Some(&custodian) {
+            return Err("Snapshot blockchain takes deposits from another custodian".to_string());
+        }
+        if !self.blockchain.is_valid() {
+            return Err("Snapshot blockchain is invalid".to_string());
+        }
+        for pair in &self.supported_pairs {
+            if !self.order_books.contains_key(&pair.symbol()) {
+                return Err(format!("Snapshot has no order book for {}", pair.symbol()));
+            }
+        }
+
+        let mut held: HashMap<(&str, &str), Amount> = HashMap::new();
+        let resting = self
+            .order_books
+            .values()
+            .flat_map(|book| book.buy_orders().chain(book.sell_orders()));
+        for order in resting.chain(self.stop_orders.values().flatten()) {
+            let key = (order.user_address.as_str(), order.hold_currency());
+            *held.entry(key).or_default() += order.locked_amount;
+        }
+
+        for wallet in self.wallet_manager.wallets() {
+            for (currency, balance) in &wallet.balances {
+                let expected = held
+                    .remove(&(wallet.address.as_str(), currency.as_str()))
+                    .unwrap_or_default();
+                if balance.locked != expected {
+                    return Err(format!(
+                        "Wallet {} has {} {} locked but its orders hold {}",
+                        wallet.address, balance.locked, currency, expected
+                    ));
+                }
+            }
+        }
+        if let Some(((address, currency), amount)) = held.iter().find(|(_, a)| !a.is_zero()) {
+            return Err(format!(
+                "Orders hold {} {} for wallet {} that has none locked",
+                amount, currency, address
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::Exchange;
+    use crate::order::{OrderSide, StopOrderKind};
+    use uuid::Uuid;
+
+    fn temp_snapshot(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("snapshot-{}-{}.json", name, Uuid::new_v4()))
+    }
+
+    /// An exchange with a trade, a resting bid, a pending stop and a mined block
+    fn busy_exchange() -> (Exchange, String, String) {
+        let mut exchange = Exchange::new("SnapshotExchange");
+        let alice = exchange.create_wallet("Alice");
+        let bob = exchange.create_wallet("Bob");
+        exchange
+            .deposit(&alice, "USDT", Amount::from(100000))
+            .unwrap();
+        exchange.deposit(&bob, "BTC", Amount::from(5)).unwrap();
+
+        let pair = TradingPair::new("BTC", "USDT");
+        let order = |user: &String, side, price| {
+            (
+                user.clone(),
+                pair.clone(),
+                side,
+                Amount::from(price),
+                Amount::from(1),
+            )
+        };
+        for (user, pair, side, price, quantity) in [
+            order(&bob, OrderSide::Sell, 30000),
+            order(&alice, OrderSide::Buy, 30000),
+            order(&alice, OrderSide::Buy, 29000),
+        ] {
+            exchange
+                .place_order(user, pair, side, price, quantity)
+                .unwrap();
+        }
+        exchange
+            .place_stop_order(
+                bob.clone(),
+                pair,
+                OrderSide::Sell,
+                StopOrderKind::StopLoss {
+                    stop_price: Amount::from(28000),
+                },
+                Amount::from(1),
+            )
+            .unwrap();
+        exchange.mine_transactions(&bob);
+        (exchange, alice, bob)
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let (exchange, alice, bob) = busy_exchange();
+        let path = temp_snapshot("round-trip");
+        exchange.save_snapshot(&path).unwrap();
+
+        let mut restored = Exchange::restore_snapshot(&path).unwrap();
+        let json = |e: &Exchange| serde_json::to_value(e.snapshot().order_books).unwrap();
+        assert_eq!(json(&restored), json(&exchange));
+        assert_eq!(restored.trades.len(), 1);
+        assert_eq!(restored.blockchain.chain.len(), 2);
+        assert_eq!(restored.stop_orders["BTC/USDT"].len(), 1);
+        assert_eq!(
+            restored.get_locked_balance(&alice, "USDT"),
+            Amount::from(29000)
+        );
+        assert_eq!(restored.get_locked_balance(&bob, "BTC"), Amount::from(1));
+
+        // The restored exchange keeps trading from where the original stopped
+        let bid = restored
+            .get_order_book(&TradingPair::new("BTC", "USDT"))
+            .unwrap();
+        let bid_id = bid.buy_orders().next().unwrap().id.clone();
+        restored
+            .cancel_order(&bid_id, &TradingPair::new("BTC", "USDT"))
+            .unwrap();
+        assert_eq!(restored.get_locked_balance(&alice, "USDT"), Amount::ZERO);
+        assert_eq!(restored.get_balance(&alice, "USDT"), Amount::from(70000));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_restore_rejects_inconsistent_snapshots() {
+        let (exchange, alice, _) = busy_exchange();
+
+        let mut wrong_version = exchange.snapshot();
+        wrong_version.version = SNAPSHOT_VERSION + 1;
+        let path = temp_snapshot("version");
+        wrong_version.save(&path).unwrap();
+        let err = Exchange::restore_snapshot(&path).unwrap_err();
+        assert!(err.contains("Unsupported snapshot version"));
+        std::fs::remove_file(&path).unwrap();
+
+        let mut tampered_chain = exchange.snapshot();
+        tampered_chain.blockchain.chain[1].transactions.clear();
+        assert!(tampered_chain.validate().is_err());
+
+        let mut other_custodian = exchange.snapshot();
+        other_custodian.blockchain.custodian = Some("00".repeat(32));
+        let err = other_custodian.validate().unwrap_err();
+        assert!(err.contains("custodian"));
+
+        let mut unbacked_lock = exchange.snapshot();
+        let wallet = unbacked_lock.wallet_manager.get_wallet_mut(&alice).unwrap();
+        wallet.balances.get_mut("USDT").unwrap().locked += Amount::from(1);
+        let err = unbacked_lock.validate().unwrap_err();
+        assert!(err.contains("locked"));
+
+        assert!(exchange.snapshot().validate().is_ok());
+    }
+}
@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Low three bytes of a compact target: its leading digits
+const MANTISSA_MASK: u32 = 0x00ff_ffff;
+
+/// Most a single retarget can scale the target by, either way
+const MAX_ADJUSTMENT: i64 = 4;
+
+/// Proof-of-work target in compact form
+///
+/// A block's hash, read as a 256-bit big-endian number, must not exceed its
+/// target. As with Bitcoin's `bits`, the top byte is the target's length in
+/// bytes and the low three bytes its leading digits, so the target is
+/// `mantissa * 256^(length - 3)`. Unlike Bitcoin's, the mantissa has no sign
+/// bit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Target(u32);
+
+impl Target {
+    /// Easiest target: every hash up to `ffffff00...00` meets it
+    pub const MAX: Target = Target(0x20ff_ffff);
+
+    /// Hardest target: only a hash of 1 or 0 meets it
+    pub const MIN: Target = Target(0x0300_0001);
+
+    pub fn from_bits(bits: u32) -> Self {
+        Target::from_parts((bits & MANTISSA_MASK) as u128, (bits >> 24) as i32)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Largest target that only hashes starting with `zeros` hex zeros meet
+    pub fn with_leading_zeros(zeros: u32) -> Self {
+        let bits = 256 - 4 * zeros.min(64) as i32;
+        Target::from_parts((1 << (bits % 8 + 24)) - 1, bits / 8)
+    }
+
+    /// Builds the compact form of `mantissa * 256^(length - 3)`
+    ///
+    /// Digits that do not fit in three bytes are dropped, rounding down.
+    /// The result is kept within [`Target::MIN`] and [`Target::MAX`].
+    fn from_parts(mut mantissa: u128, mut length: i32) -> Self {
+        while mantissa > MANTISSA_MASK as u128 || (length < 3 && mantissa > 0) {
+            mantissa >>= 8;
+            length += 1;
+        }
+        if mantissa == 0 {
+            return Target::MIN;
+        }
+        while mantissa <= 0xffff && length > 3 {
+            mantissa <<= 8;
+            length -= 1;
+        }
+        if length > 32 {
+            return Target::MAX;
+        }
+        Target(((length as u32) << 24) | mantissa as u32)
+    }
+
+    fn mantissa(self) -> u32 {
+        self.0 & MANTISSA_MASK
+    }
+
+    fn length(self) -> usize {
+        (self.0 >> 24) as usize
+    }
+
+    /// The full 256-bit target, big-endian
+    pub fn to_bytes(self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        let end = 35 - self.length();
+        bytes[end - 3..end].copy_from_slice(&self.mantissa().to_be_bytes()[1..]);
+        bytes
+    }
+
+    /// Returns true if a hex block hash is at most the target
+    pub fn is_met_by(self, hash: &str) -> bool {
+        let Some(hash) = hex::decode(hash)
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        else {
+            return false;
+        };
+        hash <= self.to_bytes()
+    }
+
+    /// Scales the target by how long blocks took against how long they should have
+    ///
+    /// Blocks that came too fast lower the target, making it harder to meet,
+    /// and slow blocks raise it, by at most a factor of four either way.
+    pub fn retarget(self, actual_seconds: i64, expected_seconds: i64) -> Self {
+        let expected = expected_seconds.max(1);
+        let actual = actual_seconds.clamp(
+            (expected / MAX_ADJUSTMENT).max(1),
+            expected * MAX_ADJUSTMENT,
+        );
+        // Four extra bytes keep the digits that the division would drop
+        let scaled = ((self.mantissa() as u128) << 32) * actual as u128 / expected as u128;
+        Target::from_parts(scaled, self.length() as i32 - 4)
+    }
+
+    /// Expected number of hashes it takes to meet the target
+    ///
+    /// Roughly `2^256 / target`. Saturates at `u128::MAX` for targets far
+    /// harder than any chain here will reach.
+    pub fn work(self) -> u128 {
+        let shift = 280 - 8 * self.length() as u32;
+        if shift > 127 {
+            return u128::MAX;
+        }
+        ((1u128 << shift) / self.mantissa() as u128).max(1)
+    }
+
+    /// How many times harder the target is to meet than [`Target::MAX`]
+    pub fn difficulty(self) -> f64 {
+        Target::MAX.value() / self.value()
+    }
+
+    fn value(self) -> f64 {
+        self.mantissa() as f64 * 256f64.powi(self.length() as i32 - 3)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex_target(target: Target) -> String {
+        hex::encode(target.to_bytes())
+    }
+
+    #[test]
+    fn test_compact_form() {
+        let two_zeros = Target::with_leading_zeros(2);
+        assert_eq!(two_zeros.bits(), 0x1fff_ffff);
+        assert!(hex_target(two_zeros).starts_with("00ffffff00"));
+        let three_zeros = Target::with_leading_zeros(3);
+        assert!(hex_target(three_zeros).starts_with("000fffff00"));
+        assert_eq!(Target::with_leading_zeros(0), Target::MAX);
+        assert_eq!(Target::with_leading_zeros(64), Target::MIN);
+        assert_eq!(Target::from_bits(two_zeros.bits()), two_zeros);
+        assert_eq!(Target::from_bits(0), Target::MIN);
+        assert_eq!(two_zeros.to_string(), "1fffffff");
+
+        assert!(two_zeros.is_met_by(&hex_target(two_zeros)));
+        assert!(two_zeros.is_met_by(&format!("00fffffe{}", "f".repeat(56))));
+        assert!(!two_zeros.is_met_by(&format!("00ffffff{}", "0".repeat(55) + "1")));
+        assert!(!two_zeros.is_met_by("not a hash"));
+        assert!(three_zeros.difficulty() > 16.0 * two_zeros.difficulty() * 0.99);
+        assert_eq!(two_zeros.work(), 256);
+        assert_eq!(Target::MAX.work(), 1);
+        assert_eq!(Target::MIN.work(), u128::MAX);
+    }
+
+    #[test]
+    fn test_retarget_scales_in_fine_steps() {
+        let target = Target::with_leading_zeros(2);
+        // Blocks twice too slow double the target, to three significant bytes
+        let doubled = target.retarget(1200, 600);
+        assert_eq!(doubled.to_bytes()[0..4], [0x01, 0xff, 0xff, 0x00]);
+        // Blocks in three quarters of the time make it a third harder
+        let harder = target.retarget(450, 600);
+        let ratio = harder.difficulty() / target.difficulty();
+        assert!((ratio - 4.0 / 3.0).abs() < 1e-6);
+        assert_eq!(target.retarget(600, 600), target);
+
+        // At most a factor of four either way
+        assert_eq!(target.retarget(1, 600), target.retarget(150, 600));
+        assert_eq!(target.retarget(-60, 600), target.retarget(150, 600));
+        assert_eq!(target.retarget(60_000, 600), target.retarget(2400, 600));
+        assert_eq!(Target::MAX.retarget(2400, 600), Target::MAX);
+        assert_eq!(Target::MIN.retarget(150, 600), Target::MIN);
+    }
+}
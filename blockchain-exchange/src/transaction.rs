@@ -1,16 +1,51 @@
 use chrono::Utc;
+use ed25519_dalek::SigningKey;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
+use crate::amount::Amount;
+use crate::keys;
+
+/// Sender of mining rewards
+pub const SYSTEM_ADDRESS: &str = "SYSTEM";
+
+/// Counterparty of deposits into and withdrawals out of the exchange
+pub const EXTERNAL_ADDRESS: &str = "EXTERNAL";
+
+/// Currency of mining rewards
+///
+/// The exchange does not trade it, so rewards never mix with wallet balances.
+pub const REWARD_CURRENCY: &str = "REWARD";
+
 /// Represents a transaction in the blockchain
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub id: String,
     pub from_address: String,
     pub to_address: String,
-    pub amount: f64,
+    /// Asset moved, e.g. `BTC`
+    pub currency: String,
+    pub amount: Amount,
+    /// Exchange fee charged on the transfer, in the same currency
+    ///
+    /// Blocks take the highest fees first. The fee itself reaches the fee
+    /// wallet through a transfer of its own.
+    #[serde(default)]
+    pub fee: Amount,
     pub timestamp: i64,
     pub transaction_type: TransactionType,
+    /// Position among the sender's signed transactions, starting at 0
+    ///
+    /// Each nonce is accepted once, so a signed transaction cannot be replayed.
+    #[serde(default)]
+    pub nonce: u64,
+    /// Hex public key of the sender, whose address it must hash to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// Hex ed25519 signature of [`Transaction::signing_bytes`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 /// Types of transactions
@@ -25,51 +60,224 @@ pub enum TransactionType {
 
 impl Transaction {
     /// Creates a new transfer transaction
-    pub fn new(from_address: String, to_address: String, amount: f64) -> Self {
+    pub fn new(from_address: String, to_address: String, currency: &str, amount: Amount) -> Self {
         Transaction {
             id: Uuid::new_v4().to_string(),
             from_address,
             to_address,
+            currency: currency.to_string(),
             amount,
+            fee: Amount::ZERO,
             timestamp: Utc::now().timestamp(),
             transaction_type: TransactionType::Transfer,
+            nonce: 0,
+            public_key: None,
+            signature: None,
         }
     }
 
     /// Creates a trade transaction
-    pub fn new_trade(from_address: String, to_address: String, amount: f64) -> Self {
+    pub fn new_trade(
+        from_address: String,
+        to_address: String,
+        currency: &str,
+        amount: Amount,
+    ) -> Self {
         Transaction {
             id: Uuid::new_v4().to_string(),
             from_address,
             to_address,
+            currency: currency.to_string(),
             amount,
+            fee: Amount::ZERO,
             timestamp: Utc::now().timestamp(),
             transaction_type: TransactionType::Trade,
+            nonce: 0,
+            public_key: None,
+            signature: None,
         }
     }
 
+    /// Replaces the generated id and timestamp
+    pub fn with_id_and_timestamp(mut self, id: String, timestamp: i64) -> Self {
+        self.id = id;
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Sets the sender's nonce
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    /// Sets the fee kept from the transferred amount
+    pub fn with_fee(mut self, fee: Amount) -> Self {
+        self.fee = fee;
+        self
+    }
+
     /// Creates a deposit transaction
-    pub fn new_deposit(to_address: String, amount: f64) -> Self {
+    pub fn new_deposit(to_address: String, currency: &str, amount: Amount) -> Self {
         Transaction {
             id: Uuid::new_v4().to_string(),
-            from_address: "EXTERNAL".to_string(),
+            from_address: EXTERNAL_ADDRESS.to_string(),
             to_address,
+            currency: currency.to_string(),
             amount,
+            fee: Amount::ZERO,
             timestamp: Utc::now().timestamp(),
             transaction_type: TransactionType::Deposit,
+            nonce: 0,
+            public_key: None,
+            signature: None,
         }
     }
 
     /// Creates a withdrawal transaction
-    pub fn new_withdrawal(from_address: String, amount: f64) -> Self {
+    pub fn new_withdrawal(from_address: String, currency: &str, amount: Amount) -> Self {
         Transaction {
             id: Uuid::new_v4().to_string(),
             from_address,
-            to_address: "EXTERNAL".to_string(),
+            to_address: EXTERNAL_ADDRESS.to_string(),
+            currency: currency.to_string(),
             amount,
+            fee: Amount::ZERO,
             timestamp: Utc::now().timestamp(),
             transaction_type: TransactionType::Withdrawal,
+            nonce: 0,
+            public_key: None,
+            signature: None,
+        }
+    }
+
+    /// Creates the reward paid to the miner of a block, in [`REWARD_CURRENCY`]
+    pub fn new_mining_reward(miner_address: String, amount: Amount) -> Self {
+        Transaction {
+            id: Uuid::new_v4().to_string(),
+            from_address: SYSTEM_ADDRESS.to_string(),
+            to_address: miner_address,
+            currency: REWARD_CURRENCY.to_string(),
+            amount,
+            fee: Amount::ZERO,
+            timestamp: Utc::now().timestamp(),
+            transaction_type: TransactionType::MiningReward,
+            nonce: 0,
+            public_key: None,
+            signature: None,
+        }
+    }
+
+    /// Canonical encoding of everything but the signature, which is what gets signed
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        #[derive(Serialize)]
+        struct Signed<'a> {
+            id: &'a str,
+            from_address: &'a str,
+            to_address: &'a str,
+            currency: &'a str,
+            amount: Amount,
+            fee: Amount,
+            timestamp: i64,
+            transaction_type: &'a TransactionType,
+            nonce: u64,
+            public_key: &'a Option<String>,
         }
+        let signed = Signed {
+            id: &self.id,
+            from_address: &self.from_address,
+            to_address: &self.to_address,
+            currency: &self.currency,
+            amount: self.amount,
+            fee: self.fee,
+            timestamp: self.timestamp,
+            transaction_type: &self.transaction_type,
+            nonce: self.nonce,
+            public_key: &self.public_key,
+        };
+        serde_json::to_vec(&signed).expect("transaction fields always serialize")
+    }
+
+    /// SHA-256 of the whole transaction, signature included, as hex
+    ///
+    /// This is the leaf the transaction contributes to its block's Merkle root.
+    pub fn hash(&self) -> String {
+        let json = serde_json::to_vec(self).expect("transactions always serialize");
+        format!("{:x}", Sha256::digest(json))
+    }
+
+    /// Signs the transaction with the sender's key
+    ///
+    /// Must be called last: changing any field afterwards invalidates the signature.
+    pub fn sign(mut self, key: &SigningKey) -> Self {
+        self.public_key = Some(hex::encode(key.verifying_key().as_bytes()));
+        self.signature = Some(keys::sign(key, &self.signing_bytes()));
+        self
+    }
+
+    /// Returns true for the transactions exempt from nonce and balance checks
+    ///
+    /// Mining rewards come from `SYSTEM` and deposits from `EXTERNAL`; neither
+    /// holds funds or counts its transactions. That does not leave them
+    /// unauthorized, see [`Transaction::verify`].
+    pub fn is_exempt(&self) -> bool {
+        matches!(
+            (self.from_address.as_str(), &self.transaction_type),
+            (SYSTEM_ADDRESS, TransactionType::MiningReward)
+                | (EXTERNAL_ADDRESS, TransactionType::Deposit)
+        )
+    }
+
+    /// Checks that the transaction is signed by whoever may send it
+    ///
+    /// Transactions must be signed by the key their `from_address` is derived
+    /// from. `SYSTEM` and `EXTERNAL` send nothing but rewards and deposits, and
+    /// only they do. Rewards are unsigned, as the block rules bound them.
+    /// Deposits must carry a valid signature too, but by the chain's
+    /// custodian, which only the chain can check, see
+    /// [`Blockchain::custodian`](crate::block::Blockchain::custodian).
+    /// Amounts and fees must lie between zero and [`Amount::MAX`].
+    pub fn verify(&self) -> Result<(), String> {
+        match (self.from_address.as_str(), &self.transaction_type) {
+            (SYSTEM_ADDRESS, TransactionType::MiningReward) => return Ok(()),
+            (EXTERNAL_ADDRESS, TransactionType::Deposit) => {}
+            (SYSTEM_ADDRESS | EXTERNAL_ADDRESS, transaction_type) => {
+                return Err(format!(
+                    "{} cannot send {:?} transactions",
+                    self.from_address, transaction_type
+                ))
+            }
+            (_, TransactionType::MiningReward) => {
+                return Err(format!("Mining rewards must come from {}", SYSTEM_ADDRESS))
+            }
+            (_, TransactionType::Deposit) => {
+                return Err(format!("Deposits must come from {}", EXTERNAL_ADDRESS))
+            }
+            _ => {}
+        }
+
+        for (name, value) in [("amount", self.amount), ("fee", self.fee)] {
+            if value < Amount::ZERO || value > Amount::MAX {
+                return Err(format!(
+                    "Transaction {} has {} {} out of range",
+                    self.id, name, value
+                ));
+            }
+        }
+
+        let (Some(public_key), Some(signature)) = (&self.public_key, &self.signature) else {
+            return Err(format!("Transaction {} is not signed", self.id));
+        };
+        if self.transaction_type != TransactionType::Deposit
+            && keys::address_of(&keys::parse_public_key(public_key)?) != self.from_address
+        {
+            return Err(format!(
+                "Public key of transaction {} does not belong to {}",
+                self.id, self.from_address
+            ));
+        }
+        keys::verify(public_key, &self.signing_bytes(), signature)
+            .map_err(|e| format!("Transaction {}: {}", self.id, e))
     }
 }
 
@@ -79,16 +287,100 @@ mod tests {
 
     #[test]
     fn test_transaction_creation() {
-        let tx = Transaction::new("Alice".to_string(), "Bob".to_string(), 100.0);
+        let tx = Transaction::new(
+            "Alice".to_string(),
+            "Bob".to_string(),
+            "BTC",
+            Amount::from(100),
+        );
         assert_eq!(tx.from_address, "Alice");
         assert_eq!(tx.to_address, "Bob");
-        assert_eq!(tx.amount, 100.0);
+        assert_eq!(tx.amount, Amount::from(100));
         assert_eq!(tx.transaction_type, TransactionType::Transfer);
     }
 
     #[test]
     fn test_trade_transaction() {
-        let tx = Transaction::new_trade("Alice".to_string(), "Bob".to_string(), 50.0);
+        let tx = Transaction::new_trade(
+            "Alice".to_string(),
+            "Bob".to_string(),
+            "BTC",
+            Amount::from(50),
+        );
         assert_eq!(tx.transaction_type, TransactionType::Trade);
+        assert_eq!(tx.fee, Amount::ZERO);
+
+        let tx = tx.with_fee(Amount::new(5, 2));
+        assert_eq!(tx.fee, Amount::new(5, 2));
+    }
+
+    #[test]
+    fn test_signed_transactions_verify() {
+        let key = keys::generate_signing_key();
+        let alice = keys::address_of(&key.verifying_key());
+        let tx =
+            Transaction::new(alice.clone(), "Bob".to_string(), "BTC", Amount::from(10)).sign(&key);
+        assert!(tx.verify().is_ok());
+
+        let unsigned = Transaction::new(alice.clone(), "Bob".to_string(), "BTC", Amount::from(10));
+        assert!(unsigned.verify().unwrap_err().contains("not signed"));
+
+        for amount in [Amount::from(-10), Amount::MAX + Amount::from(1)] {
+            let out_of_range =
+                Transaction::new(alice.clone(), "Bob".to_string(), "BTC", amount).sign(&key);
+            assert!(out_of_range.verify().unwrap_err().contains("out of range"));
+        }
+
+        let replayed_later = tx.clone().with_nonce(1);
+        assert!(replayed_later.verify().is_err());
+
+        let mut tampered = tx.clone();
+        tampered.amount = Amount::from(1000);
+        assert!(tampered
+            .verify()
+            .unwrap_err()
+            .contains("Signature does not match"));
+
+        // Mallory signs with her own key in Alice's name
+        let mallory = keys::generate_signing_key();
+        let forged =
+            Transaction::new(alice, "Mallory".to_string(), "BTC", Amount::from(10)).sign(&mallory);
+        assert!(forged.verify().unwrap_err().contains("does not belong"));
+    }
+
+    #[test]
+    fn test_system_and_external_exemptions() {
+        let reward = Transaction::new_mining_reward("Miner".to_string(), Amount::from(100));
+        assert!(reward.is_exempt());
+        assert!(reward.verify().is_ok());
+        // Deposits are exempt from nonces and balances, not from signing
+        let deposit = Transaction::new_deposit("Alice".to_string(), "BTC", Amount::from(5));
+        assert!(deposit.is_exempt());
+        assert!(deposit.verify().unwrap_err().contains("not signed"));
+        let custodian = keys::generate_signing_key();
+        let signed = deposit.sign(&custodian);
+        assert!(signed.verify().is_ok());
+        let mut inflated = signed.clone();
+        inflated.amount = Amount::from(5000);
+        assert!(inflated.verify().is_err());
+
+        let system_transfer = Transaction::new(
+            SYSTEM_ADDRESS.to_string(),
+            "Miner".to_string(),
+            "BTC",
+            Amount::from(1),
+        );
+        assert!(system_transfer.verify().is_err());
+        let mut fake_deposit =
+            Transaction::new_deposit("Alice".to_string(), "BTC", Amount::from(5));
+        fake_deposit.from_address = "Mallory".to_string();
+        assert!(fake_deposit
+            .verify()
+            .unwrap_err()
+            .contains("Deposits must come from"));
+        // Withdrawals leave from a user's address and must be signed
+        let withdrawal = Transaction::new_withdrawal("Alice".to_string(), "BTC", Amount::from(5));
+        assert!(!withdrawal.is_exempt());
+        assert!(withdrawal.verify().is_err());
     }
 }
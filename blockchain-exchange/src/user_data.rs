@@ -0,0 +1,245 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::amount::{Amount, AssetScales, Rounding};
+use crate::fee::Liquidity;
+use crate::order::{Order, OrderSide, OrderStatus, OrderType, Trade, TradingPair};
+use crate::wallet::Balance;
+
+/// What happened to an order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderEvent {
+    /// The order passed validation and its funds are held
+    Accepted,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Expired,
+}
+
+/// Why a balance changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceReason {
+    Deposit,
+    Withdrawal,
+    Trade,
+    /// Funds held for, or released from, an order that was placed or amended
+    Order,
+    /// Funds released by a cancellation or expiry
+    Cancel,
+}
+
+/// Public state of an order at the time of an event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderUpdate {
+    pub id: String,
+    pub pair: TradingPair,
+    pub side: OrderSide,
+    pub order_type: OrderType,
+    pub status: OrderStatus,
+    pub price: Amount,
+    pub quantity: Amount,
+    pub filled_quantity: Amount,
+    pub remaining_quantity: Amount,
+    /// Volume-weighted price of the fills so far, zero before the first fill
+    pub average_price: Amount,
+    pub timestamp: i64,
+}
+
+impl From<&Order> for OrderUpdate {
+    fn from(order: &Order) -> Self {
+        OrderUpdate {
+            id: order.id.clone(),
+            pair: order.pair.clone(),
+            side: order.side,
+            order_type: order.order_type,
+            status: order.status,
+            price: order.price,
+            quantity: order.quantity,
+            filled_quantity: order.filled_quantity,
+            remaining_quantity: order.remaining_quantity(),
+            average_price: if order.filled_quantity.is_positive() {
+                order.filled_quote.div_round(
+                    order.filled_quantity,
+                    AssetScales::DEFAULT_SCALE,
+                    Rounding::Down,
+                )
+            } else {
+                Amount::ZERO
+            },
+            timestamp: order.timestamp,
+        }
+    }
+}
+
+/// One side of a trade, as seen by the account that owns the order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fill {
+    pub trade_id: String,
+    pub price: Amount,
+    pub quantity: Amount,
+    pub fee: Amount,
+    pub fee_currency: String,
+    pub liquidity: Liquidity,
+}
+
+/// What changed for an account
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UserUpdate {
+    Order {
+        event: OrderEvent,
+        order: Box<OrderUpdate>,
+        /// The trade behind a fill event
+        fill: Option<Fill>,
+    },
+    Balance {
+        currency: String,
+        available: Amount,
+        locked: Amount,
+        reason: BalanceReason,
+    },
+}
+
+/// One change to an account's orders or balances
+///
+/// Sequence numbers count an account's events since the exchange started, so
+/// a gap means a missed event.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserEvent {
+    pub address: String,
+    pub sequence: u64,
+    pub timestamp: i64,
+    #[serde(flatten)]
+    pub update: UserUpdate,
+}
+
+/// Collects account events as the exchange runs
+#[derive(Debug, Clone, Default)]
+pub struct UserFeed {
+    /// Number of events per account
+    sequences: HashMap<String, u64>,
+    events: Vec<UserEvent>,
+}
+
+impl UserFeed {
+    /// Records an order event
+    pub fn order(&mut self, order: &Order, event: OrderEvent, fill: Option<Fill>, now: i64) {
+        let update = UserUpdate::Order {
+            event,
+            order: Box::new(OrderUpdate::from(order)),
+            fill,
+        };
+        self.push(&order.user_address, update, now);
+    }
+
+    /// Records a fill of `order` by `trade`
+    ///
+    /// The event is `Filled` or `PartiallyFilled` after the order's state.
+    pub fn fill(&mut self, order: &Order, trade: &Trade, now: i64) {
+        // Each side pays its fee in the currency it receives
+        let (fee, fee_currency) = match order.side {
+            OrderSide::Buy => (trade.buyer_fee, &trade.pair.base),
+            OrderSide::Sell => (trade.seller_fee, &trade.pair.quote),
+        };
+        let liquidity = if trade.taker_side == order.side {
+            Liquidity::Taker
+        } else {
+            Liquidity::Maker
+        };
+        let event = if order.status == OrderStatus::Filled {
+            OrderEvent::Filled
+        } else {
+            OrderEvent::PartiallyFilled
+        };
+        let fill = Fill {
+            trade_id: trade.id.clone(),
+            price: trade.price,
+            quantity: trade.quantity,
+            fee,
+            fee_currency: fee_currency.clone(),
+            liquidity,
+        };
+        self.order(order, event, Some(fill), now);
+    }
+
+    /// Records the new balance of one currency in an account
+    pub fn balance(
+        &mut self,
+        address: &str,
+        currency: &str,
+        balance: Balance,
+        reason: BalanceReason,
+        now: i64,
+    ) {
+        let update = UserUpdate::Balance {
+            currency: currency.to_string(),
+            available: balance.available,
+            locked: balance.locked,
+            reason,
+        };
+        self.push(address, update, now);
+    }
+
+    /// Takes the events recorded since the last call, oldest first
+    pub fn take_events(&mut self) -> Vec<UserEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn push(&mut self, address: &str, update: UserUpdate, now: i64) {
+        let sequence = self.sequences.entry(address.to_string()).or_default();
+        *sequence += 1;
+        self.events.push(UserEvent {
+            address: address.to_string(),
+            sequence: *sequence,
+            timestamp: now,
+            update,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_serialize_with_type_tag() {
+        let pair = TradingPair::new("BTC", "USDT");
+        let order = Order::new(
+            "alice".to_string(),
+            pair,
+            OrderSide::Buy,
+            Amount::from(100),
+            Amount::from(2),
+        );
+        let mut feed = UserFeed::default();
+        feed.order(&order, OrderEvent::Accepted, None, 10);
+        let balance = Balance {
+            available: Amount::from(800),
+            locked: Amount::from(200),
+        };
+        feed.balance("alice", "USDT", balance, BalanceReason::Order, 10);
+        feed.balance("bob", "BTC", Balance::default(), BalanceReason::Deposit, 11);
+
+        let events = feed.take_events();
+        let json = serde_json::to_value(&events[0]).unwrap();
+        assert_eq!(json["type"], "order");
+        assert_eq!(json["event"], "accepted");
+        assert_eq!(json["sequence"], 1);
+        assert_eq!(json["order"]["remaining_quantity"], "2");
+        assert!(json["fill"].is_null());
+        let json = serde_json::to_value(&events[1]).unwrap();
+        assert_eq!(json["type"], "balance");
+        assert_eq!(json["reason"], "order");
+        assert_eq!(json["locked"], "200");
+        assert_eq!(json["sequence"], 2);
+        // Sequences count per account
+        assert_eq!(events[2].sequence, 1);
+        assert!(feed.take_events().is_empty());
+
+        let parsed: UserEvent = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, events[1]);
+    }
+}
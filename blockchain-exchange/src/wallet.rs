@@ -1,72 +1,169 @@
+use ed25519_dalek::{SigningKey, VerifyingKey};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
-use uuid::Uuid;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::amount::Amount;
+use crate::keys;
+use crate::transaction::Transaction;
+
+/// Balance of a single currency, split into spendable and held funds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Balance {
+    /// Funds that can be traded or withdrawn
+    pub available: Amount,
+    /// Funds held for open orders
+    pub locked: Amount,
+}
+
+impl Balance {
+    /// Returns available plus locked funds
+    pub fn total(&self) -> Amount {
+        self.available + self.locked
+    }
+}
 
 /// Represents a user's wallet
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Wallet {
+    /// Derived from the public key, see [`keys::address_of`]
     pub address: String,
     pub owner: String,
     /// Balances for different cryptocurrencies
-    pub balances: HashMap<String, f64>,
+    pub balances: HashMap<String, Balance>,
+    /// Key the exchange signs the wallet's transactions with
+    #[serde(with = "keys::signing_key_hex")]
+    signing_key: SigningKey,
 }
 
 impl Wallet {
-    /// Creates a new wallet for the given owner
+    /// Creates a new wallet for the given owner, with a freshly generated key
     pub fn new(owner: &str) -> Self {
-        // Generate a unique address based on owner and UUID
-        let unique_data = format!("{}-{}", owner, Uuid::new_v4());
-        let mut hasher = Sha256::new();
-        hasher.update(unique_data.as_bytes());
-        let address = format!("{:x}", hasher.finalize())[..40].to_string();
+        Self::with_key(owner, keys::generate_signing_key())
+    }
+
+    /// Creates a wallet around an existing key, e.g. one read back from a journal
+    pub fn with_key(owner: &str, signing_key: SigningKey) -> Self {
+        let address = keys::address_of(&signing_key.verifying_key());
 
         let mut balances = HashMap::new();
-        balances.insert("BTC".to_string(), 0.0);
-        balances.insert("ETH".to_string(), 0.0);
-        balances.insert("USDT".to_string(), 0.0);
+        balances.insert("BTC".to_string(), Balance::default());
+        balances.insert("ETH".to_string(), Balance::default());
+        balances.insert("USDT".to_string(), Balance::default());
 
         Wallet {
             address,
             owner: owner.to_string(),
             balances,
+            signing_key,
+        }
+    }
+
+    pub fn public_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    /// Signs a transaction sent from this wallet
+    pub fn sign(&self, transaction: Transaction) -> Result<Transaction, String> {
+        if transaction.from_address != self.address {
+            return Err(format!(
+                "Wallet {} cannot sign for {}",
+                self.address, transaction.from_address
+            ));
         }
+        Ok(transaction.sign(&self.signing_key))
     }
 
-    /// Gets the balance for a specific cryptocurrency
-    pub fn get_balance(&self, currency: &str) -> f64 {
-        *self.balances.get(currency).unwrap_or(&0.0)
+    /// Gets the available balance for a specific cryptocurrency
+    pub fn get_balance(&self, currency: &str) -> Amount {
+        self.balance(currency).available
+    }
+
+    /// Gets the balance held for open orders in a specific cryptocurrency
+    pub fn get_locked_balance(&self, currency: &str) -> Amount {
+        self.balance(currency).locked
+    }
+
+    /// Gets the full available/locked balance for a specific cryptocurrency
+    pub fn balance(&self, currency: &str) -> Balance {
+        self.balances.get(currency).copied().unwrap_or_default()
     }
 
     /// Deposits an amount of a specific cryptocurrency
-    pub fn deposit(&mut self, currency: &str, amount: f64) -> Result<(), String> {
-        if amount <= 0.0 {
+    pub fn deposit(&mut self, currency: &str, amount: Amount) -> Result<(), String> {
+        if !amount.is_positive() {
             return Err("Deposit amount must be positive".to_string());
         }
-        let balance = self.balances.entry(currency.to_string()).or_insert(0.0);
-        *balance += amount;
+        let balance = self.balances.entry(currency.to_string()).or_default();
+        balance.available = balance.available.checked_add(amount)?;
         Ok(())
     }
 
     /// Withdraws an amount of a specific cryptocurrency
-    pub fn withdraw(&mut self, currency: &str, amount: f64) -> Result<(), String> {
-        if amount <= 0.0 {
+    pub fn withdraw(&mut self, currency: &str, amount: Amount) -> Result<(), String> {
+        if !amount.is_positive() {
             return Err("Withdrawal amount must be positive".to_string());
         }
-        let balance = self.balances.entry(currency.to_string()).or_insert(0.0);
-        if *balance < amount {
+        let balance = self.balances.entry(currency.to_string()).or_default();
+        if balance.available < amount {
             return Err(format!("Insufficient {} balance", currency));
         }
-        *balance -= amount;
+        balance.available -= amount;
+        Ok(())
+    }
+
+    /// Moves funds from available to locked
+    pub fn hold(&mut self, currency: &str, amount: Amount) -> Result<(), String> {
+        if amount < Amount::ZERO {
+            return Err("Hold amount cannot be negative".to_string());
+        }
+        let balance = self.balances.entry(currency.to_string()).or_default();
+        if balance.available < amount {
+            return Err(format!(
+                "Insufficient {} balance. Required: {}, Available: {}",
+                currency, amount, balance.available
+            ));
+        }
+        balance.locked = balance.locked.checked_add(amount)?;
+        balance.available -= amount;
+        Ok(())
+    }
+
+    /// Moves held funds back to available
+    pub fn release(&mut self, currency: &str, amount: Amount) -> Result<(), String> {
+        let balance = self.locked_entry(currency, amount)?;
+        balance.available = balance.available.checked_add(amount)?;
+        balance.locked -= amount;
         Ok(())
     }
 
+    /// Removes held funds from the wallet, e.g. when they are paid out in a trade
+    pub fn settle(&mut self, currency: &str, amount: Amount) -> Result<(), String> {
+        let balance = self.locked_entry(currency, amount)?;
+        balance.locked -= amount;
+        Ok(())
+    }
+
+    /// Returns the balance entry if at least `amount` of it is locked
+    fn locked_entry(&mut self, currency: &str, amount: Amount) -> Result<&mut Balance, String> {
+        if amount < Amount::ZERO {
+            return Err("Amount cannot be negative".to_string());
+        }
+        let balance = self.balances.entry(currency.to_string()).or_default();
+        if balance.locked < amount {
+            return Err(format!(
+                "Insufficient locked {} balance. Required: {}, Locked: {}",
+                currency, amount, balance.locked
+            ));
+        }
+        Ok(balance)
+    }
+
     /// Transfers an amount to another wallet
     pub fn transfer(
         &mut self,
         to_wallet: &mut Wallet,
         currency: &str,
-        amount: f64,
+        amount: Amount,
     ) -> Result<(), String> {
         self.withdraw(currency, amount)?;
         to_wallet.deposit(currency, amount)?;
@@ -78,18 +175,45 @@ impl Wallet {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WalletManager {
     wallets: HashMap<String, Wallet>,
+    /// Wallet that collects trading fees
+    fee_address: String,
+    /// Key that signs deposits, as the custodian of the exchange's chain
+    #[serde(with = "keys::signing_key_hex")]
+    custodian: SigningKey,
+    /// Balances changed since the last `take_changed_balances`, as (address, currency)
+    #[serde(skip)]
+    changed: BTreeSet<(String, String)>,
 }
 
 impl WalletManager {
     pub fn new() -> Self {
+        Self::with_keys(Wallet::new("Exchange fees"), keys::generate_signing_key())
+    }
+
+    /// Creates a wallet manager that collects fees into `fee_wallet` and signs
+    /// deposits with `custodian`
+    pub fn with_keys(fee_wallet: Wallet, custodian: SigningKey) -> Self {
+        let fee_address = fee_wallet.address.clone();
         WalletManager {
-            wallets: HashMap::new(),
+            wallets: HashMap::from([(fee_address.clone(), fee_wallet)]),
+            fee_address,
+            custodian,
+            changed: BTreeSet::new(),
         }
     }
 
+    /// Address of the wallet that collects trading fees
+    pub fn fee_address(&self) -> &str {
+        &self.fee_address
+    }
+
     /// Creates a new wallet for the owner
     pub fn create_wallet(&mut self, owner: &str) -> String {
-        let wallet = Wallet::new(owner);
+        self.add_wallet(Wallet::new(owner))
+    }
+
+    /// Adds a wallet and returns its address
+    pub fn add_wallet(&mut self, wallet: Wallet) -> String {
         let address = wallet.address.clone();
         self.wallets.insert(address.clone(), wallet);
         address
@@ -100,27 +224,117 @@ impl WalletManager {
         self.wallets.get(address)
     }
 
+    /// Signs a transaction with the wallet it is sent from
+    pub fn sign(&self, transaction: Transaction) -> Result<Transaction, String> {
+        self.get_wallet(&transaction.from_address)
+            .ok_or_else(|| format!("Wallet {} not found", transaction.from_address))?
+            .sign(transaction)
+    }
+
+    /// Public key deposits are signed with
+    pub fn custodian(&self) -> VerifyingKey {
+        self.custodian.verifying_key()
+    }
+
+    /// Signs a deposit into the exchange
+    pub fn sign_deposit(&self, transaction: Transaction) -> Transaction {
+        transaction.sign(&self.custodian)
+    }
+
     /// Gets a mutable reference to a wallet by address
     pub fn get_wallet_mut(&mut self, address: &str) -> Option<&mut Wallet> {
         self.wallets.get_mut(address)
     }
 
+    /// All wallets, including the fee wallet, in no particular order
+    pub fn wallets(&self) -> impl Iterator<Item = &Wallet> + '_ {
+        self.wallets.values()
+    }
+
     /// Deposits to a wallet
-    pub fn deposit(&mut self, address: &str, currency: &str, amount: f64) -> Result<(), String> {
+    pub fn deposit(
+        &mut self,
+        address: &str,
+        currency: &str,
+        amount: Amount,
+    ) -> Result<(), String> {
         let wallet = self
             .wallets
             .get_mut(address)
             .ok_or("Wallet not found")?;
-        wallet.deposit(currency, amount)
+        wallet.deposit(currency, amount)?;
+        self.touch(address, currency);
+        Ok(())
     }
 
     /// Withdraws from a wallet
-    pub fn withdraw(&mut self, address: &str, currency: &str, amount: f64) -> Result<(), String> {
+    pub fn withdraw(
+        &mut self,
+        address: &str,
+        currency: &str,
+        amount: Amount,
+    ) -> Result<(), String> {
         let wallet = self
             .wallets
             .get_mut(address)
             .ok_or("Wallet not found")?;
-        wallet.withdraw(currency, amount)
+        wallet.withdraw(currency, amount)?;
+        self.touch(address, currency);
+        Ok(())
+    }
+
+    /// Locks funds in a wallet
+    pub fn hold(&mut self, address: &str, currency: &str, amount: Amount) -> Result<(), String> {
+        let wallet = self
+            .wallets
+            .get_mut(address)
+            .ok_or("Wallet not found")?;
+        wallet.hold(currency, amount)?;
+        self.touch(address, currency);
+        Ok(())
+    }
+
+    /// Unlocks funds in a wallet
+    pub fn release(
+        &mut self,
+        address: &str,
+        currency: &str,
+        amount: Amount,
+    ) -> Result<(), String> {
+        let wallet = self
+            .wallets
+            .get_mut(address)
+            .ok_or("Wallet not found")?;
+        wallet.release(currency, amount)?;
+        self.touch(address, currency);
+        Ok(())
+    }
+
+    /// Pays out locked funds from a wallet
+    pub fn settle(&mut self, address: &str, currency: &str, amount: Amount) -> Result<(), String> {
+        let wallet = self
+            .wallets
+            .get_mut(address)
+            .ok_or("Wallet not found")?;
+        wallet.settle(currency, amount)?;
+        self.touch(address, currency);
+        Ok(())
+    }
+
+    /// Credits a trading fee to the fee wallet
+    pub fn collect_fee(&mut self, currency: &str, amount: Amount) -> Result<(), String> {
+        let fee_address = self.fee_address.clone();
+        self.deposit(&fee_address, currency, amount)
+    }
+
+    /// Takes the balances changed since the last call, as (address, currency)
+    pub fn take_changed_balances(&mut self) -> Vec<(String, String)> {
+        std::mem::take(&mut self.changed).into_iter().collect()
+    }
+
+    fn touch(&mut self, address: &str, currency: &str) {
+        self.changed
+            .insert((address.to_string(), currency.to_string()));
     }
 }
 
@@ -138,24 +352,44 @@ mod tests {
     fn test_wallet_creation() {
         let wallet = Wallet::new("Alice");
         assert_eq!(wallet.owner, "Alice");
-        assert_eq!(wallet.get_balance("BTC"), 0.0);
+        assert_eq!(wallet.get_balance("BTC"), Amount::ZERO);
+        assert_eq!(wallet.address, keys::address_of(&wallet.public_key()));
+    }
+
+    #[test]
+    fn test_wallet_signs_its_own_transactions() {
+        let wallet = Wallet::new("Alice");
+        assert_ne!(wallet.address, Wallet::new("Alice").address);
+        let key = keys::generate_signing_key();
+        let keyed = Wallet::with_key("Alice", key.clone());
+        assert_eq!(keyed.address, keys::address_of(&key.verifying_key()));
+
+        let tx = Transaction::new_withdrawal(wallet.address.clone(), "BTC", Amount::from(1));
+        assert!(wallet.sign(tx).unwrap().verify().is_ok());
+        let other = Transaction::new_withdrawal("Bob".to_string(), "BTC", Amount::from(1));
+        assert!(wallet.sign(other).is_err());
+
+        // The key survives serialization, e.g. in snapshots
+        let json = serde_json::to_string(&wallet).unwrap();
+        let restored: Wallet = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.public_key(), wallet.public_key());
     }
 
     #[test]
     fn test_deposit_withdraw() {
         let mut wallet = Wallet::new("Bob");
-        wallet.deposit("BTC", 10.0).unwrap();
-        assert_eq!(wallet.get_balance("BTC"), 10.0);
+        wallet.deposit("BTC", Amount::from(10)).unwrap();
+        assert_eq!(wallet.get_balance("BTC"), Amount::from(10));
 
-        wallet.withdraw("BTC", 3.0).unwrap();
-        assert_eq!(wallet.get_balance("BTC"), 7.0);
+        wallet.withdraw("BTC", Amount::from(3)).unwrap();
+        assert_eq!(wallet.get_balance("BTC"), Amount::from(7));
     }
 
     #[test]
     fn test_insufficient_balance() {
         let mut wallet = Wallet::new("Charlie");
-        wallet.deposit("ETH", 5.0).unwrap();
-        let result = wallet.withdraw("ETH", 10.0);
+        wallet.deposit("ETH", Amount::from(5)).unwrap();
+        let result = wallet.withdraw("ETH", Amount::from(10));
         assert!(result.is_err());
     }
 
@@ -164,10 +398,40 @@ mod tests {
         let mut alice = Wallet::new("Alice");
         let mut bob = Wallet::new("Bob");
 
-        alice.deposit("USDT", 100.0).unwrap();
-        alice.transfer(&mut bob, "USDT", 30.0).unwrap();
+        alice.deposit("USDT", Amount::from(100)).unwrap();
+        alice.transfer(&mut bob, "USDT", Amount::from(30)).unwrap();
+
+        assert_eq!(alice.get_balance("USDT"), Amount::from(70));
+        assert_eq!(bob.get_balance("USDT"), Amount::from(30));
+    }
+
+    #[test]
+    fn test_hold_release_settle() {
+        let mut wallet = Wallet::new("Dave");
+        wallet.deposit("USDT", Amount::from(100)).unwrap();
+
+        wallet.hold("USDT", Amount::from(60)).unwrap();
+        assert_eq!(wallet.get_balance("USDT"), Amount::from(40));
+        assert_eq!(wallet.get_locked_balance("USDT"), Amount::from(60));
+        assert!(wallet.hold("USDT", Amount::from(50)).is_err());
+        assert!(wallet.withdraw("USDT", Amount::from(50)).is_err());
+
+        wallet.settle("USDT", Amount::from(25)).unwrap();
+        wallet.release("USDT", Amount::from(35)).unwrap();
+        assert_eq!(wallet.get_balance("USDT"), Amount::from(75));
+        assert_eq!(wallet.get_locked_balance("USDT"), Amount::ZERO);
+        assert!(wallet.release("USDT", Amount::from(1)).is_err());
+    }
+
+    #[test]
+    fn test_fee_wallet() {
+        let mut manager = WalletManager::new();
+        let fee_address = manager.fee_address().to_string();
+        assert!(manager.get_wallet(&fee_address).is_some());
 
-        assert_eq!(alice.get_balance("USDT"), 70.0);
-        assert_eq!(bob.get_balance("USDT"), 30.0);
+        manager.collect_fee("USDT", Amount::from(3)).unwrap();
+        manager.collect_fee("USDT", Amount::from(2)).unwrap();
+        let wallet = manager.get_wallet(&fee_address).unwrap();
+        assert_eq!(wallet.get_balance("USDT"), Amount::from(5));
     }
 }
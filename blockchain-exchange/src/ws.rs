@@ -0,0 +1,529 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::api::{lock, supported_pair, ApiError, ApiState};
+use crate::exchange::Exchange;
+use crate::market_data::{Channel, MarketEvent, MarketUpdate};
+use crate::order::TradingPair;
+use crate::user_data::UserEvent;
+
+/// Trades sent in a `trades` snapshot
+const SNAPSHOT_TRADES: usize = 50;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum ClientMessage {
+    Subscribe { channel: Channel, pair: String },
+    Unsubscribe { channel: Channel, pair: String },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ServerMessage {
+    Snapshot(ChannelData),
+    Update(ChannelData),
+    Unsubscribed { channel: Channel, pair: String },
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize)]
+struct ChannelData {
+    channel: Channel,
+    pair: String,
+    sequence: u64,
+    data: Value,
+}
+
+/// Upgrades `/ws` requests to market data connections
+///
+/// Clients send `{"op": "subscribe", "channel": "depth", "pair": "BTC-USDT"}`
+/// (or `"unsubscribe"`) for the `trades`, `ticker` and `depth` channels. A
+/// subscription starts with a `snapshot` message and continues with `update`
+/// messages, all shaped as
+/// `{"type", "channel", "pair", "sequence", "data"}`. Updates are only sent
+/// when newer than the last message of their subscription, so applying them
+/// in order on top of the snapshot tracks the exchange. A client that falls
+/// too far behind is sent fresh snapshots for all its subscriptions.
+pub(crate) async fn market_data(ws: WebSocketUpgrade, State(state): State<ApiState>) -> Response {
+    ws.on_upgrade(move |socket| serve(socket, state))
+}
+
+/// Sequence of the last message sent, per subscribed pair and channel
+type Subscriptions = HashMap<(TradingPair, Channel), u64>;
+
+async fn serve(mut socket: WebSocket, state: ApiState) {
+    // Subscribe before any snapshot is taken, so no later event is missed
+    let mut events = state.events.market_data.subscribe();
+    let mut subscriptions = Subscriptions::new();
+
+    loop {
+        let outgoing = tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    handle_request(&text, &state, &mut subscriptions)
+                }
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return,
+                Some(Ok(_)) => continue,
+            },
+            event = events.recv() => match event {
+                Ok(event) => forward(&event, &mut subscriptions).into_iter().collect(),
+                Err(RecvError::Lagged(_)) => resync(&state, &mut subscriptions),
+                Err(RecvError::Closed) => return,
+            },
+        };
+
+        for message in outgoing {
+            let text = serde_json::to_string(&message).unwrap_or_default();
+            if socket.send(Message::Text(text)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Upgrades `/ws/user` requests to an account's private event stream
+///
+/// The request must carry `Authorization: Bearer <api key>`. The stream sends
+/// the account's events as they happen: `{"type": "order", "event", "order",
+/// "fill"}` when an order is accepted, fills, is cancelled or expires, and
+/// `{"type": "balance", "currency", "available", "locked", "reason"}` after a
+/// balance changes. Every event carries the account's `address`, `sequence`
+/// and `timestamp`; sequences have no gaps, so they can be checked against
+/// each other once connected. A client that falls too far behind is sent an
+/// error and disconnected, and should reload its state over HTTP.
+pub(crate) async fn user_data(
+    ws: WebSocketUpgrade,
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let address = state.authenticate(&headers)?;
+    // Subscribe before the handshake completes, so the client sees every
+    // event published after it connected
+    let events = state.events.user_data.subscribe();
+    Ok(ws.on_upgrade(move |socket| serve_user(socket, events, address)))
+}
+
+async fn serve_user(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<UserEvent>,
+    address: String,
+) {
+    loop {
+        let event: UserEvent = tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return,
+                Some(Ok(_)) => continue,
+            },
+            event = events.recv() => match event {
+                Ok(event) if event.address == address => event,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(_)) => {
+                    let message = error("Fell behind the event stream".to_string());
+                    let text = serde_json::to_string(&message).unwrap_or_default();
+                    let _ = socket.send(Message::Text(text)).await;
+                    return;
+                }
+                Err(RecvError::Closed) => return,
+            },
+        };
+
+        let text = serde_json::to_string(&event).unwrap_or_default();
+        if socket.send(Message::Text(text)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Handles a subscribe or unsubscribe request
+fn handle_request(
+    text: &str,
+    state: &ApiState,
+    subscriptions: &mut Subscriptions,
+) -> Vec<ServerMessage> {
+    let request: ClientMessage = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(e) => return vec![error(format!("Invalid request: {}", e))],
+    };
+
+    let exchange = match lock(&state.exchange) {
+        Ok(exchange) => exchange,
+        Err(e) => return vec![error(e.message)],
+    };
+    let (channel, symbol, subscribe) = match request {
+        ClientMessage::Subscribe { channel, pair } => (channel, pair, true),
+        ClientMessage::Unsubscribe { channel, pair } => (channel, pair, false),
+    };
+    let pair = match supported_pair(&exchange, &symbol) {
+        Ok(pair) => pair,
+        Err(ApiError { message, .. }) => return vec![error(message)],
+    };
+
+    if subscribe {
+        let snapshot = snapshot(&exchange, &pair, channel);
+        subscriptions.insert((pair, channel), snapshot.sequence);
+        vec![ServerMessage::Snapshot(snapshot)]
+    } else {
+        subscriptions.remove(&(pair, channel));
+        vec![ServerMessage::Unsubscribed {
+            channel,
+            pair: symbol,
+        }]
+    }
+}
+
+/// Current state of one channel of a pair
+fn snapshot(exchange: &Exchange, pair: &TradingPair, channel: Channel) -> ChannelData {
+    let (sequence, data) = match channel {
+        Channel::Trades => {
+            let mut trades: Vec<_> = exchange
+                .trades
+                .iter()
+                .rev()
+                .filter(|trade| trade.pair == *pair)
+                .take(SNAPSHOT_TRADES)
+                .collect();
+            trades.reverse();
+            (exchange.trade_sequence(pair), to_value(&trades))
+        }
+        Channel::Ticker => {
+            let sequence = exchange
+                .get_order_book(pair)
+                .map(|book| book.level_sequence());
+            (
+                sequence.unwrap_or_default(),
+                to_value(&exchange.book_ticker(pair)),
+            )
+        }
+        Channel::Depth => {
+            let depth = exchange.depth(pair, usize::MAX);
+            let sequence = depth.as_ref().map(|depth| depth.sequence);
+            (sequence.unwrap_or_default(), to_value(&depth))
+        }
+    };
+    ChannelData {
+        channel,
+        pair: symbol(pair),
+        sequence,
+        data,
+    }
+}
+
+/// Turns an event into an update for the subscription it belongs to, if any
+/// and if the subscription has not seen it yet
+fn forward(event: &MarketEvent, subscriptions: &mut Subscriptions) -> Option<ServerMessage> {
+    let key = (event.pair.clone(), event.channel());
+    let last_sequence = subscriptions.get_mut(&key)?;
+    if event.sequence <= *last_sequence {
+        return None;
+    }
+    *last_sequence = event.sequence;
+
+    let data = match &event.update {
+        MarketUpdate::Trades(trade) => to_value(trade),
+        MarketUpdate::Ticker(ticker) => to_value(ticker),
+        MarketUpdate::Depth(update) => to_value(update),
+    };
+    Some(ServerMessage::Update(ChannelData {
+        channel: event.channel(),
+        pair: symbol(&event.pair),
+        sequence: event.sequence,
+        data,
+    }))
+}
+
+/// Sends fresh snapshots for every subscription after missed events
+fn resync(state: &ApiState, subscriptions: &mut Subscriptions) -> Vec<ServerMessage> {
+    let exchange = match lock(&state.exchange) {
+        Ok(exchange) => exchange,
+        Err(e) => return vec![error(e.message)],
+    };
+    subscriptions
+        .iter_mut()
+        .map(|((pair, channel), last_sequence)| {
+            let snapshot = snapshot(&exchange, pair, *channel);
+            *last_sequence = snapshot.sequence;
+            ServerMessage::Snapshot(snapshot)
+        })
+        .collect()
+}
+
+fn error(message: String) -> ServerMessage {
+    ServerMessage::Error { message }
+}
+
+/// A pair as it appears in requests, e.g. `BTC-USDT`
+fn symbol(pair: &TradingPair) -> String {
+    format!("{}-{}", pair.base, pair.quote)
+}
+
+fn to_value<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::api::{router, SharedExchange};
+    use crate::depth::{DepthReplica, DepthSnapshot, LevelUpdate};
+    use crate::order::OrderSide;
+    use axum::body::Body;
+    use axum::http::Request;
+    use axum::Router;
+    use futures_util::{SinkExt, StreamExt};
+    use http_body_util::BodyExt;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::Error as WsError;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+    use tower::ServiceExt;
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Operator key of the test router
+    const OPERATOR_KEY: &str = "operator-secret";
+
+    /// Serves the API on a free port and returns it with the router and the exchange
+    async fn start() -> (Router, SharedExchange, String) {
+        let exchange = Arc::new(Mutex::new(Exchange::new("WsExchange")));
+        let app = router(exchange.clone(), OPERATOR_KEY);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let server = app.clone();
+        tokio::spawn(async move { axum::serve(listener, server).await.unwrap() });
+        (app, exchange, url)
+    }
+
+    async fn send(client: &mut Client, request: Value) {
+        let text = request.to_string();
+        client
+            .send(tokio_tungstenite::tungstenite::Message::Text(text))
+            .await
+            .unwrap();
+    }
+
+    async fn receive(client: &mut Client) -> Value {
+        let message = client.next().await.unwrap().unwrap();
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    /// A wallet created through the API, with the key that acts for it
+    struct Account {
+        address: String,
+        api_key: String,
+    }
+
+    /// Posts JSON to the API and returns the JSON response (null when empty)
+    async fn post(app: &Router, api_key: Option<&str>, uri: &str, body: Value) -> Value {
+        let mut request = Request::post(uri).header("content-type", "application/json");
+        if let Some(key) = api_key {
+            request = request.header("authorization", format!("Bearer {}", key));
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert!(response.status().is_success());
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap_or(Value::Null)
+    }
+
+    async fn account(app: &Router, owner: &str) -> Account {
+        let body = post(app, None, "/wallets", json!({ "owner": owner })).await;
+        Account {
+            address: body["address"].as_str().unwrap().to_string(),
+            api_key: body["api_key"].as_str().unwrap().to_string(),
+        }
+    }
+
+    async fn deposit(app: &Router, account: &Account, currency: &str, amount: i64) {
+        let uri = format!("/wallets/{}/deposits", account.address);
+        let funds = json!({ "currency": currency, "amount": amount });
+        post(app, Some(OPERATOR_KEY), &uri, funds).await;
+    }
+
+    async fn place(app: &Router, user: &Account, side: OrderSide, price: i64, quantity: i64) {
+        let body = json!({
+            "user_address": user.address,
+            "side": side,
+            "price": price,
+            "quantity": quantity,
+        });
+        post(app, Some(&user.api_key), "/pairs/BTC-USDT/orders", body).await;
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_then_sequenced_updates() {
+        let (app, exchange, url) = start().await;
+        let alice = account(&app, "Alice").await;
+        let bob = account(&app, "Bob").await;
+        deposit(&app, &alice, "USDT", 100000).await;
+        deposit(&app, &bob, "BTC", 10).await;
+        // Resting before anyone subscribes, so it arrives in the snapshot
+        place(&app, &bob, OrderSide::Sell, 101, 3).await;
+
+        let (mut client, _) = connect_async(&url).await.unwrap();
+        for channel in ["depth", "trades", "ticker"] {
+            let request = json!({ "op": "subscribe", "channel": channel, "pair": "BTC-USDT" });
+            send(&mut client, request).await;
+        }
+        let depth = receive(&mut client).await;
+        assert_eq!(depth["type"], "snapshot");
+        assert_eq!(depth["channel"], "depth");
+        let snapshot: DepthSnapshot = serde_json::from_value(depth["data"].clone()).unwrap();
+        let mut replica = DepthReplica::from_snapshot(&snapshot);
+        assert_eq!(replica.asks().len(), 1);
+        let trades = receive(&mut client).await;
+        assert_eq!(
+            (trades["channel"].clone(), trades["sequence"].clone()),
+            ("trades".into(), 0.into())
+        );
+        let ticker = receive(&mut client).await;
+        assert_eq!(ticker["data"]["best_ask"]["price"], "101");
+
+        place(&app, &alice, OrderSide::Buy, 100, 1).await;
+        place(&app, &alice, OrderSide::Buy, 101, 2).await;
+
+        // Bid insert + ticker, then the trade, the ask update and a ticker
+        let mut trade_sequences = vec![];
+        let mut tickers = vec![];
+        for _ in 0..5 {
+            let message = receive(&mut client).await;
+            assert_eq!(message["type"], "update");
+            match message["channel"].as_str().unwrap() {
+                "depth" => {
+                    let update: LevelUpdate =
+                        serde_json::from_value(message["data"].clone()).unwrap();
+                    replica.apply(&update).unwrap();
+                }
+                "trades" => trade_sequences.push(message["sequence"].as_u64().unwrap()),
+                _ => tickers.push(message["data"].clone()),
+            }
+        }
+        assert_eq!(trade_sequences, vec![1]);
+        assert_eq!(tickers.last().unwrap()["last_price"], "101");
+
+        let current = exchange
+            .lock()
+            .unwrap()
+            .depth(&TradingPair::new("BTC", "USDT"), usize::MAX);
+        let current = current.unwrap();
+        assert_eq!(replica.sequence(), current.sequence);
+        assert_eq!(replica.bids(), current.bids);
+        assert_eq!(replica.asks(), current.asks);
+
+        // After unsubscribing, depth updates stop but trades keep coming
+        send(
+            &mut client,
+            json!({ "op": "unsubscribe", "channel": "depth", "pair": "BTC-USDT" }),
+        )
+        .await;
+        assert_eq!(receive(&mut client).await["type"], "unsubscribed");
+        send(
+            &mut client,
+            json!({ "op": "unsubscribe", "channel": "ticker", "pair": "BTC-USDT" }),
+        )
+        .await;
+        receive(&mut client).await;
+        place(&app, &alice, OrderSide::Buy, 101, 1).await;
+        let message = receive(&mut client).await;
+        assert_eq!(message["channel"], "trades");
+        assert_eq!(message["sequence"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests_get_errors() {
+        let (_, _, url) = start().await;
+        let (mut client, _) = connect_async(&url).await.unwrap();
+
+        send(
+            &mut client,
+            json!({ "op": "subscribe", "channel": "depth", "pair": "DOGE-USDT" }),
+        )
+        .await;
+        let message = receive(&mut client).await;
+        assert_eq!(message["type"], "error");
+        assert!(message["message"]
+            .as_str()
+            .unwrap()
+            .contains("not supported"));
+
+        send(
+            &mut client,
+            json!({ "op": "subscribe", "channel": "candles", "pair": "BTC-USDT" }),
+        )
+        .await;
+        assert_eq!(receive(&mut client).await["type"], "error");
+    }
+
+    #[tokio::test]
+    async fn test_user_stream_sends_own_events_to_key_holder() {
+        let (app, _, url) = start().await;
+        let alice = account(&app, "Alice").await;
+        let bob = account(&app, "Bob").await;
+        deposit(&app, &bob, "BTC", 1).await;
+
+        let user_url = format!("{}/user", url);
+        for key in [None, Some("not-a-key")] {
+            let mut request = user_url.as_str().into_client_request().unwrap();
+            if let Some(key) = key {
+                let value = format!("Bearer {}", key).parse().unwrap();
+                request.headers_mut().insert("authorization", value);
+            }
+            let error = connect_async(request).await.unwrap_err();
+            assert!(matches!(error, WsError::Http(response) if response.status() == 401));
+        }
+        let mut request = user_url.as_str().into_client_request().unwrap();
+        let value = format!("Bearer {}", alice.api_key);
+        request
+            .headers_mut()
+            .insert("authorization", value.parse().unwrap());
+        let (mut client, _) = connect_async(request).await.unwrap();
+
+        deposit(&app, &alice, "USDT", 1000).await;
+        place(&app, &bob, OrderSide::Sell, 100, 1).await;
+        place(&app, &alice, OrderSide::Buy, 100, 1).await;
+
+        let mut received = vec![];
+        for _ in 0..6 {
+            received.push(receive(&mut client).await);
+        }
+        for (i, event) in received.iter().enumerate() {
+            assert_eq!(event["address"], alice.address.as_str());
+            let sequence = received[0]["sequence"].as_u64().unwrap() + i as u64;
+            assert_eq!(event["sequence"], sequence);
+        }
+        let kinds: Vec<_> = received
+            .iter()
+            .map(|event| {
+                let text = |field: &str| event[field].as_str().unwrap().to_string();
+                match text("type").as_str() {
+                    "order" => format!("order {}", text("event")),
+                    _ => format!("{} {}", text("currency"), text("reason")),
+                }
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                "USDT deposit",
+                "order accepted",
+                "USDT order",
+                "order filled",
+                "BTC trade",
+                "USDT trade",
+            ]
+        );
+        assert_eq!(received[3]["fill"]["liquidity"], "Taker");
+        assert_eq!(received[3]["order"]["status"], "Filled");
+        let usdt: Amount = serde_json::from_value(received[5]["available"].clone()).unwrap();
+        assert_eq!(usdt, Amount::from(900));
+    }
+}
@@ -0,0 +1,85 @@
+//! Nodes syncing and gossiping over real TCP connections
+
+use std::time::Duration;
+
+use blockchain_exchange::amount::Amount;
+use blockchain_exchange::block::BlockOutcome;
+use blockchain_exchange::p2p::{self, Node};
+use blockchain_exchange::transaction::Transaction;
+use ed25519_dalek::SigningKey;
+use tokio::net::TcpListener;
+use tokio::time;
+
+/// Key the test nodes take deposits from
+fn custodian() -> SigningKey {
+    SigningKey::from_bytes(&[7; 32])
+}
+
+/// Serves a node on a free port and returns it with its address
+async fn start() -> (Node, String) {
+    let node = Node::new(p2p::new_chain().with_custodian(&custodian().verifying_key()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(node.clone().serve(listener));
+    (node, address)
+}
+
+async fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = time::Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(
+            time::Instant::now() < deadline,
+            "timed out waiting until {}",
+            what
+        );
+        time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+fn tip(node: &Node) -> String {
+    node.chain().get_latest_block().hash.clone()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_nodes_sync_gossip_and_follow_the_heaviest_chain() {
+    let (a, a_address) = start().await;
+    a.mine("MinerA").await.unwrap();
+    a.mine("MinerA").await.unwrap();
+
+    // B syncs from A as it connects, and C, behind B, hears of the blocks from B
+    let (b, b_address) = start().await;
+    let (c, c_address) = start().await;
+    b.connect(&a_address).await.unwrap();
+    c.connect(&b_address).await.unwrap();
+    wait_until("C has A's blocks", || tip(&c) == tip(&a)).await;
+    assert_eq!(c.chain().chain.len(), 3);
+
+    // A transaction submitted to C reaches A through B
+    let deposit = Transaction::new_deposit("Alice".to_string(), "BTC", Amount::from(5));
+    let deposit = deposit.sign(&custodian());
+    c.submit_transaction(deposit.clone()).unwrap();
+    let pending = |node: &Node, id: &str| {
+        let chain = node.chain();
+        chain.mempool.transactions().iter().any(|tx| tx.id == id)
+    };
+    wait_until("A has the deposit", || pending(&a, &deposit.id)).await;
+    assert_eq!(a.mine("MinerA").await, Ok(BlockOutcome::Extended));
+    wait_until("C mined the deposit", || tip(&c) == tip(&a)).await;
+    assert_eq!(c.chain().get_balance("Alice", "BTC"), Amount::from(5));
+    assert!(!pending(&c, &deposit.id));
+
+    // D mined a longer chain alone; once connected, everyone switches to it
+    let (d, _) = start().await;
+    for _ in 0..5 {
+        d.mine("MinerD").await.unwrap();
+    }
+    d.connect(&c_address).await.unwrap();
+    for node in [&a, &b, &c] {
+        wait_until("the nodes follow D", || tip(node) == tip(&d)).await;
+        assert!(node.chain().is_valid());
+        // The deposit was only on the abandoned branch
+        assert_eq!(node.chain().get_balance("Alice", "BTC"), Amount::ZERO);
+    }
+    wait_until("A has the deposit again", || pending(&a, &deposit.id)).await;
+    assert_eq!(b.peer_count(), 2);
+}